@@ -0,0 +1,245 @@
+// Stand-in for a rosbridge server: a JSON over WebSocket broker relaying publish messages to the
+// subscribers of their topic, and a publisher of a synthetic sensor_msgs/JointState.
+// Run cmd: cargo run --features ros --bin mock_rosbridge -- [port] [--relay-only]
+// The joint states wave the joints of robot0_ and robot1_ around the default pose.
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tungstenite::{error::Error, Message};
+
+const DEFAULT_PORT: u16 = 9090;
+const JOINT_STATES_TOPIC: &str = "/joint_states";
+const JOINT_STATES_PERIOD: Duration = Duration::from_millis(20);
+const PREFIXES: [&str; 2] = ["robot0_", "robot1_"];
+const JOINTS: [&str; 6] = [
+    "shoulder_pan_joint",
+    "shoulder_lift_joint",
+    "elbow_joint",
+    "wrist_1_joint",
+    "wrist_2_joint",
+    "wrist_3_joint",
+];
+const POSE: [f64; 6] = [90.0, -120.0, 90.0, -60.0, -90.0, 0.0]; // deg
+
+struct Client {
+    id: u64,
+    subscriptions: HashSet<String>,
+    outgoing: flume::Sender<String>,
+}
+
+#[derive(Default, Clone)]
+struct Broker {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl Broker {
+    fn publish(&self, topic: &str, msg: &Value) {
+        let text = json!({ "op": "publish", "topic": topic, "msg": msg }).to_string();
+        for client in self.clients.lock().unwrap().iter() {
+            if client.subscriptions.contains(topic) {
+                let _ = client.outgoing.send(text.clone());
+            }
+        }
+    }
+
+    fn has_subscribers(&self, topic: &str) -> bool {
+        let clients = self.clients.lock().unwrap();
+        clients.iter().any(|c| c.subscriptions.contains(topic))
+    }
+
+    // handles one operation of a client
+    fn handle(&self, id: u64, text: &str) {
+        let Ok(message) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        let topic = message["topic"].as_str().unwrap_or_default();
+        match message["op"].as_str() {
+            Some("subscribe") => self.update(id, |s| s.insert(topic.to_string())),
+            Some("unsubscribe") => self.update(id, |s| s.remove(topic)),
+            Some("publish") => self.publish(topic, &message["msg"]),
+            // advertise, unadvertise and the rest need no bookkeeping here
+            _ => {}
+        }
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut HashSet<String>) -> bool) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.iter_mut().find(|c| c.id == id) {
+            f(&mut client.subscriptions);
+        }
+    }
+}
+
+fn serve(stream: TcpStream, broker: &Broker, id: u64) -> Result<(), String> {
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+    let mut socket = tungstenite::accept(stream).map_err(|e| e.to_string())?;
+    // poll for operations, in between send what is queued
+    socket
+        .get_mut()
+        .set_read_timeout(Some(Duration::from_millis(5)))
+        .map_err(|e| e.to_string())?;
+    let (outgoing, queue) = flume::unbounded();
+    broker.clients.lock().unwrap().push(Client {
+        id,
+        subscriptions: HashSet::new(),
+        outgoing,
+    });
+
+    let result = 'serve: loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => broker.handle(id, &text),
+            Ok(Message::Close(_)) => break Ok(()),
+            Ok(_) => {}
+            Err(Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => break Err(e.to_string()),
+        }
+        for text in queue.try_iter() {
+            if let Err(e) = socket.send(Message::Text(text)) {
+                break 'serve Err(e.to_string());
+            }
+        }
+    };
+    broker.clients.lock().unwrap().retain(|c| c.id != id);
+    result
+}
+
+// t: s
+fn joint_state(t: f64) -> Value {
+    let mut name = Vec::new();
+    let mut position = Vec::new();
+    for (r, prefix) in PREFIXES.iter().enumerate() {
+        for (k, joint) in JOINTS.iter().enumerate() {
+            let phase = t * 0.5 + r as f64 * std::f64::consts::PI + k as f64 * 0.7;
+            name.push(format!("{}{}", prefix, joint));
+            position.push((POSE[k] + 30.0 * phase.sin()).to_radians());
+        }
+    }
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    json!({
+        "header": {
+            "stamp": { "sec": stamp.as_secs(), "nanosec": stamp.subsec_nanos() },
+            "frame_id": "",
+        },
+        "name": name,
+        "position": position,
+        "velocity": [],
+        "effort": [],
+    })
+}
+
+fn main() {
+    let mut port = DEFAULT_PORT;
+    let mut relay_only = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--relay-only" => relay_only = true,
+            arg => match arg.parse() {
+                Ok(p) => port = p,
+                Err(_) => {
+                    eprintln!("usage: mock_rosbridge [port] [--relay-only]");
+                    std::process::exit(1);
+                }
+            },
+        }
+    }
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("cannot listen on port {}: {}", port, e);
+            std::process::exit(1);
+        }
+    };
+    println!("rosbridge on ws://127.0.0.1:{}", port);
+
+    let broker = Broker::default();
+    if !relay_only {
+        let broker = broker.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            loop {
+                if broker.has_subscribers(JOINT_STATES_TOPIC) {
+                    let t = start.elapsed().as_secs_f64();
+                    broker.publish(JOINT_STATES_TOPIC, &joint_state(t));
+                }
+                thread::sleep(JOINT_STATES_PERIOD);
+            }
+        });
+    }
+
+    for (id, stream) in listener.incoming().flatten().enumerate() {
+        let peer = stream
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        println!("{} connected", peer);
+        let broker = broker.clone();
+        thread::spawn(move || {
+            let result = serve(stream, &broker, id as u64);
+            println!("{} disconnected: {:?}", peer, result.err());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relays_to_subscribers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let broker = Broker::default();
+        let server = broker.clone();
+        thread::spawn(move || {
+            for (id, stream) in listener.incoming().flatten().enumerate() {
+                let broker = server.clone();
+                thread::spawn(move || serve(stream, &broker, id as u64));
+            }
+        });
+
+        let (mut subscriber, _) = tungstenite::connect(&url).unwrap();
+        let subscribe =
+            json!({ "op": "subscribe", "topic": "/tf", "type": "tf2_msgs/msg/TFMessage" });
+        subscriber
+            .send(Message::Text(subscribe.to_string()))
+            .unwrap();
+        let start = Instant::now();
+        while !broker.has_subscribers("/tf") {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let (mut publisher, _) = tungstenite::connect(&url).unwrap();
+        let other = json!({ "op": "publish", "topic": "/other", "msg": { "data": 1 } });
+        let tf = json!({ "op": "publish", "topic": "/tf", "msg": { "transforms": [] } });
+        for message in [other, tf] {
+            publisher.send(Message::Text(message.to_string())).unwrap();
+        }
+
+        let Message::Text(text) = subscriber.read().unwrap() else {
+            panic!("expected a text message");
+        };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(message["topic"], "/tf");
+        assert_eq!(message["msg"]["transforms"], json!([]));
+    }
+
+    #[test]
+    fn joint_state_names_every_joint() {
+        let state = joint_state(0.0);
+        assert_eq!(state["name"].as_array().unwrap().len(), 12);
+        assert_eq!(state["name"][0], "robot0_shoulder_pan_joint");
+        assert_eq!(state["position"].as_array().unwrap().len(), 12);
+    }
+}
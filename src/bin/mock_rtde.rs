@@ -0,0 +1,386 @@
+// Stand-in for the RTDE interface of a UR controller, it replays a recorded joint stream in a loop.
+// Run cmd: cargo run --bin mock_rtde -- [recording] [port]
+// The recording is a CSV file as written by the UR rtde record tool: a header of output names,
+// vectors split into name_0 .. name_5, then one row per sample, separated by spaces or commas.
+#[path = "../rtde.rs"]
+mod rtde;
+#[cfg(test)]
+#[path = "../rtde_client.rs"]
+mod rtde_client;
+
+use rtde::{Packet, PacketReader, VarType};
+use std::{
+    io,
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_RECORDING: &str = "assets/rtde/sample_recording.csv";
+const CONTROLLER_VERSION: [u32; 4] = [5, 11, 0, 0];
+const MAX_FREQUENCY: f64 = 500.0; // Hz
+
+// packet type only the controller side handles
+const CONTROL_PACKAGE_SETUP_INPUTS: u8 = b'I';
+
+struct Recording {
+    names: Vec<(String, usize, usize)>, // ( output, first column, columns )
+    rows: Vec<Vec<f64>>,
+    time: usize, // column of the timestamp
+}
+
+impl Recording {
+    fn parse(text: &str) -> Result<Recording, String> {
+        let split = |line: &str| -> Vec<String> {
+            line.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        };
+        let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+        let header = split(lines.next().ok_or("empty recording")?);
+
+        // group name_0 .. name_n into one output
+        let mut names: Vec<(String, usize, usize)> = Vec::new();
+        for (column, name) in header.iter().enumerate() {
+            let base = match name.rsplit_once('_') {
+                Some((base, i)) if i.parse::<usize>().is_ok() => base,
+                _ => name.as_str(),
+            };
+            match names.last_mut() {
+                Some((last, _, count)) if last == base && base != name => *count += 1,
+                _ => names.push((base.to_string(), column, 1)),
+            }
+        }
+        let time = names
+            .iter()
+            .find(|(name, _, _)| name == "timestamp")
+            .map(|(_, column, _)| *column)
+            .ok_or("no timestamp column")?;
+
+        let mut rows = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let row: Result<Vec<f64>, _> = split(line).iter().map(|v| v.parse()).collect();
+            match row {
+                Ok(row) if row.len() == header.len() => rows.push(row),
+                _ => return Err(format!("invalid row {}", i + 2)),
+            }
+        }
+        if rows.len() < 2 {
+            return Err("a recording needs at least two rows".to_string());
+        }
+        if rows.windows(2).any(|w| w[1][time] <= w[0][time]) {
+            return Err("timestamps must increase".to_string());
+        }
+        Ok(Recording { names, rows, time })
+    }
+
+    fn duration(&self) -> f64 {
+        self.rows[self.rows.len() - 1][self.time] - self.rows[0][self.time]
+    }
+
+    fn var_type(&self, name: &str) -> Option<VarType> {
+        let (_, _, count) = self.names.iter().find(|(n, _, _)| n == name)?;
+        match count {
+            1 => Some(VarType::Double),
+            3 => Some(VarType::Vector3D),
+            6 => Some(VarType::Vector6D),
+            _ => None,
+        }
+    }
+
+    // t: s since the start, the recording repeats
+    // Out: values of the output, interpolated between rows
+    fn sample(&self, name: &str, t: f64) -> Vec<f64> {
+        let Some(&(_, column, count)) = self.names.iter().find(|(n, _, _)| n == name) else {
+            return Vec::new();
+        };
+        let t = self.rows[0][self.time] + t.rem_euclid(self.duration());
+        let next = self
+            .rows
+            .partition_point(|row| row[self.time] <= t)
+            .clamp(1, self.rows.len() - 1);
+        let (a, b) = (&self.rows[next - 1], &self.rows[next]);
+        let s = ((t - a[self.time]) / (b[self.time] - a[self.time])).clamp(0.0, 1.0);
+        (column..column + count)
+            .map(|i| a[i] + (b[i] - a[i]) * s)
+            .collect()
+    }
+}
+
+struct Outputs {
+    names: Vec<String>,
+    types: Vec<VarType>,
+    period: Duration,
+}
+
+// payload of a data package after the recipe id, inverse of rtde_client::decode_data
+fn encode_data(types: &[VarType], values: &[Vec<f64>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (t, values) in types.iter().zip(values) {
+        let (count, _) = t.layout();
+        for i in 0..count {
+            let v = values.get(i).copied().unwrap_or(0.0);
+            match t {
+                VarType::Bool | VarType::Uint8 => out.push(v as u8),
+                VarType::Uint32 | VarType::Vector6Uint32 => {
+                    out.extend_from_slice(&(v as u32).to_be_bytes())
+                }
+                VarType::Int32 | VarType::Vector6Int32 => {
+                    out.extend_from_slice(&(v as i32).to_be_bytes())
+                }
+                VarType::Uint64 => out.extend_from_slice(&(v as u64).to_be_bytes()),
+                _ => out.extend_from_slice(&v.to_be_bytes()),
+            }
+        }
+    }
+    out
+}
+
+fn serve(mut stream: TcpStream, recording: &Recording, start: Instant) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = PacketReader::default();
+    let mut outputs: Option<Outputs> = None;
+    let mut running = false;
+    let mut next_data = Instant::now();
+    loop {
+        // wait for a request until the next data package is due
+        let timeout = if running {
+            next_data.saturating_duration_since(Instant::now())
+        } else {
+            Duration::from_millis(100)
+        };
+        stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        if let Some(Packet { kind, payload }) = reader.read(&mut stream)? {
+            let reply = match kind {
+                rtde::REQUEST_PROTOCOL_VERSION => {
+                    let accepted = payload == rtde::PROTOCOL_VERSION.to_be_bytes();
+                    Some(vec![accepted as u8])
+                }
+                rtde::GET_URCONTROL_VERSION => Some(
+                    CONTROLLER_VERSION
+                        .iter()
+                        .flat_map(|v| v.to_be_bytes())
+                        .collect(),
+                ),
+                rtde::CONTROL_PACKAGE_SETUP_OUTPUTS if payload.len() >= 8 => {
+                    let frequency = f64::from_be_bytes(payload[..8].try_into().unwrap());
+                    let names: Vec<String> = String::from_utf8_lossy(&payload[8..])
+                        .split(',')
+                        .map(|s| s.trim().to_string())
+                        .collect();
+                    let types: Vec<Option<VarType>> =
+                        names.iter().map(|n| recording.var_type(n)).collect();
+                    let text: Vec<&str> = types
+                        .iter()
+                        .map(|t| t.map_or("NOT_FOUND", |t| t.name()))
+                        .collect();
+                    outputs = match types.iter().all(|t| t.is_some()) {
+                        true if frequency > 0.0 => Some(Outputs {
+                            names,
+                            types: types.into_iter().flatten().collect(),
+                            period: Duration::from_secs_f64(1.0 / frequency.min(MAX_FREQUENCY)),
+                        }),
+                        _ => None,
+                    };
+                    let mut reply = vec![1];
+                    reply.extend_from_slice(text.join(",").as_bytes());
+                    Some(reply)
+                }
+                CONTROL_PACKAGE_SETUP_INPUTS => {
+                    // inputs are not supported, every one is unknown
+                    let count = String::from_utf8_lossy(&payload).split(',').count();
+                    let mut reply = vec![0];
+                    reply.extend_from_slice(vec!["NOT_FOUND"; count].join(",").as_bytes());
+                    Some(reply)
+                }
+                rtde::CONTROL_PACKAGE_START => {
+                    running = outputs.is_some();
+                    next_data = Instant::now();
+                    Some(vec![running as u8])
+                }
+                rtde::CONTROL_PACKAGE_PAUSE => {
+                    running = false;
+                    Some(vec![1])
+                }
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                rtde::write_packet(&mut stream, kind, &reply)?;
+            }
+        }
+
+        let Some(outputs) = outputs.as_ref().filter(|_| running) else {
+            continue;
+        };
+        if Instant::now() < next_data {
+            continue;
+        }
+        next_data += outputs.period;
+        let t = start.elapsed().as_secs_f64();
+        let values: Vec<Vec<f64>> = outputs
+            .names
+            .iter()
+            .map(|name| match name.as_str() {
+                // controller uptime
+                "timestamp" => vec![t],
+                name => recording.sample(name, t),
+            })
+            .collect();
+        let mut payload = vec![1];
+        payload.extend(encode_data(&outputs.types, &values));
+        rtde::write_packet(&mut stream, rtde::DATA_PACKAGE, &payload)?;
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| DEFAULT_RECORDING.to_string());
+    let port = args
+        .next()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(rtde::PORT);
+
+    let recording = std::fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| Recording::parse(&text));
+    let recording = match recording {
+        Ok(recording) => Box::leak(Box::new(recording)),
+        Err(e) => {
+            eprintln!("cannot load {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("cannot listen on port {}: {}", port, e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "replaying {} ({:.1} s) on 127.0.0.1:{}",
+        path,
+        recording.duration(),
+        port
+    );
+
+    let start = Instant::now();
+    for stream in listener.incoming().flatten() {
+        let peer = stream
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        println!("{} connected", peer);
+        let recording = &*recording;
+        thread::spawn(move || {
+            let result = serve(stream, recording, start);
+            println!("{} disconnected: {:?}", peer, result.err());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtde_client::{decode_data, parse_types, RtdeClient};
+
+    const RECORDING: &str =
+        "timestamp actual_q_0 actual_q_1 actual_q_2 actual_q_3 actual_q_4 actual_q_5 speed
+0.0 0 0 0 0 0 0 1
+1.0 1 2 3 4 5 6 1
+2.0 0 0 0 0 0 0 1
+";
+
+    #[test]
+    fn parse_and_sample() {
+        let r = Recording::parse(RECORDING).unwrap();
+        assert_eq!(r.duration(), 2.0);
+        assert_eq!(r.var_type("actual_q"), Some(VarType::Vector6D));
+        assert_eq!(r.var_type("speed"), Some(VarType::Double));
+        assert_eq!(r.var_type("actual_TCP_pose"), None);
+        assert_eq!(
+            r.sample("actual_q", 0.5),
+            vec![0.5, 1.0, 1.5, 2.0, 2.5, 3.0]
+        );
+        assert_eq!(
+            r.sample("actual_q", 3.0),
+            vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]
+        );
+        assert!(Recording::parse("timestamp a\n0 1\n0 2\n").is_err());
+        assert!(Recording::parse("a b\n0 1\n1 2\n").is_err());
+    }
+
+    #[test]
+    fn bundled_recording_is_valid() {
+        let text = include_str!("../../assets/rtde/sample_recording.csv");
+        let r = Recording::parse(text).unwrap();
+        assert_eq!(r.var_type("actual_q"), Some(VarType::Vector6D));
+        assert_eq!(r.var_type("actual_TCP_pose"), Some(VarType::Vector6D));
+    }
+
+    #[test]
+    fn client_receives_replayed_joints() {
+        let recording: &'static Recording =
+            Box::leak(Box::new(Recording::parse(RECORDING).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let start = Instant::now();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = serve(stream, recording, start);
+        });
+
+        let mut client = RtdeClient::connect(&addr, Duration::from_secs(1)).unwrap();
+        assert_eq!(client.controller_version().unwrap(), CONTROLLER_VERSION);
+        let types = client
+            .setup_outputs(&["timestamp", "actual_q", "actual_TCP_pose"], 100.0)
+            .unwrap();
+        assert_eq!(types[2], None);
+        assert!(client.start().is_err());
+        let types = client
+            .setup_outputs(&["timestamp", "actual_q"], 100.0)
+            .unwrap();
+        assert_eq!(types, vec![Some(VarType::Double), Some(VarType::Vector6D)]);
+        client.start().unwrap();
+
+        let mut samples = 0;
+        while samples < 5 {
+            let Some(packet) = client.receive().unwrap() else {
+                continue;
+            };
+            assert_eq!(packet.kind, rtde::DATA_PACKAGE);
+            let values = client.decode(&packet).unwrap();
+            let t = values[0][0];
+            assert_eq!(values[1], recording.sample("actual_q", t));
+            samples += 1;
+        }
+        client.pause().unwrap();
+    }
+
+    #[test]
+    fn data_round_trip() {
+        let types = parse_types("DOUBLE,VECTOR6D,INT32,BOOL");
+        assert_eq!(
+            types,
+            vec![
+                Some(VarType::Double),
+                Some(VarType::Vector6D),
+                Some(VarType::Int32),
+                Some(VarType::Bool)
+            ]
+        );
+        assert_eq!(parse_types("DOUBLE,NOT_FOUND")[1], None);
+        let types: Vec<VarType> = types.into_iter().flatten().collect();
+        let values = vec![
+            vec![12.5],
+            vec![1.0, -2.0, 3.0, -4.0, 5.0, -6.0],
+            vec![-7.0],
+            vec![1.0],
+        ];
+        let data = encode_data(&types, &values);
+        assert_eq!(data.len(), 8 + 48 + 4 + 1);
+        assert_eq!(decode_data(&types, &data).unwrap(), values);
+        assert!(decode_data(&types, &data[..10]).is_err());
+    }
+}
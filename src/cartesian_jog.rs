@@ -0,0 +1,172 @@
+use bevy::prelude::*;
+use bevy_egui::egui;
+use nalgebra::{Isometry3, Translation3, Unit, UnitQuaternion, Vector3};
+
+use crate::robot_ur5::{IkError, RobotUr5};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum JogFrame {
+    Base,
+    World,
+    Tool,
+}
+
+impl JogFrame {
+    const ALL: [JogFrame; 3] = [JogFrame::Base, JogFrame::World, JogFrame::Tool];
+
+    fn name(&self) -> &'static str {
+        match self {
+            JogFrame::Base => "Base",
+            JogFrame::World => "World",
+            JogFrame::Tool => "Tool",
+        }
+    }
+}
+
+const AXIS_NAME: [&str; 6] = ["X", "Y", "Z", "Rx", "Ry", "Rz"];
+
+#[derive(Component, Clone)]
+pub struct CartesianJog {
+    pub frame: JogFrame,
+    pub linear_speed: f64,        // mm/s
+    pub angular_speed: f64,       // deg/s
+    active: Option<(usize, f64)>, // ( axis, direction )
+    error: Option<IkError>,
+}
+
+impl Default for CartesianJog {
+    fn default() -> Self {
+        CartesianJog {
+            frame: JogFrame::Base,
+            linear_speed: 50.0,
+            angular_speed: 10.0,
+            active: None,
+            error: None,
+        }
+    }
+}
+
+impl CartesianJog {
+    // joints: current joints target, deg
+    // robot: its model and tool
+    pub fn show(&mut self, ui: &mut egui::Ui, id: u64, joints: &[f64; 6], robot: &RobotUr5) {
+        let joints = joints.map(f64::to_radians);
+        let pose = robot.flange_at(joints) * robot.tool;
+        let p = pose.translation.vector * 1000.0;
+        let r = pose.rotation.scaled_axis();
+
+        egui::ComboBox::from_id_source(("jog_frame", id))
+            .selected_text(self.frame.name())
+            .show_ui(ui, |ui| {
+                for frame in JogFrame::ALL {
+                    ui.selectable_value(&mut self.frame, frame, frame.name());
+                }
+            });
+
+        egui::Grid::new(("jog_axis", id))
+            .num_columns(4)
+            .show(ui, |ui| {
+                let values = [
+                    p.x,
+                    p.y,
+                    p.z,
+                    r.x.to_degrees(),
+                    r.y.to_degrees(),
+                    r.z.to_degrees(),
+                ];
+                for axis in 0..6 {
+                    ui.label(AXIS_NAME[axis]);
+                    if ui.button(" - ").is_pointer_button_down_on() {
+                        self.active = Some((axis, -1.0));
+                    }
+                    if ui.button(" + ").is_pointer_button_down_on() {
+                        self.active = Some((axis, 1.0));
+                    }
+                    let suffix = if axis < 3 { "mm" } else { "°" };
+                    ui.label(format!("{:.2}{}", values[axis], suffix));
+                    ui.end_row();
+                }
+            });
+
+        ui.add(
+            egui::Slider::new(&mut self.linear_speed, 1.0..=250.0)
+                .text("speed")
+                .suffix("mm/s"),
+        );
+        ui.add(
+            egui::Slider::new(&mut self.angular_speed, 1.0..=90.0)
+                .text("speed")
+                .suffix("°/s"),
+        );
+
+        if let Some(error) = self.error {
+            ui.colored_label(egui::Color32::RED, error.to_string());
+        }
+    }
+
+    // joints: current joints target, deg
+    // robot: its model and tool
+    // base_rotation: robot base rotation in world
+    // dt: s
+    // Out: the next joints target, deg
+    pub fn step(
+        &mut self,
+        joints: &[f64; 6],
+        robot: &RobotUr5,
+        base_rotation: &UnitQuaternion<f64>,
+        dt: f64,
+    ) -> Option<[f64; 6]> {
+        let Some((axis, direction)) = self.active.take() else {
+            self.error = None;
+            return None;
+        };
+        let joints = joints.map(f64::to_radians);
+        let pose = robot.flange_at(joints) * robot.tool;
+
+        let mut unit = Vector3::zeros();
+        unit[axis % 3] = direction;
+        let (translation, rotation) = if axis < 3 {
+            let delta = unit * self.linear_speed / 1000.0 * dt;
+            (delta, UnitQuaternion::identity())
+        } else {
+            let angle = self.angular_speed.to_radians() * dt;
+            let rotation = UnitQuaternion::from_axis_angle(&Unit::new_unchecked(unit), angle);
+            (Vector3::zeros(), rotation)
+        };
+
+        let target = match self.frame {
+            JogFrame::Base => jog_in_base(&pose, &translation, &rotation),
+            JogFrame::World => {
+                let inv = base_rotation.inverse();
+                let rotation = inv * rotation * base_rotation;
+                jog_in_base(&pose, &(inv * translation), &rotation)
+            }
+            JogFrame::Tool => {
+                pose * Isometry3::from_parts(Translation3::from(translation), rotation)
+            }
+        };
+
+        match robot.ik_near(&(target * robot.tool.inverse()), &joints) {
+            Ok(joints) => {
+                self.error = None;
+                Some(joints.map(f64::to_degrees))
+            }
+            Err(error) => {
+                self.error = Some(error);
+                None
+            }
+        }
+    }
+}
+
+// translate and rotate the tcp about its own origin with vectors expressed in base
+fn jog_in_base(
+    pose: &Isometry3<f64>,
+    translation: &Vector3<f64>,
+    rotation: &UnitQuaternion<f64>,
+) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::from(pose.translation.vector + translation),
+        rotation * pose.rotation,
+    )
+}
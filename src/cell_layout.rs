@@ -0,0 +1,442 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+    utils::BoxedFuture,
+};
+use nalgebra::{Isometry3, UnitQuaternion, Vector3};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+use crate::{
+    collision::Solid,
+    robot_cell::{RobotCellPlugin, RobotCells},
+    robot_ur5::{DEFAULT_MODEL, JOINTS_POS},
+    tool_changer::ToolChangerPlugin,
+    workpiece::WorkpiecePlugin,
+};
+
+pub const DEFAULT_CELL: &str = "cells/default.cell.ron";
+
+pub struct CellLayoutPlugin;
+
+impl Plugin for CellLayoutPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<CellLayout>()
+            .init_asset_loader::<CellLayoutLoader>()
+            .add_systems(Startup, CellLayoutPlugin::setup)
+            .add_systems(Update, CellLayoutPlugin::apply_layout);
+    }
+}
+
+// translation: m
+// rotation: fixed axis angles ( roll, pitch, yaw ), deg, R = Rz(yaw) * Ry(pitch) * Rx(roll)
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct Pose {
+    #[serde(default)]
+    pub translation: [f32; 3],
+    #[serde(default)]
+    pub rotation: [f32; 3],
+}
+
+impl Pose {
+    pub fn transform(&self) -> Transform {
+        let [roll, pitch, yaw] = self.rotation.map(f32::to_radians);
+        Transform::from_translation(Vec3::from(self.translation)).with_rotation(Quat::from_euler(
+            EulerRot::ZYX,
+            yaw,
+            pitch,
+            roll,
+        ))
+    }
+
+    pub fn isometry(&self) -> Isometry3<f64> {
+        let [x, y, z] = self.translation.map(|v| v as f64);
+        let [roll, pitch, yaw] = self.rotation.map(|v| (v as f64).to_radians());
+        Isometry3::from_parts(
+            Vector3::new(x, y, z).into(),
+            UnitQuaternion::from_euler_angles(roll, pitch, yaw),
+        )
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ToolKind {
+    Ctm2f110,
+    ParallelGripper,
+    SuctionCup,
+    Flange,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct ToolLayout {
+    pub kind: ToolKind,
+    // tool mount relative to the flange
+    #[serde(default)]
+    pub offset: Pose,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct RobotLayout {
+    pub id: u64,
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[serde(default)]
+    pub base: Pose,
+    #[serde(default = "default_home")]
+    pub home: [f64; 6], // deg
+    #[serde(default)]
+    pub tool: Option<ToolLayout>,
+    // prefix of the joint and frame names on ROS, robot<id>_ when None
+    #[serde(default)]
+    #[cfg_attr(not(feature = "ros"), allow(dead_code))]
+    pub ros_prefix: Option<String>,
+}
+
+impl RobotLayout {
+    // a UR5 standing on the floor at pos with a gripper
+    pub fn new(id: u64, pos: Vec3) -> Self {
+        RobotLayout {
+            id,
+            model: default_model(),
+            base: Pose {
+                translation: pos.to_array(),
+                rotation: [-90.0, 0.0, 0.0],
+            },
+            home: default_home(),
+            tool: Some(ToolLayout {
+                kind: ToolKind::Ctm2f110,
+                offset: Pose::default(),
+            }),
+            ros_prefix: None,
+        }
+    }
+
+    #[cfg(feature = "ros")]
+    pub fn ros_prefix(&self) -> String {
+        self.ros_prefix
+            .clone()
+            .unwrap_or_else(|| format!("robot{}_", self.id))
+    }
+}
+
+fn default_model() -> String {
+    DEFAULT_MODEL.to_string()
+}
+
+fn default_home() -> [f64; 6] {
+    JOINTS_POS
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub enum Shape {
+    Box { size: [f32; 3] },                // m
+    Cylinder { radius: f32, height: f32 }, // m, along y
+    Scene(String),                         // asset path, e.g. "table.gltf#Scene0"
+}
+
+impl Shape {
+    // None for a scene
+    pub fn mesh(&self) -> Option<(Mesh, Solid)> {
+        match self {
+            Shape::Box { size } => Some((
+                Mesh::from(shape::Box::new(size[0], size[1], size[2])),
+                Solid::Box {
+                    half_size: Vec3::from(*size) / 2.0,
+                },
+            )),
+            Shape::Cylinder { radius, height } => Some((
+                Mesh::from(shape::Cylinder {
+                    radius: *radius,
+                    height: *height,
+                    ..default()
+                }),
+                Solid::Cylinder {
+                    radius: *radius,
+                    half_height: *height / 2.0,
+                },
+            )),
+            Shape::Scene(_) => None,
+        }
+    }
+}
+
+// static geometry placed in the world
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct Fixture {
+    #[serde(default)]
+    pub name: String,
+    pub shape: Shape,
+    #[serde(default)]
+    pub pose: Pose,
+    #[serde(default = "default_color")]
+    pub color: [f32; 3], // rgb, range [0.0, 1.0]
+}
+
+fn default_color() -> [f32; 3] {
+    [0.6, 0.6, 0.6]
+}
+
+// a place for a tool in a rack
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct SlotLayout {
+    // tool mount relative to the rack, z along the tool
+    pub pose: Pose,
+    // parked tool, None if the slot is free
+    #[serde(default)]
+    pub tool: Option<ToolKind>,
+}
+
+// tool changer rack standing on the floor
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct RackLayout {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub pose: Pose,
+    pub slots: Vec<SlotLayout>,
+}
+
+#[derive(Deserialize, TypeUuid, TypePath, Clone, PartialEq, Debug, Default)]
+#[uuid = "a3f1c2d4-6b7e-4f80-9d21-3c5e8b7a9f10"]
+pub struct CellLayout {
+    #[serde(default)]
+    pub robots: Vec<RobotLayout>,
+    #[serde(default)]
+    pub fixtures: Vec<Fixture>,
+    #[serde(default)]
+    pub racks: Vec<RackLayout>,
+    // movable, picked up by the tools, pose as placed
+    #[serde(default)]
+    pub workpieces: Vec<Fixture>,
+}
+
+impl CellLayout {
+    pub fn parse(text: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(text)
+    }
+}
+
+#[derive(Default)]
+pub struct CellLayoutLoader;
+
+impl AssetLoader for CellLayoutLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let layout = CellLayout::parse(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(layout));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cell.ron"]
+    }
+}
+
+#[derive(Component)]
+struct FixtureComponent;
+
+#[derive(Resource)]
+struct CurrentLayout {
+    handle: Handle<CellLayout>,
+    robots: BTreeSet<u64>, // robots spawned from the layout
+}
+
+impl CellLayoutPlugin {
+    fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+        commands.insert_resource(CurrentLayout {
+            handle: asset_server.load(DEFAULT_CELL),
+            robots: BTreeSet::new(),
+        });
+    }
+
+    // runs on the first load and every time the file is edited
+    fn apply_layout(
+        mut commands: Commands,
+        mut events: EventReader<AssetEvent<CellLayout>>,
+        layouts: Res<Assets<CellLayout>>,
+        current: Res<CurrentLayout>,
+    ) {
+        for event in events.iter() {
+            let handle = match event {
+                AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+                AssetEvent::Removed { .. } => continue,
+            };
+            if *handle != current.handle {
+                continue;
+            }
+            if let Some(layout) = layouts.get(handle) {
+                let layout = layout.clone();
+                commands.add(move |world: &mut World| CellLayoutPlugin::apply(world, &layout));
+            }
+        }
+    }
+
+    fn apply(world: &mut World, layout: &CellLayout) {
+        // robots from the previous version of the file that are gone now
+        let old = std::mem::take(&mut world.resource_mut::<CurrentLayout>().robots);
+        for id in old.iter() {
+            if !layout.robots.iter().any(|r| r.id == *id) {
+                RobotCellPlugin::remove_cell(world, *id);
+            }
+        }
+
+        for robot in layout.robots.iter() {
+            let cell = world.resource::<RobotCells>().0.get(&robot.id).cloned();
+            match cell {
+                Some(cell) if cell.layout == *robot => {}
+                Some(cell)
+                    if RobotLayout {
+                        base: robot.base,
+                        tool: robot.tool.clone(),
+                        ..cell.layout.clone()
+                    } == *robot =>
+                {
+                    // only moved or the tool changed, keep the joints
+                    if let Some(mut tf) = world.get_mut::<Transform>(cell.robot) {
+                        *tf = robot.base.transform();
+                    }
+                    if cell.layout.tool != robot.tool {
+                        RobotCellPlugin::set_tool(world, robot.id, robot.tool.clone());
+                    }
+                    if let Some(cell) = world.resource_mut::<RobotCells>().0.get_mut(&robot.id) {
+                        cell.layout = robot.clone();
+                    }
+                }
+                Some(_) => {
+                    RobotCellPlugin::remove_cell(world, robot.id);
+                    RobotCellPlugin::add_cell(world, robot.clone());
+                }
+                None => {
+                    RobotCellPlugin::add_cell(world, robot.clone());
+                }
+            }
+        }
+        world.resource_mut::<CurrentLayout>().robots = layout.robots.iter().map(|r| r.id).collect();
+
+        let fixtures: Vec<Entity> = world
+            .query_filtered::<Entity, With<FixtureComponent>>()
+            .iter(world)
+            .collect();
+        for entity in fixtures {
+            world.entity_mut(entity).despawn_recursive();
+        }
+        for fixture in layout.fixtures.iter() {
+            CellLayoutPlugin::spawn_fixture(world, fixture);
+        }
+
+        // the parked tools go with their racks, the mounted ones stay on the robots
+        ToolChangerPlugin::despawn_racks(world);
+        for rack in layout.racks.iter() {
+            ToolChangerPlugin::spawn_rack(world, rack);
+        }
+
+        WorkpiecePlugin::despawn_workpieces(world);
+        for workpiece in layout.workpieces.iter() {
+            WorkpiecePlugin::spawn_workpiece(world, workpiece);
+        }
+    }
+
+    fn spawn_fixture(world: &mut World, fixture: &Fixture) {
+        let transform = fixture.pose.transform();
+        let name = Name::new(fixture.name.clone());
+        let [r, g, b] = fixture.color;
+        // scenes have no collision geometry
+        let Some((mesh, solid)) = fixture.shape.mesh() else {
+            if let Shape::Scene(path) = &fixture.shape {
+                let scene = world.resource::<AssetServer>().load(path.as_str());
+                world.spawn((
+                    SceneBundle {
+                        scene,
+                        transform,
+                        ..default()
+                    },
+                    name,
+                    FixtureComponent,
+                ));
+            }
+            return;
+        };
+        let mesh = world.resource_mut::<Assets<Mesh>>().add(mesh);
+        let material = world
+            .resource_mut::<Assets<StandardMaterial>>()
+            .add(Color::rgb(r, g, b).into());
+        world.spawn((
+            PbrBundle {
+                mesh,
+                material,
+                transform,
+                ..default()
+            },
+            name,
+            FixtureComponent,
+            solid,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_default_cell() {
+        let layout = CellLayout::parse(include_str!("../assets/cells/default.cell.ron")).unwrap();
+        assert_eq!(layout.robots.len(), 2);
+        assert_eq!(
+            layout.robots[0],
+            RobotLayout::new(0, Vec3::new(-0.5, 0.0, 0.0))
+        );
+        assert_eq!(
+            layout.robots[1],
+            RobotLayout::new(1, Vec3::new(0.5, 0.0, 0.0))
+        );
+        let slots = &layout.racks[0].slots;
+        assert_eq!(slots[0].tool, Some(ToolKind::ParallelGripper));
+        assert_eq!(slots[1].tool, Some(ToolKind::SuctionCup));
+        // the parked tools point down
+        let down = slots[0].pose.transform().rotation * Vec3::Z;
+        assert!((down - Vec3::NEG_Y).length() < 1e-6);
+        assert_eq!(layout.workpieces.len(), 2);
+    }
+
+    #[test]
+    fn defaults() {
+        let layout =
+            CellLayout::parse("(robots: [(id: 3)], fixtures: [(shape: Scene(\"a.gltf\"))])")
+                .unwrap();
+        let robot = &layout.robots[0];
+        assert_eq!(robot.model, DEFAULT_MODEL);
+        assert_eq!(robot.home, JOINTS_POS);
+        assert_eq!(robot.base, Pose::default());
+        assert!(robot.tool.is_none());
+        assert_eq!(robot.ros_prefix, None);
+        assert_eq!(layout.fixtures[0].color, default_color());
+        assert!(layout.racks.is_empty());
+        let layout = CellLayout::parse("(racks: [(slots: [(pose: ())])])").unwrap();
+        assert_eq!(layout.racks[0].pose, Pose::default());
+        assert_eq!(layout.racks[0].slots[0].tool, None);
+        assert!(CellLayout::parse("(robots: [(model: \"ur5/ur5.urdf\")])").is_err());
+    }
+
+    #[test]
+    fn pose_conventions_agree() {
+        let pose = Pose {
+            translation: [0.1, -0.2, 0.3],
+            rotation: [30.0, -45.0, 120.0],
+        };
+        let tf = pose.transform();
+        let iso = pose.isometry();
+        let p = Vec3::new(0.4, 0.5, -0.6);
+        let a = tf.transform_point(p);
+        let b = iso * nalgebra::Point3::new(0.4, 0.5, -0.6);
+        assert!((a.x as f64 - b.x).abs() < 1e-5);
+        assert!((a.y as f64 - b.y).abs() < 1e-5);
+        assert!((a.z as f64 - b.z).abs() < 1e-5);
+    }
+}
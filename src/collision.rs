@@ -0,0 +1,599 @@
+// Collision checking between capsules around the robot links and the tool parts, each frame:
+// self collisions, robot to robot contacts and penetration of the floor or the fixtures.
+// Colliding parts are drawn in red, a CollisionEvent is sent when a contact begins and ends.
+use bevy::{prelude::*, transform::TransformSystem};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    end_effector::{Tool, ToolPart},
+    robot_ur5::{RobotComponent, RobotModel, RobotUr5},
+    urdf::RobotDescription,
+};
+
+const HIGHLIGHT: Color = Color::rgb(0.9, 0.1, 0.1);
+const TERNARY_STEPS: usize = 32;
+
+// points within radius of the segment [ a, b ], m
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Capsule {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub radius: f32,
+}
+
+impl Capsule {
+    pub fn transformed(&self, tf: &Transform) -> Capsule {
+        Capsule {
+            a: tf.transform_point(self.a),
+            b: tf.transform_point(self.b),
+            radius: self.radius,
+        }
+    }
+
+    pub fn overlaps(&self, other: &Capsule) -> bool {
+        segment_distance(self.a, self.b, other.a, other.b) < self.radius + other.radius
+    }
+
+    // the floor is the plane y = 0 of the world
+    pub fn below_floor(&self) -> bool {
+        self.a.y.min(self.b.y) < self.radius
+    }
+}
+
+// a robot link or a tool part, the capsules are in the frame of its entity
+#[derive(Component, Clone, Debug)]
+pub struct Collider {
+    pub robot: u64,
+    pub part: String,
+    pub link: Option<usize>, // link index in the robot description, None for tool parts
+    pub adjacent: Vec<usize>, // links sharing a joint, never checked against it
+    pub tip: bool,           // the tool is mounted on it
+    pub grounded: bool,      // the robot base, standing on the floor or a fixture
+    pub capsules: Vec<Capsule>,
+}
+
+impl Collider {
+    // parts touching by construction
+    fn ignores(&self, other: &Collider) -> bool {
+        if self.robot != other.robot {
+            return false;
+        }
+        match (self.link, other.link) {
+            (Some(_), Some(link)) => self.adjacent.contains(&link),
+            (Some(_), None) => self.tip,
+            (None, Some(_)) => other.tip,
+            (None, None) => true,
+        }
+    }
+}
+
+// static obstacle in the frame of its entity
+#[derive(Component, Clone, Copy, PartialEq, Debug)]
+pub enum Solid {
+    Box { half_size: Vec3 },                    // m
+    Cylinder { radius: f32, half_height: f32 }, // m, along y
+}
+
+impl Solid {
+    // p: relative to the solid
+    // Out: m, 0 inside
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match *self {
+            Solid::Box { half_size } => (p.abs() - half_size).max(Vec3::ZERO).length(),
+            Solid::Cylinder {
+                radius,
+                half_height,
+            } => {
+                let radial = (Vec2::new(p.x, p.z).length() - radius).max(0.0);
+                let axial = (p.y.abs() - half_height).max(0.0);
+                Vec2::new(radial, axial).length()
+            }
+        }
+    }
+
+    // capsule: relative to the solid
+    pub fn overlaps(&self, capsule: &Capsule) -> bool {
+        // the distance to a convex solid is convex along the segment
+        let at = |t: f32| self.distance(capsule.a.lerp(capsule.b, t));
+        let (mut lo, mut hi) = (0.0, 1.0);
+        for _ in 0..TERNARY_STEPS {
+            let m1 = lo + (hi - lo) / 3.0;
+            let m2 = hi - (hi - lo) / 3.0;
+            if at(m1) <= at(m2) {
+                hi = m2;
+            } else {
+                lo = m1;
+            }
+        }
+        at((lo + hi) / 2.0) < capsule.radius
+    }
+}
+
+// what a part collides with
+#[derive(Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Obstacle {
+    // a part of the same robot for a self collision
+    Robot { robot: u64, part: String },
+    Floor,
+    Fixture { name: String },
+}
+
+#[derive(Event, Serialize, Clone, PartialEq, Debug)]
+pub struct CollisionEvent {
+    pub robot: u64,
+    pub part: String,
+    pub with: Obstacle,
+    pub colliding: bool, // false once the contact has ended
+}
+
+// contacts at the last check
+#[derive(Resource, Default)]
+pub struct Collisions {
+    pub contacts: BTreeSet<(u64, String, Obstacle)>,
+    pub colliding: HashSet<Entity>,
+}
+
+// Out: distance between the segments [ p1, q1 ] and [ p2, q2 ]
+pub fn segment_distance(p1: Vec3, q1: Vec3, p2: Vec3, q2: Vec3) -> f32 {
+    // closest points of two segments, Ericson, Real-Time Collision Detection 5.1.9
+    let d1 = q1 - p1;
+    let d2 = q2 - p2;
+    let r = p1 - p2;
+    let a = d1.dot(d1);
+    let e = d2.dot(d2);
+    let f = d2.dot(r);
+    let (s, t) = if a <= f32::EPSILON && e <= f32::EPSILON {
+        (0.0, 0.0)
+    } else if a <= f32::EPSILON {
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = d1.dot(r);
+        if e <= f32::EPSILON {
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = d1.dot(d2);
+            let denom = a * e - b * b;
+            let s = if denom > f32::EPSILON {
+                ((b * f - c * e) / denom).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let t = (b * s + f) / e;
+            if t < 0.0 {
+                ((-c / a).clamp(0.0, 1.0), 0.0)
+            } else if t > 1.0 {
+                (((b - c) / a).clamp(0.0, 1.0), 1.0)
+            } else {
+                (s, t)
+            }
+        }
+    };
+    (p1 + d1 * s).distance(p2 + d2 * t)
+}
+
+// ca, cb: capsules of the parts in the world
+pub fn parts_touch(a: &Collider, ca: &[Capsule], b: &Collider, cb: &[Capsule]) -> bool {
+    !a.ignores(b) && ca.iter().any(|x| cb.iter().any(|y| x.overlaps(y)))
+}
+
+// In: colliders with their capsules in the world
+// Out: index pairs of the colliding parts
+pub fn part_contacts(parts: &[(&Collider, Vec<Capsule>)]) -> Vec<(usize, usize)> {
+    let mut out = Vec::new();
+    for i in 0..parts.len() {
+        for j in i + 1..parts.len() {
+            let ((a, ca), (b, cb)) = (&parts[i], &parts[j]);
+            if parts_touch(a, ca, b, cb) {
+                out.push((i, j));
+            }
+        }
+    }
+    out
+}
+
+pub type SolidQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Solid,
+        &'static GlobalTransform,
+        Option<&'static Name>,
+    ),
+>;
+
+// Out: ( solid, world to solid, name ) of every fixture
+pub fn world_solids(q_solid: &SolidQuery) -> Vec<(Solid, Transform, String)> {
+    q_solid
+        .iter()
+        .map(|(solid, gt, name)| {
+            let name = name.map(|n| n.to_string()).unwrap_or_default();
+            (
+                *solid,
+                Transform::from_matrix(gt.compute_matrix().inverse()),
+                name,
+            )
+        })
+        .collect()
+}
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Collisions>()
+            .add_event::<CollisionEvent>()
+            .add_systems(Update, CollisionPlugin::add_colliders)
+            .add_systems(
+                PostUpdate,
+                (CollisionPlugin::check, CollisionPlugin::highlight)
+                    .chain()
+                    .after(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+impl CollisionPlugin {
+    fn add_colliders(
+        mut commands: Commands,
+        descriptions: Res<Assets<RobotDescription>>,
+        q_link: Query<(Entity, &RobotComponent, &Parent), Without<Collider>>,
+        q_robot: Query<(&RobotUr5, &RobotModel)>,
+        q_part: Query<(Entity, &ToolPart, &Parent), Without<Collider>>,
+        q_tool: Query<&Tool>,
+    ) {
+        for (entity, link, parent) in q_link.iter() {
+            let Ok((robot, model)) = q_robot.get(parent.get()) else {
+                continue;
+            };
+            let Some(description) = descriptions.get(&model.0) else {
+                continue;
+            };
+            let Some(urdf_link) = description.links.get(link.0) else {
+                continue;
+            };
+            let adjacent = description
+                .joints
+                .iter()
+                .filter_map(|j| match (j.parent, j.child) {
+                    (p, c) if p == link.0 => Some(c),
+                    (p, c) if c == link.0 => Some(p),
+                    _ => None,
+                })
+                .collect();
+            let capsules = urdf_link
+                .collisions
+                .iter()
+                .map(|c| {
+                    let (a, b) = c.segment();
+                    Capsule {
+                        a,
+                        b,
+                        radius: c.radius,
+                    }
+                })
+                .collect();
+            commands.entity(entity).insert(Collider {
+                robot: robot.id,
+                part: urdf_link.name.clone(),
+                link: Some(link.0),
+                adjacent,
+                tip: link.0 == description.tip(),
+                grounded: link.0 == description.root,
+                capsules,
+            });
+        }
+
+        for (entity, part, parent) in q_part.iter() {
+            // parked tools are not checked
+            let Ok(Tool {
+                robot: Some(robot),
+                kind,
+                ..
+            }) = q_tool.get(parent.get())
+            else {
+                continue;
+            };
+            let Some(def) = kind.end_effector().parts().get(part.0) else {
+                continue;
+            };
+            let capsules = def
+                .capsules
+                .iter()
+                .map(|&(a, b, radius)| Capsule {
+                    a: Vec3::from(a),
+                    b: Vec3::from(b),
+                    radius,
+                })
+                .collect();
+            commands.entity(entity).insert(Collider {
+                robot: *robot,
+                part: def.name.to_string(),
+                link: None,
+                adjacent: Vec::new(),
+                tip: false,
+                grounded: false,
+                capsules,
+            });
+        }
+    }
+
+    fn check(
+        mut collisions: ResMut<Collisions>,
+        mut events: EventWriter<CollisionEvent>,
+        q_collider: Query<(Entity, &Collider, &GlobalTransform)>,
+        q_solid: SolidQuery,
+    ) {
+        // sorted, a contact between two robots is always reported from the same side
+        let mut parts: Vec<(Entity, &Collider, Vec<Capsule>)> = q_collider
+            .iter()
+            .filter(|(_, c, _)| !c.capsules.is_empty())
+            .map(|(e, c, gt)| {
+                let tf = gt.compute_transform();
+                (
+                    e,
+                    c,
+                    c.capsules.iter().map(|x| x.transformed(&tf)).collect(),
+                )
+            })
+            .collect();
+        parts.sort_by(|a, b| (a.1.robot, &a.1.part).cmp(&(b.1.robot, &b.1.part)));
+
+        let mut contacts = BTreeSet::new();
+        let mut colliding = HashSet::new();
+        let placed: Vec<(&Collider, Vec<Capsule>)> =
+            parts.iter().map(|(_, c, x)| (*c, x.clone())).collect();
+        for (i, j) in part_contacts(&placed) {
+            let (a, b) = (parts[i].1, parts[j].1);
+            contacts.insert((
+                a.robot,
+                a.part.clone(),
+                Obstacle::Robot {
+                    robot: b.robot,
+                    part: b.part.clone(),
+                },
+            ));
+            colliding.extend([parts[i].0, parts[j].0]);
+        }
+
+        let solids: Vec<(Solid, Transform, String)> = q_solid
+            .iter()
+            .map(|(solid, gt, name)| {
+                let name = name.map(|n| n.to_string()).unwrap_or_default();
+                (
+                    *solid,
+                    Transform::from_matrix(gt.compute_matrix().inverse()),
+                    name,
+                )
+            })
+            .collect();
+        for (entity, collider, capsules) in parts.iter() {
+            if collider.grounded {
+                continue;
+            }
+            let mut hit = |with: Obstacle| {
+                contacts.insert((collider.robot, collider.part.clone(), with));
+                colliding.insert(*entity);
+            };
+            if capsules.iter().any(Capsule::below_floor) {
+                hit(Obstacle::Floor);
+            }
+            for (solid, inverse, name) in solids.iter() {
+                if capsules
+                    .iter()
+                    .any(|c| solid.overlaps(&c.transformed(inverse)))
+                {
+                    hit(Obstacle::Fixture { name: name.clone() });
+                }
+            }
+        }
+
+        for (robot, part, with) in contacts.difference(&collisions.contacts) {
+            events.send(CollisionEvent {
+                robot: *robot,
+                part: part.clone(),
+                with: with.clone(),
+                colliding: true,
+            });
+        }
+        for (robot, part, with) in collisions.contacts.difference(&contacts) {
+            events.send(CollisionEvent {
+                robot: *robot,
+                part: part.clone(),
+                with: with.clone(),
+                colliding: false,
+            });
+        }
+        collisions.contacts = contacts;
+        collisions.colliding = colliding;
+    }
+
+    // swaps the materials of the colliding parts' meshes for red copies
+    fn highlight(
+        collisions: Res<Collisions>,
+        mut original: Local<HashMap<Entity, Handle<StandardMaterial>>>,
+        mut red: Local<HashMap<Handle<StandardMaterial>, Handle<StandardMaterial>>>,
+        mut materials: ResMut<Assets<StandardMaterial>>,
+        q_children: Query<&Children>,
+        q_collider: Query<(), With<Collider>>,
+        mut q_material: Query<&mut Handle<StandardMaterial>>,
+    ) {
+        // the meshes of a part, without the parts mounted on it
+        let mut meshes = HashSet::new();
+        let mut open: Vec<Entity> = collisions.colliding.iter().copied().collect();
+        while let Some(entity) = open.pop() {
+            if q_material.contains(entity) {
+                meshes.insert(entity);
+            }
+            if let Ok(children) = q_children.get(entity) {
+                open.extend(children.iter().filter(|&&c| !q_collider.contains(c)));
+            }
+        }
+
+        original.retain(|entity, handle| {
+            if meshes.contains(entity) {
+                return true;
+            }
+            if let Ok(mut material) = q_material.get_mut(*entity) {
+                *material = handle.clone();
+            }
+            false
+        });
+        for entity in meshes {
+            if original.contains_key(&entity) {
+                continue;
+            }
+            let Ok(mut material) = q_material.get_mut(entity) else {
+                continue;
+            };
+            let highlighted = red.entry(material.clone()).or_insert_with(|| {
+                let mut copy = materials.get(&material).cloned().unwrap_or_default();
+                copy.base_color = HIGHLIGHT;
+                materials.add(copy)
+            });
+            original.insert(
+                entity,
+                std::mem::replace(&mut *material, highlighted.clone()),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot_ur5::JOINTS_POS;
+    use std::f32::consts::FRAC_PI_2;
+
+    const UR5: &str = include_str!("../assets/ur5/ur5.urdf");
+
+    fn capsule(a: [f32; 3], b: [f32; 3], radius: f32) -> Capsule {
+        Capsule {
+            a: Vec3::from(a),
+            b: Vec3::from(b),
+            radius,
+        }
+    }
+
+    // colliders of the robot links, with their capsules in the world
+    fn robot(id: u64, base: Transform, joints: [f64; 6]) -> Vec<(Collider, Vec<Capsule>)> {
+        let description = RobotDescription::parse(UR5, "ur5").unwrap();
+        let tfs = description.link_transforms(&joints.map(f64::to_radians));
+        description
+            .links
+            .iter()
+            .enumerate()
+            .map(|(i, link)| {
+                let collider = Collider {
+                    robot: id,
+                    part: link.name.clone(),
+                    link: Some(i),
+                    adjacent: description
+                        .joints
+                        .iter()
+                        .filter(|j| j.parent == i || j.child == i)
+                        .map(|j| if j.parent == i { j.child } else { j.parent })
+                        .collect(),
+                    tip: i == description.tip(),
+                    grounded: i == description.root,
+                    capsules: Vec::new(),
+                };
+                let capsules = link
+                    .collisions
+                    .iter()
+                    .map(|c| {
+                        let (a, b) = c.segment();
+                        capsule(a.into(), b.into(), c.radius).transformed(&(base * tfs[i]))
+                    })
+                    .collect();
+                (collider, capsules)
+            })
+            .collect()
+    }
+
+    fn contacts(parts: &[(Collider, Vec<Capsule>)]) -> Vec<(String, String)> {
+        let placed: Vec<(&Collider, Vec<Capsule>)> =
+            parts.iter().map(|(c, x)| (c, x.clone())).collect();
+        part_contacts(&placed)
+            .into_iter()
+            .map(|(i, j)| (parts[i].0.part.clone(), parts[j].0.part.clone()))
+            .collect()
+    }
+
+    fn base(x: f32) -> Transform {
+        Transform::from_xyz(x, 0.0, 0.0).with_rotation(Quat::from_rotation_x(-FRAC_PI_2))
+    }
+
+    #[test]
+    fn segments() {
+        let o = Vec3::ZERO;
+        // crossing, parallel, end to end, degenerate
+        let d = segment_distance(
+            o,
+            Vec3::X,
+            Vec3::new(0.5, 1.0, -1.0),
+            Vec3::new(0.5, 1.0, 1.0),
+        );
+        assert!((d - 1.0).abs() < 1e-6);
+        let d = segment_distance(
+            o,
+            Vec3::X,
+            Vec3::new(0.2, 0.3, 0.0),
+            Vec3::new(2.0, 0.3, 0.0),
+        );
+        assert!((d - 0.3).abs() < 1e-6);
+        let d = segment_distance(
+            o,
+            Vec3::X,
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(3.0, 0.0, 0.0),
+        );
+        assert!((d - 1.0).abs() < 1e-6);
+        let d = segment_distance(o, o, Vec3::new(1.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 0.0));
+        assert!((d - 2f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn solids() {
+        let table = Solid::Box {
+            half_size: Vec3::new(0.3, 0.01, 0.2),
+        };
+        assert!(table.overlaps(&capsule([-1.0, 0.05, 0.0], [1.0, 0.05, 0.0], 0.045)));
+        assert!(!table.overlaps(&capsule([-1.0, 0.05, 0.0], [1.0, 0.05, 0.0], 0.035)));
+        assert!(!table.overlaps(&capsule([0.35, 0.0, 0.0], [0.5, 0.0, 0.0], 0.04)));
+        let post = Solid::Cylinder {
+            radius: 0.1,
+            half_height: 0.5,
+        };
+        assert!(post.overlaps(&capsule([0.0, 0.0, 0.0], [0.0, 0.0, 0.0], 0.01)));
+        assert!(!post.overlaps(&capsule([0.0, 0.6, -1.0], [0.0, 0.6, 1.0], 0.05)));
+        assert!(capsule([0.0, 0.2, 0.0], [1.0, 0.03, 0.0], 0.04).below_floor());
+    }
+
+    #[test]
+    fn home_pose_is_free() {
+        let mut parts = robot(0, base(-0.5), JOINTS_POS);
+        parts.extend(robot(1, base(0.5), JOINTS_POS));
+        assert_eq!(contacts(&parts), vec![]);
+        for (collider, capsules) in parts.iter().filter(|(c, _)| !c.grounded) {
+            assert!(
+                !capsules.iter().any(Capsule::below_floor),
+                "{}",
+                collider.part
+            );
+        }
+    }
+
+    #[test]
+    fn robots_and_links_collide() {
+        // the arms stretched towards each other
+        let mut parts = robot(0, base(-0.5), [180.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        parts.extend(robot(1, base(0.5), [0.0, 0.0, 0.0, 0.0, 0.0, 0.0]));
+        assert!(!contacts(&parts).is_empty());
+
+        // the elbow folded back, the wrist on the shoulder
+        let parts = robot(0, base(0.0), [0.0, -90.0, 170.0, 0.0, 0.0, 0.0]);
+        assert!(!contacts(&parts).is_empty());
+    }
+}
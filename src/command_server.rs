@@ -0,0 +1,683 @@
+// Local command server of the native application, enabled by the "server" feature.
+// Clients send one JSON request per line over TCP, or one per text message over WebSocket:
+//   {"id": 1, "cmd": "set_joints", "robot": 0, "joints": [90, -120, 90, -60, -90, 0]}
+// and get a reply with the same id. After "subscribe" the state of every robot is streamed back,
+// along with the collisions as they begin and end.
+use bevy::prelude::*;
+use flume::{unbounded, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    apply_cmd,
+    collision::CollisionEvent,
+    robot_cell::{FingerPos, GripCurrent, RobotCells},
+    urscript::from_isometry,
+    Cmd, CmdRobotQuery, GripperCmd, RobotCmd,
+};
+
+pub const TCP_ADDR: &str = "127.0.0.1:7878"; // JSON lines
+pub const WS_ADDR: &str = "127.0.0.1:7879"; // WebSocket, JSON text messages
+const STATE_PERIOD: f32 = 0.05; // s, between two state updates to subscribed clients
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    // robot: all robots when none
+    GetState {
+        #[serde(default)]
+        robot: Option<u64>,
+    },
+    Subscribe {
+        #[serde(default = "enabled")]
+        enabled: bool,
+    },
+    #[serde(untagged)]
+    Cmd(CmdRequest),
+}
+
+// requests applied as the commands of the egui window and the JS API
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum CmdRequest {
+    // joints: deg
+    SetJoints {
+        robot: u16,
+        joints: [f64; 6],
+    },
+    // joint: range [1, 6]
+    // angle: deg
+    SetJoint {
+        robot: u16,
+        joint: u16,
+        angle: f32,
+    },
+    // fingers: %
+    SetFingers {
+        robot: u16,
+        fingers: [f32; 2],
+    },
+    // width: mm, between the fingertips, both jaws moving symmetrically
+    // current: motor current limit, % of the maximum, kept when missing
+    SetWidth {
+        robot: u16,
+        width: f32,
+        #[serde(default)]
+        current: Option<f32>,
+    },
+    // pose: tcp relative to robot base, ( x, y, z ) m, rotation vector rad
+    MoveToPose {
+        robot: u16,
+        pose: [f64; 6],
+    },
+    // path: program file to load first, else the program in the editor is run
+    RunProgram {
+        robot: u16,
+        #[serde(default)]
+        path: Option<String>,
+    },
+    RunScript {
+        robot: u16,
+        source: String,
+    },
+    Stop {
+        robot: u16,
+    },
+    // pos: m
+    AddRobot {
+        robot: u16,
+        pos: [f32; 3],
+    },
+    RemoveRobot {
+        robot: u16,
+    },
+}
+
+impl CmdRequest {
+    // Out: the commands in the order they apply, a program file is read here
+    fn into_cmds(self) -> Result<Vec<Cmd>, String> {
+        let request = 0; // replies go by the id of the request instead
+        let cmd = match self {
+            CmdRequest::SetJoints { robot, joints } => Cmd::Robot(RobotCmd::Joints {
+                robot,
+                joints,
+                request,
+            }),
+            CmdRequest::SetJoint {
+                robot,
+                joint,
+                angle,
+            } => Cmd::Robot(RobotCmd::JointPos {
+                robot,
+                joint,
+                angle,
+            }),
+            CmdRequest::SetFingers { robot, fingers } => Cmd::Gripper(GripperCmd::Fingers {
+                robot,
+                fingers,
+                request,
+            }),
+            CmdRequest::SetWidth {
+                robot,
+                width,
+                current,
+            } => Cmd::Gripper(GripperCmd::Width {
+                robot,
+                width,
+                current,
+                request,
+            }),
+            CmdRequest::MoveToPose { robot, pose } => Cmd::Robot(RobotCmd::Pose {
+                robot,
+                pose,
+                request,
+            }),
+            CmdRequest::RunProgram { robot, path } => {
+                let mut cmds = Vec::new();
+                if let Some(path) = path {
+                    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+                    cmds.push(Cmd::Robot(RobotCmd::LoadProgram {
+                        robot,
+                        text,
+                        request,
+                    }));
+                }
+                cmds.push(Cmd::Robot(RobotCmd::RunProgram { robot, request }));
+                return Ok(cmds);
+            }
+            CmdRequest::RunScript { robot, source } => Cmd::Robot(RobotCmd::RunScript {
+                robot,
+                source,
+                request,
+            }),
+            CmdRequest::Stop { robot } => Cmd::Robot(RobotCmd::Stop { robot, request }),
+            CmdRequest::AddRobot { robot, pos } => Cmd::AddRobot {
+                robot,
+                pos,
+                request,
+            },
+            CmdRequest::RemoveRobot { robot } => Cmd::RemoveRobot { robot, request },
+        };
+        Ok(vec![cmd])
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct RobotState {
+    pub robot: u64,
+    pub joints: [f64; 6],          // deg
+    pub fingers: Option<[f32; 2]>, // %
+    pub width: Option<f32>,        // mm, between the fingertips
+    pub tcp: [f64; 6],             // ( x, y, z ) m, rotation vector rad
+    pub running: bool,             // a program or script is moving the robot
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Reply {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<serde_json::Value>,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        robots: Option<Vec<RobotState>>,
+    },
+    State(RobotState),
+    Collision(CollisionEvent),
+}
+
+impl Message {
+    fn reply(
+        id: Option<serde_json::Value>,
+        result: Result<Option<Vec<RobotState>>, String>,
+    ) -> Self {
+        match result {
+            Ok(robots) => Message::Reply {
+                id,
+                ok: true,
+                error: None,
+                robots,
+            },
+            Err(error) => Message::Reply {
+                id,
+                ok: false,
+                error: Some(error),
+                robots: None,
+            },
+        }
+    }
+
+    fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+// from the connection threads to the app
+enum ServerEvent {
+    Connected(u64, Sender<String>),
+    Request(u64, Option<serde_json::Value>, Request),
+    Closed(u64),
+}
+
+struct Client {
+    sender: Sender<String>,
+    subscribed: bool,
+}
+
+#[derive(Resource)]
+pub struct CommandServer {
+    events: Receiver<ServerEvent>,
+    clients: BTreeMap<u64, Client>,
+    since_state: f32, // s
+}
+
+impl CommandServer {
+    fn send(&mut self, client: u64, message: &Message) {
+        if let Some(c) = self.clients.get(&client) {
+            if c.sender.send(message.to_text()).is_err() {
+                self.clients.remove(&client);
+            }
+        }
+    }
+}
+
+pub struct CommandServerPlugin;
+
+impl Plugin for CommandServerPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = unbounded();
+        let next_client = Arc::new(AtomicU64::new(0));
+        for (addr, websocket) in [(TCP_ADDR, false), (WS_ADDR, true)] {
+            match TcpListener::bind(addr) {
+                Ok(listener) => {
+                    info!("command server listening on {}", addr);
+                    let sender = sender.clone();
+                    let next_client = next_client.clone();
+                    thread::spawn(move || listen(listener, websocket, next_client, sender));
+                }
+                Err(e) => warn!("command server cannot listen on {}: {}", addr, e),
+            }
+        }
+        app.insert_resource(CommandServer {
+            events: receiver,
+            clients: BTreeMap::new(),
+            since_state: 0.0,
+        })
+        .add_systems(
+            Update,
+            (
+                serve_requests,
+                stream_state.after(serve_requests),
+                stream_collisions.after(serve_requests),
+            ),
+        );
+    }
+}
+
+fn parse(text: &str) -> Result<(Option<serde_json::Value>, Request), String> {
+    serde_json::from_str::<Envelope>(text)
+        .map(|e| (e.id, e.request))
+        .map_err(|e| format!("invalid request: {}", e))
+}
+
+fn listen(
+    listener: TcpListener,
+    websocket: bool,
+    next_client: Arc<AtomicU64>,
+    events: Sender<ServerEvent>,
+) {
+    for stream in listener.incoming().flatten() {
+        let client = next_client.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = unbounded();
+        if events
+            .send(ServerEvent::Connected(client, sender.clone()))
+            .is_err()
+        {
+            return;
+        }
+        let events = events.clone();
+        thread::spawn(move || {
+            if websocket {
+                serve_websocket(stream, client, sender, receiver, &events);
+            } else {
+                serve_lines(stream, client, sender, receiver, &events);
+            }
+            let _ = events.send(ServerEvent::Closed(client));
+        });
+    }
+}
+
+// reply: the outgoing queue of this client, for requests that can not be parsed
+fn serve_lines(
+    stream: TcpStream,
+    client: u64,
+    reply: Sender<String>,
+    outgoing: Receiver<String>,
+    events: &Sender<ServerEvent>,
+) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    // ends when the app drops the client
+    thread::spawn(move || {
+        for text in outgoing.iter() {
+            if writeln!(writer, "{}", text).is_err() {
+                break;
+            }
+        }
+    });
+    for line in BufReader::new(&stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse(&line) {
+            Ok((id, request)) => {
+                let _ = events.send(ServerEvent::Request(client, id, request));
+            }
+            Err(e) => {
+                let _ = reply.send(Message::reply(None, Err(e)).to_text());
+            }
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+fn serve_websocket(
+    stream: TcpStream,
+    client: u64,
+    reply: Sender<String>,
+    outgoing: Receiver<String>,
+    events: &Sender<ServerEvent>,
+) {
+    use tungstenite::{error::Error, Message as WsMessage};
+
+    let Ok(mut socket) = tungstenite::accept(stream) else {
+        return;
+    };
+    // poll for requests, in between send what is queued
+    if socket
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .is_err()
+    {
+        return;
+    }
+    loop {
+        match socket.read() {
+            Ok(WsMessage::Text(text)) => match parse(&text) {
+                Ok((id, request)) => {
+                    let _ = events.send(ServerEvent::Request(client, id, request));
+                }
+                Err(e) => {
+                    let _ = reply.send(Message::reply(None, Err(e)).to_text());
+                }
+            },
+            Ok(WsMessage::Close(_)) => break,
+            Ok(_) => {}
+            Err(Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => break,
+        }
+        while let Ok(text) = outgoing.try_recv() {
+            if socket.send(WsMessage::Text(text)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+type GripperQuery<'w, 's> = Query<'w, 's, (&'static mut FingerPos, &'static mut GripCurrent)>;
+
+fn robot_state(
+    id: u64,
+    cells: &RobotCells,
+    q_robot: &CmdRobotQuery,
+    q_gripper: &GripperQuery,
+) -> Option<RobotState> {
+    let cell = cells.0.get(&id)?;
+    let (robot, _, player, script) = q_robot.get(cell.robot).ok()?;
+    let fingers = cell
+        .tool
+        .and_then(|e| q_gripper.get(e).ok())
+        .map(|(f, _)| f);
+    Some(RobotState {
+        robot: id,
+        joints: robot.joints().map(f64::to_degrees),
+        fingers: fingers.map(|f| f.0),
+        width: fingers
+            .zip(cell.end_effector())
+            .map(|(f, model)| model.fingers_to_width(&f.0)),
+        tcp: from_isometry(&robot.tcp_pose()),
+        running: player.is_running() || script.is_running(),
+    })
+}
+
+fn serve_requests(
+    mut commands: Commands,
+    mut server: ResMut<CommandServer>,
+    cells: Res<RobotCells>,
+    mut q_robot: CmdRobotQuery,
+    mut q_gripper: GripperQuery,
+) {
+    while let Ok(event) = server.events.try_recv() {
+        let (client, id, request) = match event {
+            ServerEvent::Connected(client, sender) => {
+                server.clients.insert(
+                    client,
+                    Client {
+                        sender,
+                        subscribed: false,
+                    },
+                );
+                continue;
+            }
+            ServerEvent::Closed(client) => {
+                server.clients.remove(&client);
+                continue;
+            }
+            ServerEvent::Request(client, id, request) => (client, id, request),
+        };
+
+        let result = match request {
+            Request::GetState { robot: Some(robot) } => {
+                match robot_state(robot, &cells, &q_robot, &q_gripper) {
+                    Some(state) => Ok(Some(vec![state])),
+                    None => Err(format!("unknown robot {}", robot)),
+                }
+            }
+            Request::GetState { robot: None } => Ok(Some(
+                cells
+                    .0
+                    .keys()
+                    .filter_map(|id| robot_state(*id, &cells, &q_robot, &q_gripper))
+                    .collect(),
+            )),
+            Request::Subscribe { enabled } => {
+                if let Some(c) = server.clients.get_mut(&client) {
+                    c.subscribed = enabled;
+                }
+                Ok(None)
+            }
+            Request::Cmd(request) => {
+                // the program editor shows the file a program was loaded from
+                let loaded = match &request {
+                    CmdRequest::RunProgram {
+                        robot,
+                        path: Some(path),
+                    } => Some((*robot as u64, path.clone())),
+                    _ => None,
+                };
+                let result = request.into_cmds().and_then(|cmds| {
+                    for cmd in cmds {
+                        apply_cmd(cmd, &mut commands, &cells, &mut q_robot, &mut q_gripper)?;
+                    }
+                    Ok(None)
+                });
+                if let (Ok(_), Some((robot, path))) = (&result, loaded) {
+                    let cell = cells.0.get(&robot);
+                    if let Some((_, _, mut player, _)) =
+                        cell.and_then(|c| q_robot.get_mut(c.robot).ok())
+                    {
+                        player.path = path;
+                    }
+                }
+                result
+            }
+        };
+        server.send(client, &Message::reply(id, result));
+    }
+}
+
+fn stream_state(
+    time: Res<Time>,
+    mut server: ResMut<CommandServer>,
+    cells: Res<RobotCells>,
+    q_robot: CmdRobotQuery,
+    q_gripper: GripperQuery,
+) {
+    server.since_state += time.delta_seconds();
+    if server.since_state < STATE_PERIOD {
+        return;
+    }
+    server.since_state = 0.0;
+    if !server.clients.values().any(|c| c.subscribed) {
+        return;
+    }
+    let messages: Vec<String> = cells
+        .0
+        .keys()
+        .filter_map(|id| robot_state(*id, &cells, &q_robot, &q_gripper))
+        .map(|state| Message::State(state).to_text())
+        .collect();
+    server
+        .clients
+        .retain(|_, c| !c.subscribed || messages.iter().all(|m| c.sender.send(m.clone()).is_ok()));
+}
+
+fn stream_collisions(mut server: ResMut<CommandServer>, mut events: EventReader<CollisionEvent>) {
+    let messages: Vec<String> = events
+        .iter()
+        .map(|event| Message::Collision(event.clone()).to_text())
+        .collect();
+    if messages.is_empty() {
+        return;
+    }
+    server
+        .clients
+        .retain(|_, c| !c.subscribed || messages.iter().all(|m| c.sender.send(m.clone()).is_ok()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::Obstacle;
+
+    #[test]
+    fn parse_requests() {
+        let (id, request) =
+            parse(r#"{"id": 7, "cmd": "set_joint", "robot": 1, "joint": 2, "angle": -90}"#)
+                .unwrap();
+        assert_eq!(id, Some(serde_json::json!(7)));
+        assert_eq!(
+            request,
+            Request::Cmd(CmdRequest::SetJoint {
+                robot: 1,
+                joint: 2,
+                angle: -90.0
+            })
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "get_state"}"#).unwrap(),
+            (None, Request::GetState { robot: None })
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "subscribe"}"#).unwrap().1,
+            Request::Subscribe { enabled: true }
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "set_width", "robot": 1, "width": 42.5}"#)
+                .unwrap()
+                .1,
+            Request::Cmd(CmdRequest::SetWidth {
+                robot: 1,
+                width: 42.5,
+                current: None
+            })
+        );
+        let run = CmdRequest::RunProgram {
+            robot: 0,
+            path: Some("no/such/program.ron".to_string()),
+        };
+        assert!(run.into_cmds().is_err());
+        assert!(parse(r#"{"cmd": "fly", "robot": 0}"#).is_err());
+        assert!(parse(r#"{"cmd": "set_joints", "robot": 0, "joints": [1, 2]}"#).is_err());
+    }
+
+    #[test]
+    fn message_format() {
+        let reply = Message::reply(Some(serde_json::json!("a")), Err("unknown robot 3".into()));
+        assert_eq!(
+            reply.to_text(),
+            r#"{"type":"reply","id":"a","ok":false,"error":"unknown robot 3"}"#
+        );
+        let state = Message::State(RobotState {
+            robot: 0,
+            joints: [0.0; 6],
+            fingers: None,
+            width: None,
+            tcp: [0.0; 6],
+            running: false,
+        });
+        let value: serde_json::Value = serde_json::from_str(&state.to_text()).unwrap();
+        assert_eq!(value["type"], "state");
+        assert_eq!(value["robot"], 0);
+        let collision = Message::Collision(CollisionEvent {
+            robot: 1,
+            part: "forearm_link".to_string(),
+            with: Obstacle::Robot {
+                robot: 0,
+                part: "gripper".to_string(),
+            },
+            colliding: true,
+        });
+        assert_eq!(
+            collision.to_text(),
+            r#"{"type":"collision","robot":1,"part":"forearm_link","with":{"kind":"robot","robot":0,"part":"gripper"},"colliding":true}"#
+        );
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, events) = unbounded();
+        thread::spawn(move || listen(listener, false, Arc::new(AtomicU64::new(0)), sender));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(stream, "not json").unwrap();
+        writeln!(stream, r#"{{"id": 1, "cmd": "stop", "robot": 0}}"#).unwrap();
+        let timeout = Duration::from_secs(5);
+        let ServerEvent::Connected(client, reply) = events.recv_timeout(timeout).unwrap() else {
+            panic!("expected a connection");
+        };
+        let ServerEvent::Request(c, id, request) = events.recv_timeout(timeout).unwrap() else {
+            panic!("expected a request");
+        };
+        assert_eq!(
+            (c, id, request),
+            (
+                client,
+                Some(serde_json::json!(1)),
+                Request::Cmd(CmdRequest::Stop { robot: 0 })
+            )
+        );
+        reply
+            .send(Message::reply(Some(serde_json::json!(1)), Ok(None)).to_text())
+            .unwrap();
+
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        let error: serde_json::Value =
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(error["ok"], false);
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"type":"reply","id":1,"ok":true}"#
+        );
+
+        stream.shutdown(Shutdown::Both).unwrap();
+        assert!(matches!(
+            events.recv_timeout(timeout),
+            Ok(ServerEvent::Closed(c)) if c == client
+        ));
+    }
+}
@@ -16,7 +16,7 @@ struct Trail {
     data: VecDeque<(f32, Vec3)>, // ( time, point )
 }
 
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct Trails {
     map: BTreeMap<u64, Trail>,
 }
@@ -34,6 +34,10 @@ impl Trails {
         trail.data.push_back((time.abs(), point));
     }
 
+    pub fn remove(&mut self, id: u64) {
+        self.map.remove(&id);
+    }
+
     fn draw_trails(mut gizmos: Gizmos, mut trails: ResMut<Trails>, time: Res<Time>) {
         let time = time.elapsed_seconds();
         for trail in trails.map.values_mut() {
@@ -50,18 +54,10 @@ impl Trails {
             let mut point_old: Option<Vec3> = None;
             for (_, point_new) in trail.data.iter() {
                 if let Some(point_old) = point_old {
-                    gizmos.line(point_old, point_new.clone(), trail.color);
+                    gizmos.line(point_old, *point_new, trail.color);
                 }
-                point_old = Some(point_new.clone());
+                point_old = Some(*point_new);
             }
         }
     }
 }
-
-impl Default for Trails {
-    fn default() -> Self {
-        Trails {
-            map: BTreeMap::new(),
-        }
-    }
-}
@@ -0,0 +1,440 @@
+// End effectors mounted on the flange. A tool model describes its parts: collision capsules,
+// fingertip markers and where the parts go for a jaws position. Spawning, moving the parts and
+// the colliders are shared by every model.
+use bevy::prelude::*;
+use std::{f32::consts::FRAC_PI_2, ops::RangeInclusive};
+
+use crate::{cell_layout::ToolKind, gripper_ctm2f110::Ctm2f110};
+
+// collision capsules in the frame of each part, fitted to the meshes
+// ( segment start, segment end, radius ), m
+pub type Capsules = [([f32; 3], [f32; 3], f32)];
+
+pub struct PartDef {
+    pub name: &'static str,
+    pub capsules: &'static Capsules,
+    pub fingertip: Option<Finger>, // marker followed by the trails
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Finger {
+    One,
+    Two,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Actuation {
+    None,
+    Jaws,    // driven by FingerPos
+    Suction, // driven by Vacuum
+}
+
+pub trait EndEffector: Sync {
+    fn name(&self) -> &'static str;
+
+    // tcp relative to the tool mount, along z, m
+    fn tcp_offset(&self) -> f32;
+
+    fn actuation(&self) -> Actuation;
+
+    // in the order of place_parts
+    fn parts(&self) -> &'static [PartDef];
+
+    // jaws: range [0.0, 1.0], 1.0 open, ignored by the tools without jaws
+    // Out: parts relative to the tool mount
+    fn place_parts(&self, jaws: [f32; 2]) -> Vec<Transform>;
+
+    // adds the meshes or scenes to the spawned parts
+    fn add_visuals(&self, world: &mut World, parts: &[Entity]);
+
+    // opening between the fingertips
+    // In: fingers range [0.0, 100.0]
+    // Out: mm
+    fn fingers_to_width(&self, _fingers: &[f32; 2]) -> f32 {
+        0.0
+    }
+
+    // both jaws moving symmetrically
+    // In: width mm
+    // Out: fingers range [0.0, 100.0]
+    fn width_to_fingers(&self, _width: f32) -> [f32; 2] {
+        [0.0, 0.0]
+    }
+
+    // Out: mm, closed to open
+    fn width_range(&self) -> RangeInclusive<f32> {
+        0.0..=0.0
+    }
+}
+
+impl ToolKind {
+    pub const ALL: [ToolKind; 4] = [
+        ToolKind::Ctm2f110,
+        ToolKind::ParallelGripper,
+        ToolKind::SuctionCup,
+        ToolKind::Flange,
+    ];
+
+    pub fn end_effector(&self) -> &'static dyn EndEffector {
+        match self {
+            ToolKind::Ctm2f110 => &Ctm2f110,
+            ToolKind::ParallelGripper => &ParallelGripper,
+            ToolKind::SuctionCup => &SuctionCup,
+            ToolKind::Flange => &Flange,
+        }
+    }
+}
+
+// a tool, mounted on a robot or parked in a rack
+#[derive(Component)]
+pub struct Tool {
+    pub robot: Option<u64>, // None while parked
+    pub kind: ToolKind,
+    pub jaws: [f32; 2], // range [ 0.0, 1.0 ], 1.0 open
+}
+
+// index in EndEffector::parts of the tool
+#[derive(Component)]
+pub struct ToolPart(pub usize);
+
+#[derive(Component)]
+pub struct GripperFingertip {
+    pub id: u64,
+    pub finger: Finger,
+}
+
+// suction of a vacuum tool
+#[derive(Component, Default)]
+pub struct Vacuum(pub bool);
+
+pub struct EndEffectorPlugin;
+
+impl Plugin for EndEffectorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, EndEffectorPlugin::update_part_pos);
+    }
+}
+
+impl EndEffectorPlugin {
+    // robot: None for a parked tool
+    // Out: the tool, its parts are children
+    pub fn spawn_tool(
+        world: &mut World,
+        robot: Option<u64>,
+        kind: ToolKind,
+        tf: Transform,
+    ) -> Entity {
+        let model = kind.end_effector();
+        let jaws = [0.0, 0.0];
+        let parent = world
+            .spawn((
+                Tool { robot, kind, jaws },
+                SpatialBundle {
+                    transform: tf,
+                    ..default()
+                },
+            ))
+            .id();
+        let parts: Vec<Entity> = model
+            .parts()
+            .iter()
+            .zip(model.place_parts(jaws))
+            .enumerate()
+            .map(|(i, (def, transform))| {
+                let mut part = world.spawn((
+                    SpatialBundle {
+                        transform,
+                        ..default()
+                    },
+                    ToolPart(i),
+                ));
+                if let (Some(id), Some(finger)) = (robot, def.fingertip) {
+                    part.insert(GripperFingertip { id, finger });
+                }
+                part.id()
+            })
+            .collect();
+        model.add_visuals(world, &parts);
+        world.entity_mut(parent).push_children(&parts);
+        parent
+    }
+
+    pub fn update_part_pos(
+        q_tool: Query<(&Tool, &Children), Changed<Tool>>,
+        mut q_part: Query<(&ToolPart, &mut Transform)>,
+    ) {
+        for (tool, children) in q_tool.iter() {
+            let tfs = tool.kind.end_effector().place_parts(tool.jaws);
+            for &child in children.iter() {
+                if let Ok((part, mut tf)) = q_part.get_mut(child) {
+                    if let Some(placed) = tfs.get(part.0) {
+                        if *tf != *placed {
+                            *tf = *placed;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// a mesh as child of a part
+fn add_mesh(world: &mut World, part: Entity, mesh: Mesh, transform: Transform, color: Color) {
+    let mesh = world.resource_mut::<Assets<Mesh>>().add(mesh);
+    let material = world
+        .resource_mut::<Assets<StandardMaterial>>()
+        .add(StandardMaterial {
+            base_color: color,
+            perceptual_roughness: 0.6,
+            ..default()
+        });
+    let child = world
+        .spawn(PbrBundle {
+            mesh,
+            material,
+            transform,
+            ..default()
+        })
+        .id();
+    world.entity_mut(part).push_children(&[child]);
+}
+
+// bevy cylinders stand along y
+fn cylinder_z(radius: f32, height: f32, z: f32) -> (Mesh, Transform) {
+    let mesh = Mesh::from(shape::Cylinder {
+        radius,
+        height,
+        resolution: 24,
+        segments: 1,
+    });
+    let transform =
+        Transform::from_xyz(0.0, 0.0, z).with_rotation(Quat::from_rotation_x(FRAC_PI_2));
+    (mesh, transform)
+}
+
+const TOOL_COLOR: Color = Color::rgb(0.25, 0.25, 0.28);
+const PAD_COLOR: Color = Color::rgb(0.75, 0.75, 0.7);
+
+// electric parallel gripper, fingers sliding on a cylindrical body
+pub struct ParallelGripper;
+
+const PARALLEL_RADIUS: f32 = 0.04; // m
+const PARALLEL_LENGTH: f32 = 0.1; // m
+const PARALLEL_STROKE: f32 = 0.08; // m, width between the open fingers
+const PARALLEL_FINGER: [f32; 3] = [0.01, 0.02, 0.05]; // m
+
+impl EndEffector for ParallelGripper {
+    fn name(&self) -> &'static str {
+        "parallel gripper"
+    }
+
+    fn tcp_offset(&self) -> f32 {
+        PARALLEL_LENGTH + PARALLEL_FINGER[2] - 0.01
+    }
+
+    fn actuation(&self) -> Actuation {
+        Actuation::Jaws
+    }
+
+    fn parts(&self) -> &'static [PartDef] {
+        const FINGER: &Capsules = &[([0.0, 0.0, -0.015], [0.0, 0.0, 0.015], 0.01)];
+        &[
+            PartDef {
+                name: "gripper",
+                capsules: &[([0.0, 0.0, 0.04], [0.0, 0.0, 0.06], 0.04)],
+                fingertip: None,
+            },
+            PartDef {
+                name: "finger1",
+                capsules: FINGER,
+                fingertip: None,
+            },
+            PartDef {
+                name: "finger2",
+                capsules: FINGER,
+                fingertip: None,
+            },
+            PartDef {
+                name: "fingertip1",
+                capsules: &[],
+                fingertip: Some(Finger::One),
+            },
+            PartDef {
+                name: "fingertip2",
+                capsules: &[],
+                fingertip: Some(Finger::Two),
+            },
+        ]
+    }
+
+    fn place_parts(&self, jaws: [f32; 2]) -> Vec<Transform> {
+        // fingertip distance from the axis
+        let [x1, x2] = jaws.map(|j| j.clamp(0.0, 1.0) * PARALLEL_STROKE / 2.0);
+        let finger_z = PARALLEL_LENGTH + PARALLEL_FINGER[2] / 2.0;
+        let pad = PARALLEL_FINGER[0] / 2.0;
+        let tcp = self.tcp_offset();
+        vec![
+            Transform::IDENTITY,
+            Transform::from_xyz(-x1 - pad, 0.0, finger_z),
+            Transform::from_xyz(x2 + pad, 0.0, finger_z),
+            Transform::from_xyz(-x1, 0.0, tcp),
+            Transform::from_xyz(x2, 0.0, tcp),
+        ]
+    }
+
+    fn add_visuals(&self, world: &mut World, parts: &[Entity]) {
+        let (body, tf) = cylinder_z(PARALLEL_RADIUS, PARALLEL_LENGTH, PARALLEL_LENGTH / 2.0);
+        add_mesh(world, parts[0], body, tf, TOOL_COLOR);
+        let [x, y, z] = PARALLEL_FINGER;
+        for &finger in &parts[1..3] {
+            let mesh = Mesh::from(shape::Box::new(x, y, z));
+            add_mesh(world, finger, mesh, Transform::IDENTITY, PAD_COLOR);
+        }
+    }
+
+    fn fingers_to_width(&self, fingers: &[f32; 2]) -> f32 {
+        let [f1, f2] = fingers.map(|f| f.clamp(0.0, 100.0) / 100.0);
+        (f1 + f2) / 2.0 * PARALLEL_STROKE * 1000.0
+    }
+
+    fn width_to_fingers(&self, width: f32) -> [f32; 2] {
+        let pos = (width / (PARALLEL_STROKE * 1000.0)).clamp(0.0, 1.0) * 100.0;
+        [pos, pos]
+    }
+
+    fn width_range(&self) -> RangeInclusive<f32> {
+        0.0..=PARALLEL_STROKE * 1000.0
+    }
+}
+
+// vacuum suction cup on a straight extension
+pub struct SuctionCup;
+
+const SUCTION_LENGTH: f32 = 0.08; // m, extension
+const SUCTION_RADIUS: f32 = 0.015; // m, extension
+const CUP_RADIUS: f32 = 0.025; // m
+const CUP_HEIGHT: f32 = 0.02; // m
+
+impl EndEffector for SuctionCup {
+    fn name(&self) -> &'static str {
+        "suction cup"
+    }
+
+    fn tcp_offset(&self) -> f32 {
+        SUCTION_LENGTH + CUP_HEIGHT
+    }
+
+    fn actuation(&self) -> Actuation {
+        Actuation::Suction
+    }
+
+    fn parts(&self) -> &'static [PartDef] {
+        &[
+            PartDef {
+                name: "suction",
+                capsules: &[([0.0, 0.0, 0.015], [0.0, 0.0, 0.065], 0.015)],
+                fingertip: None,
+            },
+            PartDef {
+                name: "cup",
+                capsules: &[([0.0, 0.0, 0.0], [0.0, 0.0, 0.0], 0.025)],
+                fingertip: None,
+            },
+            PartDef {
+                name: "fingertip1",
+                capsules: &[],
+                fingertip: Some(Finger::One),
+            },
+        ]
+    }
+
+    fn place_parts(&self, _jaws: [f32; 2]) -> Vec<Transform> {
+        vec![
+            Transform::IDENTITY,
+            Transform::from_xyz(0.0, 0.0, SUCTION_LENGTH + CUP_HEIGHT / 2.0),
+            Transform::from_xyz(0.0, 0.0, self.tcp_offset()),
+        ]
+    }
+
+    fn add_visuals(&self, world: &mut World, parts: &[Entity]) {
+        let (extension, tf) = cylinder_z(SUCTION_RADIUS, SUCTION_LENGTH, SUCTION_LENGTH / 2.0);
+        add_mesh(world, parts[0], extension, tf, TOOL_COLOR);
+        let (cup, tf) = cylinder_z(CUP_RADIUS, CUP_HEIGHT, 0.0);
+        add_mesh(world, parts[1], cup, tf, Color::rgb(0.1, 0.3, 0.6));
+    }
+}
+
+// nothing mounted, the tcp is on the flange
+pub struct Flange;
+
+impl EndEffector for Flange {
+    fn name(&self) -> &'static str {
+        "flange"
+    }
+
+    fn tcp_offset(&self) -> f32 {
+        0.0
+    }
+
+    fn actuation(&self) -> Actuation {
+        Actuation::None
+    }
+
+    fn parts(&self) -> &'static [PartDef] {
+        &[PartDef {
+            name: "fingertip1",
+            capsules: &[],
+            fingertip: Some(Finger::One),
+        }]
+    }
+
+    fn place_parts(&self, _jaws: [f32; 2]) -> Vec<Transform> {
+        vec![Transform::IDENTITY]
+    }
+
+    fn add_visuals(&self, _world: &mut World, _parts: &[Entity]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_are_consistent() {
+        for kind in ToolKind::ALL {
+            let model = kind.end_effector();
+            let parts = model.parts();
+            assert_eq!(model.place_parts([0.5, 0.5]).len(), parts.len());
+            // the tcp sits between the fingertips
+            let tips: Vec<Transform> = parts
+                .iter()
+                .zip(model.place_parts([0.0, 0.0]))
+                .filter(|(def, _)| def.fingertip.is_some())
+                .map(|(_, tf)| tf)
+                .collect();
+            assert!(!tips.is_empty());
+            let center = tips.iter().map(|tf| tf.translation).sum::<Vec3>() / tips.len() as f32;
+            assert!(
+                (center.z - model.tcp_offset()).abs() < 1e-3,
+                "{}",
+                model.name()
+            );
+
+            if model.actuation() == Actuation::Jaws {
+                let range = model.width_range();
+                for width in [*range.start(), 30.0, *range.end()] {
+                    let fingers = model.width_to_fingers(width);
+                    assert!((model.fingers_to_width(&fingers) - width).abs() < 1e-3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_width_matches_the_fingertips() {
+        let model = &ParallelGripper;
+        let tfs = model.place_parts([0.25, 0.25]);
+        let width = tfs[3].translation.distance(tfs[4].translation) * 1000.0;
+        assert!((model.fingers_to_width(&[25.0, 25.0]) - width).abs() < 1e-3);
+    }
+}
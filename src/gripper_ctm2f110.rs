@@ -1,5 +1,10 @@
 use bevy::prelude::*;
-use std::f32::consts::{FRAC_PI_2, PI};
+use std::{
+    f32::consts::{FRAC_PI_2, PI},
+    ops::RangeInclusive,
+};
+
+use crate::end_effector::{Actuation, Capsules, EndEffector, Finger, PartDef};
 
 const ASSET: [&str; 4] = [
     "ctm2f110/ctm2f110.gltf#Scene0",
@@ -14,240 +19,166 @@ const DRIVING_ANGLE: [f32; 2] = [30.0 / 180.0 * PI, 103.5 / 180.0 * PI]; // rad
 const FOLLOWER_POS: [f32; 2] = [57.0 / 1000.0, 68.75 / 1000.0]; // [ x, z ] mm
 const FINGERTIP_POS: [f32; 2] = [-11.0 / 1000.0, 50.5 / 1000.0]; // mm
 
-#[derive(Component)]
-pub enum GripperComponent {
-    Main,
-    Driving1,
-    Driving2,
-    Follower1,
-    Follower2,
-    Finger1,
-    Finger2,
-    Fingertip1,
-    Fingertip2,
-}
+// collision capsules in the frame of each part, fitted to the meshes
+const MAIN_CAPSULES: &Capsules = &[
+    ([-0.0255, 0.0, 0.0375], [0.0255, 0.0, 0.0375], 0.0375),
+    ([-0.0255, 0.0, 0.0632], [0.0255, 0.0, 0.0632], 0.0375),
+];
+const DRIVING_CAPSULES: &Capsules = &[
+    ([0.0, 0.0, -0.018], [0.049, 0.0, -0.018], 0.012),
+    ([0.0, 0.0, 0.018], [0.049, 0.0, 0.018], 0.012),
+];
+const FOLLOWER_CAPSULES: &Capsules = &[
+    ([0.0, 0.0, -0.0066], [0.055, 0.0, -0.0066], 0.006),
+    ([0.0, 0.0, 0.0066], [0.055, 0.0, 0.0066], 0.006),
+];
+const FINGER_CAPSULES: &Capsules = &[([-0.0065, -0.0275, 0.0], [0.039, -0.0005, 0.0], 0.0115)];
+
+const PARTS: &[PartDef] = &[
+    PartDef {
+        name: "gripper",
+        capsules: MAIN_CAPSULES,
+        fingertip: None,
+    },
+    PartDef {
+        name: "driving1",
+        capsules: DRIVING_CAPSULES,
+        fingertip: None,
+    },
+    PartDef {
+        name: "driving2",
+        capsules: DRIVING_CAPSULES,
+        fingertip: None,
+    },
+    PartDef {
+        name: "follower1",
+        capsules: FOLLOWER_CAPSULES,
+        fingertip: None,
+    },
+    PartDef {
+        name: "follower2",
+        capsules: FOLLOWER_CAPSULES,
+        fingertip: None,
+    },
+    PartDef {
+        name: "finger1",
+        capsules: FINGER_CAPSULES,
+        fingertip: None,
+    },
+    PartDef {
+        name: "finger2",
+        capsules: FINGER_CAPSULES,
+        fingertip: None,
+    },
+    PartDef {
+        name: "fingertip1",
+        capsules: &[],
+        fingertip: Some(Finger::One),
+    },
+    PartDef {
+        name: "fingertip2",
+        capsules: &[],
+        fingertip: Some(Finger::Two),
+    },
+];
 
-#[derive(Eq, PartialEq)]
-pub enum Finger {
-    One,
-    Two,
-}
+// two fingers, each on a four-bar linkage
+pub struct Ctm2f110;
 
-#[derive(Component)]
-pub struct GripperFingertip {
-    pub id: u64,
-    pub finger: Finger,
-}
+impl EndEffector for Ctm2f110 {
+    fn name(&self) -> &'static str {
+        "CTM2F110"
+    }
 
-#[derive(Component)]
-pub struct GripperCtm2f110 {
-    pub id: u64,
-    pub pos1: f32, // range [ 0.0, 1.0 ]
-    pub pos2: f32, // range [ 0.0, 1.0 ]
-}
+    // at the closed fingertips
+    fn tcp_offset(&self) -> f32 {
+        compute_finger2(0.0)[3].translation.z
+    }
 
-#[derive(Resource, Clone)]
-struct GripperPluginRes {
-    main: Handle<Scene>,
-    driving: Handle<Scene>,
-    follower: Handle<Scene>,
-    finger: Handle<Scene>,
-}
+    fn actuation(&self) -> Actuation {
+        Actuation::Jaws
+    }
 
-impl FromWorld for GripperPluginRes {
-    fn from_world(world: &mut World) -> Self {
-        let asset_server = world.get_resource::<AssetServer>().unwrap();
-        let main = asset_server.load(ASSET[0]);
-        let driving = asset_server.load(ASSET[1]);
-        let follower = asset_server.load(ASSET[2]);
-        let finger = asset_server.load(ASSET[3]);
-        GripperPluginRes {
+    fn parts(&self) -> &'static [PartDef] {
+        PARTS
+    }
+
+    fn place_parts(&self, jaws: [f32; 2]) -> Vec<Transform> {
+        let [driving1, follower1, finger1, fingertip1] = compute_finger1(jaws[0]);
+        let [driving2, follower2, finger2, fingertip2] = compute_finger2(jaws[1]);
+        vec![
+            Transform::IDENTITY,
+            driving1,
+            driving2,
+            follower1,
+            follower2,
+            finger1,
+            finger2,
+            fingertip1,
+            fingertip2,
+        ]
+    }
+
+    fn add_visuals(&self, world: &mut World, parts: &[Entity]) {
+        let asset_server = world.resource::<AssetServer>();
+        let [main, driving, follower, finger] = ASSET.map(|a| asset_server.load::<Scene, _>(a));
+        let scenes = [
             main,
+            driving.clone(),
             driving,
+            follower.clone(),
             follower,
+            finger.clone(),
             finger,
+        ];
+        for (&part, scene) in parts.iter().zip(scenes) {
+            world.entity_mut(part).insert(scene);
         }
     }
-}
-
-pub struct GripperPlugin;
 
-impl GripperPlugin {
-    // (main, fingertip1, fingertip2)
-    pub fn add_gripper(
-        world: &mut World,
-        id: u64,
-        tf: Option<Transform>,
-        finger_pos: Option<[f32; 2]>,
-    ) -> (Entity, Entity, Entity) {
-        let mut spatial_bundle = SpatialBundle::default();
-        if let Some(tf) = tf {
-            spatial_bundle.transform = tf;
-        }
-        let [pos1, pos2] = finger_pos.unwrap_or([1.0, 1.0]);
-        let gripper = GripperCtm2f110 { id, pos1, pos2 };
-        let [driving1, follower1, finger1, fingertip1] = compute_finger1(pos1);
-        let [driving2, follower2, finger2, fingertip2] = compute_finger2(pos2);
-        let parent = world.spawn((gripper, spatial_bundle)).id();
+    fn fingers_to_width(&self, fingers: &[f32; 2]) -> f32 {
+        fingers_to_width(fingers)
+    }
 
-        let res: GripperPluginRes = world.get_resource::<GripperPluginRes>().unwrap().clone();
-        let child_main = world
-            .spawn((
-                SceneBundle {
-                    scene: res.main.clone(),
-                    ..default()
-                },
-                GripperComponent::Main,
-            ))
-            .id();
-        let child_driving1 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.driving.clone(),
-                    transform: driving1,
-                    ..default()
-                },
-                GripperComponent::Driving1,
-            ))
-            .id();
-        let child_driving2 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.driving.clone(),
-                    transform: driving2,
-                    ..default()
-                },
-                GripperComponent::Driving2,
-            ))
-            .id();
-        let child_follower1 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.follower.clone(),
-                    transform: follower1,
-                    ..default()
-                },
-                GripperComponent::Follower1,
-            ))
-            .id();
-        let child_follower2 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.follower.clone(),
-                    transform: follower2,
-                    ..default()
-                },
-                GripperComponent::Follower2,
-            ))
-            .id();
-        let child_finger1 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.finger.clone(),
-                    transform: finger1,
-                    ..default()
-                },
-                GripperComponent::Finger1,
-            ))
-            .id();
-        let child_finger2 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.finger.clone(),
-                    transform: finger2,
-                    ..default()
-                },
-                GripperComponent::Finger2,
-            ))
-            .id();
-        let child_fingertip1 = world
-            .spawn((
-                SpatialBundle {
-                    transform: fingertip1,
-                    ..default()
-                },
-                GripperComponent::Fingertip1,
-                GripperFingertip {
-                    id,
-                    finger: Finger::One,
-                },
-            ))
-            .id();
-        let child_fingertip2 = world
-            .spawn((
-                SpatialBundle {
-                    transform: fingertip2,
-                    ..default()
-                },
-                GripperComponent::Fingertip2,
-                GripperFingertip {
-                    id,
-                    finger: Finger::Two,
-                },
-            ))
-            .id();
-        let children = [
-            child_main,
-            child_driving1,
-            child_driving2,
-            child_follower1,
-            child_follower2,
-            child_finger1,
-            child_finger2,
-            child_fingertip1,
-            child_fingertip2,
-        ];
-        world.entity_mut(parent).push_children(&children);
-        (parent, child_fingertip1, child_fingertip2)
+    fn width_to_fingers(&self, width: f32) -> [f32; 2] {
+        width_to_fingers(width)
     }
 
-    fn update_component_pos(
-        q_parent: Query<(&GripperCtm2f110, &Children), Changed<GripperCtm2f110>>,
-        mut q_child: Query<(&GripperComponent, &mut Transform)>,
-    ) {
-        for (gripper, children) in q_parent.iter() {
-            let [driving1, follower1, finger1, fingertip1] = compute_finger1(gripper.pos1);
-            let [driving2, follower2, finger2, fingertip2] = compute_finger2(gripper.pos2);
-            for &child in children.iter() {
-                if let Ok((gc, mut tf)) = q_child.get_mut(child) {
-                    match gc {
-                        GripperComponent::Main => {}
-                        GripperComponent::Driving1 => {
-                            *tf = driving1;
-                        }
-                        GripperComponent::Driving2 => {
-                            *tf = driving2;
-                        }
-                        GripperComponent::Follower1 => {
-                            *tf = follower1;
-                        }
-                        GripperComponent::Follower2 => {
-                            *tf = follower2;
-                        }
-                        GripperComponent::Finger1 => {
-                            *tf = finger1;
-                        }
-                        GripperComponent::Finger2 => {
-                            *tf = finger2;
-                        }
-                        GripperComponent::Fingertip1 => {
-                            *tf = fingertip1;
-                        }
-                        GripperComponent::Fingertip2 => {
-                            *tf = fingertip2;
-                        }
-                    }
-                }
-            }
-        }
+    fn width_range(&self) -> RangeInclusive<f32> {
+        width_range()
     }
 }
 
-impl Plugin for GripperPlugin {
-    fn build(&self, app: &mut App) {
-        app.init_resource::<GripperPluginRes>()
-            .add_systems(Update, GripperPlugin::update_component_pos);
-    }
+// In: pos range [0.0, 1.0]
+// Out: fingertip distance from the gripper axis, m
+fn fingertip_offset(pos: f32) -> f32 {
+    compute_finger2(pos)[3].translation.x
+}
+
+// opening between the fingertips
+// In: fingers range [0.0, 100.0]
+// Out: mm
+fn fingers_to_width(fingers: &[f32; 2]) -> f32 {
+    let [pos1, pos2] = fingers.map(|f| f.clamp(0.0, 100.0) / 100.0);
+    (fingertip_offset(pos1) + fingertip_offset(pos2)) * 1000.0
+}
+
+// both jaws moving symmetrically
+// In: width mm, clamped to width_range()
+// Out: fingers range [0.0, 100.0]
+fn width_to_fingers(width: f32) -> [f32; 2] {
+    // fingertip_x = DRIVING_POS[0] + DRIVING_LENGTH * cos( angle ) + FINGERTIP_POS[0]
+    let x = width / 2.0 / 1000.0;
+    let cos = (x - DRIVING_POS[0] - FINGERTIP_POS[0]) / DRIVING_LENGTH;
+    let angle = cos.clamp(-1.0, 1.0).acos();
+    let closed = (angle - DRIVING_ANGLE[0]) / (DRIVING_ANGLE[1] - DRIVING_ANGLE[0]);
+    let pos = (1.0 - closed.clamp(0.0, 1.0)) * 100.0;
+    [pos, pos]
+}
+
+// Out: mm, closed to open
+fn width_range() -> RangeInclusive<f32> {
+    fingers_to_width(&[0.0, 0.0])..=fingers_to_width(&[100.0, 100.0])
 }
 
 // In: pos range [0.0, 1.0]
@@ -292,3 +223,28 @@ fn compute_finger1(pos: f32) -> [Transform; 4] {
     }
     tfs
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn width_follows_the_fingertips() {
+        let range = width_range();
+        assert!(*range.start() < 1.0);
+        assert!((*range.end() - 121.3).abs() < 0.1);
+        // the jaws are mirrored
+        let [_, _, _, tip1] = compute_finger1(0.3);
+        let [_, _, _, tip2] = compute_finger2(0.3);
+        let width = tip1.translation.distance(tip2.translation) * 1000.0;
+        assert!((fingers_to_width(&[30.0, 30.0]) - width).abs() < 1e-3);
+
+        for width in [1.0, 25.0, 60.0, 85.0, 120.0] {
+            let fingers = width_to_fingers(width);
+            assert_eq!(fingers[0], fingers[1]);
+            assert!((fingers_to_width(&fingers) - width).abs() < 1e-3);
+        }
+        assert_eq!(width_to_fingers(500.0), [100.0, 100.0]);
+        assert_eq!(width_to_fingers(-10.0), [0.0, 0.0]);
+    }
+}
@@ -0,0 +1,410 @@
+// JS API of the web application. Commands return promises that settle once the robot is done,
+// the robot states are kept as a snapshot readable at any time and each robot whose state changed
+// in a frame gets one "state_changed" event, dispatched on the window and to the subscribers.
+// Contacts found by the collision check are dispatched as "collision" events.
+use bevy::prelude::*;
+use js_sys::{Function, Promise};
+use serde::Serialize;
+use std::{cell::RefCell, collections::BTreeMap};
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::{
+    collision::CollisionEvent,
+    dispatch_event,
+    end_effector::Tool,
+    program::ProgramPlayer,
+    robot_cell::{FingerPos, JointsPos, RobotCells},
+    robot_ur5::RobotUr5,
+    script_runner::ScriptRunner,
+    urscript::from_isometry,
+    viewer::RobotViewer,
+    Cmd, CmdAccepted, GripperCmd, RobotCmd, Settle,
+};
+
+const JOINT_TOLERANCE: f64 = 1e-6; // deg
+const FINGER_TOLERANCE: f32 = 1e-3; // %
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_TYPES: &'static str = r#"
+export interface RobotState {
+    robot: number;
+    joints: number[];          // deg
+    target: number[];          // deg
+    tcp: number[];             // relative to robot base, [ x, y, z ] m, rotation vector rad
+    fingers?: number[];        // %
+    finger_target?: number[];  // %
+    width?: number;            // mm, between the fingertips
+    moving: boolean;           // false once the targets are reached and no program runs
+    running: boolean;          // a program or script drives the robot
+}
+
+// detail of the "collision" event
+export interface CollisionEvent {
+    robot: number;
+    part: string;              // link name, or part of the tool
+    with: { kind: "robot", robot: number, part: string } | { kind: "floor" } | { kind: "fixture", name: string };
+    colliding: boolean;        // false once the contact has ended
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "RobotState")]
+    pub type JsRobotState;
+
+    #[wasm_bindgen(typescript_type = "RobotState[]")]
+    pub type JsRobotStates;
+
+    #[wasm_bindgen(typescript_type = "Promise<RobotState>")]
+    pub type PromiseRobotState;
+
+    #[wasm_bindgen(typescript_type = "Promise<void>")]
+    pub type PromiseVoid;
+
+    #[wasm_bindgen(typescript_type = "(state: RobotState) => void")]
+    pub type StateCallback;
+}
+
+#[derive(Serialize, Clone, PartialEq)]
+struct RobotState {
+    robot: u64,
+    joints: [f64; 6],
+    target: [f64; 6],
+    tcp: [f64; 6],
+    fingers: Option<[f32; 2]>,
+    finger_target: Option<[f32; 2]>,
+    width: Option<f32>,
+    moving: bool,
+    running: bool,
+}
+
+#[derive(Serialize)]
+struct RobotRemoved {
+    robot: u64,
+}
+
+#[derive(Default)]
+struct Requests {
+    last: u32,
+    pending: BTreeMap<u32, (Function, Function)>, // ( resolve, reject )
+}
+
+#[derive(Default)]
+struct Subscribers {
+    last: u32,
+    callbacks: BTreeMap<u32, Function>,
+}
+
+// the app runs on the thread of the page
+thread_local! {
+    static REQUESTS: RefCell<Requests> = RefCell::default();
+    static SUBSCRIBERS: RefCell<Subscribers> = RefCell::default();
+    static STATES: RefCell<BTreeMap<u64, RobotState>> = RefCell::default();
+}
+
+// cmd: the command settling the request
+fn request(viewer: &RobotViewer, cmd: impl FnOnce(u32) -> Cmd) -> Promise {
+    let mut request = 0;
+    let promise = Promise::new(&mut |resolve, reject| {
+        request = REQUESTS.with(|r| {
+            let mut r = r.borrow_mut();
+            r.last = r.last.wrapping_add(1);
+            let request = r.last;
+            r.pending.insert(request, (resolve, reject));
+            request
+        });
+    });
+    if let Err(e) = viewer.send(cmd(request)) {
+        reject(request, &e.to_string());
+    }
+    promise
+}
+
+fn rejected(message: &str) -> Promise {
+    Promise::reject(&js_sys::Error::new(message))
+}
+
+fn resolve(request: u32, value: &JsValue) {
+    let pending = REQUESTS.with(|r| r.borrow_mut().pending.remove(&request));
+    if let Some((resolve, _)) = pending {
+        let _ = resolve.call1(&JsValue::UNDEFINED, value);
+    }
+}
+
+pub fn reject(request: u32, message: &str) {
+    let pending = REQUESTS.with(|r| r.borrow_mut().pending.remove(&request));
+    if let Some((_, reject)) = pending {
+        let _ = reject.call1(&JsValue::UNDEFINED, &js_sys::Error::new(message));
+    }
+}
+
+fn to_js<T: Serialize>(value: &T) -> JsValue {
+    serde_wasm_bindgen::to_value(value).unwrap_or(JsValue::UNDEFINED)
+}
+
+#[wasm_bindgen]
+impl RobotViewer {
+    // joints: deg, 6 values
+    pub fn set_joints(&self, robot: u16, joints: &[f64]) -> PromiseRobotState {
+        let promise = match <[f64; 6]>::try_from(joints) {
+            Ok(joints) => request(self, |request| {
+                Cmd::Robot(RobotCmd::Joints {
+                    robot,
+                    joints,
+                    request,
+                })
+            }),
+            Err(_) => rejected("6 joints expected"),
+        };
+        promise.unchecked_into()
+    }
+
+    // fingers: %, 2 values
+    pub fn set_fingers(&self, robot: u16, fingers: &[f32]) -> PromiseRobotState {
+        let promise = match <[f32; 2]>::try_from(fingers) {
+            Ok(fingers) => request(self, |request| {
+                Cmd::Gripper(GripperCmd::Fingers {
+                    robot,
+                    fingers,
+                    request,
+                })
+            }),
+            Err(_) => rejected("2 fingers expected"),
+        };
+        promise.unchecked_into()
+    }
+
+    // width: mm, between the fingertips, both jaws moving symmetrically
+    // current: motor current limit, % of the maximum, range [20, 100], kept when undefined
+    pub fn set_width(&self, robot: u16, width: f32, current: Option<f32>) -> PromiseRobotState {
+        request(self, |request| {
+            Cmd::Gripper(GripperCmd::Width {
+                robot,
+                width,
+                current,
+                request,
+            })
+        })
+        .unchecked_into()
+    }
+
+    // pose: tcp relative to robot base, [ x, y, z ] m, rotation vector rad
+    pub fn move_to_pose(&self, robot: u16, pose: &[f64]) -> PromiseRobotState {
+        let promise = match <[f64; 6]>::try_from(pose) {
+            Ok(pose) => request(self, |request| {
+                Cmd::Robot(RobotCmd::Pose {
+                    robot,
+                    pose,
+                    request,
+                })
+            }),
+            Err(_) => rejected("a pose has 6 values"),
+        };
+        promise.unchecked_into()
+    }
+
+    // text: content of a program file
+    pub fn load_program(&self, robot: u16, text: String) -> PromiseVoid {
+        request(self, |request| {
+            Cmd::Robot(RobotCmd::LoadProgram {
+                robot,
+                text,
+                request,
+            })
+        })
+        .unchecked_into()
+    }
+
+    // resolves when the program has ended
+    pub fn run_program(&self, robot: u16) -> PromiseRobotState {
+        request(self, |request| {
+            Cmd::Robot(RobotCmd::RunProgram { robot, request })
+        })
+        .unchecked_into()
+    }
+
+    // source: URScript, resolves when the script has ended
+    pub fn run_script(&self, robot: u16, source: String) -> PromiseRobotState {
+        request(self, |request| {
+            Cmd::Robot(RobotCmd::RunScript {
+                robot,
+                source,
+                request,
+            })
+        })
+        .unchecked_into()
+    }
+
+    // stops the program or script of the robot
+    pub fn stop(&self, robot: u16) -> PromiseVoid {
+        request(self, |request| {
+            Cmd::Robot(RobotCmd::Stop { robot, request })
+        })
+        .unchecked_into()
+    }
+
+    // x, y, z: m
+    pub fn add_robot(&self, robot: u16, x: f32, y: f32, z: f32) -> PromiseRobotState {
+        request(self, |request| Cmd::AddRobot {
+            robot,
+            pos: [x, y, z],
+            request,
+        })
+        .unchecked_into()
+    }
+
+    pub fn remove_robot(&self, robot: u16) -> PromiseVoid {
+        request(self, |request| Cmd::RemoveRobot { robot, request }).unchecked_into()
+    }
+
+    // state at the last frame, undefined for an unknown robot
+    pub fn get_state(&self, robot: u16) -> Option<JsRobotState> {
+        STATES
+            .with(|s| s.borrow().get(&(robot as u64)).map(to_js))
+            .map(JsCast::unchecked_into)
+    }
+
+    pub fn get_states(&self) -> JsRobotStates {
+        let states: Vec<RobotState> = STATES.with(|s| s.borrow().values().cloned().collect());
+        to_js(&states).unchecked_into()
+    }
+
+    // callback: called like the "state_changed" event, with the state
+    // Out: id to unsubscribe
+    pub fn subscribe_state_changed(&self, callback: StateCallback) -> u32 {
+        SUBSCRIBERS.with(|s| {
+            let mut s = s.borrow_mut();
+            s.last = s.last.wrapping_add(1);
+            let id = s.last;
+            s.callbacks.insert(id, callback.unchecked_into());
+            id
+        })
+    }
+
+    pub fn unsubscribe_state_changed(&self, id: u32) {
+        SUBSCRIBERS.with(|s| s.borrow_mut().callbacks.remove(&id));
+    }
+}
+
+// x, y, z: m
+#[wasm_bindgen]
+pub fn add_robot(robot: u16, x: f32, y: f32, z: f32) -> PromiseRobotState {
+    match RobotViewer::default_viewer() {
+        Ok(viewer) => viewer.add_robot(robot, x, y, z),
+        Err(e) => rejected(&e.to_string()).unchecked_into(),
+    }
+}
+
+#[wasm_bindgen]
+pub fn remove_robot(robot: u16) -> PromiseVoid {
+    match RobotViewer::default_viewer() {
+        Ok(viewer) => viewer.remove_robot(robot),
+        Err(e) => rejected(&e.to_string()).unchecked_into(),
+    }
+}
+
+fn state_changed(state: &RobotState) {
+    dispatch_event("state_changed", state);
+    let value = to_js(state);
+    // a callback may subscribe or unsubscribe
+    let callbacks: Vec<Function> =
+        SUBSCRIBERS.with(|s| s.borrow().callbacks.values().cloned().collect());
+    for callback in callbacks {
+        let _ = callback.call1(&JsValue::UNDEFINED, &value);
+    }
+}
+
+pub struct JsApiPlugin;
+
+impl Plugin for JsApiPlugin {
+    fn build(&self, app: &mut App) {
+        // after every change of the frame
+        app.add_systems(PostUpdate, JsApiPlugin::publish_states)
+            .add_systems(Update, JsApiPlugin::dispatch_collisions);
+    }
+}
+
+impl JsApiPlugin {
+    fn dispatch_collisions(mut events: EventReader<CollisionEvent>) {
+        for event in events.iter() {
+            dispatch_event("collision", event);
+        }
+    }
+
+    fn publish_states(
+        mut accepted: EventReader<CmdAccepted>,
+        mut pending: Local<Vec<CmdAccepted>>,
+        cells: Res<RobotCells>,
+        q_robot: Query<(&RobotUr5, &JointsPos, &ProgramPlayer, &ScriptRunner)>,
+        q_gripper: Query<(&Tool, &FingerPos)>,
+    ) {
+        let mut states = BTreeMap::new();
+        for (&id, cell) in cells.0.iter() {
+            let Ok((robot, target, player, script)) = q_robot.get(cell.robot) else {
+                continue;
+            };
+            let gripper = cell.tool.and_then(|e| q_gripper.get(e).ok());
+            let joints = robot.joints().map(f64::to_degrees);
+            let target = robot.clamp_deg(target.0);
+            let fingers = gripper.map(|(t, _)| t.jaws.map(|j| j * 100.0));
+            let finger_target = gripper.map(|(_, f)| f.0.map(|p| p.clamp(0.0, 100.0)));
+            let running = player.is_running() || script.is_running();
+            let reached = joints
+                .iter()
+                .zip(target.iter())
+                .all(|(j, t)| (j - t).abs() <= JOINT_TOLERANCE)
+                && match (fingers, finger_target) {
+                    (Some(f), Some(t)) => f
+                        .iter()
+                        .zip(t.iter())
+                        .all(|(f, t)| (f - t).abs() <= FINGER_TOLERANCE),
+                    _ => true,
+                };
+            states.insert(
+                id,
+                RobotState {
+                    robot: id,
+                    joints,
+                    target,
+                    tcp: from_isometry(&robot.tcp_pose()),
+                    fingers,
+                    finger_target,
+                    width: fingers
+                        .zip(cell.end_effector())
+                        .map(|(f, model)| model.fingers_to_width(&f)),
+                    moving: running || !reached,
+                    running,
+                },
+            );
+        }
+
+        // one event per robot whose state changed
+        let previous = STATES.with(|s| std::mem::replace(&mut *s.borrow_mut(), states.clone()));
+        for (id, state) in states.iter() {
+            if previous.get(id) != Some(state) {
+                state_changed(state);
+            }
+        }
+        for &robot in previous.keys().filter(|id| !states.contains_key(id)) {
+            dispatch_event("robot_removed", &RobotRemoved { robot });
+        }
+
+        pending.extend(accepted.iter().cloned());
+        pending.retain(|cmd| {
+            let state = states.get(&cmd.robot);
+            match (cmd.settle, state) {
+                (Settle::Applied, _) => resolve(cmd.request, &JsValue::UNDEFINED),
+                (Settle::Added, Some(state)) => resolve(cmd.request, &to_js(state)),
+                (Settle::Removed, None) => resolve(cmd.request, &JsValue::UNDEFINED),
+                (Settle::AtRest, Some(state)) if !state.moving => {
+                    resolve(cmd.request, &to_js(state))
+                }
+                (Settle::AtRest, None) => {
+                    reject(cmd.request, &format!("robot {} was removed", cmd.robot))
+                }
+                _ => return true,
+            }
+            false
+        });
+    }
+}
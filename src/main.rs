@@ -1,23 +1,77 @@
+mod cartesian_jog;
+mod cell_layout;
+mod collision;
+#[cfg(all(feature = "server", not(target_family = "wasm")))]
+mod command_server;
 mod draw_trail;
+mod end_effector;
 mod gripper_ctm2f110;
+#[cfg(target_family = "wasm")]
+mod js_api;
+mod motion;
+#[cfg(feature = "physics")]
+mod physics;
+mod planner;
+mod program;
+mod program_file;
+mod robot_cell;
 mod robot_ur5;
+#[cfg(all(feature = "ros", not(target_family = "wasm")))]
+mod ros_bridge;
+// shared with the mock controller
+#[cfg(not(target_family = "wasm"))]
+mod rtde;
+#[cfg(not(target_family = "wasm"))]
+mod rtde_client;
+#[cfg(not(target_family = "wasm"))]
+mod rtde_mirror;
+mod script_runner;
+mod tcp_gizmo;
+mod tool_changer;
+mod trajectory;
+mod urdf;
+mod urscript;
+mod viewer;
+mod workpiece;
+mod workspace;
 
+#[cfg(not(target_family = "wasm"))]
+use bevy::asset::ChangeWatcher;
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
 use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
 use flume::{unbounded, Receiver, Sender};
-use wasm_bindgen::prelude::wasm_bindgen;
+use nalgebra::{Quaternion, UnitQuaternion};
 #[cfg(target_family = "wasm")]
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+#[cfg(not(target_family = "wasm"))]
+use std::time::Duration;
 
+#[cfg(target_family = "wasm")]
+use crate::robot_cell::{FingerChanged, JointChanged};
+#[cfg(not(target_family = "wasm"))]
+use crate::rtde_mirror::RtdeMirror;
 use crate::{
+    cartesian_jog::CartesianJog,
+    cell_layout::{CellLayoutPlugin, RobotLayout, Shape, ToolKind, ToolLayout},
+    collision::CollisionPlugin,
     draw_trail::{DrawTrailPlugin, Trails},
-    gripper_ctm2f110::{Finger, GripperCtm2f110, GripperFingertip, GripperPlugin},
-    robot_ur5::{RobotPluginUr5, RobotUr5, JOINTS_POS},
+    end_effector::{Actuation, EndEffector, EndEffectorPlugin, Finger, GripperFingertip, Vacuum},
+    planner::{MotionPlanner, PlannerPlugin},
+    program::{ProgramAction, ProgramPlayer},
+    program_file::ProgramFile,
+    robot_cell::{FingerPos, GripCurrent, JointsPos, RobotCellPlugin, RobotCells, Streaming},
+    robot_ur5::{RobotPlugin, RobotUr5},
+    script_runner::ScriptRunner,
+    tcp_gizmo::{TcpDragged, TcpGizmo, TcpGizmoPlugin},
+    tool_changer::{ToolChange, ToolChanger, ToolChangerPlugin},
+    urscript::to_isometry,
+    viewer::ViewerChannels,
+    workpiece::WorkpiecePlugin,
+    workspace::{Workspace, WorkspacePlugin},
 };
 
-static mut SENDER: Option<Sender<Cmd>> = None;
-
 #[cfg(target_family = "wasm")]
 #[derive(Serialize, Deserialize)]
 pub struct EventJointChanged {
@@ -34,235 +88,574 @@ pub struct EventFingerChanged {
     pos: f32,
 }
 
-#[wasm_bindgen]
-pub fn set_robot_joint_pos(robot: u16, joint: u16, angle: f32) {
-    unsafe {
-        if let Some(sender) = &SENDER {
-            let cmd = Cmd::RobotJointPos {
-                robot,
-                joint,
-                angle,
-            };
-            let _ = sender.send(cmd);
-        }
-    }
-}
-
-#[wasm_bindgen]
-pub fn set_robot_finger_pos(robot: u16, finger: u16, pos: f32) {
-    unsafe {
-        if let Some(sender) = &SENDER {
-            let cmd = Cmd::RobotFingerPos { robot, finger, pos };
-            let _ = sender.send(cmd);
-        }
-    }
-}
-
-const ROBOT_KEY_0: u64 = 0;
-const ROBOT_KEY_1: u64 = 1;
-
 fn main() {
-    let (sender, receiver) = unbounded::<Cmd>();
-    unsafe {
-        SENDER = Some(sender);
-    }
-    let cmd_channel = CmdChannel { receiver };
-
-    App::new()
-        .init_resource::<JointsPos>()
-        .init_resource::<FingerPos>()
-        .insert_resource(cmd_channel)
-        .add_plugins((DefaultPlugins.set(WindowPlugin {
-            primary_window: Some(Window {
-                fit_canvas_to_parent: true,
-                canvas: Some("#demo-bevy_robot".to_string()),
+    let mut app = App::new();
+    app.insert_resource(ViewerChannels::start())
+        .init_resource::<ProgramFiles>()
+        .add_event::<CmdError>()
+        .add_event::<CmdAccepted>()
+        .add_plugins((DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: Some(Window {
+                    fit_canvas_to_parent: true,
+                    canvas: Some("#demo-bevy_robot".to_string()),
+                    ..default()
+                }),
                 ..default()
-            }),
-            ..default()
-        }),))
+            })
+            .set(AssetPlugin {
+                // hot reload of the cell layout, there is no file watcher on wasm
+                #[cfg(not(target_family = "wasm"))]
+                watch_for_changes: ChangeWatcher::with_delay(Duration::from_millis(200)),
+                ..default()
+            }),))
         .add_plugins((
             PanOrbitCameraPlugin,
             EguiPlugin,
-            RobotPluginUr5,
-            GripperPlugin,
+            RobotPlugin,
+            EndEffectorPlugin,
+            RobotCellPlugin,
+            CellLayoutPlugin,
             DrawTrailPlugin,
+            TcpGizmoPlugin,
+            CollisionPlugin,
+            PlannerPlugin,
+            WorkspacePlugin,
+            ToolChangerPlugin,
+            WorkpiecePlugin,
         ))
-        .add_systems(Startup, (setup_camera_light, setup_robot, setup_label))
+        .add_systems(Startup, setup_camera_light)
         .add_systems(
             Update,
             (
                 ui,
-                update_joints_pos,
-                update_finger_pos,
+                update_cartesian_jog.after(ui),
+                update_program.after(ui),
+                recv_program_files,
+                recv_tcp_dragged,
                 draw_floor_grids,
                 draw_gripper_trails,
-                update_label_pos,
                 recv_cmd,
+                report_cmd_error.after(recv_cmd),
             ),
-        )
-        .run();
+        );
+    #[cfg(target_family = "wasm")]
+    app.add_plugins(js_api::JsApiPlugin)
+        .add_systems(Update, send_changed_events);
+    #[cfg(not(target_family = "wasm"))]
+    app.add_systems(Update, update_rtde_mirror.after(update_program));
+    #[cfg(all(feature = "server", not(target_family = "wasm")))]
+    app.add_plugins(command_server::CommandServerPlugin);
+    #[cfg(all(feature = "ros", not(target_family = "wasm")))]
+    app.add_plugins(ros_bridge::RosBridgePlugin).add_systems(
+        Update,
+        ros_bridge::RosBridgePlugin::follow_joint_states.after(update_program),
+    );
+    #[cfg(feature = "physics")]
+    app.add_plugins(physics::PhysicsPlugin);
+    app.run();
 }
 
+// request: promise to settle on the JS side
 #[derive(Clone)]
+#[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
 enum Cmd {
-    RobotJointPos { robot: u16, joint: u16, angle: f32 },
-    RobotFingerPos { robot: u16, finger: u16, pos: f32 },
+    Robot(RobotCmd),
+    Gripper(GripperCmd),
+    AddRobot {
+        robot: u16,
+        pos: [f32; 3],
+        request: u32,
+    },
+    RemoveRobot {
+        robot: u16,
+        request: u32,
+    },
 }
 
-#[derive(Resource)]
-struct CmdChannel {
-    receiver: Receiver<Cmd>,
+// commands to the joints, program and script of one robot
+#[derive(Clone)]
+#[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+enum RobotCmd {
+    JointPos {
+        robot: u16,
+        joint: u16,
+        angle: f32,
+    },
+    // joints: deg
+    Joints {
+        robot: u16,
+        joints: [f64; 6],
+        request: u32,
+    },
+    // pose: tcp relative to robot base, ( x, y, z ) m, rotation vector rad
+    Pose {
+        robot: u16,
+        pose: [f64; 6],
+        request: u32,
+    },
+    // text: program file
+    LoadProgram {
+        robot: u16,
+        text: String,
+        request: u32,
+    },
+    RunProgram {
+        robot: u16,
+        request: u32,
+    },
+    // source: URScript
+    RunScript {
+        robot: u16,
+        source: String,
+        request: u32,
+    },
+    Stop {
+        robot: u16,
+        request: u32,
+    },
+}
+
+// commands to the gripper mounted on one robot
+#[derive(Clone)]
+#[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+enum GripperCmd {
+    FingerPos {
+        robot: u16,
+        finger: u16,
+        pos: f32,
+    },
+    // fingers: %
+    Fingers {
+        robot: u16,
+        fingers: [f32; 2],
+        request: u32,
+    },
+    // width: mm, both jaws moving symmetrically
+    // current: motor current limit, % of the maximum, kept when None
+    Width {
+        robot: u16,
+        width: f32,
+        current: Option<f32>,
+        request: u32,
+    },
 }
 
-#[derive(Resource, Clone)]
-struct JointsPos([[f64; 6]; 2]); // deg
+impl Cmd {
+    fn robot(&self) -> u64 {
+        match self {
+            Cmd::Robot(cmd) => cmd.robot(),
+            Cmd::Gripper(cmd) => cmd.robot(),
+            Cmd::AddRobot { robot, .. } | Cmd::RemoveRobot { robot, .. } => *robot as u64,
+        }
+    }
 
-impl Default for JointsPos {
-    fn default() -> Self {
-        JointsPos([JOINTS_POS; 2])
+    fn request(&self) -> Option<u32> {
+        match self {
+            Cmd::Robot(cmd) => cmd.request(),
+            Cmd::Gripper(cmd) => cmd.request(),
+            Cmd::AddRobot { request, .. } | Cmd::RemoveRobot { request, .. } => Some(*request),
+        }
     }
 }
 
+impl RobotCmd {
+    fn robot(&self) -> u64 {
+        match self {
+            RobotCmd::JointPos { robot, .. }
+            | RobotCmd::Joints { robot, .. }
+            | RobotCmd::Pose { robot, .. }
+            | RobotCmd::LoadProgram { robot, .. }
+            | RobotCmd::RunProgram { robot, .. }
+            | RobotCmd::RunScript { robot, .. }
+            | RobotCmd::Stop { robot, .. } => *robot as u64,
+        }
+    }
+
+    fn request(&self) -> Option<u32> {
+        match self {
+            RobotCmd::JointPos { .. } => None,
+            RobotCmd::Joints { request, .. }
+            | RobotCmd::Pose { request, .. }
+            | RobotCmd::LoadProgram { request, .. }
+            | RobotCmd::RunProgram { request, .. }
+            | RobotCmd::RunScript { request, .. }
+            | RobotCmd::Stop { request, .. } => Some(*request),
+        }
+    }
+}
+
+impl GripperCmd {
+    fn robot(&self) -> u64 {
+        match self {
+            GripperCmd::FingerPos { robot, .. }
+            | GripperCmd::Fingers { robot, .. }
+            | GripperCmd::Width { robot, .. } => *robot as u64,
+        }
+    }
+
+    fn request(&self) -> Option<u32> {
+        match self {
+            GripperCmd::FingerPos { .. } => None,
+            GripperCmd::Fingers { request, .. } | GripperCmd::Width { request, .. } => {
+                Some(*request)
+            }
+        }
+    }
+}
+
+// a command that could not be applied
+#[derive(Event, Clone)]
+#[cfg_attr(target_family = "wasm", derive(Serialize, Deserialize))]
+pub struct CmdError {
+    robot: u64,
+    message: String,
+    #[cfg_attr(target_family = "wasm", serde(skip))]
+    #[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+    request: Option<u32>,
+}
+
+// when the promise of an accepted command resolves
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+enum Settle {
+    Applied,
+    Added,
+    Removed,
+    AtRest, // the robot reached its targets and runs no program
+}
+
+// a command with a request that was applied
+#[derive(Event, Clone)]
+#[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+struct CmdAccepted {
+    robot: u64,
+    request: u32,
+    settle: Settle,
+}
+
+type CmdRobotQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static RobotUr5,
+        &'static mut JointsPos,
+        &'static mut ProgramPlayer,
+        &'static mut ScriptRunner,
+    ),
+>;
+
 fn recv_cmd(
-    cmd_channel: Res<CmdChannel>,
-    mut joints_pos: ResMut<JointsPos>,
-    mut finger_pos: ResMut<FingerPos>,
+    mut commands: Commands,
+    mut viewers: ResMut<ViewerChannels>,
+    cells: Res<RobotCells>,
+    mut errors: EventWriter<CmdError>,
+    mut accepted: EventWriter<CmdAccepted>,
+    mut q_robot: CmdRobotQuery,
+    mut q_gripper: Query<(&mut FingerPos, &mut GripCurrent)>,
 ) {
-    while let Ok(cmd) = cmd_channel.receiver.try_recv() {
-        match cmd {
-            Cmd::RobotJointPos {
-                robot,
-                joint,
-                angle,
-            } => {
-                let robot: usize = if (robot as u64) == ROBOT_KEY_0 { 0 } else { 1 };
-                let joint: usize = match joint {
-                    1 => 0,
-                    2 => 1,
-                    3 => 2,
-                    4 => 3,
-                    5 => 4,
-                    _ => 5,
-                };
-                let angle = if angle > 720.0 {
-                    720.0
-                } else if angle < -720.0 {
-                    -720.0
-                } else {
-                    angle
-                };
-                joints_pos.0[robot][joint] = angle as f64;
+    for cmd in viewers.recv() {
+        let id = cmd.robot();
+        let request = cmd.request();
+        let result = apply_cmd(cmd, &mut commands, &cells, &mut q_robot, &mut q_gripper);
+        match (result, request) {
+            (Ok(settle), Some(request)) => accepted.send(CmdAccepted {
+                robot: id,
+                request,
+                settle,
+            }),
+            (Ok(_), None) => {}
+            (Err(message), request) => errors.send(CmdError {
+                robot: id,
+                message,
+                request,
+            }),
+        }
+    }
+}
+
+// a command of the egui window, the JS API or the command server
+fn apply_cmd(
+    cmd: Cmd,
+    commands: &mut Commands,
+    cells: &RobotCells,
+    q_robot: &mut CmdRobotQuery,
+    q_gripper: &mut Query<(&mut FingerPos, &mut GripCurrent)>,
+) -> Result<Settle, String> {
+    let id = cmd.robot();
+    let unknown = || Err(format!("unknown robot {}", id));
+    let cell = cells.0.get(&id);
+    match cmd {
+        Cmd::AddRobot { pos, .. } => {
+            if cell.is_some() {
+                Err(format!("robot {} already exists", id))
+            } else {
+                commands.add(move |world: &mut World| {
+                    RobotCellPlugin::add_cell(world, RobotLayout::new(id, Vec3::from(pos)));
+                });
+                Ok(Settle::Added)
             }
-            Cmd::RobotFingerPos { robot, finger, pos } => {
-                let robot: usize = if (robot as u64) == ROBOT_KEY_0 { 0 } else { 1 };
-                let finger: usize = match finger {
-                    1 => 0,
-                    _ => 1,
-                };
-                let pos = if pos > 100.0 {
-                    100.0
-                } else if pos < 0.0 {
-                    0.0
-                } else {
-                    pos
-                };
-                finger_pos.0[robot][finger] = pos;
+        }
+        Cmd::RemoveRobot { .. } => match cell {
+            None => unknown(),
+            Some(_) => {
+                commands.add(move |world: &mut World| {
+                    RobotCellPlugin::remove_cell(world, id);
+                });
+                Ok(Settle::Removed)
+            }
+        },
+        Cmd::Gripper(cmd) => match cell {
+            None => unknown(),
+            Some(cell) => match cell.tool.and_then(|e| q_gripper.get_mut(e).ok()) {
+                None => Err("no gripper mounted".to_string()),
+                Some((mut fingers, mut current)) => match cell.end_effector() {
+                    Some(model) => apply_finger_cmd(cmd, model, &mut fingers, &mut current),
+                    None => Err("no gripper mounted".to_string()),
+                },
+            },
+        },
+        Cmd::Robot(cmd) => match cell.and_then(|cell| q_robot.get_mut(cell.robot).ok()) {
+            None => unknown(),
+            Some((robot, mut joints, mut player, mut script)) => {
+                apply_robot_cmd(cmd, robot, &mut joints, &mut player, &mut script)
+            }
+        },
+    }
+}
+
+fn apply_finger_cmd(
+    cmd: GripperCmd,
+    model: &dyn EndEffector,
+    fingers: &mut FingerPos,
+    grip_current: &mut GripCurrent,
+) -> Result<Settle, String> {
+    match cmd {
+        GripperCmd::FingerPos { finger, pos, .. } => {
+            // finger: range [1, 2]
+            if !(1..=2).contains(&finger) {
+                return Err(format!("invalid finger {}", finger));
+            }
+            fingers.0[(finger - 1) as usize] = pos.clamp(0.0, 100.0);
+        }
+        GripperCmd::Fingers { fingers: pos, .. } => fingers.0 = pos.map(|p| p.clamp(0.0, 100.0)),
+        GripperCmd::Width { width, current, .. } => {
+            fingers.0 = model.width_to_fingers(width);
+            if let Some(current) = current {
+                grip_current.set(current);
             }
         }
     }
+    Ok(Settle::AtRest)
 }
 
-fn update_joints_pos(
-    joints: Res<JointsPos>,
-    mut query: Query<&mut RobotUr5>,
-    mut now_joints: Local<JointsPos>,
-    mut last_joints: Local<JointsPos>,
-) {
-    #[cfg(target_family = "wasm")]
-    {
-        use std::ops::Deref;
-        use web_sys::CustomEvent;
-        for robot in 0..=1 {
-            for joint in 0..6 {
-                if last_joints.0[robot][joint] != joints.0[robot][joint] {
-                    let custom_event = CustomEvent::new("").unwrap();
-                    let data = EventJointChanged {
-                        robot: robot as u16,
-                        joint: (joint + 1) as u16,
-                        angle: joints.0[robot][joint] as f32,
-                    };
-                    let js_value = serde_wasm_bindgen::to_value(&data).unwrap();
-                    custom_event.init_custom_event_with_can_bubble_and_cancelable_and_detail(
-                        "joint_changed",
-                        true,
-                        true,
-                        &js_value,
-                    );
-                    let window = web_sys::window().unwrap();
-                    let _ = window.dispatch_event(custom_event.deref());
-                }
+fn apply_robot_cmd(
+    cmd: RobotCmd,
+    robot: &RobotUr5,
+    joints: &mut JointsPos,
+    player: &mut ProgramPlayer,
+    script: &mut ScriptRunner,
+) -> Result<Settle, String> {
+    match cmd {
+        RobotCmd::JointPos { joint, angle, .. } => {
+            // joint: range [1, 6]
+            if !(1..=6).contains(&joint) {
+                return Err(format!("invalid joint {}", joint));
+            }
+            let joint = (joint - 1) as usize;
+            joints.0[joint] = robot.limits[joint].clamp(angle as f64);
+        }
+        RobotCmd::Joints { joints: pos, .. } => joints.0 = robot.clamp_deg(pos),
+        RobotCmd::Pose { pose, .. } => {
+            let flange = to_isometry(&pose) * robot.tool.inverse();
+            let pos = robot.ik_nearest(&flange).map_err(|e| e.to_string())?;
+            joints.0 = robot.clamp_deg(pos.map(f64::to_degrees));
+        }
+        RobotCmd::LoadProgram { text, .. } => {
+            let file = ProgramFile::parse(&text).map_err(|e| e.to_string())?;
+            player.load(file.program, file.poses);
+            return Ok(Settle::Applied);
+        }
+        RobotCmd::RunProgram { .. } => {
+            if player.program.waypoints.is_empty() {
+                return Err("the program is empty".to_string());
+            }
+            script.stop();
+            player.play();
+        }
+        RobotCmd::RunScript { source, .. } => {
+            player.stop();
+            script.source = source;
+            script.run(robot);
+            if let Some(e) = script.error() {
+                return Err(e.to_string());
             }
         }
+        RobotCmd::Stop { .. } => {
+            player.stop();
+            script.stop();
+            return Ok(Settle::Applied);
+        }
     }
+    Ok(Settle::AtRest)
+}
 
-    for mut robot in query.iter_mut() {
-        let id = robot.id as usize;
-        let target = joints.0[id];
-        let now = now_joints.0[id];
-        let pos = ct_robot_joints(&now, &target);
-        now_joints.0[id] = pos;
-        robot.set_deg(pos)
+fn report_cmd_error(mut errors: EventReader<CmdError>) {
+    for error in errors.iter() {
+        warn!("robot {}: {}", error.robot, error.message);
+        #[cfg(target_family = "wasm")]
+        {
+            dispatch_event("cmd_error", error);
+            if let Some(request) = error.request {
+                js_api::reject(request, &error.message);
+            }
+        }
     }
+}
 
-    last_joints.0 = joints.0;
+#[cfg(target_family = "wasm")]
+fn dispatch_event<T: Serialize>(name: &str, data: &T) {
+    use std::ops::Deref;
+    use web_sys::CustomEvent;
+    let custom_event = CustomEvent::new("").unwrap();
+    let js_value = serde_wasm_bindgen::to_value(data).unwrap();
+    custom_event
+        .init_custom_event_with_can_bubble_and_cancelable_and_detail(name, true, true, &js_value);
+    let window = web_sys::window().unwrap();
+    let _ = window.dispatch_event(custom_event.deref());
 }
 
-#[derive(Resource, Clone, Default)]
-struct FingerPos([[f32; 2]; 2]); // range [0.0, 100.0]
+#[cfg(target_family = "wasm")]
+fn send_changed_events(
+    mut joint_changed: EventReader<JointChanged>,
+    mut finger_changed: EventReader<FingerChanged>,
+) {
+    for event in joint_changed.iter() {
+        let data = EventJointChanged {
+            robot: event.robot as u16,
+            joint: (event.joint + 1) as u16,
+            angle: event.angle as f32,
+        };
+        dispatch_event("joint_changed", &data);
+    }
+    for event in finger_changed.iter() {
+        let data = EventFingerChanged {
+            robot: event.robot as u16,
+            finger: (event.finger + 1) as u16,
+            pos: event.pos,
+        };
+        dispatch_event("finger_changed", &data);
+    }
+}
 
-fn update_finger_pos(
-    fingers: Res<FingerPos>,
-    mut query: Query<&mut GripperCtm2f110>,
-    mut now_fingers: Local<FingerPos>,
-    mut last_fingers: Local<FingerPos>,
+fn update_program(
+    mut commands: Commands,
+    time: Res<Time>,
+    cells: Res<RobotCells>,
+    mut q_robot: Query<(
+        &RobotUr5,
+        &mut JointsPos,
+        &mut Streaming,
+        &mut ProgramPlayer,
+        &mut ScriptRunner,
+    )>,
+    mut q_gripper: Query<&mut FingerPos>,
 ) {
-    #[cfg(target_family = "wasm")]
-    {
-        use std::ops::Deref;
-        use web_sys::CustomEvent;
-        for robot in 0..=1 {
-            for finger in 0..=1 {
-                if last_fingers.0[robot][finger] != fingers.0[robot][finger] {
-                    let custom_event = CustomEvent::new("").unwrap();
-                    let data = EventFingerChanged {
-                        robot: robot as u16,
-                        finger: (finger + 1) as u16,
-                        pos: fingers.0[robot][finger] as f32,
-                    };
-                    let js_value = serde_wasm_bindgen::to_value(&data).unwrap();
-                    custom_event.init_custom_event_with_can_bubble_and_cancelable_and_detail(
-                        "finger_changed",
-                        true,
-                        true,
-                        &js_value,
-                    );
-                    let window = web_sys::window().unwrap();
-                    let _ = window.dispatch_event(custom_event.deref());
+    let dt = time.delta_seconds_f64();
+    for cell in cells.0.values() {
+        let Ok((robot, mut joints, mut streaming, mut player, mut script)) =
+            q_robot.get_mut(cell.robot)
+        else {
+            continue;
+        };
+        if let Some(change) = player.take_tool_change() {
+            let (id, entity) = (robot.id, cell.robot);
+            commands.add(move |world: &mut World| {
+                let result = ToolChangerPlugin::change(world, id, change);
+                if let Some(mut player) = world.get_mut::<ProgramPlayer>(entity) {
+                    player.tool_changed(result);
                 }
+            });
+        }
+        if let Some(pos) = player.step(robot, dt) {
+            joints.0 = pos;
+        }
+        if let Some(pos) = script.step(robot, dt) {
+            joints.0 = pos;
+        }
+        streaming.0 = player.is_running() || script.is_running();
+        if !player.is_running() {
+            continue;
+        }
+        let finger_pos = cell.tool.and_then(|e| q_gripper.get_mut(e).ok());
+        if let (Some(fingers), Some(mut finger_pos)) = (player.fingers(), finger_pos) {
+            if finger_pos.0 != fingers {
+                finger_pos.0 = fingers;
             }
         }
     }
-    for mut gripper in query.iter_mut() {
-        let id = gripper.id as usize;
-        let target = fingers.0[id];
-        let now = now_fingers.0[id];
-        let pos = ct_gripper_finger(&now, &target);
-        now_fingers.0[id] = pos;
-        gripper.pos1 = pos[0] / 100.0;
-        gripper.pos2 = pos[1] / 100.0;
+}
+
+// a robot mirroring a controller follows it, whatever its program does
+#[cfg(not(target_family = "wasm"))]
+fn update_rtde_mirror(
+    time: Res<Time>,
+    cells: Res<RobotCells>,
+    mut q_robot: Query<(&mut JointsPos, &mut Streaming, &mut RtdeMirror)>,
+) {
+    let dt = time.delta_seconds_f64();
+    for cell in cells.0.values() {
+        let Ok((mut joints, mut streaming, mut mirror)) = q_robot.get_mut(cell.robot) else {
+            continue;
+        };
+        if let Some(pos) = mirror.update(dt) {
+            joints.0 = pos;
+        }
+        if mirror.is_active() {
+            streaming.0 = true;
+        }
+    }
+}
+
+fn update_cartesian_jog(
+    time: Res<Time>,
+    mut q_robot: Query<(
+        &RobotUr5,
+        &GlobalTransform,
+        &mut JointsPos,
+        &mut CartesianJog,
+    )>,
+) {
+    let dt = time.delta_seconds_f64();
+    for (robot, gt, mut joints, mut jog) in q_robot.iter_mut() {
+        let r = gt.compute_transform().rotation;
+        let base_rotation = UnitQuaternion::from_quaternion(Quaternion::new(
+            r.w as f64, r.x as f64, r.y as f64, r.z as f64,
+        ));
+        if let Some(pos) = jog.step(&joints.0, robot, &base_rotation, dt) {
+            joints.0 = robot.clamp_deg(pos);
+        }
     }
+}
 
-    last_fingers.0 = fingers.0;
+fn recv_tcp_dragged(
+    mut events: EventReader<TcpDragged>,
+    cells: Res<RobotCells>,
+    mut q_robot: Query<(&RobotUr5, &mut JointsPos)>,
+) {
+    for event in events.iter() {
+        let Some((robot, mut joints)) = cells
+            .0
+            .get(&event.id)
+            .and_then(|cell| q_robot.get_mut(cell.robot).ok())
+        else {
+            continue;
+        };
+        let reference = joints.0.map(f64::to_radians);
+        if let Ok(pos) = robot.ik_near(&event.target, &reference) {
+            joints.0 = robot.clamp_deg(pos.map(f64::to_degrees));
+        }
+    }
 }
 
 fn draw_floor_grids(mut gizmos: Gizmos) {
@@ -300,11 +693,31 @@ fn draw_gripper_trails(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn ui(
+    mut commands: Commands,
     mut contexts: EguiContexts,
-    mut joints: ResMut<JointsPos>,
-    mut finger_pos: ResMut<FingerPos>,
-    mut show_window: Local<[bool; 2]>,
+    cells: Res<RobotCells>,
+    mut tcp_gizmo: ResMut<TcpGizmo>,
+    mut workspace: ResMut<Workspace>,
+    tool_changer: Res<ToolChanger>,
+    mut hidden: Local<BTreeSet<u64>>,
+    mut independent_jaws: Local<BTreeSet<u64>>,
+    mut q_robot: Query<(
+        &RobotUr5,
+        &mut JointsPos,
+        &mut CartesianJog,
+        &mut ProgramPlayer,
+        &mut ScriptRunner,
+        &mut MotionPlanner,
+    )>,
+    mut q_gripper: Query<(&mut FingerPos, &mut GripCurrent)>,
+    mut q_vacuum: Query<&mut Vacuum>,
+    #[cfg(not(target_family = "wasm"))] mut q_mirror: Query<&mut RtdeMirror>,
+    #[cfg(all(feature = "ros", not(target_family = "wasm")))] mut ros_bridge: ResMut<
+        ros_bridge::RosBridge,
+    >,
+    program_files: Res<ProgramFiles>,
 ) {
     let ctx = contexts.ctx_mut();
 
@@ -312,58 +725,329 @@ fn ui(
         .resizable(true)
         .show(ctx, |ui| {
             ui.horizontal_centered(|ui| {
-                if ui.selectable_label(!show_window[0], "Robot0").clicked() {
-                    show_window[0] = !(show_window[0]);
+                for id in cells.0.keys() {
+                    if ui
+                        .selectable_label(!hidden.contains(id), format!("Robot{}", id))
+                        .clicked()
+                        && !hidden.remove(id)
+                    {
+                        hidden.insert(*id);
+                    }
                 }
 
-                if ui.selectable_label(!show_window[1], "Robot1").clicked() {
-                    show_window[1] = !(show_window[1]);
+                if ui.button("Add robot").clicked() {
+                    let id = cells.0.keys().next_back().map_or(0, |id| id + 1);
+                    let pos = Vec3::new(id as f32 - 0.5, 0.0, 0.0);
+                    commands.add(move |world: &mut World| {
+                        RobotCellPlugin::add_cell(world, RobotLayout::new(id, pos));
+                    });
+                }
+
+                ui.separator();
+                ui.checkbox(&mut tcp_gizmo.enabled, "Gizmo");
+                ui.separator();
+                workspace.show(ui);
+                ui.separator();
+                ui.menu_button("Workpieces", |ui| {
+                    let shapes = [
+                        (
+                            "add box",
+                            Shape::Box {
+                                size: [0.04, 0.04, 0.04],
+                            },
+                        ),
+                        (
+                            "add cylinder",
+                            Shape::Cylinder {
+                                radius: 0.02,
+                                height: 0.06,
+                            },
+                        ),
+                    ];
+                    for (label, shape) in shapes {
+                        if ui.button(label).clicked() {
+                            commands
+                                .add(move |world: &mut World| WorkpiecePlugin::add(world, shape));
+                            ui.close_menu();
+                        }
+                    }
+                    if ui.button("remove all").clicked() {
+                        commands.add(WorkpiecePlugin::despawn_workpieces);
+                        ui.close_menu();
+                    }
+                });
+
+                #[cfg(all(feature = "ros", not(target_family = "wasm")))]
+                {
+                    ui.separator();
+                    ros_bridge.show(ui);
                 }
             });
         });
 
-    for i in 0..2 {
-        if !show_window[i] {
-            egui::Window::new(format!("Robot{}", i)).show(ctx, |ui| {
+    for (&id, cell) in cells.0.iter() {
+        if hidden.contains(&id) {
+            continue;
+        }
+        let Ok((robot, mut joints, mut jog, mut player, mut script, mut planner)) =
+            q_robot.get_mut(cell.robot)
+        else {
+            continue;
+        };
+        let (mut finger_pos, grip_current) =
+            cell.tool.and_then(|e| q_gripper.get_mut(e).ok()).unzip();
+        let vacuum = cell.tool.and_then(|e| q_vacuum.get_mut(e).ok());
+        let jaws = cell
+            .end_effector()
+            .filter(|model| model.actuation() == Actuation::Jaws);
+        egui::Window::new(format!("Robot{}", id)).show(ctx, |ui| {
+            ui.horizontal(|ui| {
                 if ui.button("reset").clicked() {
-                    joints.0[i] = JOINTS_POS;
-                    finger_pos.0[i] = [0.0, 0.0];
+                    joints.0 = cell.layout.home;
+                    if let Some(finger_pos) = finger_pos.as_mut() {
+                        finger_pos.0 = [0.0, 0.0];
+                    }
+                }
+                if ui.button("remove").clicked() {
+                    commands.add(move |world: &mut World| {
+                        RobotCellPlugin::remove_cell(world, id);
+                    });
                 }
+            });
 
-                egui::Grid::new("robot_axis").num_columns(2).show(ui, |ui| {
-                    ui.label("Axis1");
-                    ui.add(egui::Slider::new(&mut joints.0[i][0], -360.0..=360.0).suffix("°"));
-                    ui.end_row();
+            let limits = robot.limits;
+            egui::Grid::new(("robot_axis", id))
+                .num_columns(2)
+                .show(ui, |ui| {
+                    for (joint, limit) in limits.iter().enumerate() {
+                        ui.label(format!("Axis{}", joint + 1));
+                        ui.add(
+                            egui::Slider::new(&mut joints.0[joint], limit.min..=limit.max)
+                                .suffix("°"),
+                        );
+                        ui.end_row();
+                    }
 
-                    ui.label("Axis2");
-                    ui.add(egui::Slider::new(&mut joints.0[i][1], -360.0..=360.0).suffix("°"));
+                    ui.label("Tool");
+                    let kind = cell.layout.tool.as_ref().map(|t| t.kind);
+                    egui::ComboBox::from_id_source(("tool", id))
+                        .selected_text(cell.end_effector().map_or("none", |m| m.name()))
+                        .show_ui(ui, |ui| {
+                            let mut selected = kind;
+                            ui.selectable_value(&mut selected, None, "none");
+                            for k in ToolKind::ALL {
+                                ui.selectable_value(
+                                    &mut selected,
+                                    Some(k),
+                                    k.end_effector().name(),
+                                );
+                            }
+                            if selected != kind {
+                                let offset = cell.layout.tool.as_ref().map(|t| t.offset);
+                                let tool = selected.map(|kind| ToolLayout {
+                                    kind,
+                                    offset: offset.unwrap_or_default(),
+                                });
+                                commands.add(move |world: &mut World| {
+                                    RobotCellPlugin::set_tool(world, id, tool);
+                                });
+                            }
+                        });
                     ui.end_row();
 
-                    ui.label("Axis3");
-                    ui.add(egui::Slider::new(&mut joints.0[i][2], -360.0..=360.0).suffix("°"));
+                    ui.label("");
+                    ui.horizontal(|ui| {
+                        for change in ToolChange::ALL {
+                            if ui
+                                .button(change.name())
+                                .on_hover_text("tool changer rack")
+                                .clicked()
+                            {
+                                commands.add(move |world: &mut World| {
+                                    let _ = ToolChangerPlugin::change(world, id, change);
+                                });
+                            }
+                        }
+                        if let Some(error) = tool_changer.error(id) {
+                            ui.colored_label(egui::Color32::RED, error.to_string());
+                        }
+                    });
                     ui.end_row();
 
-                    ui.label("Axis4");
-                    ui.add(egui::Slider::new(&mut joints.0[i][3], -360.0..=360.0).suffix("°"));
-                    ui.end_row();
+                    if let Some(mut vacuum) = vacuum {
+                        ui.label("");
+                        ui.checkbox(&mut vacuum.0, "vacuum");
+                        ui.end_row();
+                    }
 
-                    ui.label("Axis5");
-                    ui.add(egui::Slider::new(&mut joints.0[i][4], -360.0..=360.0).suffix("°"));
-                    ui.end_row();
+                    if let (Some(finger_pos), Some(mut grip_current), Some(model)) =
+                        (finger_pos.as_mut(), grip_current, jaws)
+                    {
+                        if independent_jaws.contains(&id) {
+                            ui.label("Finger1");
+                            ui.add(
+                                egui::Slider::new(&mut finger_pos.0[0], 0.0..=100.0).suffix("%"),
+                            );
+                            ui.end_row();
 
-                    ui.label("Axis6");
-                    ui.add(egui::Slider::new(&mut joints.0[i][5], -360.0..=360.0).suffix("°"));
-                    ui.end_row();
+                            ui.label("Finger2");
+                            ui.add(
+                                egui::Slider::new(&mut finger_pos.0[1], 0.0..=100.0).suffix("%"),
+                            );
+                            ui.end_row();
+                        } else {
+                            ui.label("Width");
+                            let mut width = model.fingers_to_width(&finger_pos.0);
+                            if ui
+                                .add(
+                                    egui::Slider::new(&mut width, model.width_range())
+                                        .suffix(" mm"),
+                                )
+                                .changed()
+                            {
+                                finger_pos.0 = model.width_to_fingers(width);
+                            }
+                            ui.end_row();
+                        }
 
-                    ui.label("Finger1");
-                    ui.add(egui::Slider::new(&mut finger_pos.0[i][0], 0.0..=100.0).suffix("%"));
-                    ui.end_row();
+                        ui.label("Current");
+                        let mut current = grip_current.0;
+                        if ui
+                            .add(
+                                egui::Slider::new(&mut current, GripCurrent::MIN..=100.0)
+                                    .suffix("%"),
+                            )
+                            .changed()
+                        {
+                            grip_current.set(current);
+                        }
+                        ui.end_row();
 
-                    ui.label("Finger2");
-                    ui.add(egui::Slider::new(&mut finger_pos.0[i][1], 0.0..=100.0).suffix("%"));
-                    ui.end_row();
+                        ui.label("");
+                        let mut synchronized = !independent_jaws.contains(&id);
+                        if ui
+                            .checkbox(&mut synchronized, "synchronized jaws")
+                            .changed()
+                        {
+                            if synchronized {
+                                independent_jaws.remove(&id);
+                                finger_pos.0 =
+                                    model.width_to_fingers(model.fingers_to_width(&finger_pos.0));
+                            } else {
+                                independent_jaws.insert(id);
+                            }
+                        }
+                        ui.end_row();
+                    }
                 });
+
+            ui.collapsing("Cartesian jog", |ui| {
+                jog.show(ui, id, &joints.0, robot);
+            });
+
+            ui.collapsing("Program", |ui| {
+                let fingers = finger_pos.as_ref().map_or([0.0, 0.0], |f| f.0);
+                let was_running = player.is_running();
+                let action = player.show(ui, id, &joints.0, &fingers, jaws);
+                // a program and a script would both stream joints, only one runs
+                if player.is_running() && !was_running {
+                    script.stop();
+                }
+                match action {
+                    Some(ProgramAction::GoTo(pose_joints, pose_fingers)) => {
+                        if planner.avoid_collisions {
+                            planner.plan_to(pose_joints);
+                        } else {
+                            planner.stop();
+                            joints.0 = robot.clamp_deg(pose_joints);
+                        }
+                        if let Some(finger_pos) = finger_pos.as_mut() {
+                            finger_pos.0 = pose_fingers;
+                        }
+                    }
+                    Some(ProgramAction::Save) => save_program(&mut player),
+                    Some(ProgramAction::Load) => load_program(id, &mut player, &program_files),
+                    None => {}
+                }
+            });
+
+            ui.collapsing("Planner", |ui| {
+                planner.show(ui, id, &joints.0, robot);
+            });
+
+            ui.collapsing("URScript", |ui| {
+                let was_running = script.is_running();
+                script.show(ui, robot);
+                if script.is_running() && !was_running {
+                    player.stop();
+                }
             });
+
+            #[cfg(not(target_family = "wasm"))]
+            if let Ok(mut mirror) = q_mirror.get_mut(cell.robot) {
+                ui.collapsing("RTDE mirror", |ui| {
+                    mirror.show(ui);
+                });
+            }
+        });
+    }
+}
+
+// programs picked in the browser arrive asynchronously
+#[derive(Resource)]
+struct ProgramFiles {
+    #[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+    sender: Sender<(u64, String)>, // ( robot, text )
+    receiver: Receiver<(u64, String)>,
+}
+
+impl Default for ProgramFiles {
+    fn default() -> Self {
+        let (sender, receiver) = unbounded();
+        ProgramFiles { sender, receiver }
+    }
+}
+
+fn save_program(player: &mut ProgramPlayer) {
+    let file = ProgramFile::new(player.program.clone(), player.poses.clone());
+    #[cfg(not(target_family = "wasm"))]
+    let result = program_file::save(&player.path, &file);
+    #[cfg(target_family = "wasm")]
+    let result = program_file::download(&player.path, &file.to_text());
+    player.file_error = result.err().map(|e| e.to_string());
+}
+
+#[cfg_attr(not(target_family = "wasm"), allow(unused_variables))]
+fn load_program(id: u64, player: &mut ProgramPlayer, program_files: &ProgramFiles) {
+    #[cfg(not(target_family = "wasm"))]
+    {
+        match program_file::load(&player.path) {
+            Ok(file) => player.load(file.program, file.poses),
+            Err(e) => player.file_error = Some(e.to_string()),
+        }
+    }
+    #[cfg(target_family = "wasm")]
+    if let Err(e) = program_file::pick(id, program_files.sender.clone()) {
+        player.file_error = Some(e.to_string());
+    }
+}
+
+fn recv_program_files(
+    program_files: Res<ProgramFiles>,
+    cells: Res<RobotCells>,
+    mut q_player: Query<&mut ProgramPlayer>,
+) {
+    while let Ok((id, text)) = program_files.receiver.try_recv() {
+        let Some(mut player) = cells
+            .0
+            .get(&id)
+            .and_then(|cell| q_player.get_mut(cell.robot).ok())
+        else {
+            continue;
+        };
+        match ProgramFile::parse(&text) {
+            Ok(file) => player.load(file.program, file.poses),
+            Err(e) => player.file_error = Some(e.to_string()),
         }
     }
 }
@@ -403,126 +1087,3 @@ fn setup_camera_light(mut commands: Commands) {
         ..default()
     });
 }
-
-fn setup_robot(mut commands: Commands) {
-    commands.add(|world: &mut World| {
-        let mut tf = Transform::from_xyz(-0.5, 0.0, 0.0);
-        tf.rotate_x(-std::f32::consts::FRAC_PI_2);
-        let (_, wrist) = RobotPluginUr5::add_robot(world, ROBOT_KEY_0, Some(tf), None);
-        let (gripper, _, _) =
-            GripperPlugin::add_gripper(world, ROBOT_KEY_0, None, Some([0.0, 0.0]));
-        world.entity_mut(wrist).push_children(&[gripper]);
-    });
-
-    commands.add(|world: &mut World| {
-        let mut tf = Transform::from_xyz(0.5, 0.0, 0.0);
-        tf.rotate_x(-std::f32::consts::FRAC_PI_2);
-        let (_, wrist) = RobotPluginUr5::add_robot(world, ROBOT_KEY_1, Some(tf), None);
-        let (gripper, _, _) =
-            GripperPlugin::add_gripper(world, ROBOT_KEY_1, None, Some([0.0, 0.0]));
-        world.entity_mut(wrist).push_children(&[gripper]);
-    });
-}
-
-#[derive(Component)]
-struct Label(u64);
-
-fn setup_label(mut commands: Commands) {
-    commands.spawn((
-        TextBundle {
-            text: Text::from_section(
-                "Robot0",
-                TextStyle {
-                    font_size: 24.0,
-                    ..default()
-                },
-            ),
-            style: Style {
-                position_type: PositionType::Absolute,
-                ..default()
-            },
-            ..default()
-        },
-        Label(ROBOT_KEY_0),
-    ));
-
-    commands.spawn((
-        TextBundle {
-            text: Text::from_section(
-                "Robot1",
-                TextStyle {
-                    font_size: 24.0,
-                    ..default()
-                },
-            ),
-            style: Style {
-                position_type: PositionType::Absolute,
-                ..default()
-            },
-            ..default()
-        },
-        Label(ROBOT_KEY_1),
-    ));
-}
-
-fn update_label_pos(
-    q_robot: Query<(&RobotUr5, &GlobalTransform)>,
-    q_camera: Query<(&Camera, &GlobalTransform)>,
-    mut q_label: Query<(&Label, &mut Style)>,
-) {
-    if let Ok((camera, camera_gt)) = q_camera.get_single() {
-        for (robot, gt) in q_robot.iter() {
-            for (label, mut style) in q_label.iter_mut() {
-                if robot.id == label.0 {
-                    if let Some(pos) = camera.world_to_viewport(camera_gt, gt.translation()) {
-                        style.left = Val::Px(pos.x - 40.0);
-                        style.top = Val::Px(pos.y + 10.0);
-                    }
-                }
-            }
-        }
-    }
-}
-
-// now 当前值
-// target 目标值
-// k 变化系数
-// d_max 最大变化值
-// 返回下一帧的值
-fn compute_track(now: f64, target: f64, k: f64, d_max: f64) -> f64 {
-    let mut delta = (2.0 * k * (target - now)).abs().sqrt();
-    if delta > d_max.abs() {
-        delta = d_max.abs();
-    }
-    if target >= now {
-        let out = now + delta;
-        if out > target {
-            target
-        } else {
-            out
-        }
-    } else {
-        let out = now - delta;
-        if out < target {
-            target
-        } else {
-            out
-        }
-    }
-}
-
-fn ct_robot_joints(now: &[f64; 6], target: &[f64; 6]) -> [f64; 6] {
-    let mut out = [0f64; 6];
-    for i in 0..6 {
-        out[i] = compute_track(now[i], target[i], 0.5, 16.0);
-    }
-    out
-}
-
-fn ct_gripper_finger(now: &[f32; 2], target: &[f32; 2]) -> [f32; 2] {
-    let mut out = [0f32; 2];
-    for i in 0..2 {
-        out[i] = compute_track(now[i] as f64, target[i] as f64, 2.0, 5.0) as f32;
-    }
-    out
-}
@@ -0,0 +1,185 @@
+use nalgebra::Isometry3;
+
+use crate::{
+    robot_ur5::{IkError, RobotUr5},
+    trajectory::Trajectory,
+};
+
+const MAX_JOINT_STEP: f64 = 20.0; // deg, a larger jump in one frame of a linear move is a singularity
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MotionError {
+    Ik(IkError),
+    Singularity,
+}
+
+impl std::fmt::Display for MotionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MotionError::Ik(e) => write!(f, "{}", e),
+            MotionError::Singularity => write!(f, "path crosses a singularity"),
+        }
+    }
+}
+
+impl From<IkError> for MotionError {
+    fn from(e: IkError) -> Self {
+        MotionError::Ik(e)
+    }
+}
+
+// One move of a program, it starts at rest from the current position.
+// The joints are streamed to the robot every frame.
+pub enum Motion {
+    Joint(Trajectory<6>),
+    Linear {
+        start: Isometry3<f64>, // tcp
+        end: Isometry3<f64>,   // tcp
+        length: [f64; 2],      // ( mm, deg )
+        profile: Trajectory<2>,
+        last: [f64; 6], // deg
+    },
+}
+
+impl Motion {
+    // target: deg
+    // velocity: deg/s
+    // acceleration: deg/s^2
+    pub fn joint(
+        robot: &RobotUr5,
+        target: [f64; 6],
+        velocity: &[f64; 6],
+        acceleration: &[f64; 6],
+    ) -> Motion {
+        let mut trajectory = Trajectory::new(robot.joints().map(f64::to_degrees));
+        trajectory.retarget(robot.clamp_deg(target), velocity, acceleration);
+        Motion::Joint(trajectory)
+    }
+
+    // target: tcp relative to robot base
+    // velocity: ( mm/s, deg/s )
+    // acceleration: ( mm/s^2, deg/s^2 )
+    pub fn linear(
+        robot: &RobotUr5,
+        target: Isometry3<f64>,
+        velocity: [f64; 2],
+        acceleration: [f64; 2],
+    ) -> Result<Motion, IkError> {
+        let start = robot.tcp_pose();
+        // the end has to be reachable from this branch
+        robot.ik_nearest(&(target * robot.tool.inverse()))?;
+        let length = [
+            (target.translation.vector - start.translation.vector).norm() * 1000.0,
+            start.rotation.angle_to(&target.rotation).to_degrees(),
+        ];
+        let mut profile = Trajectory::new([0.0, 0.0]);
+        profile.retarget(length, &velocity, &acceleration);
+        Ok(Motion::Linear {
+            start,
+            end: target,
+            length,
+            profile,
+            last: robot.joints().map(f64::to_degrees),
+        })
+    }
+
+    // dt: s
+    // Out: ( joints deg, tcp distance to the end mm, done )
+    pub fn step(
+        &mut self,
+        robot: &RobotUr5,
+        dt: f64,
+    ) -> Result<([f64; 6], f64, bool), MotionError> {
+        match self {
+            Motion::Joint(trajectory) => {
+                let joints = trajectory.step(dt);
+                let remaining = tcp_distance(robot, &joints, &trajectory.target());
+                Ok((joints, remaining, trajectory.is_done()))
+            }
+            Motion::Linear {
+                start,
+                end,
+                length,
+                profile,
+                last,
+            } => {
+                let pos = profile.step(dt);
+                let s = if length[0] > 0.0 {
+                    pos[0] / length[0]
+                } else if length[1] > 0.0 {
+                    pos[1] / length[1]
+                } else {
+                    1.0
+                };
+                let tcp = start.lerp_slerp(end, s);
+                let flange = tcp * robot.tool.inverse();
+                let joints = robot.ik_near(&flange, &last.map(f64::to_radians))?;
+                let joints = robot.clamp_deg(joints.map(f64::to_degrees));
+                if joints
+                    .iter()
+                    .zip(last.iter())
+                    .any(|(a, b)| (a - b).abs() > MAX_JOINT_STEP)
+                {
+                    return Err(MotionError::Singularity);
+                }
+                *last = joints;
+                Ok((joints, length[0] * (1.0 - s), profile.is_done()))
+            }
+        }
+    }
+
+    // joints at the end of the move, deg
+    pub fn end(&self, robot: &RobotUr5) -> Result<[f64; 6], MotionError> {
+        match self {
+            Motion::Joint(trajectory) => Ok(trajectory.target()),
+            Motion::Linear { end, last, .. } => {
+                let flange = end * robot.tool.inverse();
+                let joints = robot.ik_near(&flange, &last.map(f64::to_radians))?;
+                Ok(robot.clamp_deg(joints.map(f64::to_degrees)))
+            }
+        }
+    }
+}
+
+// A move cut short at a blend keeps running to its end while the next move starts
+// from that end, the two are added up so the joint velocity carries over the corner.
+pub struct Blend {
+    motion: Motion,
+    end: [f64; 6], // deg
+}
+
+impl Blend {
+    pub fn new(motion: Motion, robot: &RobotUr5) -> Result<Blend, MotionError> {
+        let end = motion.end(robot)?;
+        Ok(Blend { motion, end })
+    }
+
+    // where the next move starts, deg
+    pub fn end(&self) -> [f64; 6] {
+        self.end
+    }
+
+    // joints: the next move, deg
+    // dt: s
+    // Out: ( joints deg, done )
+    pub fn step(
+        &mut self,
+        robot: &RobotUr5,
+        joints: [f64; 6],
+        dt: f64,
+    ) -> Result<([f64; 6], bool), MotionError> {
+        let (rest, _, done) = self.motion.step(robot, dt)?;
+        let mut out = joints;
+        for (out, (rest, end)) in out.iter_mut().zip(rest.iter().zip(self.end.iter())) {
+            *out += rest - end;
+        }
+        Ok((out, done))
+    }
+}
+
+// tcp distance between two joint positions, mm
+fn tcp_distance(robot: &RobotUr5, a: &[f64; 6], b: &[f64; 6]) -> f64 {
+    let a = robot.flange_at(a.map(f64::to_radians)) * robot.tool;
+    let b = robot.flange_at(b.map(f64::to_radians)) * robot.tool;
+    (a.translation.vector - b.translation.vector).norm() * 1000.0
+}
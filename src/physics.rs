@@ -0,0 +1,263 @@
+// Rigid body physics of the workpieces, with the physics feature. Workpieces fall, stack and are
+// pushed by the robot links and the tools, which stay driven by the joints: kinematic bodies
+// following their entities, with the capsules of the collision check. A gripper holds a workpiece
+// by friction, its jaws stop once both sides touch it and squeeze it by the motor current; a suction
+// cup with vacuum holds what it touches. The scripted grasp of the workpiece plugin is left out.
+use bevy::prelude::*;
+use bevy_rapier3d::prelude as rapier;
+
+use crate::{
+    collision::{Collider, Solid},
+    end_effector::{EndEffectorPlugin, Tool, Vacuum},
+    robot_cell::{FingerPos, GripCurrent, RobotCellPlugin},
+    workpiece::Workpiece,
+};
+
+const FRICTION: f32 = 1.0;
+const DENSITY: f32 = 700.0; // kg/m^3
+const SQUEEZE: f32 = 0.01; // jaws travel past the contact at full current, range [0.0, 1.0]
+
+// where the jaws of a gripper stop on a workpiece, range [0.0, 1.0]
+#[derive(Component)]
+struct Squeeze([f32; 2]);
+
+pub struct PhysicsPlugin;
+
+impl Plugin for PhysicsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(rapier::RapierPhysicsPlugin::<rapier::NoUserData>::default())
+            .add_systems(Startup, PhysicsPlugin::spawn_floor)
+            .add_systems(
+                Update,
+                (
+                    PhysicsPlugin::add_workpiece_bodies,
+                    PhysicsPlugin::add_fixture_bodies,
+                    PhysicsPlugin::add_part_bodies,
+                    PhysicsPlugin::remove_part_bodies,
+                    PhysicsPlugin::squeeze
+                        .after(RobotCellPlugin::update_finger_pos)
+                        .before(EndEffectorPlugin::update_part_pos),
+                    PhysicsPlugin::suction,
+                ),
+            );
+    }
+}
+
+// center: of the solid in the frame of its entity
+fn solid_collider(solid: &Solid, center: Vec3) -> rapier::Collider {
+    let shape = match *solid {
+        Solid::Box { half_size } => rapier::Collider::cuboid(half_size.x, half_size.y, half_size.z),
+        Solid::Cylinder {
+            radius,
+            half_height,
+        } => rapier::Collider::cylinder(half_height, radius),
+    };
+    if center == Vec3::ZERO {
+        shape
+    } else {
+        rapier::Collider::compound(vec![(center, Quat::IDENTITY, shape)])
+    }
+}
+
+// In: the colliders of a contact, one of them
+// Out: the other one
+fn other((collider1, collider2): (Entity, Entity), entity: Entity) -> Entity {
+    if collider1 == entity {
+        collider2
+    } else {
+        collider1
+    }
+}
+
+impl PhysicsPlugin {
+    fn spawn_floor(mut commands: Commands) {
+        commands.spawn((
+            rapier::RigidBody::Fixed,
+            rapier::Collider::halfspace(Vec3::Y).unwrap(),
+            rapier::Friction::coefficient(FRICTION),
+            TransformBundle::default(),
+            Name::new("floor"),
+        ));
+    }
+
+    // once the shape is known, for an imported mesh
+    fn add_workpiece_bodies(
+        mut commands: Commands,
+        q_workpiece: Query<(Entity, &Workpiece), Without<rapier::RigidBody>>,
+    ) {
+        for (entity, workpiece) in q_workpiece.iter() {
+            let Some((solid, center)) = workpiece.shape else {
+                continue;
+            };
+            commands.entity(entity).insert((
+                rapier::RigidBody::Dynamic,
+                solid_collider(&solid, center),
+                rapier::Friction::coefficient(FRICTION),
+                rapier::ColliderMassProperties::Density(DENSITY),
+                rapier::Ccd::enabled(),
+            ));
+        }
+    }
+
+    fn add_fixture_bodies(
+        mut commands: Commands,
+        q_fixture: Query<(Entity, &Solid), Without<rapier::RigidBody>>,
+    ) {
+        for (entity, solid) in q_fixture.iter() {
+            commands.entity(entity).insert((
+                rapier::RigidBody::Fixed,
+                solid_collider(solid, Vec3::ZERO),
+                rapier::Friction::coefficient(FRICTION),
+            ));
+        }
+    }
+
+    // robot links and mounted tool parts
+    fn add_part_bodies(
+        mut commands: Commands,
+        q_part: Query<(Entity, &Collider), Added<Collider>>,
+    ) {
+        for (entity, collider) in q_part.iter() {
+            if collider.capsules.is_empty() {
+                continue;
+            }
+            let capsules = collider
+                .capsules
+                .iter()
+                .map(|c| {
+                    let capsule = rapier::Collider::capsule(c.a, c.b, c.radius);
+                    (Vec3::ZERO, Quat::IDENTITY, capsule)
+                })
+                .collect();
+            commands.entity(entity).insert((
+                rapier::RigidBody::KinematicPositionBased,
+                rapier::Collider::compound(capsules),
+                rapier::Friction::coefficient(FRICTION),
+            ));
+        }
+    }
+
+    // parked tools, unless mounted again meanwhile
+    fn remove_part_bodies(
+        mut commands: Commands,
+        mut removed: RemovedComponents<Collider>,
+        q_part: Query<(), With<Collider>>,
+    ) {
+        for entity in removed.iter() {
+            if q_part.contains(entity) {
+                continue;
+            }
+            if let Some(mut entity) = commands.get_entity(entity) {
+                entity.remove::<(rapier::RigidBody, rapier::Collider)>();
+            }
+        }
+    }
+
+    // the jaws stop on a workpiece touched on both sides of the tool axis
+    #[allow(clippy::type_complexity)]
+    fn squeeze(
+        mut commands: Commands,
+        context: Res<rapier::RapierContext>,
+        mut q_tool: Query<(
+            Entity,
+            &mut Tool,
+            &Children,
+            &FingerPos,
+            &GripCurrent,
+            Option<&Squeeze>,
+        )>,
+        q_part: Query<&Transform, With<Collider>>,
+        q_workpiece: Query<(), With<Workpiece>>,
+    ) {
+        for (entity, mut tool, children, fingers, current, squeeze) in q_tool.iter_mut() {
+            // ( workpiece, side ), the finger one side is at -x
+            let mut touching = Vec::new();
+            for &child in children.iter() {
+                let Ok(tf) = q_part.get(child) else {
+                    continue;
+                };
+                if tool.robot.is_none() || tf.translation.x.abs() < 1e-3 {
+                    continue;
+                }
+                let side = tf.translation.x > 0.0;
+                for pair in context.contacts_with(child) {
+                    let workpiece = other((pair.collider1(), pair.collider2()), child);
+                    if pair.has_any_active_contacts() && q_workpiece.contains(workpiece) {
+                        touching.push((workpiece, side));
+                    }
+                }
+            }
+            let gripped = touching
+                .iter()
+                .any(|&(w, side)| !side && touching.contains(&(w, true)));
+
+            let target = fingers.0.map(|f| f / 100.0);
+            match squeeze {
+                Some(Squeeze(jaws)) => {
+                    let open = (0..2).any(|i| target[i] > jaws[i] + SQUEEZE);
+                    if open || !gripped {
+                        commands.entity(entity).remove::<Squeeze>();
+                    } else {
+                        let stopped = [0, 1].map(|i| tool.jaws[i].max(jaws[i]));
+                        if tool.jaws != stopped {
+                            tool.jaws = stopped;
+                        }
+                    }
+                }
+                None if gripped && (0..2).any(|i| target[i] < tool.jaws[i]) => {
+                    let depth = SQUEEZE * current.0 / 100.0;
+                    let jaws = tool.jaws.map(|j| (j - depth).max(0.0));
+                    commands.entity(entity).insert(Squeeze(jaws));
+                }
+                None => {}
+            }
+        }
+    }
+
+    // a suction cup with vacuum holds the workpieces it touches by a fixed joint
+    fn suction(
+        mut commands: Commands,
+        context: Res<rapier::RapierContext>,
+        q_tool: Query<(&Tool, &Vacuum, &Children)>,
+        q_part: Query<&GlobalTransform, With<Collider>>,
+        q_workpiece: Query<(&GlobalTransform, Option<&rapier::ImpulseJoint>), With<Workpiece>>,
+    ) {
+        for (tool, vacuum, children) in q_tool.iter() {
+            let holding = vacuum.0 && tool.robot.is_some();
+            for &child in children.iter() {
+                let Ok(part_gt) = q_part.get(child) else {
+                    continue;
+                };
+                for pair in context.contacts_with(child) {
+                    let workpiece = other((pair.collider1(), pair.collider2()), child);
+                    let Ok((gt, joint)) = q_workpiece.get(workpiece) else {
+                        continue;
+                    };
+                    if joint.is_none() && holding && pair.has_any_active_contacts() {
+                        // held where it is
+                        let (_, rotation, translation) = (part_gt.affine().inverse() * gt.affine())
+                            .to_scale_rotation_translation();
+                        let fixed = rapier::FixedJointBuilder::new()
+                            .local_anchor1(translation)
+                            .local_basis1(rotation);
+                        commands
+                            .entity(workpiece)
+                            .insert(rapier::ImpulseJoint::new(child, fixed));
+                    }
+                }
+            }
+            if !holding {
+                for &child in children.iter() {
+                    for pair in context.contacts_with(child) {
+                        let workpiece = other((pair.collider1(), pair.collider2()), child);
+                        if let Ok((_, Some(joint))) = q_workpiece.get(workpiece) {
+                            if joint.parent == child {
+                                commands.entity(workpiece).remove::<rapier::ImpulseJoint>();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
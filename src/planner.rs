@@ -0,0 +1,687 @@
+// Collision free motions in joint space: RRT-Connect from the current joints to a target,
+// then shortcutting, the path is followed waypoint by waypoint through JointsPos.
+// The surroundings are taken when planning starts, the other robots are obstacles where they stand.
+// Planning runs on a thread in the native application, a few iterations per frame in the browser.
+use bevy::prelude::*;
+use bevy_egui::egui;
+use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use std::collections::VecDeque;
+#[cfg(not(target_family = "wasm"))]
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    collision::{parts_touch, world_solids, Capsule, Collider, Solid, SolidQuery},
+    robot_cell::JointsPos,
+    robot_ur5::{JointLimit, RobotModel, RobotUr5},
+    urdf::RobotDescription,
+};
+
+const STEP: f64 = 6.0; // deg, longest extension of a tree
+const RESOLUTION: f64 = 1.5; // deg, between two checked configurations of an edge
+const MAX_ITERATIONS: usize = 20000;
+const SHORTCUTS: usize = 200;
+const SPREAD: f64 = 180.0; // deg, sampled beyond the start and goal joints
+const REACHED: f64 = 1e-3; // deg, a waypoint is reached
+const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+#[cfg(target_family = "wasm")]
+const FRAME_ITERATIONS: usize = 40;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlanError {
+    // ( the touching parts )
+    StartInCollision(String),
+    GoalInCollision(String),
+    NoPath,
+}
+
+impl std::fmt::Display for PlanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanError::StartInCollision(c) => write!(f, "the start collides: {}", c),
+            PlanError::GoalInCollision(c) => write!(f, "the goal collides: {}", c),
+            PlanError::NoPath => write!(f, "no path found in {} iterations", MAX_ITERATIONS),
+        }
+    }
+}
+
+// a robot and what it must not touch, owned by the planner
+pub struct PlanningScene {
+    pub description: RobotDescription,
+    pub base: Transform,
+    pub limits: [JointLimit; 6],
+    pub links: Vec<Collider>,
+    pub tool: Vec<(Collider, Transform)>, // relative to the tip link
+    pub others: Vec<(Collider, Vec<Capsule>)>, // parts of the other robots, in the world
+    pub solids: Vec<(Solid, Transform, String)>, // ( solid, world to solid, name )
+}
+
+impl PlanningScene {
+    // q: deg
+    // Out: the first contact found, None if the robot is free
+    pub fn collision(&self, q: &[f64; 6]) -> Option<String> {
+        let tfs = self.description.link_transforms(&q.map(f64::to_radians));
+        let tip = self.base * tfs[self.description.tip()];
+        let place = |c: &Collider, tf: Transform| -> Vec<Capsule> {
+            c.capsules.iter().map(|x| x.transformed(&tf)).collect()
+        };
+        let parts: Vec<(&Collider, Vec<Capsule>)> = self
+            .links
+            .iter()
+            .map(|c| (c, place(c, self.base * tfs[c.link.unwrap_or_default()])))
+            .chain(self.tool.iter().map(|(c, tf)| (c, place(c, tip * *tf))))
+            .collect();
+
+        for (i, (a, ca)) in parts.iter().enumerate() {
+            for (b, cb) in parts[i + 1..].iter() {
+                if parts_touch(a, ca, b, cb) {
+                    return Some(format!("{} and {}", a.part, b.part));
+                }
+            }
+            for (b, cb) in self.others.iter() {
+                if parts_touch(a, ca, b, cb) {
+                    return Some(format!("{} and robot{} {}", a.part, b.robot, b.part));
+                }
+            }
+            if a.grounded {
+                continue;
+            }
+            if ca.iter().any(Capsule::below_floor) {
+                return Some(format!("{} and the floor", a.part));
+            }
+            for (solid, inverse, name) in self.solids.iter() {
+                if ca.iter().any(|c| solid.overlaps(&c.transformed(inverse))) {
+                    return Some(format!("{} and {}", a.part, name));
+                }
+            }
+        }
+        None
+    }
+
+    // a, b: deg
+    fn edge_free(&self, a: &[f64; 6], b: &[f64; 6]) -> bool {
+        let longest = (0..6).map(|i| (b[i] - a[i]).abs()).fold(0.0, f64::max);
+        let n = (longest / RESOLUTION).ceil().max(1.0) as usize;
+        (1..=n).all(|k| self.collision(&lerp(a, b, k as f64 / n as f64)).is_none())
+    }
+}
+
+fn lerp(a: &[f64; 6], b: &[f64; 6], t: f64) -> [f64; 6] {
+    let mut out = [0.0; 6];
+    for i in 0..6 {
+        out[i] = a[i] + (b[i] - a[i]) * t;
+    }
+    out
+}
+
+fn distance(a: &[f64; 6], b: &[f64; 6]) -> f64 {
+    (0..6).map(|i| (b[i] - a[i]).powi(2)).sum::<f64>().sqrt()
+}
+
+// xorshift64*, the paths are the same from run to run
+struct Rng(u64);
+
+impl Rng {
+    // Out: range [0.0, 1.0)
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Out: range [0, n)
+    fn below(&mut self, n: usize) -> usize {
+        ((self.next() * n as f64) as usize).min(n - 1)
+    }
+}
+
+// ( joints deg, parent ), the root is its own parent
+struct Tree(Vec<([f64; 6], usize)>);
+
+impl Tree {
+    fn nearest(&self, q: &[f64; 6]) -> usize {
+        let mut best = (0, f64::MAX);
+        for (i, (node, _)) in self.0.iter().enumerate() {
+            let d = distance(node, q);
+            if d < best.1 {
+                best = (i, d);
+            }
+        }
+        best.0
+    }
+
+    // Out: from the root to the node
+    fn path(&self, mut i: usize) -> Vec<[f64; 6]> {
+        let mut out = vec![self.0[i].0];
+        while self.0[i].1 != i {
+            i = self.0[i].1;
+            out.push(self.0[i].0);
+        }
+        out.reverse();
+        out
+    }
+
+    // Out: true once the tree reaches q, the new node is the last one
+    fn extend(&mut self, scene: &PlanningScene, q: &[f64; 6]) -> Option<bool> {
+        let near = self.nearest(q);
+        let from = self.0[near].0;
+        let d = distance(&from, q);
+        let to = if d <= STEP {
+            *q
+        } else {
+            lerp(&from, q, STEP / d)
+        };
+        if !scene.edge_free(&from, &to) {
+            return None;
+        }
+        self.0.push((to, near));
+        Some(d <= STEP)
+    }
+
+    // Out: true if the tree reaches q
+    fn connect(&mut self, scene: &PlanningScene, q: &[f64; 6]) -> bool {
+        loop {
+            match self.extend(scene, q) {
+                Some(true) => return true,
+                Some(false) => {}
+                None => return false,
+            }
+        }
+    }
+}
+
+pub struct RrtConnect {
+    trees: [Tree; 2], // from the start, from the goal
+    bounds: [(f64, f64); 6],
+    rng: Rng,
+    iterations: usize,
+}
+
+impl RrtConnect {
+    // start, goal: deg
+    pub fn new(
+        scene: &PlanningScene,
+        start: [f64; 6],
+        goal: [f64; 6],
+    ) -> Result<RrtConnect, PlanError> {
+        if let Some(contact) = scene.collision(&start) {
+            return Err(PlanError::StartInCollision(contact));
+        }
+        if let Some(contact) = scene.collision(&goal) {
+            return Err(PlanError::GoalInCollision(contact));
+        }
+        let mut bounds = [(0.0, 0.0); 6];
+        for i in 0..6 {
+            let limit = scene.limits[i];
+            bounds[i] = (
+                (start[i].min(goal[i]) - SPREAD).max(limit.min),
+                (start[i].max(goal[i]) + SPREAD).min(limit.max),
+            );
+        }
+        Ok(RrtConnect {
+            trees: [Tree(vec![(start, 0)]), Tree(vec![(goal, 0)])],
+            bounds,
+            rng: Rng(SEED),
+            iterations: 0,
+        })
+    }
+
+    fn sample(&mut self) -> [f64; 6] {
+        let rng = &mut self.rng;
+        self.bounds.map(|(lo, hi)| lo + (hi - lo) * rng.next())
+    }
+
+    // Out: the path from start to goal, deg, None while still searching
+    pub fn step(&mut self, scene: &PlanningScene) -> Option<Result<Vec<[f64; 6]>, PlanError>> {
+        if self.iterations == 0 {
+            let (start, goal) = (self.trees[0].0[0].0, self.trees[1].0[0].0);
+            if scene.edge_free(&start, &goal) {
+                self.iterations = 1;
+                return Some(Ok(vec![start, goal]));
+            }
+        }
+        if self.iterations >= MAX_ITERATIONS {
+            return Some(Err(PlanError::NoPath));
+        }
+        // the trees take turns growing towards the samples
+        let grow = self.iterations % 2;
+        self.iterations += 1;
+        let q = self.sample();
+        let [a, b] = &mut self.trees;
+        let (grown, other) = if grow == 0 { (a, b) } else { (b, a) };
+        grown.extend(scene, &q)?;
+        let new = *grown.0.last().map(|(q, _)| q)?;
+        if !other.connect(scene, &new) {
+            return None;
+        }
+        let mut path = self.trees[0].path(self.trees[0].0.len() - 1);
+        let mut back = self.trees[1].path(self.trees[1].0.len() - 1);
+        back.reverse();
+        // the meeting point is in both
+        path.extend(back.into_iter().skip(1));
+        Some(Ok(shortcut(scene, path, &mut self.rng)))
+    }
+}
+
+// removes the waypoints that can be skipped
+fn shortcut(scene: &PlanningScene, mut path: Vec<[f64; 6]>, rng: &mut Rng) -> Vec<[f64; 6]> {
+    for _ in 0..SHORTCUTS {
+        if path.len() < 3 {
+            break;
+        }
+        let i = rng.below(path.len() - 2);
+        let j = i + 2 + rng.below(path.len() - i - 2);
+        if scene.edge_free(&path[i], &path[j]) {
+            path.drain(i + 1..j);
+        }
+    }
+    path
+}
+
+#[cfg(not(target_family = "wasm"))]
+struct Planning {
+    receiver: flume::Receiver<Result<Vec<[f64; 6]>, PlanError>>,
+    cancel: Arc<AtomicBool>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Drop for Planning {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Planning {
+    fn start(scene: PlanningScene, start: [f64; 6], goal: [f64; 6]) -> Self {
+        let (sender, receiver) = flume::bounded(1);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let thread_cancel = cancel.clone();
+        thread::spawn(move || {
+            let result = RrtConnect::new(&scene, start, goal).map(|mut rrt| loop {
+                if thread_cancel.load(Ordering::Relaxed) {
+                    return None;
+                }
+                if let Some(result) = rrt.step(&scene) {
+                    return Some(result);
+                }
+            });
+            let result = match result {
+                Ok(Some(result)) => result,
+                Ok(None) => return,
+                Err(e) => Err(e),
+            };
+            let _ = sender.send(result);
+        });
+        Planning { receiver, cancel }
+    }
+
+    fn poll(&mut self) -> Option<Result<Vec<[f64; 6]>, PlanError>> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+// no threads in the browser
+#[cfg(target_family = "wasm")]
+struct Planning {
+    scene: PlanningScene,
+    rrt: Result<RrtConnect, Option<PlanError>>,
+}
+
+#[cfg(target_family = "wasm")]
+impl Planning {
+    fn start(scene: PlanningScene, start: [f64; 6], goal: [f64; 6]) -> Self {
+        let rrt = RrtConnect::new(&scene, start, goal).map_err(Some);
+        Planning { scene, rrt }
+    }
+
+    fn poll(&mut self) -> Option<Result<Vec<[f64; 6]>, PlanError>> {
+        let rrt = match &mut self.rrt {
+            Ok(rrt) => rrt,
+            Err(e) => return e.take().map(Err),
+        };
+        (0..FRAME_ITERATIONS).find_map(|_| rrt.step(&self.scene))
+    }
+}
+
+#[derive(Default)]
+enum PlanState {
+    #[default]
+    Idle,
+    Planning(Box<Planning>),
+    // target: the waypoint set in JointsPos
+    Following {
+        path: VecDeque<[f64; 6]>,
+        target: [f64; 6],
+    },
+    Failed(String),
+}
+
+#[derive(Component)]
+pub struct MotionPlanner {
+    pub avoid_collisions: bool, // the program poses are reached through planned paths
+    goal: [f64; 6],             // deg
+    goal_pose: [f64; 6],        // tcp relative to robot base, ( x, y, z ) mm, rotation vector deg
+    request: Option<[f64; 6]>,  // deg
+    state: PlanState,
+}
+
+impl Default for MotionPlanner {
+    fn default() -> Self {
+        MotionPlanner {
+            avoid_collisions: false,
+            goal: [0.0; 6],
+            goal_pose: [0.0; 6],
+            request: None,
+            state: PlanState::Idle,
+        }
+    }
+}
+
+impl MotionPlanner {
+    // goal: deg, planned from the current joints at the next frame
+    pub fn plan_to(&mut self, goal: [f64; 6]) {
+        self.request = Some(goal);
+    }
+
+    pub fn stop(&mut self) {
+        self.request = None;
+        self.state = PlanState::Idle;
+    }
+
+    // joints: current joints target, deg
+    // robot: its model and tool
+    pub fn show(&mut self, ui: &mut egui::Ui, id: u64, joints: &[f64; 6], robot: &RobotUr5) {
+        egui::Grid::new(("planner_goal", id))
+            .num_columns(4)
+            .show(ui, |ui| {
+                for i in 0..6 {
+                    ui.label(format!("Axis{}", i + 1));
+                    ui.add(egui::DragValue::new(&mut self.goal[i]).suffix("°"));
+                    let (name, suffix) = (["X", "Y", "Z", "Rx", "Ry", "Rz"][i], ["mm", "°"][i / 3]);
+                    ui.label(name);
+                    ui.add(egui::DragValue::new(&mut self.goal_pose[i]).suffix(suffix));
+                    ui.end_row();
+                }
+            });
+        ui.horizontal(|ui| {
+            if ui.button("current").clicked() {
+                self.goal = *joints;
+                let pose = robot.flange_at(joints.map(f64::to_radians)) * robot.tool;
+                let p = pose.translation.vector * 1000.0;
+                let r = pose.rotation.scaled_axis().map(f64::to_degrees);
+                self.goal_pose = [p.x, p.y, p.z, r.x, r.y, r.z];
+            }
+            if ui.button("plan to joints").clicked() {
+                self.plan_to(self.goal);
+            }
+            if ui.button("plan to pose").clicked() {
+                let [x, y, z, rx, ry, rz] = self.goal_pose;
+                let rotation = Vector3::new(rx, ry, rz).map(f64::to_radians);
+                let pose = Isometry3::from_parts(
+                    Translation3::new(x / 1000.0, y / 1000.0, z / 1000.0),
+                    UnitQuaternion::from_scaled_axis(rotation),
+                );
+                let reference = joints.map(f64::to_radians);
+                match robot.ik_near(&(pose * robot.tool.inverse()), &reference) {
+                    Ok(goal) => {
+                        self.goal = goal.map(f64::to_degrees);
+                        self.plan_to(self.goal);
+                    }
+                    Err(e) => self.state = PlanState::Failed(e.to_string()),
+                }
+            }
+            if ui.button("stop").clicked() {
+                self.stop();
+            }
+        });
+        ui.checkbox(&mut self.avoid_collisions, "plan the program moves");
+        match &self.state {
+            PlanState::Idle => {}
+            PlanState::Planning(_) => {
+                ui.label("planning...");
+            }
+            PlanState::Following { path, .. } => {
+                ui.label(format!("following, {} waypoints left", path.len() + 1));
+            }
+            PlanState::Failed(e) => {
+                ui.colored_label(egui::Color32::RED, e);
+            }
+        }
+    }
+}
+
+pub struct PlannerPlugin;
+
+impl Plugin for PlannerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                PlannerPlugin::start,
+                PlannerPlugin::poll.after(PlannerPlugin::start),
+                PlannerPlugin::follow.after(PlannerPlugin::poll),
+            ),
+        );
+    }
+}
+
+impl PlannerPlugin {
+    fn start(
+        descriptions: Res<Assets<RobotDescription>>,
+        mut q_robot: Query<(&RobotUr5, &RobotModel, &GlobalTransform, &mut MotionPlanner)>,
+        q_collider: Query<(&Collider, &GlobalTransform)>,
+        q_solid: SolidQuery,
+    ) {
+        for (robot, model, gt, mut planner) in q_robot.iter_mut() {
+            let Some(goal) = planner.request.take() else {
+                continue;
+            };
+            let Some(description) = descriptions.get(&model.0) else {
+                planner.state = PlanState::Failed("the robot model is not loaded".to_string());
+                continue;
+            };
+            let start = robot.joints().map(f64::to_degrees);
+            let base = gt.compute_transform();
+            let tip = base * description.link_transforms(&robot.joints())[description.tip()];
+            let tip_inverse = tip.compute_matrix().inverse();
+
+            let mut links = Vec::new();
+            let mut tool = Vec::new();
+            let mut others = Vec::new();
+            for (collider, gt) in q_collider.iter() {
+                if collider.capsules.is_empty() {
+                    continue;
+                }
+                if collider.robot != robot.id {
+                    let tf = gt.compute_transform();
+                    let capsules = collider.capsules.iter().map(|c| c.transformed(&tf));
+                    others.push((collider.clone(), capsules.collect()));
+                } else if collider.link.is_some() {
+                    links.push(collider.clone());
+                } else {
+                    let tf = Transform::from_matrix(tip_inverse * gt.compute_matrix());
+                    tool.push((collider.clone(), tf));
+                }
+            }
+            let scene = PlanningScene {
+                description: description.clone(),
+                base,
+                limits: robot.limits,
+                links,
+                tool,
+                others,
+                solids: world_solids(&q_solid),
+            };
+            let goal = robot.clamp_deg(goal);
+            planner.state = PlanState::Planning(Box::new(Planning::start(scene, start, goal)));
+        }
+    }
+
+    fn poll(mut q_planner: Query<&mut MotionPlanner>) {
+        for mut planner in q_planner.iter_mut() {
+            let PlanState::Planning(planning) = &mut planner.state else {
+                continue;
+            };
+            match planning.poll() {
+                Some(Ok(path)) => {
+                    let mut path: VecDeque<_> = path.into();
+                    // the start is where the robot is
+                    path.pop_front();
+                    planner.state = PlanState::Following {
+                        path,
+                        target: [f64::NAN; 6],
+                    };
+                }
+                Some(Err(e)) => planner.state = PlanState::Failed(e.to_string()),
+                None => {}
+            }
+        }
+    }
+
+    // sets the next waypoint once the robot is at the current one
+    fn follow(mut q_robot: Query<(&RobotUr5, &mut JointsPos, &mut MotionPlanner)>) {
+        for (robot, mut joints, mut planner) in q_robot.iter_mut() {
+            let PlanState::Following { path, target } = &mut planner.state else {
+                continue;
+            };
+            let started = !target[0].is_nan();
+            if started && joints.0 != *target {
+                // moved by someone else
+                planner.state = PlanState::Idle;
+                continue;
+            }
+            let current = robot.joints().map(f64::to_degrees);
+            if started && (0..6).any(|i| (current[i] - target[i]).abs() > REACHED) {
+                continue;
+            }
+            match path.pop_front() {
+                Some(next) => {
+                    *target = next;
+                    joints.0 = next;
+                }
+                None => planner.state = PlanState::Idle,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot_ur5::{JOINTS_POS, JOINT_LIMITS};
+
+    const UR5: &str = include_str!("../assets/ur5/ur5.urdf");
+
+    // start, goal: deg
+    // Out: the path from start to goal, deg
+    fn plan(
+        scene: &PlanningScene,
+        start: [f64; 6],
+        goal: [f64; 6],
+    ) -> Result<Vec<[f64; 6]>, PlanError> {
+        let mut rrt = RrtConnect::new(scene, start, goal)?;
+        loop {
+            if let Some(result) = rrt.step(scene) {
+                return result;
+            }
+        }
+    }
+
+    fn colliders(description: &RobotDescription, id: u64) -> Vec<Collider> {
+        description
+            .links
+            .iter()
+            .enumerate()
+            .map(|(i, link)| Collider {
+                robot: id,
+                part: link.name.clone(),
+                link: Some(i),
+                adjacent: description
+                    .joints
+                    .iter()
+                    .filter(|j| j.parent == i || j.child == i)
+                    .map(|j| if j.parent == i { j.child } else { j.parent })
+                    .collect(),
+                tip: i == description.tip(),
+                grounded: i == description.root,
+                capsules: link
+                    .collisions
+                    .iter()
+                    .map(|c| {
+                        let (a, b) = c.segment();
+                        Capsule {
+                            a,
+                            b,
+                            radius: c.radius,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn base(x: f32) -> Transform {
+        Transform::from_xyz(x, 0.0, 0.0)
+            .with_rotation(Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2))
+    }
+
+    // robot0 at x = -0.5 next to robot1 at x = 0.5, standing at `other`
+    fn scene(other: [f64; 6]) -> PlanningScene {
+        let description = RobotDescription::parse(UR5, "ur5").unwrap();
+        let tfs = description.link_transforms(&other.map(f64::to_radians));
+        let others = colliders(&description, 1)
+            .into_iter()
+            .map(|c| {
+                let tf = base(0.5) * tfs[c.link.unwrap()];
+                let capsules = c.capsules.iter().map(|x| x.transformed(&tf)).collect();
+                (c, capsules)
+            })
+            .collect();
+        PlanningScene {
+            links: colliders(&description, 0),
+            description,
+            base: base(-0.5),
+            limits: JOINT_LIMITS,
+            tool: Vec::new(),
+            others,
+            solids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn path_avoids_the_other_robot() {
+        // robot1 reaches up towards robot0, which swings its arm past it
+        let scene = scene([0.0, -45.0, 0.0, -90.0, -90.0, 0.0]);
+        let start = [90.0, -60.0, 60.0, -90.0, -90.0, 0.0];
+        let goal = [270.0, -60.0, 60.0, -90.0, -90.0, 0.0];
+        assert!(scene.collision(&start).is_none());
+        assert!(!scene.edge_free(&start, &goal));
+
+        let path = plan(&scene, start, goal).unwrap();
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&goal));
+        for edge in path.windows(2) {
+            assert!(scene.edge_free(&edge[0], &edge[1]));
+        }
+    }
+
+    #[test]
+    fn blocked_goal_is_reported() {
+        let scene = scene(JOINTS_POS);
+        let start = JOINTS_POS;
+        let mut goal = JOINTS_POS;
+        goal[1] = 0.0; // the arm down through the floor
+        assert!(matches!(
+            plan(&scene, start, goal),
+            Err(PlanError::GoalInCollision(_))
+        ));
+        assert_eq!(plan(&scene, start, start), Ok(vec![start, start]));
+    }
+}
@@ -0,0 +1,772 @@
+use bevy::prelude::*;
+use bevy_egui::egui;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    end_effector::EndEffector,
+    motion::{Blend, Motion, MotionError},
+    robot_ur5::{IkError, RobotUr5},
+    tool_changer::{ToolChange, ToolChangeError},
+};
+
+const LINEAR_VELOCITY: f64 = 250.0; // mm/s, at 100% speed
+const LINEAR_ACCELERATION: f64 = 1000.0; // mm/s^2
+const ANGULAR_VELOCITY: f64 = 90.0; // deg/s, at 100% speed
+const ANGULAR_ACCELERATION: f64 = 360.0; // deg/s^2
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MoveKind {
+    MoveJ, // joint interpolated
+    MoveL, // tcp moves on a straight line
+}
+
+impl MoveKind {
+    const ALL: [MoveKind; 2] = [MoveKind::MoveJ, MoveKind::MoveL];
+
+    fn name(&self) -> &'static str {
+        match self {
+            MoveKind::MoveJ => "MoveJ",
+            MoveKind::MoveL => "MoveL",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Waypoint {
+    pub name: String,
+    pub joints: [f64; 6], // deg
+    // gripper action when the move starts, None keeps the gripper as it is
+    #[serde(default)]
+    pub fingers: Option<[f32; 2]>, // range [0.0, 100.0]
+    pub kind: MoveKind,
+    #[serde(default = "default_speed")]
+    pub speed: f64, // % of the max speed, range [1.0, 100.0]
+    #[serde(default)]
+    pub blend: f64, // mm, the next move starts once the tcp is this close and rounds the corner
+    #[serde(default)]
+    pub wait: f64, // s, pause after the waypoint is reached
+    // tool change once the waypoint is reached, before the pause
+    #[serde(default)]
+    pub tool: Option<ToolChange>,
+}
+
+fn default_speed() -> f64 {
+    50.0
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Program {
+    pub waypoints: Vec<Waypoint>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct NamedPose {
+    pub name: String,
+    pub joints: [f64; 6], // deg
+    #[serde(default)]
+    pub fingers: [f32; 2], // range [0.0, 100.0]
+}
+
+// requests from the editor that reach outside the player
+pub enum ProgramAction {
+    Save,
+    Load,
+    GoTo([f64; 6], [f32; 2]), // ( joints deg, fingers )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PlayState {
+    #[default]
+    Stopped,
+    Running,
+    Paused,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WaypointError {
+    Motion(MotionError),
+    ToolChange(ToolChangeError),
+}
+
+impl std::fmt::Display for WaypointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaypointError::Motion(e) => write!(f, "{}", e),
+            WaypointError::ToolChange(e) => write!(f, "tool change: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ProgramError {
+    pub waypoint: usize,
+    pub error: WaypointError,
+}
+
+impl std::fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "waypoint {}: {}", self.waypoint + 1, self.error)
+    }
+}
+
+enum Segment {
+    Move(Box<Motion>),
+    // requested: handed to the tool changer, waiting for the result
+    ToolChange(ToolChange, bool),
+    Wait(f64), // s left
+}
+
+#[derive(Component)]
+pub struct ProgramPlayer {
+    pub program: Program,
+    pub poses: Vec<NamedPose>,
+    pub looping: bool,
+    pub path: String, // program file, the download name on wasm
+    pub file_error: Option<String>,
+    state: PlayState,
+    current: usize,
+    single_step: bool,
+    segment: Option<Segment>,
+    blend: Option<Blend>, // the move before, running out under the current one
+    error: Option<ProgramError>,
+}
+
+impl Default for ProgramPlayer {
+    fn default() -> Self {
+        ProgramPlayer {
+            program: Program::default(),
+            poses: Vec::new(),
+            looping: false,
+            path: "program.ron".to_string(),
+            file_error: None,
+            state: PlayState::Stopped,
+            current: 0,
+            single_step: false,
+            segment: None,
+            blend: None,
+            error: None,
+        }
+    }
+}
+
+enum Edit {
+    Up(usize),
+    Down(usize),
+    Remove(usize),
+}
+
+impl ProgramPlayer {
+    pub fn is_running(&self) -> bool {
+        self.state == PlayState::Running
+    }
+
+    // index of the waypoint being approached
+    pub fn current(&self) -> Option<usize> {
+        (self.state != PlayState::Stopped).then_some(self.current)
+    }
+
+    pub fn play(&mut self) {
+        self.start(false);
+    }
+
+    // run to the next waypoint and pause
+    pub fn step_once(&mut self) {
+        self.start(true);
+    }
+
+    pub fn pause(&mut self) {
+        if self.state == PlayState::Running {
+            self.state = PlayState::Paused;
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.state = PlayState::Stopped;
+        self.current = 0;
+        self.segment = None;
+        self.blend = None;
+    }
+
+    fn start(&mut self, single_step: bool) {
+        if self.state == PlayState::Stopped {
+            self.current = 0;
+            self.segment = None;
+            self.blend = None;
+        }
+        self.state = PlayState::Running;
+        self.single_step = single_step;
+        self.error = None;
+    }
+
+    // joints: deg
+    // fingers: range [0.0, 100.0]
+    pub fn capture(&mut self, joints: &[f64; 6], fingers: &[f32; 2]) {
+        let name = unique_name("P", self.program.waypoints.iter().map(|w| &w.name));
+        self.program.waypoints.push(Waypoint {
+            name,
+            joints: *joints,
+            fingers: Some(*fingers),
+            kind: MoveKind::MoveJ,
+            speed: default_speed(),
+            blend: 0.0,
+            wait: 0.0,
+            tool: None,
+        });
+    }
+
+    // replace the program and poses, e.g. after loading a file
+    pub fn load(&mut self, program: Program, poses: Vec<NamedPose>) {
+        self.stop();
+        self.program = program;
+        self.poses = poses;
+        self.error = None;
+        self.file_error = None;
+    }
+
+    // fingers target of the waypoint being approached
+    pub fn fingers(&self) -> Option<[f32; 2]> {
+        if self.state == PlayState::Stopped {
+            return None;
+        }
+        self.program
+            .waypoints
+            .get(self.current)
+            .and_then(|w| w.fingers)
+    }
+
+    // the tool change of the reached waypoint, handed out once, the frame after the robot stopped
+    // so the tool and the slots are seen where the robot left them
+    pub fn take_tool_change(&mut self) -> Option<ToolChange> {
+        if self.state != PlayState::Running {
+            return None;
+        }
+        match self.segment.as_mut() {
+            Some(Segment::ToolChange(change, requested)) if !*requested => {
+                *requested = true;
+                Some(*change)
+            }
+            _ => None,
+        }
+    }
+
+    // result of the tool change handed out by take_tool_change
+    pub fn tool_changed(&mut self, result: Result<(), ToolChangeError>) {
+        if !matches!(self.segment, Some(Segment::ToolChange(_, true))) {
+            return;
+        }
+        let wait = self
+            .program
+            .waypoints
+            .get(self.current)
+            .map_or(0.0, |w| w.wait);
+        match result {
+            Ok(()) if wait > 0.0 => self.segment = Some(Segment::Wait(wait)),
+            Ok(()) => self.advance(),
+            Err(e) => {
+                self.fail(WaypointError::ToolChange(e));
+            }
+        }
+    }
+
+    // joints: current joints target, deg
+    // fingers: current fingers target, range [0.0, 100.0]
+    // jaws: the mounted gripper, the grips are shown as its opening
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        id: u64,
+        joints: &[f64; 6],
+        fingers: &[f32; 2],
+        jaws: Option<&dyn EndEffector>,
+    ) -> Option<ProgramAction> {
+        let mut action = None;
+        ui.horizontal(|ui| {
+            #[cfg(not(target_family = "wasm"))]
+            ui.add(egui::TextEdit::singleline(&mut self.path).desired_width(120.0));
+            if ui.button("save").clicked() {
+                action = Some(ProgramAction::Save);
+            }
+            if ui.button("load").clicked() {
+                action = Some(ProgramAction::Load);
+            }
+        });
+        if let Some(error) = &self.file_error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+
+        ui.collapsing("Poses", |ui| {
+            if ui.button("save pose").clicked() {
+                let name = unique_name("pose", self.poses.iter().map(|p| &p.name));
+                self.poses.push(NamedPose {
+                    name,
+                    joints: *joints,
+                    fingers: *fingers,
+                });
+            }
+            let mut remove = None;
+            egui::Grid::new(("poses", id))
+                .num_columns(4)
+                .show(ui, |ui| {
+                    for (i, pose) in self.poses.iter_mut().enumerate() {
+                        ui.add(egui::TextEdit::singleline(&mut pose.name).desired_width(60.0));
+                        if ui.button("go").clicked() {
+                            action = Some(ProgramAction::GoTo(pose.joints, pose.fingers));
+                        }
+                        if ui.button("add").on_hover_text("add as waypoint").clicked() {
+                            self.program.waypoints.push(Waypoint {
+                                name: pose.name.clone(),
+                                joints: pose.joints,
+                                fingers: Some(pose.fingers),
+                                kind: MoveKind::MoveJ,
+                                speed: default_speed(),
+                                blend: 0.0,
+                                wait: 0.0,
+                                tool: None,
+                            });
+                        }
+                        if ui.button("del").clicked() {
+                            remove = Some(i);
+                        }
+                        ui.end_row();
+                    }
+                });
+            if let Some(i) = remove {
+                self.poses.remove(i);
+            }
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("capture").clicked() {
+                self.capture(joints, fingers);
+            }
+            ui.separator();
+            if ui.button("play").clicked() {
+                self.play();
+            }
+            if ui.button("step").clicked() {
+                self.step_once();
+            }
+            if ui.button("pause").clicked() {
+                self.pause();
+            }
+            if ui.button("stop").clicked() {
+                self.stop();
+            }
+            ui.checkbox(&mut self.looping, "loop");
+        });
+
+        let current = self.current();
+        let mut edit = None;
+        let count = self.program.waypoints.len();
+        egui::Grid::new(("program", id))
+            .num_columns(11)
+            .show(ui, |ui| {
+                for (i, waypoint) in self.program.waypoints.iter_mut().enumerate() {
+                    ui.label(if current == Some(i) { ">" } else { "" });
+                    ui.add(egui::TextEdit::singleline(&mut waypoint.name).desired_width(40.0));
+                    egui::ComboBox::from_id_source(("move_kind", id, i))
+                        .width(60.0)
+                        .selected_text(waypoint.kind.name())
+                        .show_ui(ui, |ui| {
+                            for kind in MoveKind::ALL {
+                                ui.selectable_value(&mut waypoint.kind, kind, kind.name());
+                            }
+                        });
+                    ui.add(
+                        egui::DragValue::new(&mut waypoint.speed)
+                            .clamp_range(1.0..=100.0)
+                            .suffix("%"),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut waypoint.blend)
+                            .clamp_range(0.0..=200.0)
+                            .suffix("mm"),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut waypoint.wait)
+                            .clamp_range(0.0..=60.0)
+                            .speed(0.1)
+                            .suffix("s"),
+                    );
+                    let mut grip = waypoint.fingers.is_some();
+                    if ui.checkbox(&mut grip, "grip").changed() {
+                        waypoint.fingers = grip.then_some(*fingers);
+                    }
+                    if let (Some(fingers), Some(model)) = (waypoint.fingers.as_mut(), jaws) {
+                        // opening of the synchronized jaws
+                        let mut width = model.fingers_to_width(fingers);
+                        if ui
+                            .add(
+                                egui::DragValue::new(&mut width)
+                                    .clamp_range(model.width_range())
+                                    .suffix("mm"),
+                            )
+                            .changed()
+                        {
+                            *fingers = model.width_to_fingers(width);
+                        }
+                    } else {
+                        ui.label("");
+                    }
+                    egui::ComboBox::from_id_source(("tool_change", id, i))
+                        .width(60.0)
+                        .selected_text(waypoint.tool.map_or("", |c| c.name()))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut waypoint.tool, None, "none");
+                            for change in ToolChange::ALL {
+                                ui.selectable_value(
+                                    &mut waypoint.tool,
+                                    Some(change),
+                                    change.name(),
+                                );
+                            }
+                        })
+                        .response
+                        .on_hover_text("tool change once reached");
+                    if ui.add_enabled(i > 0, egui::Button::new("up")).clicked() {
+                        edit = Some(Edit::Up(i));
+                    }
+                    if ui
+                        .add_enabled(i + 1 < count, egui::Button::new("down"))
+                        .clicked()
+                    {
+                        edit = Some(Edit::Down(i));
+                    }
+                    if ui.button("del").clicked() {
+                        edit = Some(Edit::Remove(i));
+                    }
+                    ui.end_row();
+                }
+            });
+
+        // the program changes under the player, start over
+        if let Some(edit) = edit {
+            let waypoints = &mut self.program.waypoints;
+            match edit {
+                Edit::Up(i) => waypoints.swap(i - 1, i),
+                Edit::Down(i) => waypoints.swap(i, i + 1),
+                Edit::Remove(i) => {
+                    waypoints.remove(i);
+                }
+            }
+            self.stop();
+        }
+
+        if let Some(error) = self.error {
+            ui.colored_label(egui::Color32::RED, error.to_string());
+        }
+        action
+    }
+
+    // dt: s
+    // Out: the next joints target, deg
+    pub fn step(&mut self, robot: &RobotUr5, dt: f64) -> Option<[f64; 6]> {
+        if self.state != PlayState::Running {
+            return None;
+        }
+        let Some(waypoint) = self.program.waypoints.get(self.current).cloned() else {
+            self.stop();
+            return None;
+        };
+
+        match self.segment.as_mut() {
+            Some(Segment::Wait(left)) => {
+                *left -= dt;
+                if *left <= 0.0 {
+                    self.advance();
+                }
+                return None;
+            }
+            Some(Segment::ToolChange(..)) => return None,
+            _ => {}
+        }
+
+        let segment = match self.segment.as_mut() {
+            Some(Segment::Move(motion)) => motion,
+            _ => {
+                // a blended move starts where the one before ends
+                let mut from = robot.clone();
+                if let Some(blend) = &self.blend {
+                    from.set_deg(blend.end());
+                }
+                match ProgramPlayer::start_motion(&from, &waypoint) {
+                    Ok(motion) => match self.segment.insert(Segment::Move(Box::new(motion))) {
+                        Segment::Move(motion) => motion,
+                        _ => unreachable!(),
+                    },
+                    Err(e) => return self.fail(WaypointError::Motion(e.into())),
+                }
+            }
+        };
+        let (mut joints, remaining, mut done) = match segment.step(robot, dt) {
+            Ok(step) => step,
+            Err(e) => return self.fail(WaypointError::Motion(e)),
+        };
+        if let Some(blend) = self.blend.as_mut() {
+            match blend.step(robot, joints, dt) {
+                Ok((blended, blend_done)) => {
+                    joints = blended;
+                    if blend_done {
+                        self.blend = None;
+                    } else {
+                        done = false;
+                    }
+                }
+                Err(e) => return self.fail(WaypointError::Motion(e)),
+            }
+        }
+
+        let last = self.current + 1 == self.program.waypoints.len();
+        let blend = !self.single_step
+            && (!last || self.looping)
+            && waypoint.wait <= 0.0
+            && waypoint.tool.is_none()
+            && self.blend.is_none()
+            && remaining <= waypoint.blend;
+        if let (true, Some(change)) = (done, waypoint.tool) {
+            self.segment = Some(Segment::ToolChange(change, false));
+        } else if done && waypoint.wait > 0.0 {
+            self.segment = Some(Segment::Wait(waypoint.wait));
+        } else if done {
+            self.advance();
+        } else if blend {
+            if let Some(Segment::Move(motion)) = self.segment.take() {
+                match Blend::new(*motion, robot) {
+                    Ok(blend) => self.blend = Some(blend),
+                    Err(e) => return self.fail(WaypointError::Motion(e)),
+                }
+            }
+            self.advance();
+        }
+        Some(joints)
+    }
+
+    fn advance(&mut self) {
+        self.segment = None;
+        self.current += 1;
+        if self.current >= self.program.waypoints.len() {
+            self.current = 0;
+            if !self.looping {
+                self.state = PlayState::Stopped;
+            }
+        }
+        if self.single_step && self.state == PlayState::Running {
+            self.state = PlayState::Paused;
+        }
+    }
+
+    fn fail(&mut self, error: WaypointError) -> Option<[f64; 6]> {
+        self.error = Some(ProgramError {
+            waypoint: self.current,
+            error,
+        });
+        self.stop();
+        None
+    }
+
+    fn start_motion(robot: &RobotUr5, waypoint: &Waypoint) -> Result<Motion, IkError> {
+        let scale = waypoint.speed.clamp(1.0, 100.0) / 100.0;
+        match waypoint.kind {
+            MoveKind::MoveJ => {
+                let velocity = robot.limits.map(|l| l.velocity * scale);
+                let acceleration = robot.limits.map(|l| l.acceleration * scale);
+                Ok(Motion::joint(
+                    robot,
+                    waypoint.joints,
+                    &velocity,
+                    &acceleration,
+                ))
+            }
+            MoveKind::MoveL => {
+                let target = robot.clamp_deg(waypoint.joints).map(f64::to_radians);
+                Motion::linear(
+                    robot,
+                    robot.flange_at(target) * robot.tool,
+                    [LINEAR_VELOCITY * scale, ANGULAR_VELOCITY * scale],
+                    [LINEAR_ACCELERATION * scale, ANGULAR_ACCELERATION * scale],
+                )
+            }
+        }
+    }
+}
+
+// prefix followed by the first number not taken
+fn unique_name<'a>(prefix: &str, names: impl Iterator<Item = &'a String> + Clone) -> String {
+    let mut n = 1;
+    while names
+        .clone()
+        .any(|name| *name == format!("{}{}", prefix, n))
+    {
+        n += 1;
+    }
+    format!("{}{}", prefix, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot_ur5::JOINTS_POS;
+    use nalgebra::Isometry3;
+
+    fn waypoint(joints: [f64; 6], kind: MoveKind) -> Waypoint {
+        Waypoint {
+            name: String::new(),
+            joints,
+            fingers: None,
+            kind,
+            speed: 100.0,
+            blend: 0.0,
+            wait: 0.0,
+            tool: None,
+        }
+    }
+
+    // the robot follows the streamed joints exactly
+    fn run(player: &mut ProgramPlayer, robot: &mut RobotUr5, mut each: impl FnMut(&RobotUr5)) {
+        for _ in 0..100_000 {
+            if !player.is_running() {
+                return;
+            }
+            if let Some(joints) = player.step(robot, 0.01) {
+                robot.set_deg(joints);
+                each(robot);
+            }
+        }
+        panic!("program did not finish");
+    }
+
+    #[test]
+    fn movej_reaches_every_waypoint() {
+        let mut robot = RobotUr5::new(0, JOINTS_POS.map(f64::to_radians));
+        let a = [0.0, -90.0, 45.0, -45.0, -90.0, 30.0];
+        let mut player = ProgramPlayer::default();
+        player.program.waypoints = vec![
+            waypoint(a, MoveKind::MoveJ),
+            waypoint(JOINTS_POS, MoveKind::MoveJ),
+        ];
+
+        player.step_once();
+        run(&mut player, &mut robot, |_| {});
+        assert_eq!(player.current(), Some(1));
+        for (q, t) in robot.joints().iter().zip(a.iter()) {
+            assert!((q.to_degrees() - t).abs() < 1e-6);
+        }
+
+        player.play();
+        run(&mut player, &mut robot, |_| {});
+        assert_eq!(player.current(), None);
+        for (q, t) in robot.joints().iter().zip(JOINTS_POS.iter()) {
+            assert!((q.to_degrees() - t).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn tool_change_waits_for_the_changer() {
+        let mut robot = RobotUr5::new(0, JOINTS_POS.map(f64::to_radians));
+        let a = [0.0, -90.0, 45.0, -45.0, -90.0, 30.0];
+        let mut player = ProgramPlayer::default();
+        player.program.waypoints = vec![
+            Waypoint {
+                tool: Some(ToolChange::Detach),
+                blend: 50.0,
+                ..waypoint(a, MoveKind::MoveJ)
+            },
+            waypoint(JOINTS_POS, MoveKind::MoveJ),
+        ];
+
+        player.play();
+        while player.take_tool_change().is_none() {
+            assert_eq!(player.current(), Some(0));
+            if let Some(joints) = player.step(&robot, 0.01) {
+                robot.set_deg(joints);
+            }
+        }
+        // not blended, the robot stands at the waypoint until the change is done
+        for (q, t) in robot.joints().iter().zip(a.iter()) {
+            assert!((q.to_degrees() - t).abs() < 1e-6);
+        }
+        assert_eq!(player.step(&robot, 0.01), None);
+        assert_eq!(player.take_tool_change(), None);
+        player.tool_changed(Ok(()));
+        assert_eq!(player.current(), Some(1));
+
+        player.stop();
+        player.play();
+        while player.take_tool_change().is_none() {
+            if let Some(joints) = player.step(&robot, 0.01) {
+                robot.set_deg(joints);
+            }
+        }
+        player.tool_changed(Err(ToolChangeError::NoFreeSlot));
+        assert_eq!(player.current(), None);
+        assert_eq!(
+            player.error.map(|e| e.error),
+            Some(WaypointError::ToolChange(ToolChangeError::NoFreeSlot))
+        );
+    }
+
+    #[test]
+    fn movel_keeps_tcp_on_a_line() {
+        let mut robot = RobotUr5::new(0, JOINTS_POS.map(f64::to_radians));
+        robot.tool = Isometry3::translation(0.0, 0.0, 0.15);
+        let start = robot.tcp_pose();
+        let end = Isometry3::translation(0.1, -0.05, 0.08) * start;
+        let joints = robot.ik_nearest(&(end * robot.tool.inverse())).unwrap();
+        let mut player = ProgramPlayer::default();
+        player.program.waypoints = vec![waypoint(joints.map(f64::to_degrees), MoveKind::MoveL)];
+
+        let a = start.translation.vector;
+        let d = (end.translation.vector - a).normalize();
+        player.play();
+        run(&mut player, &mut robot, |robot| {
+            let p = robot.tcp_pose().translation.vector - a;
+            assert!((p - d * p.dot(&d)).norm() < 1e-6);
+        });
+        assert!((robot.tcp_pose().translation.vector - end.translation.vector).norm() < 1e-6);
+    }
+
+    #[test]
+    fn blend_keeps_the_velocity() {
+        let mut robot = RobotUr5::new(0, JOINTS_POS.map(f64::to_radians));
+        let a = [60.0, -100.0, 70.0, -60.0, -90.0, 0.0];
+        let pose = Isometry3::translation(0.0, 0.0, 0.1) * robot.flange_at(a.map(f64::to_radians));
+        let b = robot.ik_near(&pose, &a.map(f64::to_radians)).unwrap();
+        let mut player = ProgramPlayer::default();
+        player.program.waypoints = vec![
+            Waypoint {
+                blend: 50.0,
+                ..waypoint(a, MoveKind::MoveJ)
+            },
+            Waypoint {
+                blend: 30.0,
+                ..waypoint(b.map(f64::to_degrees), MoveKind::MoveL)
+            },
+            waypoint(JOINTS_POS, MoveKind::MoveJ),
+        ];
+
+        let dt = 0.01;
+        let mut points = vec![JOINTS_POS];
+        player.play();
+        run(&mut player, &mut robot, |robot| {
+            points.push(robot.joints().map(f64::to_degrees))
+        });
+        for (q, t) in robot.joints().iter().zip(JOINTS_POS.iter()) {
+            assert!((q.to_degrees() - t).abs() < 1e-6);
+        }
+        // the corners are cut
+        for corner in [a, b.map(f64::to_degrees)] {
+            assert!(points.iter().all(|p| p
+                .iter()
+                .zip(corner.iter())
+                .any(|(p, c)| (p - c).abs() > 1e-3)));
+        }
+        // the velocity changes no faster than both moves accelerate together
+        for w in points.windows(3) {
+            for (i, limit) in robot.limits.iter().enumerate() {
+                let v0 = (w[1][i] - w[0][i]) / dt;
+                let v1 = (w[2][i] - w[1][i]) / dt;
+                assert!(((v1 - v0) / dt).abs() <= 2.0 * limit.acceleration * 1.01);
+            }
+        }
+    }
+}
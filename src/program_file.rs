@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+
+use crate::program::{NamedPose, Program};
+
+// bumped on every incompatible change of ProgramFile
+pub const FORMAT_VERSION: u32 = 1;
+
+// Named poses and a robot program, stored as RON
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ProgramFile {
+    pub version: u32,
+    #[serde(default)]
+    pub poses: Vec<NamedPose>,
+    #[serde(default)]
+    pub program: Program,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgramFileError {
+    Io(String),
+    Parse(String),
+    Version(u32),
+    // ( item, problem )
+    Invalid(String, String),
+}
+
+impl std::fmt::Display for ProgramFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgramFileError::Io(e) => write!(f, "{}", e),
+            ProgramFileError::Parse(e) => write!(f, "invalid file: {}", e),
+            ProgramFileError::Version(v) => {
+                write!(f, "unsupported version {}, expected {}", v, FORMAT_VERSION)
+            }
+            ProgramFileError::Invalid(item, problem) => write!(f, "{}: {}", item, problem),
+        }
+    }
+}
+
+impl std::error::Error for ProgramFileError {}
+
+impl ProgramFile {
+    pub fn new(program: Program, poses: Vec<NamedPose>) -> Self {
+        ProgramFile {
+            version: FORMAT_VERSION,
+            poses,
+            program,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ProgramFileError> {
+        let file: ProgramFile =
+            ron::from_str(text).map_err(|e| ProgramFileError::Parse(e.to_string()))?;
+        file.validate()?;
+        Ok(file)
+    }
+
+    pub fn to_text(&self) -> String {
+        let config = ron::ser::PrettyConfig::new().struct_names(false);
+        ron::ser::to_string_pretty(self, config).unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<(), ProgramFileError> {
+        if self.version != FORMAT_VERSION {
+            return Err(ProgramFileError::Version(self.version));
+        }
+        let invalid =
+            |item: String, problem: &str| Err(ProgramFileError::Invalid(item, problem.to_string()));
+        for (i, pose) in self.poses.iter().enumerate() {
+            let item = format!("pose {} \"{}\"", i + 1, pose.name);
+            if pose.name.is_empty() {
+                return invalid(item, "empty name");
+            }
+            if self.poses[..i].iter().any(|p| p.name == pose.name) {
+                return invalid(item, "duplicate name");
+            }
+            if !pose.joints.iter().all(|j| j.is_finite()) {
+                return invalid(item, "joints must be finite");
+            }
+            if !fingers_valid(&pose.fingers) {
+                return invalid(item, "fingers must be in [0, 100]");
+            }
+        }
+        for (i, waypoint) in self.program.waypoints.iter().enumerate() {
+            let item = format!("waypoint {} \"{}\"", i + 1, waypoint.name);
+            if !waypoint.joints.iter().all(|j| j.is_finite()) {
+                return invalid(item, "joints must be finite");
+            }
+            if !waypoint.fingers.as_ref().is_none_or(fingers_valid) {
+                return invalid(item, "fingers must be in [0, 100]");
+            }
+            if !(1.0..=100.0).contains(&waypoint.speed) {
+                return invalid(item, "speed must be in [1, 100]");
+            }
+            if waypoint.blend.is_nan() || waypoint.blend < 0.0 {
+                return invalid(item, "blend must not be negative");
+            }
+            if waypoint.wait.is_nan() || waypoint.wait < 0.0 {
+                return invalid(item, "wait must not be negative");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fingers_valid(fingers: &[f32; 2]) -> bool {
+    fingers.iter().all(|f| (0.0..=100.0).contains(f))
+}
+
+#[cfg(not(target_family = "wasm"))]
+pub fn load(path: &str) -> Result<ProgramFile, ProgramFileError> {
+    let text = std::fs::read_to_string(path).map_err(|e| ProgramFileError::Io(e.to_string()))?;
+    ProgramFile::parse(&text)
+}
+
+#[cfg(not(target_family = "wasm"))]
+pub fn save(path: &str, file: &ProgramFile) -> Result<(), ProgramFileError> {
+    std::fs::write(path, file.to_text()).map_err(|e| ProgramFileError::Io(e.to_string()))
+}
+
+// let the browser download the text as a file
+#[cfg(target_family = "wasm")]
+pub fn download(name: &str, text: &str) -> Result<(), ProgramFileError> {
+    use wasm_bindgen::{closure::Closure, JsCast};
+    let err = |e: wasm_bindgen::JsValue| ProgramFileError::Io(format!("{:?}", e));
+
+    let parts = js_sys::Array::of1(&wasm_bindgen::JsValue::from_str(text));
+    let blob = web_sys::Blob::new_with_str_sequence(&parts).map_err(err)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob).map_err(err)?;
+    let window = web_sys::window().unwrap();
+    let document = window.document().unwrap();
+    let a: web_sys::HtmlAnchorElement = document
+        .create_element("a")
+        .map_err(err)?
+        .dyn_into()
+        .unwrap();
+    a.set_href(&url);
+    a.set_download(name);
+    a.click();
+    // revoked once the download started, firefox cancels it otherwise
+    let revoke = Closure::once_into_js(move || {
+        let _ = web_sys::Url::revoke_object_url(&url);
+    });
+    window
+        .set_timeout_with_callback_and_timeout_and_arguments_0(revoke.unchecked_ref(), 0)
+        .map_err(err)?;
+    Ok(())
+}
+
+// open the browser file picker, the text of the chosen file is sent with the robot id
+#[cfg(target_family = "wasm")]
+pub fn pick(id: u64, sender: flume::Sender<(u64, String)>) -> Result<(), ProgramFileError> {
+    use wasm_bindgen::{closure::Closure, JsCast};
+    let err = |e: wasm_bindgen::JsValue| ProgramFileError::Io(format!("{:?}", e));
+
+    let document = web_sys::window().unwrap().document().unwrap();
+    let input: web_sys::HtmlInputElement = document
+        .create_element("input")
+        .map_err(err)?
+        .dyn_into()
+        .unwrap();
+    input.set_type("file");
+    input.set_accept(".ron");
+
+    let on_change = Closure::once(move |event: web_sys::Event| {
+        let input: web_sys::HtmlInputElement = event.target().unwrap().dyn_into().unwrap();
+        let Some(file) = input.files().and_then(|files| files.get(0)) else {
+            return;
+        };
+        let reader = web_sys::FileReader::new().unwrap();
+        let on_load = Closure::once(move |event: web_sys::Event| {
+            let reader: web_sys::FileReader = event.target().unwrap().dyn_into().unwrap();
+            if let Some(text) = reader.result().ok().and_then(|r| r.as_string()) {
+                let _ = sender.send((id, text));
+            }
+        });
+        reader.set_onload(Some(on_load.as_ref().unchecked_ref()));
+        on_load.forget();
+        let _ = reader.read_as_text(&file);
+    });
+    input.set_onchange(Some(on_change.as_ref().unchecked_ref()));
+    on_change.forget();
+    input.click();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::{MoveKind, Waypoint};
+
+    fn file() -> ProgramFile {
+        ProgramFile::new(
+            Program {
+                waypoints: vec![Waypoint {
+                    name: "pick".to_string(),
+                    joints: [90.0, -120.0, 90.0, -60.0, -90.0, 0.0],
+                    fingers: Some([100.0, 100.0]),
+                    kind: MoveKind::MoveL,
+                    speed: 30.0,
+                    blend: 5.0,
+                    wait: 0.5,
+                    tool: None,
+                }],
+            },
+            vec![NamedPose {
+                name: "home".to_string(),
+                joints: [90.0, -120.0, 90.0, -60.0, -90.0, 0.0],
+                fingers: [0.0, 0.0],
+            }],
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = file();
+        assert_eq!(ProgramFile::parse(&file.to_text()).unwrap(), file);
+    }
+
+    #[test]
+    fn validation_errors() {
+        let mut f = file();
+        f.version = FORMAT_VERSION + 1;
+        assert_eq!(
+            ProgramFile::parse(&f.to_text()),
+            Err(ProgramFileError::Version(FORMAT_VERSION + 1))
+        );
+
+        let mut f = file();
+        f.program.waypoints[0].speed = 0.0;
+        assert!(matches!(
+            ProgramFile::parse(&f.to_text()),
+            Err(ProgramFileError::Invalid(..))
+        ));
+
+        let mut f = file();
+        f.poses.push(f.poses[0].clone());
+        assert!(matches!(
+            ProgramFile::parse(&f.to_text()),
+            Err(ProgramFileError::Invalid(..))
+        ));
+
+        assert!(matches!(
+            ProgramFile::parse("(version: 1, program: (waypoints: [(name: \"a\")]))"),
+            Err(ProgramFileError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,357 @@
+use bevy::prelude::*;
+use nalgebra::Isometry3;
+use std::collections::BTreeMap;
+
+#[cfg(not(target_family = "wasm"))]
+use crate::rtde_mirror::RtdeMirror;
+#[cfg(not(feature = "physics"))]
+use crate::workpiece::WorkpiecePlugin;
+use crate::{
+    cartesian_jog::CartesianJog,
+    cell_layout::{RobotLayout, ToolKind, ToolLayout},
+    draw_trail::Trails,
+    end_effector::{Actuation, EndEffector, EndEffectorPlugin, Tool, Vacuum},
+    planner::MotionPlanner,
+    program::ProgramPlayer,
+    robot_ur5::{RobotPlugin, RobotUr5},
+    script_runner::ScriptRunner,
+    trajectory::Trajectory,
+};
+
+const FINGER_VELOCITY: f64 = 200.0; // %/s
+const FINGER_ACCELERATION: f64 = 2000.0; // %/s^2
+
+pub struct RobotCellPlugin;
+
+impl Plugin for RobotCellPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RobotCells>()
+            .add_event::<JointChanged>()
+            .add_event::<FingerChanged>()
+            .add_systems(
+                Update,
+                (
+                    RobotCellPlugin::update_joints_pos,
+                    RobotCellPlugin::update_finger_pos,
+                    RobotCellPlugin::update_label_pos,
+                ),
+            );
+    }
+}
+
+// joints target of a robot, deg
+#[derive(Component, Clone)]
+pub struct JointsPos(pub [f64; 6]);
+
+// fingers target of a gripper, range [0.0, 100.0]
+#[derive(Component, Clone, Default)]
+pub struct FingerPos(pub [f32; 2]);
+
+// motor current limit of a gripper, % of the maximum, range [20.0, 100.0]
+// it scales the finger velocity and acceleration; the scripted grasp holds a workpiece at any
+// current, only with the physics feature the jaws squeeze harder with it
+#[derive(Component, Clone, Copy)]
+pub struct GripCurrent(pub f32);
+
+impl Default for GripCurrent {
+    fn default() -> Self {
+        GripCurrent(100.0)
+    }
+}
+
+impl GripCurrent {
+    pub const MIN: f32 = 20.0;
+
+    pub fn set(&mut self, current: f32) {
+        self.0 = current.clamp(GripCurrent::MIN, 100.0);
+    }
+}
+
+// JointsPos is followed without the trajectory, set while the target is already a smooth path
+#[derive(Component, Default)]
+pub struct Streaming(pub bool);
+
+#[derive(Component)]
+struct JointsMotion(Trajectory<6>);
+
+#[derive(Component)]
+pub struct FingerMotion(Trajectory<2>);
+
+#[derive(Component)]
+struct Label;
+
+// a robot with its tool and label
+#[derive(Clone)]
+pub struct RobotCell {
+    pub robot: Entity,
+    pub wrist: Entity,
+    pub tool: Option<Entity>,
+    label: Entity,
+    pub layout: RobotLayout,
+}
+
+impl RobotCell {
+    pub fn end_effector(&self) -> Option<&'static dyn EndEffector> {
+        self.layout.tool.as_ref().map(|t| t.kind.end_effector())
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct RobotCells(pub BTreeMap<u64, RobotCell>);
+
+// joint: range [0, 5]
+#[derive(Event)]
+#[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+pub struct JointChanged {
+    pub robot: u64,
+    pub joint: usize,
+    pub angle: f64, // deg
+}
+
+// finger: range [0, 1]
+#[derive(Event)]
+#[cfg_attr(not(target_family = "wasm"), allow(dead_code))]
+pub struct FingerChanged {
+    pub robot: u64,
+    pub finger: usize,
+    pub pos: f32, // range [0.0, 100.0]
+}
+
+impl RobotCellPlugin {
+    // None if the id is taken
+    pub fn add_cell(world: &mut World, layout: RobotLayout) -> Option<RobotCell> {
+        let id = layout.id;
+        if world.resource::<RobotCells>().0.contains_key(&id) {
+            return None;
+        }
+        let home = layout.home;
+        let (robot, wrist) = RobotPlugin::add_robot(
+            world,
+            id,
+            Some(&layout.model),
+            Some(layout.base.transform()),
+            Some(home.map(f64::to_radians)),
+        );
+        world.entity_mut(robot).insert((
+            JointsPos(home),
+            JointsMotion(Trajectory::new(home)),
+            Streaming::default(),
+            CartesianJog::default(),
+            ProgramPlayer::default(),
+            ScriptRunner::default(),
+            MotionPlanner::default(),
+        ));
+        #[cfg(not(target_family = "wasm"))]
+        world.entity_mut(robot).insert(RtdeMirror::default());
+
+        let tool = layout
+            .tool
+            .as_ref()
+            .map(|tool| RobotCellPlugin::mount_tool(world, id, robot, wrist, tool));
+
+        let label = world
+            .spawn((
+                TextBundle {
+                    text: Text::from_section(
+                        format!("Robot{}", id),
+                        TextStyle {
+                            font_size: 24.0,
+                            ..default()
+                        },
+                    ),
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        ..default()
+                    },
+                    ..default()
+                },
+                Label,
+            ))
+            .id();
+
+        let cell = RobotCell {
+            robot,
+            wrist,
+            tool,
+            label,
+            layout,
+        };
+        world
+            .resource_mut::<RobotCells>()
+            .0
+            .insert(id, cell.clone());
+        Some(cell)
+    }
+
+    // robot: None for a parked tool
+    // Out: the tool with its actuation
+    pub fn spawn_tool(
+        world: &mut World,
+        robot: Option<u64>,
+        kind: ToolKind,
+        tf: Transform,
+    ) -> Entity {
+        let entity = EndEffectorPlugin::spawn_tool(world, robot, kind, tf);
+        match kind.end_effector().actuation() {
+            Actuation::Jaws => {
+                world.entity_mut(entity).insert((
+                    FingerPos([0.0, 0.0]),
+                    GripCurrent::default(),
+                    FingerMotion(Trajectory::new([0.0, 0.0])),
+                ));
+            }
+            Actuation::Suction => {
+                world.entity_mut(entity).insert(Vacuum::default());
+            }
+            Actuation::None => {}
+        }
+        entity
+    }
+
+    // Out: tcp relative to the flange
+    pub fn tcp(tool: &ToolLayout) -> Isometry3<f64> {
+        let offset = tool.kind.end_effector().tcp_offset() as f64;
+        tool.offset.isometry() * Isometry3::translation(0.0, 0.0, offset)
+    }
+
+    // Out: the tool, the tcp of the robot follows
+    fn mount_tool(
+        world: &mut World,
+        id: u64,
+        robot: Entity,
+        wrist: Entity,
+        tool: &ToolLayout,
+    ) -> Entity {
+        let entity =
+            RobotCellPlugin::spawn_tool(world, Some(id), tool.kind, tool.offset.transform());
+        world.entity_mut(wrist).push_children(&[entity]);
+        if let Some(mut robot) = world.get_mut::<RobotUr5>(robot) {
+            robot.tool = RobotCellPlugin::tcp(tool);
+        }
+        entity
+    }
+
+    // swaps the tool of a robot, false if there is no such cell
+    pub fn set_tool(world: &mut World, id: u64, tool: Option<ToolLayout>) -> bool {
+        let Some(cell) = world.resource::<RobotCells>().0.get(&id).cloned() else {
+            return false;
+        };
+        if let Some(entity) = cell.tool {
+            #[cfg(not(feature = "physics"))]
+            WorkpiecePlugin::release_grasps(world, entity);
+            world.entity_mut(entity).despawn_recursive();
+        }
+        let entity = match tool.as_ref() {
+            Some(tool) => Some(RobotCellPlugin::mount_tool(
+                world, id, cell.robot, cell.wrist, tool,
+            )),
+            None => {
+                if let Some(mut robot) = world.get_mut::<RobotUr5>(cell.robot) {
+                    robot.tool = Isometry3::identity();
+                }
+                None
+            }
+        };
+        if let Some(cell) = world.resource_mut::<RobotCells>().0.get_mut(&id) {
+            cell.tool = entity;
+            cell.layout.tool = tool;
+        }
+        true
+    }
+
+    // false if there is no such cell
+    pub fn remove_cell(world: &mut World, id: u64) -> bool {
+        let Some(cell) = world.resource_mut::<RobotCells>().0.remove(&id) else {
+            return false;
+        };
+        #[cfg(not(feature = "physics"))]
+        if let Some(tool) = cell.tool {
+            WorkpiecePlugin::release_grasps(world, tool);
+        }
+        world.entity_mut(cell.robot).despawn_recursive();
+        world.entity_mut(cell.label).despawn_recursive();
+        world.resource_mut::<Trails>().remove(id);
+        true
+    }
+
+    fn update_joints_pos(
+        time: Res<Time>,
+        mut events: EventWriter<JointChanged>,
+        mut query: Query<(&mut RobotUr5, &JointsPos, &mut JointsMotion, &Streaming)>,
+    ) {
+        let dt = time.delta_seconds_f64();
+        for (mut robot, joints, mut motion, streaming) in query.iter_mut() {
+            let target = robot.clamp_deg(joints.0);
+            let last = motion.0.target();
+            for joint in 0..6 {
+                if last[joint] != target[joint] {
+                    events.send(JointChanged {
+                        robot: robot.id,
+                        joint,
+                        angle: target[joint],
+                    });
+                }
+            }
+            if streaming.0 {
+                motion.0 = Trajectory::new(target);
+                robot.set_deg(target);
+                continue;
+            }
+            let velocity = robot.limits.map(|l| l.velocity);
+            let acceleration = robot.limits.map(|l| l.acceleration);
+            motion.0.retarget(target, &velocity, &acceleration);
+            let pos = motion.0.step(dt);
+            robot.set_deg(pos)
+        }
+    }
+
+    pub fn update_finger_pos(
+        time: Res<Time>,
+        mut events: EventWriter<FingerChanged>,
+        mut query: Query<(&mut Tool, &FingerPos, &GripCurrent, &mut FingerMotion)>,
+    ) {
+        let dt = time.delta_seconds_f64();
+        for (mut tool, fingers, current, mut motion) in query.iter_mut() {
+            let target = fingers.0.map(|p| p.clamp(0.0, 100.0) as f64);
+            let last = motion.0.target();
+            for finger in 0..2 {
+                if let (Some(robot), true) = (tool.robot, last[finger] != target[finger]) {
+                    events.send(FingerChanged {
+                        robot,
+                        finger,
+                        pos: target[finger] as f32,
+                    });
+                }
+            }
+            let scale = (current.0.clamp(GripCurrent::MIN, 100.0) / 100.0) as f64;
+            motion.0.retarget(
+                target,
+                &[FINGER_VELOCITY * scale; 2],
+                &[FINGER_ACCELERATION * scale; 2],
+            );
+            let pos = motion.0.step(dt);
+            tool.jaws = pos.map(|p| (p / 100.0) as f32);
+        }
+    }
+
+    fn update_label_pos(
+        cells: Res<RobotCells>,
+        q_robot: Query<&GlobalTransform>,
+        q_camera: Query<(&Camera, &GlobalTransform)>,
+        mut q_label: Query<&mut Style, With<Label>>,
+    ) {
+        if let Ok((camera, camera_gt)) = q_camera.get_single() {
+            for cell in cells.0.values() {
+                let (Ok(gt), Ok(mut style)) =
+                    (q_robot.get(cell.robot), q_label.get_mut(cell.label))
+                else {
+                    continue;
+                };
+                if let Some(pos) = camera.world_to_viewport(camera_gt, gt.translation()) {
+                    style.left = Val::Px(pos.x - 40.0);
+                    style.top = Val::Px(pos.y + 10.0);
+                }
+            }
+        }
+    }
+}
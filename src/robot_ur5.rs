@@ -1,51 +1,76 @@
-use bevy::prelude::*;
-use nalgebra::{matrix, Matrix4};
-use std::f64::consts::{FRAC_PI_2, PI};
-
-const ASSET: [&str; 7] = [
-    "ur5/ur5.gltf#Scene0",
-    "ur5/ur5.gltf#Scene1",
-    "ur5/ur5.gltf#Scene2",
-    "ur5/ur5.gltf#Scene3",
-    "ur5/ur5.gltf#Scene4",
-    "ur5/ur5.gltf#Scene5",
-    "ur5/ur5.gltf#Scene6",
-];
+use bevy::{prelude::*, transform::TransformSystem};
+use nalgebra::{
+    matrix, Isometry3, Matrix4, Matrix6, Rotation3, Translation3, UnitQuaternion, Vector3, Vector6,
+};
+use std::{
+    f64::consts::{FRAC_PI_2, PI},
+    sync::Arc,
+};
+
+use crate::urdf::{RobotDescription, UrdfLoader};
+
+pub const DEFAULT_MODEL: &str = "ur5/ur5.urdf";
 
+// link index in the robot description
 #[derive(Component)]
-pub enum RobotComponent {
-    Base,
-    Arm1,
-    Arm2,
-    Arm3,
-    Arm4,
-    Arm5,
-    Arm6,
-}
+pub struct RobotComponent(pub usize);
 
 pub const JOINTS_POS: [f64; 6] = [90.0, -120.0, 90.0, -60.0, -90.0, 0.0];
 
-#[derive(Component)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct JointLimit {
+    pub min: f64,          // deg
+    pub max: f64,          // deg
+    pub velocity: f64,     // deg/s
+    pub acceleration: f64, // deg/s^2
+}
+
+impl JointLimit {
+    pub fn clamp(&self, pos: f64) -> f64 {
+        pos.clamp(self.min, self.max)
+    }
+}
+
+// position and velocity follow the UR5 datasheet, the urdf overrides them
+pub const JOINT_LIMITS: [JointLimit; 6] = [JointLimit {
+    min: -360.0,
+    max: 360.0,
+    velocity: 180.0,
+    acceleration: 360.0,
+}; 6];
+
+#[derive(Component, Clone)]
 pub struct RobotUr5 {
     pub id: u64,
-    joints: [f64; 6], // rad
+    joints: [f64; 6],         // rad
+    pub tool: Isometry3<f64>, // tcp relative to flange
+    pub limits: [JointLimit; 6],
+    // chain of a model that does not match the DH table, None for the UR5
+    // and until the description is loaded
+    urdf: Option<Arc<RobotDescription>>,
 }
 
 #[derive(Component)]
-pub struct RobotWrist(pub u64);
+pub struct RobotWrist;
+
+// Flange and tcp pose in world, updated in PostUpdate from the current joints
+// and the propagated base transform
+#[derive(Component, Default, Clone, Copy)]
+pub struct TcpPose {
+    pub flange: Transform,
+    pub tcp: Transform,
+}
 
 impl RobotUr5 {
-    fn get_local_tfs(&self) -> [Transform; 7] {
-        let m4s = compute_joint_to_base(self.joints);
-        [
-            Transform::default(),
-            matrix4_to_tf(m4s[0]),
-            matrix4_to_tf(m4s[1]),
-            matrix4_to_tf(m4s[2]),
-            matrix4_to_tf(m4s[3]),
-            matrix4_to_tf(m4s[4]),
-            matrix4_to_tf(m4s[5]),
-        ]
+    // joints: rad
+    pub fn new(id: u64, joints: [f64; 6]) -> Self {
+        RobotUr5 {
+            id,
+            joints,
+            tool: Isometry3::identity(),
+            limits: JOINT_LIMITS,
+            urdf: None,
+        }
     }
 
     fn default_joints() -> [f64; 6] {
@@ -56,53 +81,135 @@ impl RobotUr5 {
         out
     }
 
-    pub fn set_deg(&mut self, j: [f64; 6]) {
+    // In: joints, rad
+    // Out: frame 1 to frame 6 relative to robot base, the last one is the flange
+    fn fk(joints: [f64; 6]) -> [Isometry3<f64>; 6] {
+        compute_joint_to_base(joints).map(matrix4_to_isometry)
+    }
+
+    // In: joints, rad
+    // Out: flange pose relative to robot base, from the model of this robot
+    pub fn flange_at(&self, joints: [f64; 6]) -> Isometry3<f64> {
+        match &self.urdf {
+            Some(description) => description.tip_pose(&joints),
+            None => RobotUr5::fk(joints)[5],
+        }
+    }
+
+    // flange pose relative to robot base
+    pub fn flange_pose(&self) -> Isometry3<f64> {
+        self.flange_at(self.joints)
+    }
+
+    // In: joints, rad
+    // Out: tcp velocity ( linear, angular ) in the robot base per joint velocity
+    pub fn jacobian(&self, joints: [f64; 6]) -> Matrix6<f64> {
+        let Some(description) = &self.urdf else {
+            let frames = RobotUr5::fk(joints);
+            let tcp = (frames[5] * self.tool).translation.vector;
+            let mut jacobian = Matrix6::zeros();
+            for (i, frame) in frames.iter().enumerate() {
+                // joint i turns about z of frame i
+                let axis = frame.rotation * Vector3::z();
+                let linear = axis.cross(&(tcp - frame.translation.vector));
+                jacobian.set_column(
+                    i,
+                    &Vector6::new(linear.x, linear.y, linear.z, axis.x, axis.y, axis.z),
+                );
+            }
+            return jacobian;
+        };
+        numeric_jacobian(|q| description.tip_pose(&q) * self.tool, joints)
+    }
+
+    // both robots move by the same kinematics
+    pub fn same_model(&self, other: &RobotUr5) -> bool {
+        match (&self.urdf, &other.urdf) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    // tcp pose relative to robot base
+    pub fn tcp_pose(&self) -> Isometry3<f64> {
+        self.flange_pose() * self.tool
+    }
+
+    // rad
+    pub fn joints(&self) -> [f64; 6] {
+        self.joints
+    }
+
+    // In: deg
+    // Out: deg, inside the position limits
+    pub fn clamp_deg(&self, j: [f64; 6]) -> [f64; 6] {
+        let mut out = [0.0; 6];
         for i in 0..6 {
-            self.joints[i] = d2r(j[i]);
+            out[i] = self.limits[i].clamp(j[i]);
+        }
+        out
+    }
+
+    pub fn set_deg(&mut self, j: [f64; 6]) {
+        self.joints = j.map(d2r);
+    }
+
+    // target: flange pose relative to robot base
+    // Out: the ik solution closest to the current joints, rad
+    pub fn ik_nearest(&self, target: &Isometry3<f64>) -> Result<[f64; 6], IkError> {
+        self.ik_near(target, &self.joints)
+    }
+
+    // target: flange pose relative to robot base
+    // reference: rad
+    // Out: the ik solution closest to reference, rad
+    // closed-form for the UR5, numeric for other models
+    pub fn ik_near(
+        &self,
+        target: &Isometry3<f64>,
+        reference: &[f64; 6],
+    ) -> Result<[f64; 6], IkError> {
+        match &self.urdf {
+            Some(description) => numeric_ik(description, target, reference),
+            None => nearest_ik(target, reference),
         }
     }
 }
 
-#[derive(Resource, Clone)]
-struct RobotPluginResUr5 {
-    base: Handle<Scene>,
-    arm1: Handle<Scene>,
-    arm2: Handle<Scene>,
-    arm3: Handle<Scene>,
-    arm4: Handle<Scene>,
-    arm5: Handle<Scene>,
-    arm6: Handle<Scene>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IkError {
+    // the wrist center is out of the arm's reach
+    Unreachable,
+    // joint 5 is at 0 or 180 degrees, joint 4 and joint 6 are coaxial
+    Singular,
 }
 
-impl FromWorld for RobotPluginResUr5 {
-    fn from_world(world: &mut World) -> Self {
-        let asset_server = world.get_resource::<AssetServer>().unwrap();
-        let base = asset_server.load(ASSET[0]);
-        let arm1 = asset_server.load(ASSET[1]);
-        let arm2 = asset_server.load(ASSET[2]);
-        let arm3 = asset_server.load(ASSET[3]);
-        let arm4 = asset_server.load(ASSET[4]);
-        let arm5 = asset_server.load(ASSET[5]);
-        let arm6 = asset_server.load(ASSET[6]);
-        RobotPluginResUr5 {
-            base,
-            arm1,
-            arm2,
-            arm3,
-            arm4,
-            arm5,
-            arm6,
+impl std::fmt::Display for IkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IkError::Unreachable => write!(f, "target is unreachable"),
+            IkError::Singular => write!(f, "target is at a wrist singularity"),
         }
     }
 }
 
-pub struct RobotPluginUr5;
+#[derive(Component)]
+pub struct RobotModel(pub Handle<RobotDescription>);
+
+// marks robots whose links are spawned
+#[derive(Component)]
+struct RobotLinks;
+
+pub struct RobotPlugin;
 
-impl RobotPluginUr5 {
-    // (base,arm6)
+impl RobotPlugin {
+    // (base, wrist)
+    // model: urdf asset path, DEFAULT_MODEL if None
     pub fn add_robot(
         world: &mut World,
         id: u64,
+        model: Option<&str>,
         tf: Option<Transform>,
         joints: Option<[f64; 6]>, // rad
     ) -> (Entity, Entity) {
@@ -115,135 +222,156 @@ impl RobotPluginUr5 {
                 .rotate_x(-std::f32::consts::FRAC_PI_2);
         }
         let joints = joints.unwrap_or(RobotUr5::default_joints());
-        let robot = RobotUr5 { id, joints };
-        let component_tfs = robot.get_local_tfs();
-        let parent = world.spawn((robot, spatial_bundle)).id();
-
-        let res: RobotPluginResUr5 = world.get_resource::<RobotPluginResUr5>().unwrap().clone();
-        let child_base = world
-            .spawn((
-                SceneBundle {
-                    scene: res.base.clone(),
-                    transform: component_tfs[0],
-                    ..default()
-                },
-                RobotComponent::Base,
-            ))
-            .id();
-        let child_arm1 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.arm1.clone(),
-                    transform: component_tfs[1],
-                    ..default()
-                },
-                RobotComponent::Arm1,
-            ))
-            .id();
-        let child_arm2 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.arm2.clone(),
-                    transform: component_tfs[2],
-                    ..default()
-                },
-                RobotComponent::Arm2,
-            ))
-            .id();
-        let child_arm3 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.arm3.clone(),
-                    transform: component_tfs[3],
-                    ..default()
-                },
-                RobotComponent::Arm3,
-            ))
-            .id();
-        let child_arm4 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.arm4.clone(),
-                    transform: component_tfs[4],
-                    ..default()
-                },
-                RobotComponent::Arm4,
-            ))
-            .id();
-        let child_arm5 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.arm5.clone(),
-                    transform: component_tfs[5],
-                    ..default()
-                },
-                RobotComponent::Arm5,
-            ))
-            .id();
-        let child_arm6 = world
-            .spawn((
-                SceneBundle {
-                    scene: res.arm6.clone(),
-                    transform: component_tfs[6],
-                    ..default()
-                },
-                RobotComponent::Arm6,
-                RobotWrist(id),
-            ))
+        let robot = RobotUr5::new(id, joints);
+        let asset_server = world.get_resource::<AssetServer>().unwrap();
+        let model = RobotModel(asset_server.load(model.unwrap_or(DEFAULT_MODEL)));
+        let parent = world
+            .spawn((robot, spatial_bundle, TcpPose::default(), model))
             .id();
 
-        let children = [
-            child_base, child_arm1, child_arm2, child_arm3, child_arm4, child_arm5, child_arm6,
-        ];
+        // tools can be mounted before the description is loaded,
+        // the wrist becomes the tip link in spawn_links
+        let wrist = world.spawn((SpatialBundle::default(), RobotWrist)).id();
+        world.entity_mut(parent).push_children(&[wrist]);
+        (parent, wrist)
+    }
 
-        world.entity_mut(parent).push_children(&children);
-        (parent, child_arm6)
+    fn spawn_links(
+        mut commands: Commands,
+        asset_server: Res<AssetServer>,
+        descriptions: Res<Assets<RobotDescription>>,
+        mut q_robot: Query<(Entity, &mut RobotUr5, &RobotModel, &Children), Without<RobotLinks>>,
+        q_wrist: Query<(), With<RobotWrist>>,
+    ) {
+        for (entity, mut ur5, model, children) in q_robot.iter_mut() {
+            let Some(description) = descriptions.get(&model.0) else {
+                continue;
+            };
+            let movable = description.movable_joints().len();
+            if movable != 6 {
+                warn!(
+                    "robot {}: {} has {} movable joints, the first 6 are driven",
+                    ur5.id, description.name, movable
+                );
+            }
+            if !matches_dh(description) {
+                ur5.urdf = Some(Arc::new(description.clone()));
+            }
+            for (limit, j) in ur5.limits.iter_mut().zip(description.movable_joints()) {
+                if let Some(urdf_limit) = description.joints[j].limit {
+                    if urdf_limit.lower < urdf_limit.upper {
+                        limit.min = urdf_limit.lower.to_degrees();
+                        limit.max = urdf_limit.upper.to_degrees();
+                    }
+                    if urdf_limit.velocity > 0.0 {
+                        limit.velocity = urdf_limit.velocity.to_degrees();
+                    }
+                }
+            }
+            let tfs = description.link_transforms(&ur5.joints);
+            let tip = description.tip();
+            let wrist = children.iter().copied().find(|&c| q_wrist.contains(c));
+            for (i, link) in description.links.iter().enumerate() {
+                let link_entity = match wrist {
+                    Some(wrist) if i == tip => {
+                        commands.entity(wrist).insert((RobotComponent(i), tfs[i]));
+                        wrist
+                    }
+                    _ => commands
+                        .spawn((
+                            SpatialBundle {
+                                transform: tfs[i],
+                                ..default()
+                            },
+                            RobotComponent(i),
+                        ))
+                        .set_parent(entity)
+                        .id(),
+                };
+                for visual in link.visuals.iter() {
+                    commands
+                        .spawn(SceneBundle {
+                            scene: asset_server.load(&visual.mesh),
+                            transform: visual.origin,
+                            ..default()
+                        })
+                        .set_parent(link_entity);
+                }
+            }
+            commands.entity(entity).insert(RobotLinks);
+        }
     }
 
     fn update_component_pos(
-        q_parent: Query<(&RobotUr5, &Children), Changed<RobotUr5>>,
+        descriptions: Res<Assets<RobotDescription>>,
+        q_parent: Query<(&RobotUr5, &RobotModel, &Children), Changed<RobotUr5>>,
         mut q_child: Query<(&RobotComponent, &mut Transform)>,
     ) {
-        for (ur5, children) in q_parent.iter() {
-            let tfs = ur5.get_local_tfs();
+        for (ur5, model, children) in q_parent.iter() {
+            let Some(description) = descriptions.get(&model.0) else {
+                continue;
+            };
+            let tfs = description.link_transforms(&ur5.joints);
             for &child in children.iter() {
                 if let Ok((rc, mut tf)) = q_child.get_mut(child) {
-                    match rc {
-                        RobotComponent::Base => {
-                            *tf = tfs[0];
-                        }
-                        RobotComponent::Arm1 => {
-                            *tf = tfs[1];
-                        }
-                        RobotComponent::Arm2 => {
-                            *tf = tfs[2];
-                        }
-                        RobotComponent::Arm3 => {
-                            *tf = tfs[3];
-                        }
-                        RobotComponent::Arm4 => {
-                            *tf = tfs[4];
-                        }
-                        RobotComponent::Arm5 => {
-                            *tf = tfs[5];
-                        }
-                        RobotComponent::Arm6 => {
-                            *tf = tfs[6];
-                        }
+                    if let Some(link_tf) = tfs.get(rc.0) {
+                        *tf = *link_tf;
                     }
                 }
             }
         }
     }
+
+    fn update_tcp_pose(
+        descriptions: Res<Assets<RobotDescription>>,
+        mut query: Query<(&RobotUr5, &RobotModel, &GlobalTransform, &mut TcpPose)>,
+    ) {
+        for (ur5, model, gt, mut tcp_pose) in query.iter_mut() {
+            let Some(description) = descriptions.get(&model.0) else {
+                continue;
+            };
+            let flange = description.link_transforms(&ur5.joints)[description.tip()];
+            let base = gt.compute_transform();
+            tcp_pose.flange = base * flange;
+            tcp_pose.tcp = tcp_pose.flange * isometry_to_tf(&ur5.tool);
+        }
+    }
 }
 
-impl Plugin for RobotPluginUr5 {
+impl Plugin for RobotPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<RobotPluginResUr5>()
-            .add_systems(Update, RobotPluginUr5::update_component_pos);
+        app.add_asset::<RobotDescription>()
+            .init_asset_loader::<UrdfLoader>()
+            .add_systems(
+                Update,
+                (RobotPlugin::spawn_links, RobotPlugin::update_component_pos),
+            )
+            .add_systems(
+                PostUpdate,
+                RobotPlugin::update_tcp_pose.after(TransformSystem::TransformPropagate),
+            );
     }
 }
 
+// The closed-form kinematics use the UR5 geometry in PARS,
+// a description with other link lengths or joint axes is driven by the numeric ik.
+pub fn matches_dh(description: &RobotDescription) -> bool {
+    if description.movable_joints().len() != 6 {
+        return false;
+    }
+    let samples = [
+        [0.0; 6],
+        RobotUr5::default_joints(),
+        [0.3, -1.2, 1.5, -0.7, 2.1, -2.8],
+    ];
+    samples.iter().all(|&joints| {
+        let flange = description.link_transforms(&joints)[description.tip()];
+        let expected = isometry_to_tf(&RobotUr5::fk(joints)[5]);
+        flange.translation.distance(expected.translation) < 1e-4
+            && flange.rotation.angle_between(expected.rotation) < 1e-3
+    })
+}
+
 fn t_(a: f64, alpha: f64, d: f64, theta: f64) -> Matrix4<f64> {
     let i11 = theta.cos();
     let i12 = -theta.sin();
@@ -270,6 +398,7 @@ fn t_(a: f64, alpha: f64, d: f64, theta: f64) -> Matrix4<f64> {
     out
 }
 
+// UR5 geometry for the closed-form kinematics, the displayed links follow the urdf
 struct RobotPar {
     a: f64,
     alpha: f64,
@@ -333,22 +462,389 @@ fn compute_joint_to_base(joints: [f64; 6]) -> [Matrix4<f64>; 6] {
     let mut buf = ts[0];
     out[0] = buf;
     for i in 1..6 {
-        buf = buf * ts[i];
+        buf *= ts[i];
         out[i] = buf;
     }
     out
 }
 
-fn matrix4_to_tf(m: Matrix4<f64>) -> Transform {
-    let x_axis = Vec4::new(m.m11 as f32, m.m21 as f32, m.m31 as f32, m.m41 as f32);
-    let y_axis = Vec4::new(m.m12 as f32, m.m22 as f32, m.m32 as f32, m.m42 as f32);
-    let z_axis = Vec4::new(m.m13 as f32, m.m23 as f32, m.m33 as f32, m.m43 as f32);
-    let w_axis = Vec4::new(m.m14 as f32, m.m24 as f32, m.m34 as f32, m.m44 as f32);
-    let mat = Mat4::from_cols(x_axis, y_axis, z_axis, w_axis);
-    Transform::from_matrix(mat)
+fn matrix4_to_isometry(m: Matrix4<f64>) -> Isometry3<f64> {
+    let rotation = Rotation3::from_matrix_unchecked(m.fixed_view::<3, 3>(0, 0).into_owned());
+    Isometry3::from_parts(
+        Translation3::new(m.m14, m.m24, m.m34),
+        UnitQuaternion::from_rotation_matrix(&rotation),
+    )
+}
+
+fn isometry_to_tf(iso: &Isometry3<f64>) -> Transform {
+    let t = iso.translation.vector;
+    let r = iso.rotation;
+    Transform::from_xyz(t.x as f32, t.y as f32, t.z as f32).with_rotation(Quat::from_xyzw(
+        r.i as f32, r.j as f32, r.k as f32, r.w as f32,
+    ))
+}
+
+const IK_EPSILON: f64 = 1e-9;
+
+// solutions sharing joint 1
+struct ShoulderBranch {
+    shoulder: f64, // joint 1, rad
+    // joint 5 at 0 or 180 degrees, the solutions of the branch are left out
+    singular: bool,
+    solutions: Vec<[f64; 6]>,
+}
+
+// Closed-form ik for the chain described by PARS.
+// target: flange pose relative to robot base
+// Out: both shoulder branches, each with up to four solutions (wrist flip, elbow up/down)
+fn shoulder_branches(target: &Isometry3<f64>) -> Result<Vec<ShoulderBranch>, IkError> {
+    let m = target.to_homogeneous();
+    let p6 = Vector3::new(m.m14, m.m24, m.m34);
+    let z6 = Vector3::new(m.m13, m.m23, m.m33);
+    // joint 2, 3, 4 are parallel, the wrist center keeps a constant offset from the base plane
+    let d_lat = PARS[1].d - PARS[2].d + PARS[3].d;
+    let p5 = p6 - z6 * PARS[5].d;
+    let r = (p5.x * p5.x + p5.y * p5.y).sqrt();
+    if r < d_lat {
+        return Err(IkError::Unreachable);
+    }
+    let phi = p5.y.atan2(p5.x);
+    let psi = (d_lat / r).asin();
+    let mut branches = Vec::with_capacity(2);
+    for theta1 in [phi - psi, phi - (PI - psi)] {
+        let mut branch = ShoulderBranch {
+            shoulder: wrap_angle(theta1 - PI),
+            singular: false,
+            solutions: Vec::with_capacity(4),
+        };
+        let y1 = Vector3::new(-theta1.sin(), theta1.cos(), 0.0);
+        let c5 = -z6.dot(&y1);
+        if c5.abs() > 1.0 + IK_EPSILON {
+            branches.push(branch);
+            continue;
+        }
+        let c5 = c5.clamp(-1.0, 1.0);
+        let acos5 = c5.acos();
+        for theta5 in [acos5, -acos5] {
+            let s5 = theta5.sin();
+            if s5.abs() < 1e-6 {
+                branch.singular = true;
+                continue;
+            }
+            let w = target.rotation.inverse() * y1;
+            let theta6 = (w.y / s5).atan2(-w.x / s5);
+
+            // the remaining chain joint 2 to joint 4 is a planar 3R arm
+            let t1 = t_(PARS[0].a, PARS[0].alpha, PARS[0].d, theta1);
+            let t5 = t_(PARS[4].a, PARS[4].alpha, PARS[4].d, theta5);
+            let t6 = t_(PARS[5].a, PARS[5].alpha, PARS[5].d, theta6);
+            let rx = t_(PARS[1].a, PARS[1].alpha, 0.0, 0.0);
+            let (Some(t1_inv), Some(t5_inv), Some(t6_inv), Some(rx_inv)) = (
+                t1.try_inverse(),
+                t5.try_inverse(),
+                t6.try_inverse(),
+                rx.try_inverse(),
+            ) else {
+                continue;
+            };
+            let planar = rx_inv * t1_inv * m * t6_inv * t5_inv;
+            let (px, py) = (planar.m14, planar.m24);
+            let (a2, a3) = (PARS[2].a, PARS[3].a);
+            let c3 = (px * px + py * py - a2 * a2 - a3 * a3) / (2.0 * a2 * a3);
+            if c3.abs() > 1.0 + IK_EPSILON {
+                continue;
+            }
+            let acos3 = c3.clamp(-1.0, 1.0).acos();
+            let sum = planar.m21.atan2(planar.m11);
+            for phi3 in [acos3, -acos3] {
+                let theta2 = py.atan2(px) - (a3 * phi3.sin()).atan2(a2 + a3 * phi3.cos());
+                let theta3 = -phi3;
+                let theta4 = sum - theta2 + theta3;
+                // undo the revision in compute_joint_to_base
+                let joints = [theta1 - PI, theta2, -theta3, theta4, theta5 - PI, theta6];
+                branch.solutions.push(joints.map(wrap_angle));
+            }
+        }
+        // joint 4 and joint 6 can't be separated, the branch set is incomplete
+        if branch.singular {
+            branch.solutions.clear();
+        }
+        branches.push(branch);
+    }
+    Ok(branches)
+}
+
+// target: flange pose relative to robot base
+// reference: joints to stay close to, rad
+// Out: rad, Singular if the shoulder branch of the reference is at a wrist singularity
+fn nearest_ik(target: &Isometry3<f64>, reference: &[f64; 6]) -> Result<[f64; 6], IkError> {
+    let branches = shoulder_branches(target)?;
+    let own = branches.iter().min_by(|a, b| {
+        let da = wrap_angle(a.shoulder - reference[0]).abs();
+        let db = wrap_angle(b.shoulder - reference[0]).abs();
+        da.total_cmp(&db)
+    });
+    if own.is_some_and(|b| b.singular) {
+        return Err(IkError::Singular);
+    }
+    let solutions: Vec<[f64; 6]> = branches.into_iter().flat_map(|b| b.solutions).collect();
+    let Some(&first) = solutions.first() else {
+        return Err(IkError::Unreachable);
+    };
+    let mut best = first;
+    let mut best_dist = f64::MAX;
+    for solution in solutions.iter() {
+        let joints = unwrap_near(solution, reference);
+        let dist: f64 = (0..6).map(|i| (joints[i] - reference[i]).powi(2)).sum();
+        if dist < best_dist {
+            best_dist = dist;
+            best = joints;
+        }
+    }
+    Ok(best)
+}
+
+const NUMERIC_IK_ITERATIONS: usize = 200;
+const NUMERIC_IK_TOLERANCE: f64 = 1e-7; // m and rad
+const NUMERIC_IK_DAMPING: f64 = 1e-2;
+const JACOBIAN_STEP: f64 = 1e-6; // rad
+
+// Damped least squares on the urdf chain, starting at the reference,
+// so the solution stays on the reference's branch.
+// target: flange pose relative to robot base
+// Out: rad
+fn numeric_ik(
+    description: &RobotDescription,
+    target: &Isometry3<f64>,
+    reference: &[f64; 6],
+) -> Result<[f64; 6], IkError> {
+    let mut joints = *reference;
+    for _ in 0..NUMERIC_IK_ITERATIONS {
+        let error = pose_error(&description.tip_pose(&joints), target);
+        if error.norm() < NUMERIC_IK_TOLERANCE {
+            return Ok(joints);
+        }
+        let jacobian = numeric_jacobian(|q| description.tip_pose(&q), joints);
+        let damped =
+            jacobian * jacobian.transpose() + Matrix6::identity() * NUMERIC_IK_DAMPING.powi(2);
+        let Some(cholesky) = damped.cholesky() else {
+            return Err(IkError::Unreachable);
+        };
+        let step = jacobian.transpose() * cholesky.solve(&error);
+        for i in 0..6 {
+            joints[i] += step[i];
+        }
+    }
+    Err(IkError::Unreachable)
+}
+
+// finite differences of a pose of the joints, rad
+fn numeric_jacobian(pose: impl Fn([f64; 6]) -> Isometry3<f64>, joints: [f64; 6]) -> Matrix6<f64> {
+    let current = pose(joints);
+    let mut jacobian = Matrix6::zeros();
+    for i in 0..6 {
+        let mut moved = joints;
+        moved[i] += JACOBIAN_STEP;
+        jacobian.set_column(i, &(pose_error(&current, &pose(moved)) / JACOBIAN_STEP));
+    }
+    jacobian
+}
+
+// translation and rotation vector from current to target, in the base frame
+fn pose_error(current: &Isometry3<f64>, target: &Isometry3<f64>) -> Vector6<f64> {
+    let p = target.translation.vector - current.translation.vector;
+    let r = (target.rotation * current.rotation.inverse()).scaled_axis();
+    Vector6::new(p.x, p.y, p.z, r.x, r.y, r.z)
+}
+
+// wrap to (-PI, PI]
+fn wrap_angle(ang: f64) -> f64 {
+    let mut out = ang % (2.0 * PI);
+    if out > PI {
+        out -= 2.0 * PI;
+    } else if out <= -PI {
+        out += 2.0 * PI;
+    }
+    out
+}
+
+// shift each joint by multiples of 2*PI to be closest to the reference, rad
+fn unwrap_near(joints: &[f64; 6], reference: &[f64; 6]) -> [f64; 6] {
+    let mut out = [0.0; 6];
+    for i in 0..6 {
+        let turns = ((reference[i] - joints[i]) / (2.0 * PI)).round();
+        out[i] = joints[i] + turns * 2.0 * PI;
+    }
+    out
 }
 
 #[inline]
 fn d2r(ang: f64) -> f64 {
     ang / 180.0 * PI
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UR5: &str = include_str!("../assets/ur5/ur5.urdf");
+
+    fn fk(joints: [f64; 6]) -> Isometry3<f64> {
+        RobotUr5::fk(joints)[5]
+    }
+
+    // deterministic pseudo random joints in (-PI, PI)
+    fn random_joints(seed: &mut u64) -> [f64; 6] {
+        let mut out = [0.0; 6];
+        for j in out.iter_mut() {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let unit = (*seed >> 11) as f64 / (1u64 << 53) as f64;
+            *j = (unit * 2.0 - 1.0) * PI;
+        }
+        out
+    }
+
+    // Out: the solutions of all shoulder branches, rad
+    fn inverse_kinematics(target: &Isometry3<f64>) -> Result<Vec<[f64; 6]>, IkError> {
+        let branches = shoulder_branches(target)?;
+        let singular = branches.iter().any(|b| b.singular);
+        let out: Vec<[f64; 6]> = branches.into_iter().flat_map(|b| b.solutions).collect();
+        if !out.is_empty() {
+            Ok(out)
+        } else if singular {
+            // joint 4 and joint 6 can't be separated
+            Err(IkError::Singular)
+        } else {
+            Err(IkError::Unreachable)
+        }
+    }
+
+    fn assert_pose_eq(a: &Isometry3<f64>, b: &Isometry3<f64>) {
+        let dp = (a.translation.vector - b.translation.vector).norm();
+        let dr = a.rotation.angle_to(&b.rotation);
+        assert!(dp < 1e-6, "position error {}", dp);
+        assert!(dr < 1e-6, "rotation error {}", dr);
+    }
+
+    #[test]
+    fn ik_round_trip() {
+        let mut seed = 7;
+        for _ in 0..500 {
+            let joints = random_joints(&mut seed);
+            if joints[4].sin().abs() < 1e-3 {
+                continue;
+            }
+            let target = fk(joints);
+            let solutions = inverse_kinematics(&target).unwrap();
+            assert!(!solutions.is_empty());
+            for solution in solutions.iter() {
+                assert_pose_eq(&fk(*solution), &target);
+            }
+            let found = solutions
+                .iter()
+                .any(|s| (0..6).all(|i| wrap_angle(s[i] - joints[i]).abs() < 1e-6));
+            assert!(found, "original joints not in solutions");
+        }
+    }
+
+    #[test]
+    fn ik_nearest_picks_current_branch() {
+        let mut seed = 42;
+        for _ in 0..100 {
+            let joints = random_joints(&mut seed);
+            if joints[4].sin().abs() < 1e-3 {
+                continue;
+            }
+            let robot = RobotUr5 {
+                id: 0,
+                joints,
+                tool: Isometry3::identity(),
+                limits: JOINT_LIMITS,
+                urdf: None,
+            };
+            let solution = robot.ik_nearest(&fk(joints)).unwrap();
+            for i in 0..6 {
+                assert!((solution[i] - joints[i]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn fk_matches_local_tfs() {
+        let description = RobotDescription::parse(UR5, "ur5").unwrap();
+        let robot = RobotUr5 {
+            id: 0,
+            joints: RobotUr5::default_joints(),
+            tool: Isometry3::translation(0.0, 0.0, 0.1),
+            limits: JOINT_LIMITS,
+            urdf: None,
+        };
+        let tfs = description.link_transforms(&robot.joints);
+        let isos = RobotUr5::fk(robot.joints);
+        for i in 0..6 {
+            let tf = isometry_to_tf(&isos[i]);
+            assert!(tf.translation.distance(tfs[i + 1].translation) < 1e-5);
+            assert!(tf.rotation.angle_between(tfs[i + 1].rotation) < 1e-3);
+        }
+        let offset = robot.flange_pose().inverse() * robot.tcp_pose();
+        assert!((offset.translation.vector - Vector3::new(0.0, 0.0, 0.1)).norm() < 1e-9);
+        assert!(matches_dh(&description));
+    }
+
+    #[test]
+    fn other_geometry_does_not_match_dh() {
+        let longer = UR5.replace("0.425 0 -0.11895", "0.5 0 -0.11895");
+        let description = RobotDescription::parse(&longer, "ur5").unwrap();
+        assert!(!matches_dh(&description));
+    }
+
+    #[test]
+    fn other_geometry_moves_by_its_urdf() {
+        let longer = UR5.replace("0.425 0 -0.11895", "0.5 0 -0.11895");
+        let description = RobotDescription::parse(&longer, "ur5").unwrap();
+        let mut robot = RobotUr5::new(0, RobotUr5::default_joints());
+        robot.urdf = Some(Arc::new(description.clone()));
+        let joints = robot.joints();
+        let tf = description.link_transforms(&joints)[description.tip()];
+        let flange = isometry_to_tf(&robot.flange_pose());
+        assert!(tf.translation.distance(flange.translation) < 1e-5);
+        assert!(tf.rotation.angle_between(flange.rotation) < 1e-4);
+
+        // a small move stays on the branch of the reference
+        let target = Isometry3::translation(0.05, -0.03, 0.02) * robot.flange_pose();
+        let solution = robot.ik_nearest(&target).unwrap();
+        assert_pose_eq(&robot.flange_at(solution), &target);
+        assert!((0..6).all(|i| (solution[i] - joints[i]).abs() < 0.5));
+
+        let far = Isometry3::translation(2.0, 0.0, 0.0);
+        assert_eq!(robot.ik_nearest(&far), Err(IkError::Unreachable));
+    }
+
+    #[test]
+    fn ik_errors() {
+        let far = Isometry3::translation(2.0, 0.0, 0.0);
+        assert_eq!(inverse_kinematics(&far), Err(IkError::Unreachable));
+
+        let mut joints = RobotUr5::default_joints();
+        joints[4] = 0.0 - PI;
+        assert_eq!(nearest_ik(&fk(joints), &joints), Err(IkError::Singular));
+    }
+
+    #[test]
+    fn singular_branch_keeps_the_other_one() {
+        let mut joints = RobotUr5::default_joints();
+        joints[4] = 0.0 - PI;
+        let target = fk(joints);
+        let solutions = inverse_kinematics(&target).unwrap();
+        for solution in solutions.iter() {
+            assert_pose_eq(&fk(*solution), &target);
+            assert!(wrap_angle(solution[0] - joints[0]).abs() > 1e-3);
+        }
+        // from the other shoulder branch the target is reachable
+        let other = solutions[0];
+        let solution = nearest_ik(&target, &other).unwrap();
+        assert_pose_eq(&fk(solution), &target);
+    }
+}
@@ -0,0 +1,487 @@
+// Bridge to ROS 2 through a rosbridge server (JSON over WebSocket), enabled by the "ros" feature.
+// sensor_msgs/JointState on /joint_states drives the robots: a joint name is the ros prefix of the
+// robot in the cell layout followed by the URDF joint name, e.g. robot0_shoulder_pan_joint.
+// Every link frame is published on /tf as tf2_msgs/TFMessage, the robot bases below "world".
+use bevy::prelude::*;
+use bevy_egui::egui;
+use flume::{unbounded, Receiver, Sender};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    robot_cell::{JointsPos, RobotCells, Streaming},
+    robot_ur5::{RobotModel, RobotUr5},
+    urdf::RobotDescription,
+};
+
+pub const DEFAULT_URL: &str = "ws://127.0.0.1:9090";
+pub const JOINT_STATES_TOPIC: &str = "/joint_states";
+pub const TF_TOPIC: &str = "/tf";
+pub const WORLD_FRAME: &str = "world";
+const TF_PERIOD: f32 = 0.05; // s
+const FOLLOW_TIMEOUT: f32 = 0.5; // s, a robot streams joint states until none arrived for that long
+
+#[derive(Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct JointState {
+    #[serde(default)]
+    pub name: Vec<String>,
+    #[serde(default)]
+    pub position: Vec<f64>, // rad
+}
+
+#[derive(Deserialize)]
+struct Incoming {
+    op: String,
+    #[serde(default)]
+    topic: String,
+    #[serde(default)]
+    msg: serde_json::Value,
+}
+
+// a JointState published on JOINT_STATES_TOPIC, None for any other message
+fn parse_joint_state(text: &str) -> Option<JointState> {
+    let incoming: Incoming = serde_json::from_str(text).ok()?;
+    if incoming.op != "publish" || incoming.topic != JOINT_STATES_TOPIC {
+        return None;
+    }
+    serde_json::from_value(incoming.msg).ok()
+}
+
+// robots: ( id, prefix, names of the movable joints )
+// Out: the joints named in the message for each robot, rad
+pub fn map_joint_state(
+    state: &JointState,
+    robots: &[(u64, String, Vec<String>)],
+) -> BTreeMap<u64, [Option<f64>; 6]> {
+    let mut out: BTreeMap<u64, [Option<f64>; 6]> = BTreeMap::new();
+    for (name, position) in state.name.iter().zip(state.position.iter()) {
+        for (id, prefix, joints) in robots {
+            let Some(joint) = name.strip_prefix(prefix.as_str()) else {
+                continue;
+            };
+            if let Some(k) = joints.iter().take(6).position(|j| j == joint) {
+                out.entry(*id).or_default()[k] = Some(*position);
+            }
+        }
+    }
+    out
+}
+
+// Out: ( parent frame, child frame, transform ) of the base and of every joint of a robot,
+// in ROS conventions: z up, m
+pub fn robot_frames(
+    prefix: &str,
+    base: &Transform,
+    description: &RobotDescription,
+    joints: &[f64],
+) -> Vec<(String, String, Transform)> {
+    let links = description.link_transforms(joints);
+    // the world is y up in bevy
+    let world = Transform::from_rotation(Quat::from_rotation_x(std::f32::consts::FRAC_PI_2));
+    let frame = |link: usize| format!("{}{}", prefix, description.links[link].name);
+    let mut out = vec![(
+        WORLD_FRAME.to_string(),
+        frame(description.root),
+        world * *base,
+    )];
+    for joint in description.joints.iter() {
+        let parent = links[joint.parent].compute_matrix();
+        let child = links[joint.child].compute_matrix();
+        out.push((
+            frame(joint.parent),
+            frame(joint.child),
+            Transform::from_matrix(parent.inverse() * child),
+        ));
+    }
+    out
+}
+
+fn tf_message(frames: &[(String, String, Transform)], stamp: Duration) -> serde_json::Value {
+    let transforms: Vec<serde_json::Value> = frames
+        .iter()
+        .map(|(parent, child, tf)| {
+            json!({
+                "header": {
+                    "stamp": { "sec": stamp.as_secs(), "nanosec": stamp.subsec_nanos() },
+                    "frame_id": parent,
+                },
+                "child_frame_id": child,
+                "transform": {
+                    "translation": { "x": tf.translation.x, "y": tf.translation.y, "z": tf.translation.z },
+                    "rotation": { "x": tf.rotation.x, "y": tf.rotation.y, "z": tf.rotation.z, "w": tf.rotation.w },
+                },
+            })
+        })
+        .collect();
+    json!({ "op": "publish", "topic": TF_TOPIC, "msg": { "transforms": transforms } })
+}
+
+// from the connection thread
+enum Update {
+    Connected,
+    JointState(JointState),
+    Disconnected(Option<String>),
+}
+
+struct Link {
+    updates: Receiver<Update>,
+    outgoing: Sender<String>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Resource)]
+pub struct RosBridge {
+    pub url: String,
+    link: Option<Link>,
+    connected: bool,
+    error: Option<String>,
+    since_tf: f32,                 // s
+    following: BTreeMap<u64, f32>, // robot, s since its last joint state
+}
+
+impl Default for RosBridge {
+    fn default() -> Self {
+        RosBridge {
+            url: DEFAULT_URL.to_string(),
+            link: None,
+            connected: false,
+            error: None,
+            since_tf: 0.0,
+            following: BTreeMap::new(),
+        }
+    }
+}
+
+impl RosBridge {
+    pub fn connect(&mut self) {
+        let (update_sender, updates) = unbounded();
+        let (outgoing, outgoing_receiver) = unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+        let url = self.url.clone();
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            let result = run(&url, &thread_stop, &update_sender, &outgoing_receiver);
+            let _ = update_sender.send(Update::Disconnected(result.err()));
+        });
+        self.link = Some(Link {
+            updates,
+            outgoing,
+            stop,
+        });
+        self.connected = false;
+        self.error = None;
+    }
+
+    pub fn disconnect(&mut self) {
+        self.link = None;
+        self.connected = false;
+        self.following.clear();
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.label("ROS");
+        ui.add_enabled(
+            self.link.is_none(),
+            egui::TextEdit::singleline(&mut self.url).desired_width(140.0),
+        );
+        if self.link.is_some() {
+            if ui.button("disconnect").clicked() {
+                self.disconnect();
+            }
+        } else if ui.button("connect").clicked() {
+            self.connect();
+        }
+        if self.connected {
+            ui.label(format!("{} robots following", self.following.len()));
+        } else if self.link.is_some() {
+            ui.label("connecting…");
+        } else if let Some(error) = &self.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+    }
+
+    // Out: the joint states received since the last call
+    fn receive(&mut self) -> Vec<JointState> {
+        let Some(link) = self.link.as_ref() else {
+            return Vec::new();
+        };
+        let mut states = Vec::new();
+        let mut closed = None;
+        for update in link.updates.try_iter() {
+            match update {
+                Update::Connected => self.connected = true,
+                Update::JointState(state) => states.push(state),
+                Update::Disconnected(error) => closed = Some(error),
+            }
+        }
+        if let Some(error) = closed {
+            self.disconnect();
+            self.error = error;
+        }
+        states
+    }
+}
+
+// runs on the connection thread until stopped or the connection fails
+fn run(
+    url: &str,
+    stop: &AtomicBool,
+    updates: &Sender<Update>,
+    outgoing: &Receiver<String>,
+) -> Result<(), String> {
+    use tungstenite::{error::Error, stream::MaybeTlsStream, Message};
+
+    let (mut socket, _) = tungstenite::connect(url).map_err(|e| e.to_string())?;
+    // poll for messages, in between send what is queued
+    if let MaybeTlsStream::Plain(stream) = socket.get_mut() {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .map_err(|e| e.to_string())?;
+    }
+    let setup = [
+        json!({ "op": "subscribe", "topic": JOINT_STATES_TOPIC, "type": "sensor_msgs/msg/JointState" }),
+        json!({ "op": "advertise", "topic": TF_TOPIC, "type": "tf2_msgs/msg/TFMessage" }),
+    ];
+    for message in setup {
+        socket
+            .send(Message::Text(message.to_string()))
+            .map_err(|e| e.to_string())?;
+    }
+    let _ = updates.send(Update::Connected);
+
+    while !stop.load(Ordering::Relaxed) {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if let Some(state) = parse_joint_state(&text) {
+                    let _ = updates.send(Update::JointState(state));
+                }
+            }
+            Ok(Message::Close(_)) => return Err("closed by the server".to_string()),
+            Ok(_) => {}
+            Err(Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        while let Ok(text) = outgoing.try_recv() {
+            socket
+                .send(Message::Text(text))
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    let _ = socket.close(None);
+    Ok(())
+}
+
+pub struct RosBridgePlugin;
+
+impl Plugin for RosBridgePlugin {
+    fn build(&self, app: &mut App) {
+        let mut bridge = RosBridge::default();
+        // connect at startup when the url is given
+        if let Ok(url) = std::env::var("ROSBRIDGE_URL") {
+            bridge.url = url;
+            bridge.connect();
+        }
+        app.insert_resource(bridge)
+            .add_systems(Update, RosBridgePlugin::publish_tf);
+    }
+}
+
+impl RosBridgePlugin {
+    // robots that received joint states stream them, it runs after the program players
+    pub fn follow_joint_states(
+        time: Res<Time>,
+        mut bridge: ResMut<RosBridge>,
+        cells: Res<RobotCells>,
+        descriptions: Res<Assets<RobotDescription>>,
+        mut q_robot: Query<(&RobotUr5, &RobotModel, &mut JointsPos, &mut Streaming)>,
+    ) {
+        let dt = time.delta_seconds();
+        bridge.following.retain(|_, since| {
+            *since += dt;
+            *since < FOLLOW_TIMEOUT
+        });
+
+        let states = bridge.receive();
+        if !states.is_empty() {
+            let robots: Vec<(u64, String, Vec<String>)> = cells
+                .0
+                .iter()
+                .filter_map(|(id, cell)| {
+                    let (_, model, _, _) = q_robot.get(cell.robot).ok()?;
+                    let description = descriptions.get(&model.0)?;
+                    let joints = description
+                        .movable_joints()
+                        .iter()
+                        .map(|&j| description.joints[j].name.clone())
+                        .collect();
+                    Some((*id, cell.layout.ros_prefix(), joints))
+                })
+                .collect();
+            for state in states.iter() {
+                for (id, positions) in map_joint_state(state, &robots) {
+                    let Some(cell) = cells.0.get(&id) else {
+                        continue;
+                    };
+                    let Ok((robot, _, mut joints, _)) = q_robot.get_mut(cell.robot) else {
+                        continue;
+                    };
+                    for (k, position) in positions.iter().enumerate() {
+                        if let Some(position) = position {
+                            joints.0[k] = robot.limits[k].clamp(position.to_degrees());
+                        }
+                    }
+                    bridge.following.insert(id, 0.0);
+                }
+            }
+        }
+
+        for id in bridge.following.keys() {
+            if let Some(mut streaming) = cells
+                .0
+                .get(id)
+                .and_then(|cell| q_robot.get_mut(cell.robot).ok())
+                .map(|(_, _, _, streaming)| streaming)
+            {
+                streaming.0 = true;
+            }
+        }
+    }
+
+    fn publish_tf(
+        time: Res<Time>,
+        mut bridge: ResMut<RosBridge>,
+        cells: Res<RobotCells>,
+        descriptions: Res<Assets<RobotDescription>>,
+        q_robot: Query<(&RobotUr5, &RobotModel, &Transform)>,
+    ) {
+        bridge.since_tf += time.delta_seconds();
+        if bridge.since_tf < TF_PERIOD || !bridge.connected {
+            return;
+        }
+        bridge.since_tf = 0.0;
+        let Some(link) = bridge.link.as_ref() else {
+            return;
+        };
+
+        let mut frames = Vec::new();
+        for cell in cells.0.values() {
+            let Ok((robot, model, base)) = q_robot.get(cell.robot) else {
+                continue;
+            };
+            let Some(description) = descriptions.get(&model.0) else {
+                continue;
+            };
+            frames.extend(robot_frames(
+                &cell.layout.ros_prefix(),
+                base,
+                description,
+                &robot.joints(),
+            ));
+        }
+        if frames.is_empty() {
+            return;
+        }
+        let stamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let _ = link.outgoing.send(tf_message(&frames, stamp).to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot_ur5::JOINTS_POS;
+
+    fn robots() -> Vec<(u64, String, Vec<String>)> {
+        let names = [
+            "shoulder_pan_joint",
+            "shoulder_lift_joint",
+            "elbow_joint",
+            "wrist_1_joint",
+            "wrist_2_joint",
+            "wrist_3_joint",
+        ];
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        vec![
+            (0, "robot0_".to_string(), names.clone()),
+            (1, "left/".to_string(), names),
+        ]
+    }
+
+    #[test]
+    fn parse_and_map_joint_states() {
+        let text = r#"{"op": "publish", "topic": "/joint_states", "msg": {
+            "header": {"stamp": {"sec": 1, "nanosec": 2}, "frame_id": ""},
+            "name": ["left/elbow_joint", "robot0_wrist_3_joint", "gripper_joint", "robot0_elbow_joint"],
+            "position": [1.5, -0.5, 0.02, 0.25], "velocity": [], "effort": []}}"#;
+        let state = parse_joint_state(text).unwrap();
+        let mapped = map_joint_state(&state, &robots());
+        assert_eq!(mapped[&0], [None, None, Some(0.25), None, None, Some(-0.5)]);
+        assert_eq!(mapped[&1], [None, None, Some(1.5), None, None, None]);
+
+        assert_eq!(
+            parse_joint_state(r#"{"op": "publish", "topic": "/tf", "msg": {}}"#),
+            None
+        );
+        assert_eq!(
+            parse_joint_state(r#"{"op": "status", "level": "error"}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn tf_chain_ends_at_the_flange() {
+        let text = include_str!("../assets/ur5/ur5.urdf");
+        let description = RobotDescription::parse(text, "ur5").unwrap();
+        let joints = JOINTS_POS.map(f64::to_radians);
+        let base = Transform::from_xyz(0.5, 0.0, 0.2)
+            .with_rotation(Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2));
+        let frames = robot_frames("robot0_", &base, &description, &joints);
+        assert_eq!(frames.len(), description.joints.len() + 1);
+        assert_eq!(frames[0].0, WORLD_FRAME);
+        assert_eq!(frames[0].1, "robot0_base_link");
+
+        // compose the published frames from the world to the tip link
+        let tip = format!("robot0_{}", description.links[description.tip()].name);
+        let mut frame = tip.as_str();
+        let mut chain = Transform::IDENTITY;
+        while frame != WORLD_FRAME {
+            let (parent, _, tf) = frames.iter().find(|(_, c, _)| c == frame).unwrap();
+            chain = *tf * chain;
+            frame = parent;
+        }
+        // the base is level in ROS, the tip is where the scene puts it, turned z up
+        assert!(frames[0].2.rotation.abs_diff_eq(Quat::IDENTITY, 1e-6));
+        let expected = Transform::from_rotation(Quat::from_rotation_x(std::f32::consts::FRAC_PI_2))
+            * base
+            * description.link_transforms(&joints)[description.tip()];
+        assert!(chain.translation.abs_diff_eq(expected.translation, 1e-5));
+        // q and -q are the same rotation
+        assert!(chain.rotation.dot(expected.rotation).abs() > 1.0 - 1e-5);
+
+        let message = tf_message(&frames, Duration::from_millis(1500));
+        let first = &message["msg"]["transforms"][0];
+        assert_eq!(first["header"]["stamp"]["nanosec"], 500_000_000);
+        assert_eq!(first["child_frame_id"], "robot0_base_link");
+    }
+}
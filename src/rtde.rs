@@ -0,0 +1,166 @@
+// UR Real-Time Data Exchange, protocol version 2.
+// Every packet is a big endian header ( u16 size including the header, u8 type ) and a payload.
+// This is the part both sides share, the client is in rtde_client.rs. Only std is used, the mock
+// controller in src/bin/mock_rtde.rs includes this file too.
+use std::io::{self, Read, Write};
+
+pub const PORT: u16 = 30004;
+pub const PROTOCOL_VERSION: u16 = 2;
+
+// packet types
+pub const REQUEST_PROTOCOL_VERSION: u8 = b'V';
+pub const GET_URCONTROL_VERSION: u8 = b'v';
+pub const DATA_PACKAGE: u8 = b'U';
+pub const CONTROL_PACKAGE_SETUP_OUTPUTS: u8 = b'O';
+pub const CONTROL_PACKAGE_START: u8 = b'S';
+pub const CONTROL_PACKAGE_PAUSE: u8 = b'P';
+
+const HEADER_SIZE: usize = 3;
+
+// the mock controller replays only doubles and vectors
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub enum VarType {
+    Bool,
+    Uint8,
+    Uint32,
+    Uint64,
+    Int32,
+    Double,
+    Vector3D,
+    Vector6D,
+    Vector6Int32,
+    Vector6Uint32,
+}
+
+impl VarType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VarType::Bool => "BOOL",
+            VarType::Uint8 => "UINT8",
+            VarType::Uint32 => "UINT32",
+            VarType::Uint64 => "UINT64",
+            VarType::Int32 => "INT32",
+            VarType::Double => "DOUBLE",
+            VarType::Vector3D => "VECTOR3D",
+            VarType::Vector6D => "VECTOR6D",
+            VarType::Vector6Int32 => "VECTOR6INT32",
+            VarType::Vector6Uint32 => "VECTOR6UINT32",
+        }
+    }
+
+    // ( element count, bytes per element )
+    pub fn layout(&self) -> (usize, usize) {
+        match self {
+            VarType::Bool | VarType::Uint8 => (1, 1),
+            VarType::Uint32 | VarType::Int32 => (1, 4),
+            VarType::Uint64 | VarType::Double => (1, 8),
+            VarType::Vector3D => (3, 8),
+            VarType::Vector6D => (6, 8),
+            VarType::Vector6Int32 | VarType::Vector6Uint32 => (6, 4),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Packet {
+    pub kind: u8,
+    pub payload: Vec<u8>,
+}
+
+pub fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub fn write_packet(w: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let size = HEADER_SIZE + payload.len();
+    let size = u16::try_from(size).map_err(|_| invalid("packet too large"))?;
+    let mut data = Vec::with_capacity(size as usize);
+    data.extend_from_slice(&size.to_be_bytes());
+    data.push(kind);
+    data.extend_from_slice(payload);
+    w.write_all(&data)
+}
+
+// Collects bytes until a packet is complete, so a read timeout never splits a packet
+#[derive(Default)]
+pub struct PacketReader {
+    buf: Vec<u8>,
+}
+
+impl PacketReader {
+    // Out: the next packet, None when the read timed out first
+    pub fn read(&mut self, r: &mut impl Read) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.take()? {
+                return Ok(Some(packet));
+            }
+            let mut chunk = [0u8; 4096];
+            match r.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn take(&mut self) -> io::Result<Option<Packet>> {
+        if self.buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let size = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+        if size < HEADER_SIZE {
+            return Err(invalid(format!("invalid packet size {}", size)));
+        }
+        if self.buf.len() < size {
+            return Ok(None);
+        }
+        let packet = Packet {
+            kind: self.buf[2],
+            payload: self.buf[HEADER_SIZE..size].to_vec(),
+        };
+        self.buf.drain(..size);
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_survive_split_reads() {
+        let mut data = Vec::new();
+        write_packet(&mut data, DATA_PACKAGE, &[1, 2, 3]).unwrap();
+        write_packet(&mut data, CONTROL_PACKAGE_START, &[]).unwrap();
+        assert_eq!(&data[..3], &[0, 6, b'U']);
+
+        // hand the bytes over one at a time, like a slow connection
+        struct Trickle(Vec<u8>);
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                buf[0] = self.0.remove(0);
+                Ok(1)
+            }
+        }
+        let mut r = Trickle(data);
+        let mut reader = PacketReader::default();
+        let first = reader.read(&mut r).unwrap().unwrap();
+        assert_eq!(first.kind, DATA_PACKAGE);
+        assert_eq!(first.payload, vec![1, 2, 3]);
+        let second = reader.read(&mut r).unwrap().unwrap();
+        assert_eq!(second.kind, CONTROL_PACKAGE_START);
+        assert_eq!(reader.read(&mut r).unwrap(), None);
+    }
+}
@@ -0,0 +1,181 @@
+// Client side of the UR Real-Time Data Exchange, the mock controller includes it for its tests.
+use std::{
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use crate::rtde::{
+    invalid, write_packet, Packet, PacketReader, VarType, CONTROL_PACKAGE_PAUSE,
+    CONTROL_PACKAGE_SETUP_OUTPUTS, CONTROL_PACKAGE_START, GET_URCONTROL_VERSION, PROTOCOL_VERSION,
+    REQUEST_PROTOCOL_VERSION,
+};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+const VAR_TYPES: [VarType; 10] = [
+    VarType::Bool,
+    VarType::Uint8,
+    VarType::Uint32,
+    VarType::Uint64,
+    VarType::Int32,
+    VarType::Double,
+    VarType::Vector3D,
+    VarType::Vector6D,
+    VarType::Vector6Int32,
+    VarType::Vector6Uint32,
+];
+
+// comma separated variable types of a setup reply, None for NOT_FOUND
+pub fn parse_types(text: &str) -> Vec<Option<VarType>> {
+    text.split(',')
+        .map(|name| VAR_TYPES.into_iter().find(|t| t.name() == name.trim()))
+        .collect()
+}
+
+// payload of a data package after the recipe id, every variable as f64 values
+pub fn decode_data(types: &[VarType], data: &[u8]) -> io::Result<Vec<Vec<f64>>> {
+    let mut pos = 0;
+    let mut out = Vec::with_capacity(types.len());
+    for t in types {
+        let (count, size) = t.layout();
+        if data.len() < pos + count * size {
+            return Err(invalid("data package too short"));
+        }
+        let values = (0..count)
+            .map(|i| {
+                let b = &data[pos + i * size..pos + (i + 1) * size];
+                match t {
+                    VarType::Bool | VarType::Uint8 => b[0] as f64,
+                    VarType::Uint32 | VarType::Vector6Uint32 => {
+                        u32::from_be_bytes(b.try_into().unwrap()) as f64
+                    }
+                    VarType::Int32 | VarType::Vector6Int32 => {
+                        i32::from_be_bytes(b.try_into().unwrap()) as f64
+                    }
+                    VarType::Uint64 => u64::from_be_bytes(b.try_into().unwrap()) as f64,
+                    _ => f64::from_be_bytes(b.try_into().unwrap()),
+                }
+            })
+            .collect();
+        pos += count * size;
+        out.push(values);
+    }
+    Ok(out)
+}
+
+pub struct RtdeClient {
+    stream: TcpStream,
+    reader: PacketReader,
+    recipe: u8,
+    types: Vec<VarType>,
+}
+
+impl RtdeClient {
+    // address: host:port, negotiates the protocol version
+    pub fn connect(address: &str, timeout: Duration) -> io::Result<Self> {
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| invalid(format!("invalid address {}", address)))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut client = RtdeClient {
+            stream,
+            reader: PacketReader::default(),
+            recipe: 0,
+            types: Vec::new(),
+        };
+        let reply = client.request(REQUEST_PROTOCOL_VERSION, &PROTOCOL_VERSION.to_be_bytes())?;
+        if reply.first() != Some(&1) {
+            return Err(invalid(format!(
+                "protocol version {} not accepted",
+                PROTOCOL_VERSION
+            )));
+        }
+        Ok(client)
+    }
+
+    // Out: ( major, minor, bugfix, build )
+    pub fn controller_version(&mut self) -> io::Result<[u32; 4]> {
+        let reply = self.request(GET_URCONTROL_VERSION, &[])?;
+        parse_version(&reply)
+    }
+
+    // frequency: Hz
+    // Out: type of every variable, None when the controller does not know it
+    pub fn setup_outputs(
+        &mut self,
+        names: &[&str],
+        frequency: f64,
+    ) -> io::Result<Vec<Option<VarType>>> {
+        let mut payload = frequency.to_be_bytes().to_vec();
+        payload.extend_from_slice(names.join(",").as_bytes());
+        let reply = self.request(CONTROL_PACKAGE_SETUP_OUTPUTS, &payload)?;
+        let Some((&recipe, types)) = reply.split_first() else {
+            return Err(invalid("empty setup reply"));
+        };
+        let types = parse_types(&String::from_utf8_lossy(types));
+        self.recipe = recipe;
+        self.types = types.iter().flatten().copied().collect();
+        Ok(types)
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        match self.request(CONTROL_PACKAGE_START, &[])?.first() {
+            Some(1) => Ok(()),
+            _ => Err(invalid("start not accepted")),
+        }
+    }
+
+    pub fn pause(&mut self) -> io::Result<()> {
+        self.request(CONTROL_PACKAGE_PAUSE, &[]).map(|_| ())
+    }
+
+    pub fn send(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        write_packet(&mut self.stream, kind, payload)
+    }
+
+    // Out: the next packet, None when nothing arrived within the read timeout
+    pub fn receive(&mut self) -> io::Result<Option<Packet>> {
+        self.reader.read(&mut self.stream)
+    }
+
+    // Out: the output variables of a data package, in the order of setup_outputs
+    pub fn decode(&self, packet: &Packet) -> io::Result<Vec<Vec<f64>>> {
+        match packet.payload.split_first() {
+            Some((&recipe, data)) if recipe == self.recipe => decode_data(&self.types, data),
+            _ => Err(invalid("data package of an unknown recipe")),
+        }
+    }
+
+    // send a request and wait for the reply of the same type, data packages in between are dropped
+    fn request(&mut self, kind: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+        self.send(kind, payload)?;
+        let start = Instant::now();
+        while start.elapsed() < REPLY_TIMEOUT {
+            if let Some(packet) = self.receive()? {
+                if packet.kind == kind {
+                    return Ok(packet.payload);
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no reply to request '{}'", kind as char),
+        ))
+    }
+}
+
+pub fn parse_version(payload: &[u8]) -> io::Result<[u32; 4]> {
+    if payload.len() < 16 {
+        return Err(invalid("version reply too short"));
+    }
+    let mut version = [0; 4];
+    for (i, v) in version.iter_mut().enumerate() {
+        *v = u32::from_be_bytes(payload[i * 4..i * 4 + 4].try_into().unwrap());
+    }
+    Ok(version)
+}
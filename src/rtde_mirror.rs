@@ -0,0 +1,241 @@
+// Mirrors the joints of a UR controller into a robot cell over RTDE, native only
+use bevy::prelude::*;
+use bevy_egui::egui;
+use flume::{unbounded, Receiver, Sender};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{rtde, rtde_client::RtdeClient};
+
+const FREQUENCY: f64 = 125.0; // Hz, of the data packages
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const PING_PERIOD: Duration = Duration::from_secs(1); // between two round trip measurements
+const OUTPUTS: [&str; 3] = ["timestamp", "actual_q", "actual_TCP_pose"];
+
+// from the connection thread
+enum Update {
+    Connected([u32; 4]), // controller version
+    State {
+        joints: [f64; 6],      // rad
+        tcp: Option<[f64; 6]>, // ( x, y, z ) m, rotation vector rad
+    },
+    Latency(f64), // s, round trip
+    Disconnected(Option<String>),
+}
+
+enum Status {
+    Disconnected(Option<String>), // the error that closed the connection
+    Connecting,
+    Connected([u32; 4]),
+}
+
+struct Link {
+    receiver: Receiver<Update>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Component)]
+pub struct RtdeMirror {
+    pub address: String,
+    link: Option<Link>,
+    status: Status,
+    latency: Option<f64>,     // s
+    rate: f64,                // Hz, of the received data packages
+    received: (u32, f64),     // ( packages, s ) since the rate was updated
+    tcp: Option<[f64; 6]>,    // reported by the controller
+    joints: Option<[f64; 6]>, // deg, not applied yet
+}
+
+impl Default for RtdeMirror {
+    fn default() -> Self {
+        RtdeMirror {
+            address: format!("127.0.0.1:{}", rtde::PORT),
+            link: None,
+            status: Status::Disconnected(None),
+            latency: None,
+            rate: 0.0,
+            received: (0, 0.0),
+            tcp: None,
+            joints: None,
+        }
+    }
+}
+
+impl RtdeMirror {
+    // true while the robot follows the controller
+    pub fn is_active(&self) -> bool {
+        self.link.is_some()
+    }
+
+    pub fn connect(&mut self) {
+        let (sender, receiver) = unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+        let address = self.address.clone();
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            let result = mirror(&address, &thread_stop, &sender);
+            let _ = sender.send(Update::Disconnected(result.err().map(|e| e.to_string())));
+        });
+        self.link = Some(Link { receiver, stop });
+        self.status = Status::Connecting;
+        self.latency = None;
+        self.rate = 0.0;
+        self.received = (0, 0.0);
+        self.tcp = None;
+    }
+
+    pub fn disconnect(&mut self) {
+        self.link = None;
+        self.status = Status::Disconnected(None);
+    }
+
+    // dt: s
+    // Out: the latest joints of the controller, deg
+    pub fn update(&mut self, dt: f64) -> Option<[f64; 6]> {
+        let link = self.link.as_ref()?;
+        let mut closed = None;
+        for update in link.receiver.try_iter() {
+            match update {
+                Update::Connected(version) => self.status = Status::Connected(version),
+                Update::State { joints, tcp } => {
+                    self.joints = Some(joints.map(f64::to_degrees));
+                    self.tcp = tcp;
+                    self.received.0 += 1;
+                }
+                Update::Latency(latency) => self.latency = Some(latency),
+                Update::Disconnected(error) => closed = Some(error),
+            }
+        }
+        if let Some(error) = closed {
+            self.link = None;
+            self.status = Status::Disconnected(error);
+        }
+
+        self.received.1 += dt;
+        if self.received.1 >= 1.0 {
+            self.rate = self.received.0 as f64 / self.received.1;
+            self.received = (0, 0.0);
+        }
+        self.joints.take()
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.add_enabled(
+                !self.is_active(),
+                egui::TextEdit::singleline(&mut self.address).desired_width(140.0),
+            );
+            if self.is_active() {
+                if ui.button("disconnect").clicked() {
+                    self.disconnect();
+                }
+            } else if ui.button("connect").clicked() {
+                self.connect();
+            }
+        });
+        match &self.status {
+            Status::Disconnected(None) => {
+                ui.label("disconnected");
+            }
+            Status::Disconnected(Some(error)) => {
+                ui.colored_label(egui::Color32::RED, format!("disconnected: {}", error));
+            }
+            Status::Connecting => {
+                ui.label("connecting…");
+            }
+            Status::Connected(v) => {
+                ui.label(format!(
+                    "connected, URControl {}.{}.{}.{}",
+                    v[0], v[1], v[2], v[3]
+                ));
+                ui.label(format!(
+                    "latency {}, {:.0} Hz",
+                    self.latency
+                        .map_or("-".to_string(), |l| format!("{:.1} ms", l * 1000.0)),
+                    self.rate
+                ));
+                if let Some(tcp) = self.tcp {
+                    ui.label(format!(
+                        "TCP {:.1} {:.1} {:.1} mm",
+                        tcp[0] * 1000.0,
+                        tcp[1] * 1000.0,
+                        tcp[2] * 1000.0
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// runs on the connection thread until stopped or the connection fails
+fn mirror(address: &str, stop: &AtomicBool, updates: &Sender<Update>) -> io::Result<()> {
+    let mut client = RtdeClient::connect(address, CONNECT_TIMEOUT)?;
+    let version = client.controller_version()?;
+
+    // the tcp pose is optional
+    let mut outputs = OUTPUTS.to_vec();
+    let mut types = client.setup_outputs(&outputs, FREQUENCY)?;
+    if types[2].is_none() {
+        outputs.pop();
+        types = client.setup_outputs(&outputs, FREQUENCY)?;
+    }
+    if let Some(i) = types.iter().position(|t| t.is_none()) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("the controller has no output {}", outputs[i]),
+        ));
+    }
+    client.start()?;
+    if updates.send(Update::Connected(version)).is_err() {
+        return Ok(());
+    }
+
+    let mut ping: Option<Instant> = None;
+    let mut last_ping = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        if ping.is_none() && last_ping.elapsed() >= PING_PERIOD {
+            client.send(rtde::GET_URCONTROL_VERSION, &[])?;
+            ping = Some(Instant::now());
+            last_ping = Instant::now();
+        }
+        let Some(packet) = client.receive()? else {
+            continue;
+        };
+        let update = match packet.kind {
+            rtde::DATA_PACKAGE => {
+                let values = client.decode(&packet)?;
+                let vector = |v: &Vec<f64>| -> Option<[f64; 6]> { v.as_slice().try_into().ok() };
+                let Some(joints) = vector(&values[1]) else {
+                    continue;
+                };
+                Update::State {
+                    joints,
+                    tcp: values.get(2).and_then(vector),
+                }
+            }
+            rtde::GET_URCONTROL_VERSION => match ping.take() {
+                Some(sent) => Update::Latency(sent.elapsed().as_secs_f64()),
+                None => continue,
+            },
+            _ => continue,
+        };
+        if updates.send(update).is_err() {
+            break;
+        }
+    }
+    let _ = client.pause();
+    Ok(())
+}
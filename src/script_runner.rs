@@ -0,0 +1,427 @@
+use bevy::prelude::*;
+use bevy_egui::egui;
+use nalgebra::Isometry3;
+
+use crate::{
+    motion::{Blend, Motion},
+    robot_ur5::RobotUr5,
+    urscript::{Move, MoveKind, ScriptError, Target, Vm, Yield},
+};
+
+const OPS_PER_FRAME: usize = 10000; // a script looping without motion yields after that many ops
+const MAX_MESSAGES: usize = 50;
+
+const EXAMPLE: &str = "def demo():
+  home = get_actual_joint_positions()
+  i = 0
+  while i < 2:
+    start = get_actual_tcp_pose()
+    movel(pose_trans(start, p[0.1, 0, 0, 0, 0, 0]), a=1.2, v=0.25)
+    movel(pose_trans(start, p[0.1, 0, 0.1, 0, 0, 0]), a=1.2, v=0.25, r=0.02)
+    movel(start, a=1.2, v=0.25)
+    sleep(0.5)
+    i = i + 1
+  end
+  movej(home, a=1.4, v=1.05)
+  textmsg(\"done after \", i, \" rounds\")
+end
+";
+
+// Runs a URScript program against the simulated robot
+#[derive(Component)]
+pub struct ScriptRunner {
+    pub source: String,
+    vm: Option<Vm>,
+    tool: Isometry3<f64>,          // tcp set by set_tcp, relative to flange
+    motion: Option<(Motion, f64)>, // ( motion, blend radius mm )
+    blend: Option<Blend>,          // the move before, running out under the current one
+    // what the script does after the current move, looked ahead at its blend radius
+    next: Option<Result<Yield, ScriptError>>,
+    sleep: f64, // s
+    error: Option<ScriptError>,
+    messages: Vec<String>,
+}
+
+impl Default for ScriptRunner {
+    fn default() -> Self {
+        ScriptRunner {
+            source: EXAMPLE.to_string(),
+            vm: None,
+            tool: Isometry3::identity(),
+            motion: None,
+            blend: None,
+            next: None,
+            sleep: 0.0,
+            error: None,
+            messages: Vec::new(),
+        }
+    }
+}
+
+impl ScriptRunner {
+    pub fn is_running(&self) -> bool {
+        self.vm.is_some()
+    }
+
+    pub fn run(&mut self, robot: &RobotUr5) {
+        self.stop();
+        self.error = None;
+        self.messages.clear();
+        match Vm::compile(&self.source) {
+            Ok(vm) => {
+                self.vm = Some(vm);
+                self.tool = robot.tool;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.vm = None;
+        self.motion = None;
+        self.blend = None;
+        self.next = None;
+        self.sleep = 0.0;
+    }
+
+    pub fn error(&self) -> Option<&ScriptError> {
+        self.error.as_ref()
+    }
+
+    // line being executed, 1 based
+    pub fn line(&self) -> Option<usize> {
+        self.vm.as_ref().map(|vm| vm.line())
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui, robot: &RobotUr5) {
+        ui.horizontal(|ui| {
+            if ui.button("run").clicked() {
+                self.run(robot);
+            }
+            if ui
+                .add_enabled(self.is_running(), egui::Button::new("stop"))
+                .clicked()
+            {
+                self.stop();
+            }
+            if let Some(line) = self.line() {
+                ui.label(format!("line {}", line));
+            }
+        });
+
+        let running = self.is_running();
+        let current = self.line();
+        let error = self.error.as_ref().map(|e| e.line);
+        let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+            let mut job = egui::text::LayoutJob::default();
+            job.wrap.max_width = wrap_width;
+            for (i, line) in text.split_inclusive('\n').enumerate() {
+                let background = if Some(i + 1) == error {
+                    egui::Color32::from_rgb(96, 32, 32)
+                } else if Some(i + 1) == current {
+                    egui::Color32::from_rgb(32, 64, 96)
+                } else {
+                    egui::Color32::TRANSPARENT
+                };
+                job.append(
+                    line,
+                    0.0,
+                    egui::TextFormat {
+                        font_id: egui::FontId::monospace(12.0),
+                        color: ui.visuals().text_color(),
+                        background,
+                        ..Default::default()
+                    },
+                );
+            }
+            ui.fonts(|f| f.layout_job(job))
+        };
+        egui::ScrollArea::vertical()
+            .max_height(240.0)
+            .show(ui, |ui| {
+                ui.add(
+                    egui::TextEdit::multiline(&mut self.source)
+                        .code_editor()
+                        .desired_width(f32::INFINITY)
+                        .interactive(!running)
+                        .layouter(&mut layouter),
+                );
+            });
+
+        if let Some(error) = &self.error {
+            ui.colored_label(egui::Color32::RED, error.to_string());
+        }
+        for message in self.messages.iter() {
+            ui.label(message);
+        }
+    }
+
+    // dt: s
+    // Out: the next joints target, deg
+    pub fn step(&mut self, robot: &RobotUr5, dt: f64) -> Option<[f64; 6]> {
+        let mut sim = robot.clone();
+        sim.tool = self.tool;
+
+        if self.motion.is_some() {
+            return self.step_motion(&mut sim, dt);
+        }
+        if self.sleep > 0.0 {
+            self.sleep -= dt;
+            return None;
+        }
+
+        loop {
+            let result = match self.next.take() {
+                Some(result) => result,
+                None => self.resume(&mut sim)?,
+            };
+            match result {
+                Ok(Yield::Message(text)) => self.message(text),
+                Ok(Yield::Sleep(t)) => {
+                    self.sleep = t;
+                    return None;
+                }
+                Ok(Yield::Move(m)) => {
+                    self.start(&sim, &m);
+                    return None;
+                }
+                Ok(Yield::Budget) => return None,
+                Ok(Yield::Done) => {
+                    self.stop();
+                    return None;
+                }
+                Err(e) => {
+                    self.error = Some(e);
+                    self.stop();
+                    return None;
+                }
+            }
+        }
+    }
+
+    // sim: the simulated robot, with the script tcp
+    fn step_motion(&mut self, sim: &mut RobotUr5, dt: f64) -> Option<[f64; 6]> {
+        let (motion, radius) = self.motion.as_mut()?;
+        let radius = *radius;
+        let (mut joints, remaining, mut done) = match motion.step(sim, dt) {
+            Ok(step) => step,
+            Err(e) => return self.fail(e.to_string()),
+        };
+        if let Some(blend) = self.blend.as_mut() {
+            match blend.step(sim, joints, dt) {
+                Ok((blended, blend_done)) => {
+                    joints = blended;
+                    if blend_done {
+                        self.blend = None;
+                    } else {
+                        done = false;
+                    }
+                }
+                Err(e) => return self.fail(e.to_string()),
+            }
+        }
+        if done {
+            self.motion = None;
+        } else if remaining <= radius && self.blend.is_none() && self.next.is_none() {
+            // the next move takes over if there is one, anything else waits for the end
+            loop {
+                match self.resume(sim) {
+                    Some(Ok(Yield::Message(text))) => self.message(text),
+                    Some(Ok(Yield::Move(m))) => {
+                        let Some((motion, _)) = self.motion.take() else {
+                            break;
+                        };
+                        match Blend::new(motion, sim) {
+                            Ok(blend) => self.blend = Some(blend),
+                            Err(e) => return self.fail(e.to_string()),
+                        }
+                        self.start(sim, &m);
+                        break;
+                    }
+                    Some(Ok(Yield::Budget)) | None => break,
+                    Some(result) => {
+                        self.next = Some(result);
+                        break;
+                    }
+                }
+            }
+        }
+        Some(joints)
+    }
+
+    // runs the script to its next yield
+    fn resume(&mut self, sim: &mut RobotUr5) -> Option<Result<Yield, ScriptError>> {
+        let vm = self.vm.as_mut()?;
+        let result = vm.run(sim, OPS_PER_FRAME);
+        self.tool = sim.tool;
+        Some(result)
+    }
+
+    fn message(&mut self, text: String) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push(text);
+    }
+
+    // a blended move starts where the one before ends
+    fn start(&mut self, sim: &RobotUr5, m: &Move) {
+        let mut from = sim.clone();
+        if let Some(blend) = &self.blend {
+            from.set_deg(blend.end());
+        }
+        match start_motion(&from, m) {
+            Ok(motion) => self.motion = Some((motion, m.r * 1000.0)),
+            Err(message) => {
+                let line = self.line().unwrap_or(1);
+                self.error = Some(ScriptError { line, message });
+                self.stop();
+            }
+        }
+    }
+
+    fn fail(&mut self, message: String) -> Option<[f64; 6]> {
+        let line = self.line().unwrap_or(1);
+        self.error = Some(ScriptError { line, message });
+        self.stop();
+        None
+    }
+}
+
+// robot: the simulated robot, with the script tcp
+fn start_motion(robot: &RobotUr5, m: &Move) -> Result<Motion, String> {
+    let joints = robot.joints();
+    match m.kind {
+        MoveKind::J => {
+            let target = match m.target {
+                Target::Joints(q) => q,
+                Target::Pose(pose) => robot
+                    .ik_near(&(pose * robot.tool.inverse()), &joints)
+                    .map_err(|e| e.to_string())?,
+            };
+            let target = robot.clamp_deg(target.map(f64::to_degrees));
+            let distance = target
+                .iter()
+                .zip(joints.iter())
+                .map(|(t, j)| (t - j.to_degrees()).abs())
+                .fold(0.0, f64::max);
+            let (velocity, acceleration) = if m.t > 0.0 {
+                // the move takes t seconds on a triangle profile
+                let v = 2.0 * distance / m.t;
+                ([v; 6], [2.0 * v / m.t; 6])
+            } else {
+                (
+                    robot.limits.map(|l| l.velocity.min(m.v.to_degrees())),
+                    robot.limits.map(|l| l.acceleration.min(m.a.to_degrees())),
+                )
+            };
+            Ok(Motion::joint(robot, target, &velocity, &acceleration))
+        }
+        // movep keeps the tool speed constant through blends on the controller,
+        // here it moves linearly like movel
+        MoveKind::L | MoveKind::P => {
+            let target = match m.target {
+                Target::Pose(pose) => pose,
+                Target::Joints(q) => robot.flange_at(q) * robot.tool,
+            };
+            let (velocity, acceleration) = if m.t > 0.0 {
+                let start = robot.tcp_pose();
+                let length = [
+                    (target.translation.vector - start.translation.vector).norm() * 1000.0,
+                    start.rotation.angle_to(&target.rotation).to_degrees(),
+                ];
+                let v = length.map(|l| 2.0 * l / m.t);
+                (v, v.map(|v| 2.0 * v / m.t))
+            } else {
+                (
+                    [m.v * 1000.0, m.v.to_degrees()],
+                    [m.a * 1000.0, m.a.to_degrees()],
+                )
+            };
+            Motion::linear(robot, target, velocity, acceleration).map_err(|e| e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot_ur5::JOINTS_POS;
+
+    // step the runner like update_program, streaming every target to the robot
+    fn run(source: &str) -> (ScriptRunner, RobotUr5) {
+        run_with(source, |_| {})
+    }
+
+    // each: called with the robot after every frame
+    fn run_with(source: &str, mut each: impl FnMut(&RobotUr5)) -> (ScriptRunner, RobotUr5) {
+        let mut robot = RobotUr5::new(0, JOINTS_POS.map(f64::to_radians));
+        let mut runner = ScriptRunner {
+            source: source.to_string(),
+            ..Default::default()
+        };
+        runner.run(&robot);
+        for _ in 0..10000 {
+            if !runner.is_running() {
+                break;
+            }
+            if let Some(joints) = runner.step(&robot, 0.01) {
+                robot.set_deg(joints);
+            }
+            each(&robot);
+        }
+        (runner, robot)
+    }
+
+    #[test]
+    fn example_runs_to_the_end() {
+        let (runner, robot) = run(EXAMPLE);
+        assert_eq!(runner.error, None);
+        assert!(!runner.is_running());
+        assert_eq!(runner.messages, vec!["done after 2 rounds".to_string()]);
+        for (j, home) in robot.joints().iter().zip(JOINTS_POS) {
+            assert!((j.to_degrees() - home).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn movel_reaches_pose_with_tcp() {
+        let (runner, _) = run("set_tcp(p[0, 0, 0.1, 0, 0, 0])
+target = pose_trans(get_actual_tcp_pose(), p[0, 0.05, 0.05, 0, 0, 0])
+movel(target, t=1.5)
+textmsg(pose_dist(get_actual_tcp_pose(), target) < 0.000001)
+");
+        assert_eq!(runner.error, None);
+        assert_eq!(runner.messages, vec!["True".to_string()]);
+    }
+
+    #[test]
+    fn unreachable_move_reports_its_line() {
+        let (runner, _) = run("x = 1\n\nmovel(p[5, 0, 0, 0, 0, 0])\n");
+        assert_eq!(runner.error.map(|e| e.line), Some(3));
+    }
+
+    #[test]
+    fn blend_radius_keeps_the_velocity() {
+        let dt = 0.01;
+        let mut points = Vec::new();
+        let (runner, _) = run_with(
+            "start = get_actual_tcp_pose()
+corner = pose_trans(start, p[0.1, 0, 0, 0, 0, 0])
+end = pose_trans(start, p[0.1, 0.1, 0, 0, 0, 0])
+movel(corner, a=1.2, v=0.25, r=0.03)
+textmsg(\"blending\")
+movel(end, a=1.2, v=0.25)
+textmsg(pose_dist(get_actual_tcp_pose(), end) < 0.000001)
+",
+            |robot| points.push(robot.tcp_pose().translation.vector),
+        );
+        assert_eq!(runner.error, None);
+        assert_eq!(runner.messages, vec!["blending", "True"]);
+        // the tcp velocity changes no faster than both moves accelerate together
+        for w in points.windows(3) {
+            let acceleration = ((w[2] - w[1]) - (w[1] - w[0])).norm() / (dt * dt);
+            assert!(acceleration <= 2.0 * 1.2 * 1.01, "{} m/s^2", acceleration);
+        }
+    }
+}
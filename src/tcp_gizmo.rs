@@ -0,0 +1,288 @@
+use bevy::{ecs::system::SystemParam, prelude::*, window::PrimaryWindow};
+use bevy_egui::EguiContexts;
+use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraSystemSet};
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+
+use crate::robot_ur5::{RobotUr5, TcpPose};
+
+const AXIS_LENGTH: f32 = 0.12; // m
+const RING_RADIUS: f32 = 0.08; // m
+const RING_SEGMENTS: usize = 32;
+const PICK_DISTANCE: f32 = 8.0; // px
+
+pub struct TcpGizmoPlugin;
+
+impl Plugin for TcpGizmoPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(TcpGizmo { enabled: true })
+            .init_resource::<TcpDrag>()
+            .add_event::<TcpDragged>()
+            .add_systems(
+                Update,
+                (
+                    // a grab turns the orbit off before the camera sees the press
+                    TcpGizmoPlugin::drag_handles.before(PanOrbitCameraSystemSet),
+                    TcpGizmoPlugin::draw_handles,
+                )
+                    .chain(),
+            );
+    }
+}
+
+#[derive(Resource)]
+pub struct TcpGizmo {
+    pub enabled: bool,
+}
+
+// The flange of robot `id` was dragged to `target`, relative to robot base
+#[derive(Event)]
+pub struct TcpDragged {
+    pub id: u64,
+    pub target: Isometry3<f64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum GizmoHandle {
+    Translate(usize),
+    Rotate(usize),
+}
+
+struct Grab {
+    robot: Entity,
+    handle: GizmoHandle,
+    start: Transform, // tcp in world
+    start_param: f32, // position along the axis or angle around it
+}
+
+#[derive(Resource, Default)]
+struct TcpDrag {
+    hovered: Option<(Entity, GizmoHandle)>,
+    grab: Option<Grab>,
+}
+
+// cursor and mouse buttons, unless egui has them
+#[derive(SystemParam)]
+struct Pointer<'w, 's> {
+    contexts: EguiContexts<'w, 's>,
+    mouse: Res<'w, Input<MouseButton>>,
+    q_window: Query<'w, 's, &'static Window, With<PrimaryWindow>>,
+}
+
+const AXES: [Vec3; 3] = [Vec3::X, Vec3::Y, Vec3::Z];
+const COLORS: [Color; 3] = [Color::RED, Color::GREEN, Color::BLUE];
+
+impl TcpGizmoPlugin {
+    fn drag_handles(
+        gizmo: Res<TcpGizmo>,
+        mut drag: ResMut<TcpDrag>,
+        mut events: EventWriter<TcpDragged>,
+        mut pointer: Pointer,
+        mut q_camera: Query<(&Camera, &GlobalTransform, &mut PanOrbitCamera)>,
+        q_robot: Query<(Entity, &RobotUr5, &GlobalTransform, &TcpPose)>,
+    ) {
+        let Ok((camera, camera_gt, mut orbit)) = q_camera.get_single_mut() else {
+            return;
+        };
+        let cursor = pointer
+            .q_window
+            .get_single()
+            .ok()
+            .and_then(|w| w.cursor_position());
+
+        if (!gizmo.enabled || pointer.mouse.just_released(MouseButton::Left))
+            && drag.grab.take().is_some()
+        {
+            orbit.enabled = true;
+        }
+        drag.hovered = None;
+        let (Some(cursor), true) = (cursor, gizmo.enabled) else {
+            return;
+        };
+        let Some(ray) = camera.viewport_to_world(camera_gt, cursor) else {
+            return;
+        };
+
+        if let Some(grab) = &drag.grab {
+            let Ok((_, robot, robot_gt, _)) = q_robot.get(grab.robot) else {
+                return;
+            };
+            let Some(param) = handle_param(grab.handle, &grab.start, &ray) else {
+                return;
+            };
+            let mut tf = grab.start;
+            match grab.handle {
+                GizmoHandle::Translate(axis) => {
+                    tf.translation += AXES[axis] * (param - grab.start_param);
+                }
+                GizmoHandle::Rotate(axis) => {
+                    let angle = param - grab.start_param;
+                    let angle = angle.sin().atan2(angle.cos());
+                    let rotation = Quat::from_axis_angle(AXES[axis], angle);
+                    tf.rotation = rotation * tf.rotation;
+                }
+            }
+            let local = robot_gt.affine().inverse() * tf.compute_affine();
+            let (_, rotation, translation) = local.to_scale_rotation_translation();
+            let tcp = to_isometry(translation, rotation);
+            events.send(TcpDragged {
+                id: robot.id,
+                target: tcp * robot.tool.inverse(),
+            });
+            return;
+        }
+
+        if pointer.contexts.ctx_mut().is_pointer_over_area() {
+            return;
+        }
+        let mut best: Option<(f32, Entity, GizmoHandle)> = None;
+        for (entity, _, _, tcp_pose) in q_robot.iter() {
+            let center = tcp_pose.tcp.translation;
+            for (axis, direction) in AXES.iter().enumerate() {
+                let end = center + *direction * AXIS_LENGTH;
+                let d = screen_distance(camera, camera_gt, cursor, &[center, end]);
+                if d < best.map_or(PICK_DISTANCE, |b| b.0) {
+                    best = Some((d, entity, GizmoHandle::Translate(axis)));
+                }
+                let ring = ring_points(center, axis);
+                let d = screen_distance(camera, camera_gt, cursor, &ring);
+                if d < best.map_or(PICK_DISTANCE, |b| b.0) {
+                    best = Some((d, entity, GizmoHandle::Rotate(axis)));
+                }
+            }
+        }
+        let Some((_, entity, handle)) = best else {
+            return;
+        };
+        drag.hovered = Some((entity, handle));
+
+        if pointer.mouse.just_pressed(MouseButton::Left) {
+            let Ok((_, _, _, tcp_pose)) = q_robot.get(entity) else {
+                return;
+            };
+            let start = tcp_pose.tcp;
+            if let Some(start_param) = handle_param(handle, &start, &ray) {
+                orbit.enabled = false;
+                drag.grab = Some(Grab {
+                    robot: entity,
+                    handle,
+                    start,
+                    start_param,
+                });
+            }
+        }
+    }
+
+    fn draw_handles(
+        gizmo: Res<TcpGizmo>,
+        drag: Res<TcpDrag>,
+        mut gizmos: Gizmos,
+        q_robot: Query<(Entity, &TcpPose)>,
+    ) {
+        if !gizmo.enabled {
+            return;
+        }
+        let active = drag
+            .grab
+            .as_ref()
+            .map(|g| (g.robot, g.handle))
+            .or(drag.hovered);
+        for (entity, tcp_pose) in q_robot.iter() {
+            let center = tcp_pose.tcp.translation;
+            for axis in 0..3 {
+                let color = |handle| {
+                    if active == Some((entity, handle)) {
+                        Color::YELLOW
+                    } else {
+                        COLORS[axis]
+                    }
+                };
+                gizmos.line(
+                    center,
+                    center + AXES[axis] * AXIS_LENGTH,
+                    color(GizmoHandle::Translate(axis)),
+                );
+                gizmos.circle(
+                    center,
+                    AXES[axis],
+                    RING_RADIUS,
+                    color(GizmoHandle::Rotate(axis)),
+                );
+            }
+        }
+    }
+}
+
+// Translate: position of the cursor ray projected on the axis, m
+// Rotate: angle of the cursor ray hit on the ring plane, rad
+fn handle_param(handle: GizmoHandle, start: &Transform, ray: &Ray) -> Option<f32> {
+    let center = start.translation;
+    match handle {
+        GizmoHandle::Translate(axis) => {
+            let a = AXES[axis];
+            let w = center - ray.origin;
+            let b = a.dot(ray.direction);
+            let denom = 1.0 - b * b;
+            if denom < 1e-4 {
+                return None;
+            }
+            Some((b * ray.direction.dot(w) - a.dot(w)) / denom)
+        }
+        GizmoHandle::Rotate(axis) => {
+            let n = AXES[axis];
+            let t = ray.intersect_plane(center, n)?;
+            let v = ray.get_point(t) - center;
+            let u = n.any_orthonormal_vector();
+            Some(n.cross(u).dot(v).atan2(u.dot(v)))
+        }
+    }
+}
+
+fn ring_points(center: Vec3, axis: usize) -> Vec<Vec3> {
+    let n = AXES[axis];
+    let u = n.any_orthonormal_vector();
+    let v = n.cross(u);
+    (0..=RING_SEGMENTS)
+        .map(|i| {
+            let a = i as f32 / RING_SEGMENTS as f32 * std::f32::consts::TAU;
+            center + (u * a.cos() + v * a.sin()) * RING_RADIUS
+        })
+        .collect()
+}
+
+// shortest distance from the cursor to a polyline on screen, px
+fn screen_distance(
+    camera: &Camera,
+    camera_gt: &GlobalTransform,
+    cursor: Vec2,
+    points: &[Vec3],
+) -> f32 {
+    let mut out = f32::MAX;
+    let screen: Vec<Option<Vec2>> = points
+        .iter()
+        .map(|p| camera.world_to_viewport(camera_gt, *p))
+        .collect();
+    for pair in screen.windows(2) {
+        if let (Some(a), Some(b)) = (pair[0], pair[1]) {
+            let ab = b - a;
+            let t = ((cursor - a).dot(ab) / ab.length_squared().max(1e-6)).clamp(0.0, 1.0);
+            out = out.min(cursor.distance(a + ab * t));
+        }
+    }
+    out
+}
+
+fn to_isometry(translation: Vec3, rotation: Quat) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::new(
+            translation.x as f64,
+            translation.y as f64,
+            translation.z as f64,
+        ),
+        UnitQuaternion::from_quaternion(Quaternion::new(
+            rotation.w as f64,
+            rotation.x as f64,
+            rotation.y as f64,
+            rotation.z as f64,
+        )),
+    )
+}
@@ -0,0 +1,322 @@
+// Tool changer: tools are parked in the slots of racks standing in the cell. A robot detaches its
+// tool into the free slot its tool mount is at and attaches the parked tool its flange is at,
+// the tcp follows the mounted tool.
+use bevy::prelude::*;
+use nalgebra::Isometry3;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{
+    cell_layout::{Pose, RackLayout, ToolLayout},
+    collision::Collider,
+    end_effector::{GripperFingertip, Tool, ToolPart},
+    robot_cell::{RobotCellPlugin, RobotCells},
+    robot_ur5::RobotUr5,
+};
+
+const REACH_DISTANCE: f32 = 0.02; // m, between the tool mount and the slot
+const REACH_ANGLE: f32 = 10.0; // deg
+const RACK_COLOR: Color = Color::rgb(0.45, 0.5, 0.55);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ToolChange {
+    Detach, // park the mounted tool in the slot at the tool mount
+    Attach, // pick up the parked tool at the flange
+}
+
+impl ToolChange {
+    pub const ALL: [ToolChange; 2] = [ToolChange::Detach, ToolChange::Attach];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToolChange::Detach => "detach",
+            ToolChange::Attach => "attach",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ToolChangeError {
+    UnknownRobot,
+    NoTool,        // nothing to detach
+    ToolMounted,   // detach the tool before attaching another
+    NoFreeSlot,    // no free slot at the tool mount
+    NoToolInReach, // no parked tool at the flange
+}
+
+impl std::fmt::Display for ToolChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            ToolChangeError::UnknownRobot => "unknown robot",
+            ToolChangeError::NoTool => "no tool mounted",
+            ToolChangeError::ToolMounted => "a tool is already mounted",
+            ToolChangeError::NoFreeSlot => "no free rack slot at the tool",
+            ToolChangeError::NoToolInReach => "no parked tool at the flange",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+// a place for one tool in a rack
+#[derive(Component, Default)]
+pub struct ToolSlot {
+    pub tool: Option<Entity>,
+}
+
+#[derive(Component)]
+struct ToolRack;
+
+// result of the last tool change of each robot
+#[derive(Resource, Default)]
+pub struct ToolChanger {
+    errors: BTreeMap<u64, ToolChangeError>,
+}
+
+impl ToolChanger {
+    pub fn error(&self, id: u64) -> Option<ToolChangeError> {
+        self.errors.get(&id).copied()
+    }
+}
+
+pub struct ToolChangerPlugin;
+
+impl Plugin for ToolChangerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ToolChanger>();
+    }
+}
+
+// a tool mount at a slot, or a flange at a parked tool
+fn within_reach(a: &Transform, b: &Transform) -> bool {
+    a.translation.distance(b.translation) <= REACH_DISTANCE
+        && a.rotation.angle_between(b.rotation) <= REACH_ANGLE.to_radians()
+}
+
+impl ToolChangerPlugin {
+    // the result is kept for the robot window
+    pub fn change(world: &mut World, id: u64, change: ToolChange) -> Result<(), ToolChangeError> {
+        let result = match change {
+            ToolChange::Detach => ToolChangerPlugin::detach(world, id),
+            ToolChange::Attach => ToolChangerPlugin::attach(world, id),
+        };
+        let mut changer = world.resource_mut::<ToolChanger>();
+        match result {
+            Ok(()) => changer.errors.remove(&id),
+            Err(e) => changer.errors.insert(id, e),
+        };
+        result
+    }
+
+    // parks the tool in the nearest free slot within reach of its mount
+    pub fn detach(world: &mut World, id: u64) -> Result<(), ToolChangeError> {
+        let cell = world
+            .resource::<RobotCells>()
+            .0
+            .get(&id)
+            .cloned()
+            .ok_or(ToolChangeError::UnknownRobot)?;
+        let tool = cell.tool.ok_or(ToolChangeError::NoTool)?;
+        let mount = world
+            .get::<GlobalTransform>(tool)
+            .ok_or(ToolChangeError::NoTool)?
+            .compute_transform();
+        let slot = world
+            .query::<(Entity, &ToolSlot, &GlobalTransform)>()
+            .iter(world)
+            .map(|(entity, slot, tf)| (entity, slot, tf.compute_transform()))
+            .filter(|(_, slot, tf)| slot.tool.is_none() && within_reach(&mount, tf))
+            .min_by(|(_, _, a), (_, _, b)| {
+                let da = a.translation.distance(mount.translation);
+                let db = b.translation.distance(mount.translation);
+                da.total_cmp(&db)
+            })
+            .map(|(entity, _, _)| entity)
+            .ok_or(ToolChangeError::NoFreeSlot)?;
+
+        world.entity_mut(slot).push_children(&[tool]);
+        world.entity_mut(tool).insert(Transform::IDENTITY);
+        if let Some(mut slot) = world.get_mut::<ToolSlot>(slot) {
+            slot.tool = Some(tool);
+        }
+        ToolChangerPlugin::set_robot(world, tool, None);
+        if let Some(mut robot) = world.get_mut::<RobotUr5>(cell.robot) {
+            robot.tool = Isometry3::identity();
+        }
+        if let Some(cell) = world.resource_mut::<RobotCells>().0.get_mut(&id) {
+            cell.tool = None;
+            cell.layout.tool = None;
+        }
+        Ok(())
+    }
+
+    // mounts the nearest parked tool within reach of the flange
+    pub fn attach(world: &mut World, id: u64) -> Result<(), ToolChangeError> {
+        let cell = world
+            .resource::<RobotCells>()
+            .0
+            .get(&id)
+            .cloned()
+            .ok_or(ToolChangeError::UnknownRobot)?;
+        if cell.tool.is_some() {
+            return Err(ToolChangeError::ToolMounted);
+        }
+        let flange = world
+            .get::<GlobalTransform>(cell.wrist)
+            .ok_or(ToolChangeError::UnknownRobot)?
+            .compute_transform();
+        let (tool, kind, slot) = world
+            .query::<(Entity, &Tool, &GlobalTransform, &Parent)>()
+            .iter(world)
+            .map(|(entity, tool, tf, parent)| (entity, tool, tf.compute_transform(), parent))
+            .filter(|(_, tool, tf, _)| tool.robot.is_none() && within_reach(&flange, tf))
+            .min_by(|(_, _, a, _), (_, _, b, _)| {
+                let da = a.translation.distance(flange.translation);
+                let db = b.translation.distance(flange.translation);
+                da.total_cmp(&db)
+            })
+            .map(|(entity, tool, _, parent)| (entity, tool.kind, parent.get()))
+            .ok_or(ToolChangeError::NoToolInReach)?;
+
+        if let Some(mut slot) = world.get_mut::<ToolSlot>(slot) {
+            slot.tool = None;
+        }
+        world.entity_mut(cell.wrist).push_children(&[tool]);
+        world.entity_mut(tool).insert(Transform::IDENTITY);
+        ToolChangerPlugin::set_robot(world, tool, Some(id));
+        let layout = ToolLayout {
+            kind,
+            offset: Pose::default(),
+        };
+        if let Some(mut robot) = world.get_mut::<RobotUr5>(cell.robot) {
+            robot.tool = RobotCellPlugin::tcp(&layout);
+        }
+        if let Some(cell) = world.resource_mut::<RobotCells>().0.get_mut(&id) {
+            cell.tool = Some(tool);
+            cell.layout.tool = Some(layout);
+        }
+        Ok(())
+    }
+
+    // a mounted tool has fingertip markers and colliders, a parked one has neither,
+    // the colliders are added back by the collision plugin
+    fn set_robot(world: &mut World, tool: Entity, robot: Option<u64>) {
+        let Some(mut t) = world.get_mut::<Tool>(tool) else {
+            return;
+        };
+        t.robot = robot;
+        let parts = t.kind.end_effector().parts();
+        let children: Vec<Entity> = world
+            .get::<Children>(tool)
+            .map(|c| c.iter().copied().collect())
+            .unwrap_or_default();
+        for child in children {
+            let Some(index) = world.get::<ToolPart>(child).map(|p| p.0) else {
+                continue;
+            };
+            let mut part = world.entity_mut(child);
+            part.remove::<(Collider, GripperFingertip)>();
+            if let (Some(id), Some(finger)) = (robot, parts.get(index).and_then(|d| d.fingertip)) {
+                part.insert(GripperFingertip { id, finger });
+            }
+        }
+    }
+
+    // a post and a beam behind the slots, with the parked tools
+    // racks are not checked for collisions, the approach of the robots is left to the program
+    pub fn spawn_rack(world: &mut World, rack: &RackLayout) {
+        let slots: Vec<Vec3> = rack
+            .slots
+            .iter()
+            .map(|s| Vec3::from(s.pose.translation))
+            .collect();
+        let height = slots.iter().map(|p| p.y).fold(0.0, f32::max);
+        let (min_x, max_x) = slots.iter().fold((0.0f32, 0.0f32), |(min, max), p| {
+            (min.min(p.x), max.max(p.x))
+        });
+        let back = slots.iter().map(|p| p.z).fold(0.0, f32::max) + 0.06;
+        let material = world
+            .resource_mut::<Assets<StandardMaterial>>()
+            .add(RACK_COLOR.into());
+        let mut meshes = world.resource_mut::<Assets<Mesh>>();
+        let post = meshes.add(Mesh::from(shape::Box::new(0.04, height, 0.04)));
+        let beam = meshes.add(Mesh::from(shape::Box::new(
+            max_x - min_x + 0.12,
+            0.04,
+            0.04,
+        )));
+
+        let entity = world
+            .spawn((
+                SpatialBundle {
+                    transform: rack.pose.transform(),
+                    ..default()
+                },
+                Name::new(rack.name.clone()),
+                ToolRack,
+            ))
+            .with_children(|parent| {
+                parent.spawn(PbrBundle {
+                    mesh: post,
+                    material: material.clone(),
+                    transform: Transform::from_xyz(0.0, height / 2.0, back),
+                    ..default()
+                });
+                parent.spawn(PbrBundle {
+                    mesh: beam,
+                    material,
+                    transform: Transform::from_xyz((min_x + max_x) / 2.0, height - 0.02, back),
+                    ..default()
+                });
+            })
+            .id();
+
+        for slot in rack.slots.iter() {
+            let tool = slot
+                .tool
+                .map(|kind| RobotCellPlugin::spawn_tool(world, None, kind, Transform::IDENTITY));
+            let child = world
+                .spawn((
+                    SpatialBundle {
+                        transform: slot.pose.transform(),
+                        ..default()
+                    },
+                    ToolSlot { tool },
+                ))
+                .id();
+            if let Some(tool) = tool {
+                world.entity_mut(child).push_children(&[tool]);
+            }
+            world.entity_mut(entity).push_children(&[child]);
+        }
+    }
+
+    pub fn despawn_racks(world: &mut World) {
+        let racks: Vec<Entity> = world
+            .query_filtered::<Entity, With<ToolRack>>()
+            .iter(world)
+            .collect();
+        for entity in racks {
+            world.entity_mut(entity).despawn_recursive();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reach_of_a_slot() {
+        let slot = Transform::from_xyz(0.1, 0.35, 0.45)
+            .with_rotation(Quat::from_rotation_x(std::f32::consts::FRAC_PI_2));
+        assert!(within_reach(&slot, &slot));
+        let near = slot
+            .with_translation(slot.translation + Vec3::new(0.01, 0.0, 0.01))
+            .with_rotation(slot.rotation * Quat::from_rotation_z(5f32.to_radians()));
+        assert!(within_reach(&near, &slot));
+        let far = slot.with_translation(slot.translation + Vec3::Y * 0.05);
+        assert!(!within_reach(&far, &slot));
+        let tilted = slot.with_rotation(slot.rotation * Quat::from_rotation_x(0.3));
+        assert!(!within_reach(&tilted, &slot));
+    }
+}
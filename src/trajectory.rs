@@ -0,0 +1,368 @@
+// Synchronized trapezoidal velocity profile, every axis starts and stops together.
+// From rest all axes share one profile, so the path is a straight line.
+// A new target while moving keeps the current velocity of each axis,
+// the slowest axis sets the duration and the others are slowed down to match it.
+#[derive(Clone, Debug)]
+pub struct Trajectory<const N: usize> {
+    axes: [Profile; N],
+    target: [f64; N],
+    duration: f64, // s
+    elapsed: f64,  // s
+}
+
+// accelerate from v0 to cruise, cruise, decelerate to rest at the target
+#[derive(Clone, Copy, Debug, Default)]
+struct Profile {
+    start: f64,
+    v0: f64,     // unit/s
+    cruise: f64, // unit/s
+    t1: f64,     // s, accelerating
+    t2: f64,     // s, cruising
+    t3: f64,     // s, decelerating
+}
+
+impl<const N: usize> Trajectory<N> {
+    // at rest at pos
+    pub fn new(pos: [f64; N]) -> Self {
+        Trajectory {
+            axes: pos.map(|start| Profile {
+                start,
+                ..Default::default()
+            }),
+            target: pos,
+            duration: 0.0,
+            elapsed: 0.0,
+        }
+    }
+
+    // velocity: max velocity of each axis, unit/s
+    // acceleration: max acceleration of each axis, unit/s^2
+    pub fn retarget(&mut self, target: [f64; N], velocity: &[f64; N], acceleration: &[f64; N]) {
+        if target == self.target {
+            return;
+        }
+        let start = self.position();
+        let v0 = self.velocity();
+        self.target = target;
+        self.elapsed = 0.0;
+        self.duration = 0.0;
+        self.axes = start.map(|start| Profile {
+            start,
+            ..Default::default()
+        });
+
+        let moving = v0.iter().any(|v| *v != 0.0);
+        for i in 0..N {
+            let d = target[i] - start[i];
+            if (d != 0.0 || v0[i] != 0.0) && (velocity[i] <= 0.0 || acceleration[i] <= 0.0) {
+                // no limits to move with, jump to the target
+                return;
+            }
+        }
+        if moving {
+            self.retarget_moving(&start, &v0, velocity, acceleration);
+        } else {
+            self.retarget_at_rest(&start, velocity, acceleration);
+        }
+    }
+
+    fn retarget_at_rest(&mut self, start: &[f64; N], velocity: &[f64; N], acceleration: &[f64; N]) {
+        // scale every axis to a distance of 1, the slowest axis bounds the shared profile
+        let mut v = f64::MAX;
+        let mut a = f64::MAX;
+        for i in 0..N {
+            let d = (self.target[i] - start[i]).abs();
+            if d > 0.0 {
+                v = v.min(velocity[i].abs() / d);
+                a = a.min(acceleration[i].abs() / d);
+            }
+        }
+        if v == f64::MAX {
+            return;
+        }
+        let (accel_time, cruise_time) = if v * v >= a {
+            // triangle, the max velocity is never reached
+            ((1.0 / a).sqrt(), 0.0)
+        } else {
+            (v / a, 1.0 / v - v / a)
+        };
+        self.duration = 2.0 * accel_time + cruise_time;
+        let scale = 1.0 / (accel_time + cruise_time);
+        for (axis, target) in self.axes.iter_mut().zip(self.target) {
+            axis.cruise = (target - axis.start) * scale;
+            axis.t1 = accel_time;
+            axis.t2 = cruise_time;
+            axis.t3 = accel_time;
+        }
+    }
+
+    fn retarget_moving(
+        &mut self,
+        start: &[f64; N],
+        v0: &[f64; N],
+        velocity: &[f64; N],
+        acceleration: &[f64; N],
+    ) {
+        let mut duration: f64 = 0.0;
+        for i in 0..N {
+            let d = self.target[i] - start[i];
+            if d != 0.0 || v0[i] != 0.0 {
+                duration = duration.max(min_time(d, v0[i], velocity[i], acceleration[i]));
+            }
+        }
+        self.duration = duration;
+        for (i, axis) in self.axes.iter_mut().enumerate() {
+            let d = self.target[i] - start[i];
+            if d != 0.0 || v0[i] != 0.0 {
+                *axis = Profile::fit(start[i], d, v0[i], velocity[i], acceleration[i], duration);
+            }
+        }
+    }
+
+    // dt: s
+    pub fn step(&mut self, dt: f64) -> [f64; N] {
+        self.elapsed = (self.elapsed + dt.max(0.0)).min(self.duration);
+        self.position()
+    }
+
+    pub fn position(&self) -> [f64; N] {
+        let mut out = self.target;
+        for (out, (axis, target)) in out.iter_mut().zip(self.axes.iter().zip(self.target)) {
+            *out = axis.position(self.elapsed, self.duration, target);
+        }
+        out
+    }
+
+    // unit/s
+    pub fn velocity(&self) -> [f64; N] {
+        self.axes
+            .map(|axis| axis.velocity(self.elapsed, self.duration))
+    }
+
+    pub fn target(&self) -> [f64; N] {
+        self.target
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+}
+
+impl Profile {
+    // the profile of the given duration that covers distance d, starting at velocity v0
+    // and accelerating with the full acceleration a
+    fn fit(start: f64, d: f64, v0: f64, v: f64, a: f64, duration: f64) -> Profile {
+        let (mut lo, mut hi) = cruise_range(v0, v, a, duration);
+        let cruise = if d >= distance(hi, v0, a, duration) {
+            hi
+        } else if d <= distance(lo, v0, a, duration) {
+            lo
+        } else {
+            // the distance grows with the cruise velocity
+            for _ in 0..100 {
+                let mid = 0.5 * (lo + hi);
+                if distance(mid, v0, a, duration) < d {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+            0.5 * (lo + hi)
+        };
+        let t1 = (cruise - v0).abs() / a;
+        let t3 = cruise.abs() / a;
+        Profile {
+            start,
+            v0,
+            cruise,
+            t1,
+            t2: (duration - t1 - t3).max(0.0),
+            t3,
+        }
+    }
+
+    fn position(&self, t: f64, duration: f64, target: f64) -> f64 {
+        if t >= duration {
+            return target;
+        }
+        if t < self.t1 {
+            return self.start + self.v0 * t + 0.5 * (self.cruise - self.v0) / self.t1 * t * t;
+        }
+        let t = t - self.t1;
+        if t < self.t2 {
+            return self.start + 0.5 * (self.v0 + self.cruise) * self.t1 + self.cruise * t;
+        }
+        // decelerating, counted back from the target
+        let r = duration - self.t1 - t;
+        if self.t3 > 0.0 {
+            target - 0.5 * self.cruise / self.t3 * r * r
+        } else {
+            target
+        }
+    }
+
+    fn velocity(&self, t: f64, duration: f64) -> f64 {
+        if t >= duration {
+            0.0
+        } else if t < self.t1 {
+            self.v0 + (self.cruise - self.v0) / self.t1 * t
+        } else if t < self.t1 + self.t2 || self.t3 <= 0.0 {
+            self.cruise
+        } else {
+            self.cruise * (duration - t) / self.t3
+        }
+    }
+}
+
+// range of the cruise velocity so that accelerating to it from v0 and stopping
+// fits into the duration
+fn cruise_range(v0: f64, v: f64, a: f64, duration: f64) -> (f64, f64) {
+    let hi = v.min(0.5 * (a * duration + v0));
+    let lo = (-v).max(0.5 * (v0 - a * duration));
+    (lo, hi.max(lo))
+}
+
+// distance covered by the profile with the given cruise velocity
+fn distance(cruise: f64, v0: f64, a: f64, duration: f64) -> f64 {
+    let t1 = (cruise - v0).abs() / a;
+    let t3 = cruise.abs() / a;
+    let t2 = (duration - t1 - t3).max(0.0);
+    0.5 * (v0 + cruise) * t1 + cruise * t2 + 0.5 * cruise * t3
+}
+
+// shortest time to cover distance d starting at velocity v0 and ending at rest
+fn min_time(d: f64, v0: f64, v: f64, a: f64) -> f64 {
+    let reachable = |duration: f64| {
+        let (lo, hi) = cruise_range(v0, v, a, duration);
+        let eps = 1e-12 * (1.0 + d.abs());
+        distance(lo, v0, a, duration) - eps <= d && d <= distance(hi, v0, a, duration) + eps
+    };
+    let mut lo = v0.abs() / a;
+    if reachable(lo) {
+        return lo;
+    }
+    // stopping first and then moving from rest always works
+    let rest = (d - 0.5 * v0 * v0.abs() / a).abs();
+    let from_rest = if rest >= v * v / a {
+        rest / v + v / a
+    } else {
+        2.0 * (rest / a).sqrt()
+    };
+    let mut hi = lo + from_rest;
+    for _ in 0..100 {
+        let mid = 0.5 * (lo + hi);
+        if reachable(mid) {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    hi
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(trajectory: &mut Trajectory<3>, dt: f64) -> Vec<[f64; 3]> {
+        let mut out = vec![trajectory.position()];
+        while !trajectory.is_done() {
+            out.push(trajectory.step(dt));
+        }
+        out
+    }
+
+    #[test]
+    fn axes_finish_together_within_limits() {
+        let velocity = [180.0, 90.0, 180.0];
+        let acceleration = [360.0, 360.0, 90.0];
+        let mut trajectory = Trajectory::new([0.0, 10.0, -20.0]);
+        trajectory.retarget([90.0, 100.0, -30.0], &velocity, &acceleration);
+        let dt = 0.001;
+        let points = run(&mut trajectory, dt);
+        assert_eq!(*points.last().unwrap(), [90.0, 100.0, -30.0]);
+        for w in points.windows(3) {
+            for i in 0..3 {
+                let v0 = (w[1][i] - w[0][i]) / dt;
+                let v1 = (w[2][i] - w[1][i]) / dt;
+                assert!(v1.abs() <= velocity[i] * 1.001);
+                assert!(((v1 - v0) / dt).abs() <= acceleration[i] * 1.01);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_rate_independent() {
+        let velocity = [180.0; 3];
+        let acceleration = [360.0; 3];
+        let mut slow = Trajectory::new([0.0; 3]);
+        let mut fast = Trajectory::new([0.0; 3]);
+        slow.retarget([45.0, -90.0, 10.0], &velocity, &acceleration);
+        fast.retarget([45.0, -90.0, 10.0], &velocity, &acceleration);
+        for _ in 0..30 {
+            slow.step(1.0 / 60.0);
+            fast.step(1.0 / 120.0);
+            fast.step(1.0 / 120.0);
+        }
+        for i in 0..3 {
+            assert!((slow.position()[i] - fast.position()[i]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn retarget_every_frame_keeps_velocity() {
+        // a target moving at a constant speed, like jogging or dragging a slider
+        let velocity = [180.0; 3];
+        let acceleration = [360.0; 3];
+        let speed = [90.0, -45.0, 30.0];
+        let dt = 1.0 / 60.0;
+        let mut trajectory = Trajectory::new([0.0; 3]);
+        let mut points = vec![trajectory.position()];
+        let mut target = [0.0; 3];
+        for _ in 0..120 {
+            for i in 0..3 {
+                target[i] += speed[i] * dt;
+            }
+            trajectory.retarget(target, &velocity, &acceleration);
+            points.push(trajectory.step(dt));
+        }
+        let pos = trajectory.position();
+        for i in 0..3 {
+            // follows at the target speed, a fraction of a second behind it
+            assert!((target[i] - pos[i]).abs() < speed[i].abs() * 0.25);
+            assert!((trajectory.velocity()[i] - speed[i]).abs() < speed[i].abs() * 0.05);
+        }
+        for w in points.windows(3) {
+            for i in 0..3 {
+                let v0 = (w[1][i] - w[0][i]) / dt;
+                let v1 = (w[2][i] - w[1][i]) / dt;
+                assert!(v1.abs() <= velocity[i] * 1.001);
+                assert!(((v1 - v0) / dt).abs() <= acceleration[i] * 1.01);
+            }
+        }
+    }
+
+    #[test]
+    fn retarget_while_moving_reverses_smoothly() {
+        let velocity = [180.0; 3];
+        let acceleration = [360.0; 3];
+        let dt = 0.001;
+        let mut trajectory = Trajectory::new([0.0; 3]);
+        trajectory.retarget([90.0, 90.0, 0.0], &velocity, &acceleration);
+        let mut points = vec![trajectory.position()];
+        for _ in 0..300 {
+            points.push(trajectory.step(dt));
+        }
+        trajectory.retarget([-30.0, 90.0, 20.0], &velocity, &acceleration);
+        points.extend(run(&mut trajectory, dt).into_iter().skip(1));
+        assert_eq!(*points.last().unwrap(), [-30.0, 90.0, 20.0]);
+        for w in points.windows(3) {
+            for i in 0..3 {
+                let v0 = (w[1][i] - w[0][i]) / dt;
+                let v1 = (w[2][i] - w[1][i]) / dt;
+                assert!(v1.abs() <= velocity[i] * 1.001);
+                assert!(((v1 - v0) / dt).abs() <= acceleration[i] * 1.01);
+            }
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+    utils::BoxedFuture,
+};
+use nalgebra::{Isometry3, Quaternion, Translation3, Unit, UnitQuaternion, Vector3};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub enum UrdfError {
+    Xml(String),
+    // ( element, attribute )
+    MissingAttribute(&'static str, &'static str),
+    // ( element, attribute, value )
+    InvalidValue(&'static str, &'static str, String),
+    UnknownLink(String),
+    NoRoot,
+}
+
+impl std::fmt::Display for UrdfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UrdfError::Xml(e) => write!(f, "invalid xml: {}", e),
+            UrdfError::MissingAttribute(e, a) => write!(f, "<{}> is missing \"{}\"", e, a),
+            UrdfError::InvalidValue(e, a, v) => {
+                write!(f, "<{}> has invalid \"{}\": \"{}\"", e, a, v)
+            }
+            UrdfError::UnknownLink(name) => write!(f, "unknown link \"{}\"", name),
+            UrdfError::NoRoot => write!(f, "links do not form a tree"),
+        }
+    }
+}
+
+impl std::error::Error for UrdfError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JointKind {
+    Revolute,
+    Continuous,
+    Prismatic,
+    Fixed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UrdfLimit {
+    pub lower: f64,    // rad or m
+    pub upper: f64,    // rad or m
+    pub velocity: f64, // rad/s or m/s
+}
+
+#[derive(Debug, Clone)]
+pub struct UrdfVisual {
+    pub origin: Transform,
+    pub mesh: String, // asset path
+}
+
+// capsule around the segment [ -length / 2, length / 2 ] along z of origin, m
+// cylinders are taken as capsules, spheres have no length
+#[derive(Debug, Clone, Copy)]
+pub struct UrdfCollision {
+    pub origin: Transform,
+    pub radius: f32,
+    pub length: f32,
+}
+
+impl UrdfCollision {
+    // ends of the segment relative to the link
+    pub fn segment(&self) -> (Vec3, Vec3) {
+        let half = Vec3::Z * self.length / 2.0;
+        (
+            self.origin.transform_point(-half),
+            self.origin.transform_point(half),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UrdfLink {
+    pub name: String,
+    pub visuals: Vec<UrdfVisual>,
+    pub collisions: Vec<UrdfCollision>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UrdfJoint {
+    #[cfg_attr(not(feature = "ros"), allow(dead_code))]
+    pub name: String,
+    pub kind: JointKind,
+    pub parent: usize, // link index
+    pub child: usize,  // link index
+    pub origin: Transform,
+    pub axis: Vec3,
+    pub limit: Option<UrdfLimit>,
+}
+
+impl UrdfJoint {
+    pub fn is_movable(&self) -> bool {
+        self.kind != JointKind::Fixed
+    }
+
+    // In: joint value, rad or m
+    // Out: child link relative to parent link
+    fn transform(&self, q: f64) -> Transform {
+        let motion = match self.kind {
+            JointKind::Revolute | JointKind::Continuous => {
+                Transform::from_rotation(Quat::from_axis_angle(self.axis, q as f32))
+            }
+            JointKind::Prismatic => Transform::from_translation(self.axis * q as f32),
+            JointKind::Fixed => Transform::IDENTITY,
+        };
+        self.origin * motion
+    }
+
+    // transform in f64, the numeric ik differentiates it
+    fn isometry(&self, q: f64) -> Isometry3<f64> {
+        let axis = Vector3::new(self.axis.x as f64, self.axis.y as f64, self.axis.z as f64);
+        let motion = match self.kind {
+            JointKind::Revolute | JointKind::Continuous => Isometry3::from_parts(
+                Translation3::identity(),
+                UnitQuaternion::from_axis_angle(&Unit::new_normalize(axis), q),
+            ),
+            JointKind::Prismatic => Isometry3::translation(axis.x * q, axis.y * q, axis.z * q),
+            JointKind::Fixed => Isometry3::identity(),
+        };
+        let t = self.origin.translation;
+        let r = self.origin.rotation;
+        let origin = Isometry3::from_parts(
+            Translation3::new(t.x as f64, t.y as f64, t.z as f64),
+            UnitQuaternion::new_normalize(Quaternion::new(
+                r.w as f64, r.x as f64, r.y as f64, r.z as f64,
+            )),
+        );
+        origin * motion
+    }
+}
+
+#[derive(Debug, Clone, TypeUuid, TypePath)]
+#[uuid = "5d4c6f5e-8f2b-4a36-9a5b-0c3b7a4e1d21"]
+pub struct RobotDescription {
+    pub name: String,
+    pub links: Vec<UrdfLink>,
+    pub joints: Vec<UrdfJoint>,
+    pub root: usize,   // link index
+    order: Vec<usize>, // joint indices, from root to leaves
+}
+
+impl RobotDescription {
+    // mesh paths are resolved relative to `dir`
+    pub fn parse(text: &str, dir: &str) -> Result<RobotDescription, UrdfError> {
+        let doc = roxmltree::Document::parse(text).map_err(|e| UrdfError::Xml(e.to_string()))?;
+        let robot = doc.root_element();
+        let name = robot.attribute("name").unwrap_or_default().to_string();
+
+        let mut links = Vec::new();
+        let mut link_index = HashMap::new();
+        for node in robot.children().filter(|n| n.has_tag_name("link")) {
+            let name = attr(&node, "link", "name")?.to_string();
+            let mut visuals = Vec::new();
+            for visual in node.children().filter(|n| n.has_tag_name("visual")) {
+                let origin = parse_origin(&visual)?;
+                let mesh = visual
+                    .descendants()
+                    .find(|n| n.has_tag_name("mesh"))
+                    .map(|n| attr(&n, "mesh", "filename"))
+                    .transpose()?;
+                if let Some(mesh) = mesh {
+                    visuals.push(UrdfVisual {
+                        origin,
+                        mesh: resolve_path(dir, mesh),
+                    });
+                }
+            }
+            let mut collisions = Vec::new();
+            for collision in node.children().filter(|n| n.has_tag_name("collision")) {
+                if let Some(shape) = parse_collision(&collision)? {
+                    collisions.push(shape);
+                }
+            }
+            link_index.insert(name.clone(), links.len());
+            links.push(UrdfLink {
+                name,
+                visuals,
+                collisions,
+            });
+        }
+
+        let find_link = |node: &roxmltree::Node, tag: &'static str| -> Result<usize, UrdfError> {
+            let name = node
+                .children()
+                .find(|n| n.has_tag_name(tag))
+                .ok_or(UrdfError::MissingAttribute("joint", tag))
+                .and_then(|n| attr(&n, tag, "link"))?;
+            link_index
+                .get(name)
+                .copied()
+                .ok_or_else(|| UrdfError::UnknownLink(name.to_string()))
+        };
+
+        let mut joints = Vec::new();
+        for node in robot.children().filter(|n| n.has_tag_name("joint")) {
+            let name = attr(&node, "joint", "name")?.to_string();
+            let kind = match attr(&node, "joint", "type")? {
+                "revolute" => JointKind::Revolute,
+                "continuous" => JointKind::Continuous,
+                "prismatic" => JointKind::Prismatic,
+                "fixed" => JointKind::Fixed,
+                v => return Err(UrdfError::InvalidValue("joint", "type", v.to_string())),
+            };
+            let axis = match node.children().find(|n| n.has_tag_name("axis")) {
+                Some(n) => parse_vec3(attr(&n, "axis", "xyz")?, "axis", "xyz")?.normalize(),
+                None => Vec3::X,
+            };
+            let limit = match node.children().find(|n| n.has_tag_name("limit")) {
+                Some(n) => Some(UrdfLimit {
+                    lower: parse_f64(n.attribute("lower").unwrap_or("0"), "limit", "lower")?,
+                    upper: parse_f64(n.attribute("upper").unwrap_or("0"), "limit", "upper")?,
+                    velocity: parse_f64(attr(&n, "limit", "velocity")?, "limit", "velocity")?,
+                }),
+                None => None,
+            };
+            joints.push(UrdfJoint {
+                name,
+                kind,
+                parent: find_link(&node, "parent")?,
+                child: find_link(&node, "child")?,
+                origin: parse_origin(&node)?,
+                axis,
+                limit,
+            });
+        }
+
+        // the root is the only link which is no joint's child
+        let mut roots = (0..links.len()).filter(|&l| joints.iter().all(|j| j.child != l));
+        let root = roots.next().ok_or(UrdfError::NoRoot)?;
+        if roots.next().is_some() {
+            return Err(UrdfError::NoRoot);
+        }
+        let mut order = Vec::with_capacity(joints.len());
+        let mut open = vec![root];
+        while let Some(link) = open.pop() {
+            for (i, joint) in joints.iter().enumerate() {
+                if joint.parent == link {
+                    order.push(i);
+                    open.push(joint.child);
+                }
+            }
+            if order.len() > joints.len() {
+                return Err(UrdfError::NoRoot);
+            }
+        }
+        if order.len() != joints.len() {
+            return Err(UrdfError::NoRoot);
+        }
+
+        Ok(RobotDescription {
+            name,
+            links,
+            joints,
+            root,
+            order,
+        })
+    }
+
+    // movable joint indices, from root to leaves
+    pub fn movable_joints(&self) -> Vec<usize> {
+        self.order
+            .iter()
+            .copied()
+            .filter(|&j| self.joints[j].is_movable())
+            .collect()
+    }
+
+    // the last link of the chain, where tools are mounted
+    pub fn tip(&self) -> usize {
+        self.order
+            .last()
+            .map_or(self.root, |&j| self.joints[j].child)
+    }
+
+    // In: movable joint values, in the order of `movable_joints`
+    // Out: every link relative to the robot root, indexed like `links`
+    pub fn link_transforms(&self, q: &[f64]) -> Vec<Transform> {
+        let mut out = vec![Transform::IDENTITY; self.links.len()];
+        let mut k = 0;
+        for &j in self.order.iter() {
+            let joint = &self.joints[j];
+            let value = if joint.is_movable() {
+                k += 1;
+                q.get(k - 1).copied().unwrap_or(0.0)
+            } else {
+                0.0
+            };
+            out[joint.child] = out[joint.parent] * joint.transform(value);
+        }
+        out
+    }
+
+    // In: movable joint values, in the order of `movable_joints`
+    // Out: the tip link relative to the robot root
+    pub fn tip_pose(&self, q: &[f64]) -> Isometry3<f64> {
+        let mut out = vec![Isometry3::identity(); self.links.len()];
+        let mut k = 0;
+        for &j in self.order.iter() {
+            let joint = &self.joints[j];
+            let value = if joint.is_movable() {
+                k += 1;
+                q.get(k - 1).copied().unwrap_or(0.0)
+            } else {
+                0.0
+            };
+            out[joint.child] = out[joint.parent] * joint.isometry(value);
+        }
+        out[self.tip()]
+    }
+}
+
+fn attr<'a>(
+    node: &roxmltree::Node<'a, '_>,
+    element: &'static str,
+    name: &'static str,
+) -> Result<&'a str, UrdfError> {
+    node.attribute(name)
+        .ok_or(UrdfError::MissingAttribute(element, name))
+}
+
+fn parse_f64(text: &str, element: &'static str, name: &'static str) -> Result<f64, UrdfError> {
+    text.trim()
+        .parse()
+        .map_err(|_| UrdfError::InvalidValue(element, name, text.to_string()))
+}
+
+fn parse_vec3(text: &str, element: &'static str, name: &'static str) -> Result<Vec3, UrdfError> {
+    let v: Vec<f32> = text
+        .split_whitespace()
+        .map(|s| s.parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| UrdfError::InvalidValue(element, name, text.to_string()))?;
+    if v.len() != 3 {
+        return Err(UrdfError::InvalidValue(element, name, text.to_string()));
+    }
+    Ok(Vec3::new(v[0], v[1], v[2]))
+}
+
+// <origin xyz rpy>, rpy are fixed axis angles: R = Rz(yaw) * Ry(pitch) * Rx(roll)
+fn parse_origin(node: &roxmltree::Node) -> Result<Transform, UrdfError> {
+    let Some(origin) = node.children().find(|n| n.has_tag_name("origin")) else {
+        return Ok(Transform::IDENTITY);
+    };
+    let xyz = parse_vec3(origin.attribute("xyz").unwrap_or("0 0 0"), "origin", "xyz")?;
+    let rpy = parse_vec3(origin.attribute("rpy").unwrap_or("0 0 0"), "origin", "rpy")?;
+    Ok(
+        Transform::from_translation(xyz).with_rotation(Quat::from_euler(
+            EulerRot::ZYX,
+            rpy.z,
+            rpy.y,
+            rpy.x,
+        )),
+    )
+}
+
+// None for the geometries not checked, boxes and meshes
+fn parse_collision(node: &roxmltree::Node) -> Result<Option<UrdfCollision>, UrdfError> {
+    for tag in ["capsule", "cylinder", "sphere"] {
+        let Some(geometry) = node.descendants().find(|n| n.has_tag_name(tag)) else {
+            continue;
+        };
+        let radius = parse_f64(attr(&geometry, tag, "radius")?, tag, "radius")?;
+        let length = match tag {
+            "sphere" => 0.0,
+            _ => parse_f64(attr(&geometry, tag, "length")?, tag, "length")?,
+        };
+        return Ok(Some(UrdfCollision {
+            origin: parse_origin(node)?,
+            radius: radius as f32,
+            length: length as f32,
+        }));
+    }
+    Ok(None)
+}
+
+fn resolve_path(dir: &str, filename: &str) -> String {
+    if let Some(path) = filename.strip_prefix("package://") {
+        path.to_string()
+    } else if dir.is_empty() {
+        filename.to_string()
+    } else {
+        format!("{}/{}", dir, filename)
+    }
+}
+
+#[derive(Default)]
+pub struct UrdfLoader;
+
+impl AssetLoader for UrdfLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let text = std::str::from_utf8(bytes)?;
+            let dir = load_context
+                .path()
+                .parent()
+                .map(|p| p.to_string_lossy().replace('\\', "/"))
+                .unwrap_or_default();
+            let description = RobotDescription::parse(text, &dir)?;
+            load_context.set_default_asset(LoadedAsset::new(description));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["urdf"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UR5: &str = include_str!("../assets/ur5/ur5.urdf");
+
+    #[test]
+    fn parse_ur5() {
+        let description = RobotDescription::parse(UR5, "ur5").unwrap();
+        assert_eq!(description.name, "ur5");
+        assert_eq!(description.links.len(), 7);
+        assert_eq!(description.movable_joints().len(), 6);
+        assert_eq!(description.links[description.root].name, "base_link");
+        assert_eq!(description.links[description.tip()].name, "wrist_3_link");
+        assert_eq!(description.links[1].visuals[0].mesh, "ur5/ur5.gltf#Scene1");
+        assert!(description.links.iter().all(|l| !l.collisions.is_empty()));
+    }
+
+    #[test]
+    fn parse_collisions() {
+        let text = r#"<robot name="r"><link name="a">
+            <collision><origin xyz="0.1 0 0" rpy="0 1.5707963 0"/>
+                <geometry><cylinder radius="0.05" length="0.2"/></geometry></collision>
+            <collision><geometry><sphere radius="0.03"/></geometry></collision>
+            <collision><geometry><box size="1 1 1"/></geometry></collision>
+            </link></robot>"#;
+        let description = RobotDescription::parse(text, "").unwrap();
+        let collisions = &description.links[0].collisions;
+        assert_eq!(collisions.len(), 2);
+        let (a, b) = collisions[0].segment();
+        assert!(a.distance(Vec3::new(0.0, 0.0, 0.0)) < 1e-5);
+        assert!(b.distance(Vec3::new(0.2, 0.0, 0.0)) < 1e-5);
+        assert_eq!(collisions[1].radius, 0.03);
+        assert_eq!(collisions[1].segment().0, collisions[1].segment().1);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let text = r#"<robot name="r"><link name="a"/>
+            <joint name="j" type="revolute"><parent link="a"/><child link="b"/></joint></robot>"#;
+        assert!(matches!(
+            RobotDescription::parse(text, ""),
+            Err(UrdfError::UnknownLink(_))
+        ));
+    }
+
+    #[test]
+    fn tip_pose_follows_link_transforms() {
+        let description = RobotDescription::parse(UR5, "ur5").unwrap();
+        let q = [0.3, -1.2, 1.5, -0.7, 2.1, -2.8];
+        let tf = description.link_transforms(&q)[description.tip()];
+        let iso = description.tip_pose(&q);
+        let t = iso.translation.vector;
+        let r = iso.rotation;
+        let rotation = Quat::from_xyzw(r.i as f32, r.j as f32, r.k as f32, r.w as f32);
+        assert!(
+            tf.translation
+                .distance(Vec3::new(t.x as f32, t.y as f32, t.z as f32))
+                < 1e-5
+        );
+        assert!(tf.rotation.angle_between(rotation) < 1e-4);
+    }
+}
@@ -0,0 +1,1361 @@
+// A practical subset of URScript:
+// numbers, booleans, strings, lists, poses p[x, y, z, rx, ry, rz], variables, global,
+// def/end with parameters and return, while, if/elif/else, arithmetic, comparison and logic.
+// The script is compiled to a flat list of ops, motion statements make the vm yield
+// so the caller can run the move over several frames and resume afterwards.
+use nalgebra::{Isometry3, Vector3};
+use std::collections::{HashMap, HashSet};
+
+use crate::robot_ur5::RobotUr5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    None,
+    Number(f64),
+    Bool(bool),
+    Str(String),
+    List(Vec<f64>),
+    Pose([f64; 6]), // ( x, y, z ) m, rotation vector rad
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "none",
+            Value::Number(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Pose(_) => "pose",
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |f: &mut std::fmt::Formatter<'_>, values: &[f64]| {
+            let values: Vec<String> = values.iter().map(|v| format!("{:.4}", v)).collect();
+            write!(f, "[{}]", values.join(", "))
+        };
+        match self {
+            Value::None => write!(f, "None"),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", if *b { "True" } else { "False" }),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(values) => list(f, values),
+            Value::Pose(values) => {
+                write!(f, "p")?;
+                list(f, values)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    pub line: usize, // 1 based
+    pub message: String,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, ScriptError> {
+    Err(ScriptError {
+        line,
+        message: message.into(),
+    })
+}
+
+// ---------------------------------------------------------------- lexer
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Num(f64),
+    Ident(String),
+    Str(String),
+    PoseOpen, // p[
+    Op(&'static str),
+    Newline,
+    Eof,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    tok: Tok,
+    line: usize,
+}
+
+const OPS: [&str; 18] = [
+    "==", "!=", "<=", ">=", "+", "-", "*", "/", "%", "<", ">", "=", "(", ")", "[", "]", ",", ":",
+];
+
+fn lex(text: &str) -> Result<Vec<Token>, ScriptError> {
+    let mut out = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let n = i + 1;
+        let chars: Vec<char> = line.chars().collect();
+        let mut pos = 0;
+        while pos < chars.len() {
+            let c = chars[pos];
+            if c.is_whitespace() {
+                pos += 1;
+            } else if c == '#' || c == '$' {
+                // comment, or a "$ 1 ..." label written by the teach pendant
+                break;
+            } else if c.is_ascii_digit()
+                || (c == '.' && chars.get(pos + 1).is_some_and(|c| c.is_ascii_digit()))
+            {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].is_ascii_digit() || chars[pos] == '.') {
+                    pos += 1;
+                }
+                if pos < chars.len() && (chars[pos] == 'e' || chars[pos] == 'E') {
+                    pos += 1;
+                    if pos < chars.len() && (chars[pos] == '+' || chars[pos] == '-') {
+                        pos += 1;
+                    }
+                    while pos < chars.len() && chars[pos].is_ascii_digit() {
+                        pos += 1;
+                    }
+                }
+                let s: String = chars[start..pos].iter().collect();
+                match s.parse() {
+                    Ok(v) => out.push(Token {
+                        tok: Tok::Num(v),
+                        line: n,
+                    }),
+                    Err(_) => return error(n, format!("invalid number \"{}\"", s)),
+                }
+            } else if c.is_alphabetic() || c == '_' {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                    pos += 1;
+                }
+                let s: String = chars[start..pos].iter().collect();
+                if s == "p" && chars.get(pos) == Some(&'[') {
+                    pos += 1;
+                    out.push(Token {
+                        tok: Tok::PoseOpen,
+                        line: n,
+                    });
+                } else {
+                    out.push(Token {
+                        tok: Tok::Ident(s),
+                        line: n,
+                    });
+                }
+            } else if c == '"' || c == '\'' {
+                let start = pos + 1;
+                pos = start;
+                while pos < chars.len() && chars[pos] != c {
+                    pos += 1;
+                }
+                if pos >= chars.len() {
+                    return error(n, "unterminated string");
+                }
+                out.push(Token {
+                    tok: Tok::Str(chars[start..pos].iter().collect()),
+                    line: n,
+                });
+                pos += 1;
+            } else {
+                let rest: String = chars[pos..].iter().take(2).collect();
+                let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) else {
+                    return error(n, format!("unexpected character '{}'", c));
+                };
+                pos += op.len();
+                out.push(Token {
+                    tok: Tok::Op(op),
+                    line: n,
+                });
+            }
+        }
+        out.push(Token {
+            tok: Tok::Newline,
+            line: n,
+        });
+    }
+    let line = out.last().map_or(1, |t| t.line);
+    out.push(Token {
+        tok: Tok::Eof,
+        line,
+    });
+    Ok(out)
+}
+
+// ---------------------------------------------------------------- parser
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Const(Value),
+    Var(String),
+    List(Vec<Expr>),
+    Pose(Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    // ( name, positional arguments, keyword arguments )
+    Call(String, Vec<Expr>, Vec<(String, Expr)>),
+}
+
+#[derive(Clone, Debug)]
+enum StmtKind {
+    Assign {
+        name: String,
+        global: bool,
+        value: Expr,
+    },
+    Expr(Expr),
+    // ( condition, body ) of if and every elif, then the else body
+    If(Vec<(Expr, Vec<Stmt>)>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Return(Option<Expr>),
+    Halt,
+}
+
+#[derive(Clone, Debug)]
+struct Stmt {
+    kind: StmtKind,
+    line: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Def {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+    line: usize,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].tok
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.pos].line
+    }
+
+    fn next(&mut self) -> Tok {
+        let tok = self.tokens[self.pos].tok.clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Tok::Op(o) if *o == op)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Tok::Ident(s) if s == keyword)
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), ScriptError> {
+        if self.is_op(op) {
+            self.next();
+            Ok(())
+        } else {
+            error(self.line(), format!("expected '{}'", op))
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String, ScriptError> {
+        match self.next() {
+            Tok::Ident(s) => Ok(s),
+            _ => error(self.line(), "expected a name"),
+        }
+    }
+
+    fn expect_newline(&mut self) -> Result<(), ScriptError> {
+        match self.peek() {
+            Tok::Newline => {
+                self.next();
+                Ok(())
+            }
+            Tok::Eof => Ok(()),
+            _ => error(self.line(), "unexpected text at the end of the statement"),
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while *self.peek() == Tok::Newline {
+            self.next();
+        }
+    }
+
+    // top level: defs and statements
+    fn program(&mut self) -> Result<(Vec<Def>, Vec<Stmt>), ScriptError> {
+        let mut defs = Vec::new();
+        let mut main = Vec::new();
+        loop {
+            self.skip_newlines();
+            if *self.peek() == Tok::Eof {
+                return Ok((defs, main));
+            }
+            if self.is_keyword("def") {
+                defs.push(self.def()?);
+            } else {
+                main.push(self.statement()?);
+            }
+        }
+    }
+
+    fn def(&mut self) -> Result<Def, ScriptError> {
+        let line = self.line();
+        self.next();
+        let name = self.expect_ident()?;
+        self.expect_op("(")?;
+        let mut params = Vec::new();
+        while !self.is_op(")") {
+            params.push(self.expect_ident()?);
+            if !self.is_op(")") {
+                self.expect_op(",")?;
+            }
+        }
+        self.next();
+        self.expect_op(":")?;
+        self.expect_newline()?;
+        let (body, _) = self.block(&["end"])?;
+        Ok(Def {
+            name,
+            params,
+            body,
+            line,
+        })
+    }
+
+    // statements until one of the keywords, which is consumed and returned
+    fn block(&mut self, until: &[&str]) -> Result<(Vec<Stmt>, String), ScriptError> {
+        let mut body = Vec::new();
+        loop {
+            self.skip_newlines();
+            if let Tok::Ident(s) = self.peek() {
+                if until.contains(&s.as_str()) {
+                    let s = s.clone();
+                    self.next();
+                    return Ok((body, s));
+                }
+                if s == "def" {
+                    return error(self.line(), "def is only allowed at the top level");
+                }
+            }
+            if *self.peek() == Tok::Eof {
+                return error(self.line(), format!("missing '{}'", until[0]));
+            }
+            body.push(self.statement()?);
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, ScriptError> {
+        let line = self.line();
+        let kind = if self.is_keyword("while") {
+            self.next();
+            let condition = self.expr()?;
+            self.expect_op(":")?;
+            self.expect_newline()?;
+            let (body, _) = self.block(&["end"])?;
+            StmtKind::While(condition, body)
+        } else if self.is_keyword("if") {
+            self.next();
+            let mut branches = Vec::new();
+            let mut otherwise = Vec::new();
+            let mut condition = self.expr()?;
+            loop {
+                self.expect_op(":")?;
+                self.expect_newline()?;
+                let (body, end) = self.block(&["end", "elif", "else"])?;
+                branches.push((condition, body));
+                match end.as_str() {
+                    "elif" => condition = self.expr()?,
+                    "else" => {
+                        self.expect_op(":")?;
+                        self.expect_newline()?;
+                        otherwise = self.block(&["end"])?.0;
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+            StmtKind::If(branches, otherwise)
+        } else if self.is_keyword("return") {
+            self.next();
+            if matches!(self.peek(), Tok::Newline | Tok::Eof) {
+                StmtKind::Return(None)
+            } else {
+                StmtKind::Return(Some(self.expr()?))
+            }
+        } else if self.is_keyword("halt") {
+            self.next();
+            StmtKind::Halt
+        } else if self.is_keyword("global") || self.is_keyword("local") {
+            let global = self.is_keyword("global");
+            self.next();
+            let name = self.expect_ident()?;
+            self.expect_op("=")?;
+            StmtKind::Assign {
+                name,
+                global,
+                value: self.expr()?,
+            }
+        } else {
+            let expr = self.expr()?;
+            if self.is_op("=") {
+                let Expr::Var(name) = expr else {
+                    return error(line, "can only assign to a variable");
+                };
+                self.next();
+                StmtKind::Assign {
+                    name,
+                    global: false,
+                    value: self.expr()?,
+                }
+            } else {
+                StmtKind::Expr(expr)
+            }
+        };
+        self.expect_newline()?;
+        Ok(Stmt { kind, line })
+    }
+
+    fn expr(&mut self) -> Result<Expr, ScriptError> {
+        self.binary(0)
+    }
+
+    // precedence climbing, level 0 binds the weakest
+    fn binary(&mut self, level: usize) -> Result<Expr, ScriptError> {
+        const LEVELS: [&[(&str, BinOp)]; 5] = [
+            &[("or", BinOp::Or)],
+            &[("and", BinOp::And)],
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<=", BinOp::Le),
+                (">=", BinOp::Ge),
+                ("<", BinOp::Lt),
+                (">", BinOp::Gt),
+            ],
+            &[("+", BinOp::Add), ("-", BinOp::Sub)],
+            &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = LEVELS[level].iter().find(|(s, _)| match self.peek() {
+                Tok::Op(o) => o == s,
+                Tok::Ident(i) => i == s,
+                _ => false,
+            });
+            let Some((_, op)) = op else {
+                return Ok(lhs);
+            };
+            self.next();
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ScriptError> {
+        if self.is_op("-") {
+            self.next();
+            return Ok(Expr::Unary(UnOp::Neg, Box::new(self.unary()?)));
+        }
+        if self.is_keyword("not") {
+            self.next();
+            return Ok(Expr::Unary(UnOp::Not, Box::new(self.unary()?)));
+        }
+        let mut expr = self.primary()?;
+        while self.is_op("[") {
+            self.next();
+            let index = self.expr()?;
+            self.expect_op("]")?;
+            expr = Expr::Index(Box::new(expr), Box::new(index));
+        }
+        Ok(expr)
+    }
+
+    fn list(&mut self, close: &str) -> Result<Vec<Expr>, ScriptError> {
+        let mut items = Vec::new();
+        while !self.is_op(close) {
+            items.push(self.expr()?);
+            if !self.is_op(close) {
+                self.expect_op(",")?;
+            }
+        }
+        self.next();
+        Ok(items)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ScriptError> {
+        let line = self.line();
+        match self.next() {
+            Tok::Num(v) => Ok(Expr::Const(Value::Number(v))),
+            Tok::Str(s) => Ok(Expr::Const(Value::Str(s))),
+            Tok::PoseOpen => Ok(Expr::Pose(self.list("]")?)),
+            Tok::Op("[") => Ok(Expr::List(self.list("]")?)),
+            Tok::Op("(") => {
+                let expr = self.expr()?;
+                self.expect_op(")")?;
+                Ok(expr)
+            }
+            Tok::Ident(name) => match name.as_str() {
+                "True" => Ok(Expr::Const(Value::Bool(true))),
+                "False" => Ok(Expr::Const(Value::Bool(false))),
+                "pi" => Ok(Expr::Const(Value::Number(std::f64::consts::PI))),
+                _ if self.is_op("(") => {
+                    self.next();
+                    let mut args = Vec::new();
+                    let mut kwargs = Vec::new();
+                    while !self.is_op(")") {
+                        let keyword = match (self.peek(), &self.tokens[self.pos + 1].tok) {
+                            (Tok::Ident(k), Tok::Op("=")) => Some(k.clone()),
+                            _ => None,
+                        };
+                        if let Some(keyword) = keyword {
+                            self.next();
+                            self.next();
+                            kwargs.push((keyword, self.expr()?));
+                        } else if kwargs.is_empty() {
+                            args.push(self.expr()?);
+                        } else {
+                            return error(line, "positional argument after keyword argument");
+                        }
+                        if !self.is_op(")") {
+                            self.expect_op(",")?;
+                        }
+                    }
+                    self.next();
+                    Ok(Expr::Call(name, args, kwargs))
+                }
+                _ => Ok(Expr::Var(name)),
+            },
+            Tok::Newline | Tok::Eof => error(line, "unexpected end of line"),
+            Tok::Op(op) => error(line, format!("unexpected '{}'", op)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------- compiler
+
+#[derive(Clone, Debug)]
+enum Op {
+    Const(Value),
+    Load(String),
+    Store(String),
+    StoreGlobal(String),
+    List(usize),
+    Pose(usize),
+    Index,
+    Unary(UnOp),
+    Binary(BinOp),
+    // ( name, count of positional arguments, names of the keyword arguments after them )
+    Call(String, usize, Vec<String>),
+    Pop,
+    Jump(usize),
+    JumpIfFalse(usize),
+    Return,
+    Halt,
+}
+
+struct Function {
+    params: Vec<String>,
+    entry: usize,
+}
+
+struct Compiler {
+    code: Vec<Op>,
+    lines: Vec<usize>,
+    globals: HashSet<String>, // declared global in the function being compiled
+    in_function: bool,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op, line: usize) -> usize {
+        self.code.push(op);
+        self.lines.push(line);
+        self.code.len() - 1
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        match &mut self.code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+            _ => unreachable!(),
+        }
+    }
+
+    fn block(&mut self, body: &[Stmt]) {
+        for stmt in body {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        let line = stmt.line;
+        match &stmt.kind {
+            StmtKind::Assign {
+                name,
+                global,
+                value,
+            } => {
+                self.expr(value, line);
+                if *global && self.in_function {
+                    self.globals.insert(name.clone());
+                }
+                if !self.in_function || self.globals.contains(name) {
+                    self.emit(Op::StoreGlobal(name.clone()), line);
+                } else {
+                    self.emit(Op::Store(name.clone()), line);
+                }
+            }
+            StmtKind::Expr(expr) => {
+                self.expr(expr, line);
+                self.emit(Op::Pop, line);
+            }
+            StmtKind::If(branches, otherwise) => {
+                let mut ends = Vec::new();
+                for (condition, body) in branches {
+                    self.expr(condition, line);
+                    let skip = self.emit(Op::JumpIfFalse(0), line);
+                    self.block(body);
+                    ends.push(self.emit(Op::Jump(0), line));
+                    let next = self.code.len();
+                    self.patch(skip, next);
+                }
+                self.block(otherwise);
+                let end = self.code.len();
+                for at in ends {
+                    self.patch(at, end);
+                }
+            }
+            StmtKind::While(condition, body) => {
+                let start = self.code.len();
+                self.expr(condition, line);
+                let exit = self.emit(Op::JumpIfFalse(0), line);
+                self.block(body);
+                self.emit(Op::Jump(start), line);
+                let end = self.code.len();
+                self.patch(exit, end);
+            }
+            StmtKind::Return(value) => {
+                match value {
+                    Some(value) => self.expr(value, line),
+                    None => {
+                        self.emit(Op::Const(Value::None), line);
+                    }
+                }
+                self.emit(Op::Return, line);
+            }
+            StmtKind::Halt => {
+                self.emit(Op::Halt, line);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, line: usize) {
+        match expr {
+            Expr::Const(value) => {
+                self.emit(Op::Const(value.clone()), line);
+            }
+            Expr::Var(name) => {
+                self.emit(Op::Load(name.clone()), line);
+            }
+            Expr::List(items) => {
+                for item in items {
+                    self.expr(item, line);
+                }
+                self.emit(Op::List(items.len()), line);
+            }
+            Expr::Pose(items) => {
+                for item in items {
+                    self.expr(item, line);
+                }
+                self.emit(Op::Pose(items.len()), line);
+            }
+            Expr::Index(value, index) => {
+                self.expr(value, line);
+                self.expr(index, line);
+                self.emit(Op::Index, line);
+            }
+            Expr::Unary(op, value) => {
+                self.expr(value, line);
+                self.emit(Op::Unary(*op), line);
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                self.expr(lhs, line);
+                self.expr(rhs, line);
+                self.emit(Op::Binary(*op), line);
+            }
+            Expr::Call(name, args, kwargs) => {
+                for arg in args {
+                    self.expr(arg, line);
+                }
+                for (_, arg) in kwargs {
+                    self.expr(arg, line);
+                }
+                let names = kwargs.iter().map(|(k, _)| k.clone()).collect();
+                self.emit(Op::Call(name.clone(), args.len(), names), line);
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------- vm
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MoveKind {
+    J, // movej
+    L, // movel
+    P, // movep
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Joints([f64; 6]),     // rad
+    Pose(Isometry3<f64>), // tcp relative to robot base
+}
+
+// units as in URScript: rad, rad/s, m/s, s, m
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Move {
+    pub kind: MoveKind,
+    pub target: Target,
+    pub a: f64,
+    pub v: f64,
+    pub t: f64, // move time, 0 when the speed limits it
+    pub r: f64, // blend radius
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Yield {
+    Move(Move),
+    Sleep(f64), // s
+    Message(String),
+    Budget, // ran out of ops for this frame
+    Done,
+}
+
+struct Frame {
+    ret: usize,
+    locals: HashMap<String, Value>,
+}
+
+pub struct Vm {
+    code: Vec<Op>,
+    lines: Vec<usize>,
+    functions: HashMap<String, Function>,
+    pc: usize,
+    line: usize,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    globals: HashMap<String, Value>,
+}
+
+impl Vm {
+    pub fn compile(text: &str) -> Result<Vm, ScriptError> {
+        let tokens = lex(text)?;
+        let (defs, main) = Parser { tokens, pos: 0 }.program()?;
+
+        let mut compiler = Compiler {
+            code: Vec::new(),
+            lines: Vec::new(),
+            globals: HashSet::new(),
+            in_function: false,
+        };
+        compiler.block(&main);
+        // a script that only defines functions runs the last one, helpers are defined before it
+        if main.is_empty() {
+            if let Some(def) = defs.last() {
+                compiler.emit(Op::Call(def.name.clone(), 0, Vec::new()), def.line);
+                compiler.emit(Op::Pop, def.line);
+            }
+        }
+        let end_line = compiler.lines.last().copied().unwrap_or(1);
+        compiler.emit(Op::Halt, end_line);
+
+        let mut functions = HashMap::new();
+        for def in defs.iter() {
+            if functions.contains_key(&def.name) {
+                return error(def.line, format!("{} is defined twice", def.name));
+            }
+            compiler.in_function = true;
+            compiler.globals.clear();
+            let entry = compiler.code.len();
+            compiler.block(&def.body);
+            compiler.emit(Op::Const(Value::None), def.line);
+            compiler.emit(Op::Return, def.line);
+            functions.insert(
+                def.name.clone(),
+                Function {
+                    params: def.params.clone(),
+                    entry,
+                },
+            );
+        }
+
+        Ok(Vm {
+            code: compiler.code,
+            lines: compiler.lines,
+            functions,
+            pc: 0,
+            line: 1,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+        })
+    }
+
+    // line of the statement being executed
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    // robot: the simulated robot, its tool is the tcp set by set_tcp
+    // budget: max ops to run
+    pub fn run(&mut self, robot: &mut RobotUr5, budget: usize) -> Result<Yield, ScriptError> {
+        for _ in 0..budget {
+            let Some(op) = self.code.get(self.pc).cloned() else {
+                return Ok(Yield::Done);
+            };
+            self.line = self.lines[self.pc];
+            self.pc += 1;
+            match op {
+                Op::Const(value) => self.stack.push(value),
+                Op::Load(name) => {
+                    let value = self
+                        .frames
+                        .last()
+                        .and_then(|f| f.locals.get(&name))
+                        .or_else(|| self.globals.get(&name));
+                    match value {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => return self.fail(format!("unknown variable {}", name)),
+                    }
+                }
+                Op::Store(name) => {
+                    let value = self.pop();
+                    match self.frames.last_mut() {
+                        Some(frame) => frame.locals.insert(name, value),
+                        None => self.globals.insert(name, value),
+                    };
+                }
+                Op::StoreGlobal(name) => {
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                Op::List(n) => {
+                    let items = self.pop_n(n);
+                    let list = self.numbers(&items)?;
+                    self.stack.push(Value::List(list));
+                }
+                Op::Pose(n) => {
+                    let items = self.pop_n(n);
+                    let list = self.numbers(&items)?;
+                    let Ok(pose) = <[f64; 6]>::try_from(list) else {
+                        return self.fail("a pose has 6 elements");
+                    };
+                    self.stack.push(Value::Pose(pose));
+                }
+                Op::Index => {
+                    let index = self.pop();
+                    let value = self.pop();
+                    let values: &[f64] = match &value {
+                        Value::List(v) => v,
+                        Value::Pose(v) => v,
+                        _ => return self.fail(format!("cannot index a {}", value.type_name())),
+                    };
+                    let i = self.number(&index)?;
+                    if i < 0.0 || i.fract() != 0.0 || i as usize >= values.len() {
+                        return self.fail(format!("index {} out of range", i));
+                    }
+                    self.stack.push(Value::Number(values[i as usize]));
+                }
+                Op::Unary(op) => {
+                    let value = self.pop();
+                    let out = match (op, &value) {
+                        (UnOp::Neg, Value::Number(n)) => Value::Number(-n),
+                        (UnOp::Neg, Value::List(v)) => Value::List(v.iter().map(|n| -n).collect()),
+                        (UnOp::Not, Value::Bool(b)) => Value::Bool(!b),
+                        _ => return self.fail(format!("invalid operand {}", value.type_name())),
+                    };
+                    self.stack.push(out);
+                }
+                Op::Binary(op) => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let out = self.binary(op, &lhs, &rhs)?;
+                    self.stack.push(out);
+                }
+                Op::Call(name, argc, kwnames) => {
+                    let kwvalues = self.pop_n(kwnames.len());
+                    let args = self.pop_n(argc);
+                    let kwargs: Vec<(String, Value)> = kwnames.into_iter().zip(kwvalues).collect();
+                    if let Some(function) = self.functions.get(&name) {
+                        if !kwargs.is_empty() || args.len() != function.params.len() {
+                            return self.fail(format!(
+                                "{} takes {} arguments",
+                                name,
+                                function.params.len()
+                            ));
+                        }
+                        let locals = function.params.iter().cloned().zip(args).collect();
+                        self.frames.push(Frame {
+                            ret: self.pc,
+                            locals,
+                        });
+                        self.pc = function.entry;
+                        continue;
+                    }
+                    let (value, yielded) = self.builtin(robot, &name, &args, &kwargs)?;
+                    self.stack.push(value);
+                    if let Some(yielded) = yielded {
+                        return Ok(yielded);
+                    }
+                }
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Jump(target) => self.pc = target,
+                Op::JumpIfFalse(target) => {
+                    let value = self.pop();
+                    match value {
+                        Value::Bool(false) => self.pc = target,
+                        Value::Bool(true) => {}
+                        _ => {
+                            return self
+                                .fail(format!("condition is a {}, not a bool", value.type_name()))
+                        }
+                    }
+                }
+                Op::Return => match self.frames.pop() {
+                    Some(frame) => self.pc = frame.ret,
+                    None => return Ok(Yield::Done),
+                },
+                Op::Halt => {
+                    self.pc = self.code.len();
+                    return Ok(Yield::Done);
+                }
+            }
+        }
+        Ok(Yield::Budget)
+    }
+
+    fn fail<T>(&self, message: impl Into<String>) -> Result<T, ScriptError> {
+        error(self.line, message)
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap_or(Value::None)
+    }
+
+    fn pop_n(&mut self, n: usize) -> Vec<Value> {
+        let at = self.stack.len().saturating_sub(n);
+        self.stack.split_off(at)
+    }
+
+    fn number(&self, value: &Value) -> Result<f64, ScriptError> {
+        match value {
+            Value::Number(n) => Ok(*n),
+            _ => self.fail(format!("expected a number, got a {}", value.type_name())),
+        }
+    }
+
+    fn numbers(&self, values: &[Value]) -> Result<Vec<f64>, ScriptError> {
+        values.iter().map(|v| self.number(v)).collect()
+    }
+
+    fn pose(&self, value: &Value) -> Result<Isometry3<f64>, ScriptError> {
+        match value {
+            Value::Pose(p) => Ok(to_isometry(p)),
+            _ => self.fail(format!("expected a pose, got a {}", value.type_name())),
+        }
+    }
+
+    fn joints(&self, value: &Value) -> Result<[f64; 6], ScriptError> {
+        match value {
+            Value::List(v) if v.len() == 6 => Ok([v[0], v[1], v[2], v[3], v[4], v[5]]),
+            _ => self.fail(format!(
+                "expected 6 joint positions, got a {}",
+                value.type_name()
+            )),
+        }
+    }
+
+    fn binary(&self, op: BinOp, lhs: &Value, rhs: &Value) -> Result<Value, ScriptError> {
+        use Value::*;
+        let out = match (op, lhs, rhs) {
+            (BinOp::And, Bool(a), Bool(b)) => Bool(*a && *b),
+            (BinOp::Or, Bool(a), Bool(b)) => Bool(*a || *b),
+            (BinOp::Eq, a, b) => Bool(a == b),
+            (BinOp::Ne, a, b) => Bool(a != b),
+            (BinOp::Add, Str(a), b) => Str(format!("{}{}", a, b)),
+            (BinOp::Add, List(a), List(b)) if a.len() == b.len() => {
+                List(a.iter().zip(b).map(|(a, b)| a + b).collect())
+            }
+            (BinOp::Sub, List(a), List(b)) if a.len() == b.len() => {
+                List(a.iter().zip(b).map(|(a, b)| a - b).collect())
+            }
+            (BinOp::Mul, List(a), Number(b)) | (BinOp::Mul, Number(b), List(a)) => {
+                List(a.iter().map(|a| a * b).collect())
+            }
+            (op, Number(a), Number(b)) => match op {
+                BinOp::Add => Number(a + b),
+                BinOp::Sub => Number(a - b),
+                BinOp::Mul => Number(a * b),
+                BinOp::Div if *b == 0.0 => return self.fail("division by zero"),
+                BinOp::Div => Number(a / b),
+                BinOp::Rem => Number(a % b),
+                BinOp::Lt => Bool(a < b),
+                BinOp::Le => Bool(a <= b),
+                BinOp::Gt => Bool(a > b),
+                BinOp::Ge => Bool(a >= b),
+                _ => return self.fail(format!("invalid operands for {:?}", op)),
+            },
+            _ => {
+                return self.fail(format!(
+                    "invalid operands for {:?}: {} and {}",
+                    op,
+                    lhs.type_name(),
+                    rhs.type_name()
+                ))
+            }
+        };
+        Ok(out)
+    }
+
+    // Out: ( return value, what to yield to the caller )
+    fn builtin(
+        &self,
+        robot: &mut RobotUr5,
+        name: &str,
+        args: &[Value],
+        kwargs: &[(String, Value)],
+    ) -> Result<(Value, Option<Yield>), ScriptError> {
+        // argument by position or keyword
+        let arg = |i: usize, key: &str| -> Option<&Value> {
+            args.get(i)
+                .or_else(|| kwargs.iter().find(|(k, _)| k == key).map(|(_, v)| v))
+        };
+        let required = |i: usize, key: &str| -> Result<&Value, ScriptError> {
+            arg(i, key).map_or_else(|| self.fail(format!("{} needs {}", name, key)), Ok)
+        };
+        let number = |i: usize, key: &str, default: f64| -> Result<f64, ScriptError> {
+            arg(i, key).map_or(Ok(default), |v| self.number(v))
+        };
+        let unary = |f: fn(f64) -> f64| -> Result<(Value, Option<Yield>), ScriptError> {
+            Ok((Value::Number(f(self.number(required(0, "x")?)?)), None))
+        };
+
+        match name {
+            "movej" | "movel" | "movep" => {
+                let (kind, a, v) = match name {
+                    "movej" => (MoveKind::J, 1.4, 1.05),
+                    "movel" => (MoveKind::L, 1.2, 0.25),
+                    _ => (MoveKind::P, 1.2, 0.25),
+                };
+                let target = match required(0, if kind == MoveKind::J { "q" } else { "pose" })? {
+                    Value::Pose(p) => Target::Pose(to_isometry(p)),
+                    value => Target::Joints(self.joints(value)?),
+                };
+                let (t, r) = if kind == MoveKind::P {
+                    (0.0, number(3, "r", 0.0)?)
+                } else {
+                    (number(3, "t", 0.0)?, number(4, "r", 0.0)?)
+                };
+                let m = Move {
+                    kind,
+                    target,
+                    a: number(1, "a", a)?,
+                    v: number(2, "v", v)?,
+                    t,
+                    r,
+                };
+                if m.a <= 0.0 || m.v <= 0.0 || m.t < 0.0 || m.r < 0.0 {
+                    return self.fail(format!("{} needs a > 0, v > 0, t >= 0 and r >= 0", name));
+                }
+                Ok((Value::None, Some(Yield::Move(m))))
+            }
+            "sleep" => {
+                let t = self.number(required(0, "t")?)?;
+                if t < 0.0 {
+                    return self.fail("sleep time must not be negative");
+                }
+                Ok((Value::None, Some(Yield::Sleep(t))))
+            }
+            "sync" => Ok((Value::None, Some(Yield::Budget))),
+            "textmsg" => {
+                let text: Vec<String> = args.iter().map(|v| v.to_string()).collect();
+                Ok((Value::None, Some(Yield::Message(text.concat()))))
+            }
+            "set_tcp" => {
+                robot.tool = self.pose(required(0, "pose")?)?;
+                Ok((Value::None, None))
+            }
+            "get_actual_joint_positions" | "get_target_joint_positions" => {
+                Ok((Value::List(robot.joints().to_vec()), None))
+            }
+            "get_actual_tcp_pose" | "get_target_tcp_pose" => {
+                Ok((Value::Pose(from_isometry(&robot.tcp_pose())), None))
+            }
+            "get_forward_kin" => {
+                let joints = match arg(0, "q") {
+                    Some(q) => self.joints(q)?,
+                    None => robot.joints(),
+                };
+                let pose = robot.flange_at(joints) * robot.tool;
+                Ok((Value::Pose(from_isometry(&pose)), None))
+            }
+            "get_inverse_kin" => {
+                let pose = self.pose(required(0, "x")?)?;
+                let near = match arg(1, "qnear") {
+                    Some(q) => self.joints(q)?,
+                    None => robot.joints(),
+                };
+                match robot.ik_near(&(pose * robot.tool.inverse()), &near) {
+                    Ok(joints) => Ok((Value::List(joints.to_vec()), None)),
+                    Err(e) => self.fail(e.to_string()),
+                }
+            }
+            "pose_trans" => {
+                let a = self.pose(required(0, "p_from")?)?;
+                let b = self.pose(required(1, "p_from_to")?)?;
+                Ok((Value::Pose(from_isometry(&(a * b))), None))
+            }
+            "pose_inv" => {
+                let a = self.pose(required(0, "p_from")?)?;
+                Ok((Value::Pose(from_isometry(&a.inverse())), None))
+            }
+            "pose_add" => {
+                let a = self.pose(required(0, "p_1")?)?;
+                let b = self.pose(required(1, "p_2")?)?;
+                let pose = Isometry3::from_parts(
+                    (a.translation.vector + b.translation.vector).into(),
+                    a.rotation * b.rotation,
+                );
+                Ok((Value::Pose(from_isometry(&pose)), None))
+            }
+            "pose_dist" => {
+                let a = self.pose(required(0, "p_from")?)?;
+                let b = self.pose(required(1, "p_to")?)?;
+                let d = (a.translation.vector - b.translation.vector).norm();
+                Ok((Value::Number(d), None))
+            }
+            "d2r" => unary(f64::to_radians),
+            "r2d" => unary(f64::to_degrees),
+            "sin" => unary(f64::sin),
+            "cos" => unary(f64::cos),
+            "tan" => unary(f64::tan),
+            "asin" => unary(f64::asin),
+            "acos" => unary(f64::acos),
+            "atan" => unary(f64::atan),
+            "sqrt" => unary(f64::sqrt),
+            "fabs" => unary(f64::abs),
+            "floor" => unary(f64::floor),
+            "ceil" => unary(f64::ceil),
+            "atan2" => {
+                let y = self.number(required(0, "y")?)?;
+                let x = self.number(required(1, "x")?)?;
+                Ok((Value::Number(y.atan2(x)), None))
+            }
+            "pow" => {
+                let base = self.number(required(0, "base")?)?;
+                let exponent = self.number(required(1, "exponent")?)?;
+                Ok((Value::Number(base.powf(exponent)), None))
+            }
+            "norm" => match required(0, "a")? {
+                Value::Number(n) => Ok((Value::Number(n.abs()), None)),
+                Value::List(v) => Ok((
+                    Value::Number(v.iter().map(|x| x * x).sum::<f64>().sqrt()),
+                    None,
+                )),
+                Value::Pose(p) => Ok((Value::Number(Vector3::new(p[0], p[1], p[2]).norm()), None)),
+                v => self.fail(format!("cannot take the norm of a {}", v.type_name())),
+            },
+            _ => self.fail(format!("unknown function {}", name)),
+        }
+    }
+}
+
+// p[x, y, z, rx, ry, rz] to isometry, the rotation is a rotation vector
+pub fn to_isometry(p: &[f64; 6]) -> Isometry3<f64> {
+    Isometry3::new(
+        Vector3::new(p[0], p[1], p[2]),
+        Vector3::new(p[3], p[4], p[5]),
+    )
+}
+
+pub fn from_isometry(iso: &Isometry3<f64>) -> [f64; 6] {
+    let t = iso.translation.vector;
+    let r = iso.rotation.scaled_axis();
+    [t.x, t.y, t.z, r.x, r.y, r.z]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot_ur5::JOINTS_POS;
+    use nalgebra::UnitQuaternion;
+
+    fn robot() -> RobotUr5 {
+        RobotUr5::new(0, JOINTS_POS.map(f64::to_radians))
+    }
+
+    // run to the end, collecting everything the script yields
+    fn run(text: &str) -> Result<(Vm, Vec<Yield>), ScriptError> {
+        let mut vm = Vm::compile(text)?;
+        let mut robot = robot();
+        let mut out = Vec::new();
+        loop {
+            match vm.run(&mut robot, 10_000)? {
+                Yield::Done => return Ok((vm, out)),
+                y => out.push(y),
+            }
+        }
+    }
+
+    #[test]
+    fn control_flow_and_functions() {
+        let text = "
+def add(a, b):
+  return a + b
+end
+def main():
+  global total = 0
+  i = 0
+  while i < 5:
+    if i % 2 == 0:
+      total = add(total, i)
+    elif i == 3:
+      total = total + 100
+    else:
+      total = total - 1
+    end
+    i = i + 1
+  end
+  textmsg(\"total \", total)
+end
+";
+        let (_, out) = run(text).unwrap();
+        assert_eq!(out, vec![Yield::Message("total 105".to_string())]);
+    }
+
+    #[test]
+    fn moves_yield_with_arguments() {
+        let text = "
+q = [d2r(90), d2r(-120), d2r(90), d2r(-60), d2r(-90), 0]
+movej(q, a=1.0, v=0.5)
+movel(p[0.1, 0.2, 0.3, 0, 0, 0], 1.2, 0.25, r=0.01)
+sleep(0.5)
+";
+        let (_, out) = run(text).unwrap();
+        assert_eq!(out.len(), 3);
+        let Yield::Move(m) = &out[0] else { panic!() };
+        assert_eq!(m.kind, MoveKind::J);
+        assert_eq!((m.a, m.v, m.t, m.r), (1.0, 0.5, 0.0, 0.0));
+        assert_eq!(m.target, Target::Joints(JOINTS_POS.map(f64::to_radians)));
+        let Yield::Move(m) = &out[1] else { panic!() };
+        assert_eq!(m.kind, MoveKind::L);
+        assert_eq!((m.a, m.v, m.r), (1.2, 0.25, 0.01));
+        assert_eq!(out[2], Yield::Sleep(0.5));
+    }
+
+    #[test]
+    fn poses_round_trip() {
+        let text = "
+start = get_actual_tcp_pose()
+back = pose_trans(pose_trans(start, p[0, 0, 0.1, 0, 0.3, 0]), pose_inv(p[0, 0, 0.1, 0, 0.3, 0]))
+textmsg(pose_dist(start, back) < 0.000001)
+q = get_inverse_kin(start)
+textmsg(norm(q - get_actual_joint_positions()) < 0.000001)
+";
+        let (_, out) = run(text).unwrap();
+        assert_eq!(
+            out,
+            vec![
+                Yield::Message("True".to_string()),
+                Yield::Message("True".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn pose_add_rotates_in_order() {
+        let text = "
+movel(pose_add(p[0.1, 0, 0.2, 0.5, 0, 0], p[0, 0.3, 0, 0, 0.7, 0]))
+";
+        let (_, out) = run(text).unwrap();
+        let Yield::Move(Move {
+            target: Target::Pose(pose),
+            ..
+        }) = &out[0]
+        else {
+            panic!()
+        };
+        // p_3.R = p_1.R * p_2.R
+        let expected = UnitQuaternion::from_scaled_axis(Vector3::new(0.5, 0.0, 0.0))
+            * UnitQuaternion::from_scaled_axis(Vector3::new(0.0, 0.7, 0.0));
+        assert!(pose.rotation.angle_to(&expected) < 1e-9);
+        assert!((pose.translation.vector - Vector3::new(0.1, 0.3, 0.2)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn errors_have_lines() {
+        let err = |text: &str| match run(text) {
+            Err(e) => e,
+            Ok(_) => panic!("{} should fail", text),
+        };
+        assert_eq!(err("x = 1\ny = x +\n").line, 2);
+        assert_eq!(err("def f():\n  x = 1\n").message, "missing 'end'");
+        assert_eq!(err("x = 1\n\nmovej(x)\n").line, 3);
+        assert_eq!(err("a = 1\nb = c\n").message, "unknown variable c");
+        assert_eq!(err("if 1:\nend\n").line, 1);
+        assert_eq!(err("x = p[1, 2]\n").message, "a pose has 6 elements");
+        assert_eq!(err("foo()\n").message, "unknown function foo");
+    }
+
+    #[test]
+    fn locals_do_not_leak() {
+        let text = "
+def f():
+  x = 1
+end
+f()
+textmsg(x)
+";
+        assert_eq!(run(text).err().map(|e| e.line), Some(6));
+    }
+}
@@ -0,0 +1,244 @@
+// Handles to the app for JS. Each RobotViewer owns a channel whose receiver the app takes over,
+// commands sent before the app starts wait in the channel, after the app is gone sending fails.
+// The page has one app, winit runs a single event loop, but any number of viewers.
+use bevy::prelude::*;
+use flume::{unbounded, Receiver, Sender};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use wasm_bindgen::prelude::*;
+
+use crate::{Cmd, GripperCmd, RobotCmd};
+
+static HUB: Hub = Hub::new();
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ViewerError {
+    AppGone,
+}
+
+impl fmt::Display for ViewerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViewerError::AppGone => write!(f, "the app is gone"),
+        }
+    }
+}
+
+impl std::error::Error for ViewerError {}
+
+// channels of the viewers not taken over by the app yet
+struct Hub {
+    gone: AtomicBool,
+    receivers: Mutex<Vec<Receiver<Cmd>>>,
+}
+
+impl Hub {
+    const fn new() -> Self {
+        Hub {
+            gone: AtomicBool::new(false),
+            receivers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn receivers(&self) -> std::sync::MutexGuard<'_, Vec<Receiver<Cmd>>> {
+        // a panic while locked leaves the list valid
+        self.receivers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn connect(&self) -> Result<Sender<Cmd>, ViewerError> {
+        if self.is_gone() {
+            return Err(ViewerError::AppGone);
+        }
+        let (sender, receiver) = unbounded();
+        self.receivers().push(receiver);
+        Ok(sender)
+    }
+
+    fn send(&self, sender: &Sender<Cmd>, cmd: Cmd) -> Result<(), ViewerError> {
+        if self.is_gone() {
+            return Err(ViewerError::AppGone);
+        }
+        sender.send(cmd).map_err(|_| ViewerError::AppGone)
+    }
+
+    fn take(&self) -> Vec<Receiver<Cmd>> {
+        std::mem::take(&mut *self.receivers())
+    }
+
+    fn close(&self) {
+        self.gone.store(true, Ordering::SeqCst);
+        self.receivers().clear();
+    }
+
+    fn is_gone(&self) -> bool {
+        self.gone.load(Ordering::SeqCst)
+    }
+}
+
+#[wasm_bindgen]
+pub struct RobotViewer {
+    sender: Sender<Cmd>,
+}
+
+#[wasm_bindgen]
+impl RobotViewer {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Result<RobotViewer, JsError> {
+        Ok(RobotViewer::connect()?)
+    }
+
+    // false once the app is gone
+    pub fn is_alive(&self) -> bool {
+        !HUB.is_gone() && !self.sender.is_disconnected()
+    }
+
+    // joint: range [1, 6]
+    // angle: deg
+    pub fn set_joint_pos(&self, robot: u16, joint: u16, angle: f32) -> Result<(), JsError> {
+        Ok(self.send(Cmd::Robot(RobotCmd::JointPos {
+            robot,
+            joint,
+            angle,
+        }))?)
+    }
+
+    // finger: range [1, 2]
+    // pos: %
+    pub fn set_finger_pos(&self, robot: u16, finger: u16, pos: f32) -> Result<(), JsError> {
+        Ok(self.send(Cmd::Gripper(GripperCmd::FingerPos { robot, finger, pos }))?)
+    }
+}
+
+impl RobotViewer {
+    pub(crate) fn connect() -> Result<RobotViewer, ViewerError> {
+        Ok(RobotViewer {
+            sender: HUB.connect()?,
+        })
+    }
+
+    pub(crate) fn send(&self, cmd: Cmd) -> Result<(), ViewerError> {
+        HUB.send(&self.sender, cmd)
+    }
+
+    // shared by the functions of the page that take no viewer
+    pub(crate) fn default_viewer() -> Result<&'static RobotViewer, ViewerError> {
+        static DEFAULT: std::sync::OnceLock<RobotViewer> = std::sync::OnceLock::new();
+        if let Some(viewer) = DEFAULT.get() {
+            return Ok(viewer);
+        }
+        let viewer = RobotViewer::connect()?;
+        Ok(DEFAULT.get_or_init(|| viewer))
+    }
+}
+
+#[wasm_bindgen]
+pub fn set_robot_joint_pos(robot: u16, joint: u16, angle: f32) -> Result<(), JsError> {
+    RobotViewer::default_viewer()?.set_joint_pos(robot, joint, angle)
+}
+
+#[wasm_bindgen]
+pub fn set_robot_finger_pos(robot: u16, finger: u16, pos: f32) -> Result<(), JsError> {
+    RobotViewer::default_viewer()?.set_finger_pos(robot, finger, pos)
+}
+
+// the commands of the viewers, owned by the app
+#[derive(Resource)]
+pub struct ViewerChannels {
+    hub: &'static Hub,
+    receivers: Vec<Receiver<Cmd>>,
+}
+
+impl ViewerChannels {
+    // takes over the viewers, a panic of the app makes them fail like its exit
+    pub fn start() -> Self {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            HUB.gone.store(true, Ordering::SeqCst);
+            hook(info);
+        }));
+        ViewerChannels::with_hub(&HUB)
+    }
+
+    fn with_hub(hub: &'static Hub) -> Self {
+        ViewerChannels {
+            hub,
+            receivers: hub.take(),
+        }
+    }
+
+    // Out: the commands of every viewer, in the order each one sent them
+    pub fn recv(&mut self) -> Vec<Cmd> {
+        self.receivers.extend(self.hub.take());
+        let mut cmds = Vec::new();
+        self.receivers.retain(|receiver| {
+            cmds.extend(receiver.try_iter());
+            // dropped by JS
+            !receiver.is_disconnected()
+        });
+        cmds
+    }
+}
+
+impl Drop for ViewerChannels {
+    fn drop(&mut self) {
+        self.hub.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joint(angle: f32) -> Cmd {
+        Cmd::Robot(RobotCmd::JointPos {
+            robot: 0,
+            joint: 1,
+            angle,
+        })
+    }
+
+    fn angles(cmds: &[Cmd]) -> Vec<f32> {
+        cmds.iter()
+            .map(|cmd| match cmd {
+                Cmd::Robot(RobotCmd::JointPos { angle, .. }) => *angle,
+                _ => panic!("unexpected command"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn early_commands_are_queued() {
+        let hub: &'static Hub = Box::leak(Box::new(Hub::new()));
+        let early = hub.connect().unwrap();
+        hub.send(&early, joint(1.0)).unwrap();
+        hub.send(&early, joint(2.0)).unwrap();
+
+        let mut channels = ViewerChannels::with_hub(hub);
+        let late = hub.connect().unwrap();
+        hub.send(&late, joint(3.0)).unwrap();
+        hub.send(&early, joint(4.0)).unwrap();
+        assert_eq!(angles(&channels.recv()), vec![1.0, 2.0, 4.0, 3.0]);
+
+        // a dropped viewer is forgotten once drained
+        hub.send(&late, joint(5.0)).unwrap();
+        drop(late);
+        assert_eq!(angles(&channels.recv()), vec![5.0]);
+        assert_eq!(channels.receivers.len(), 1);
+    }
+
+    #[test]
+    fn sending_fails_once_the_app_is_gone() {
+        let hub: &'static Hub = Box::leak(Box::new(Hub::new()));
+        let sender = hub.connect().unwrap();
+        let channels = ViewerChannels::with_hub(hub);
+        hub.send(&sender, joint(1.0)).unwrap();
+        drop(channels);
+        assert_eq!(hub.send(&sender, joint(2.0)), Err(ViewerError::AppGone));
+        assert_eq!(hub.connect().err(), Some(ViewerError::AppGone));
+    }
+}
@@ -0,0 +1,475 @@
+// Workpieces for pick and place: boxes, cylinders and imported meshes. A gripper closing its
+// fingertips on a workpiece, or a suction cup with vacuum touching one, picks it up: the workpiece
+// becomes a child of the tool and follows it. Opening the jaws or releasing the vacuum drops it
+// onto the floor, a fixture or another workpiece. With the physics feature the workpieces are
+// rigid bodies instead, grasped by the contacts of the tools.
+use bevy::{prelude::*, render::primitives::Aabb};
+#[cfg(not(feature = "physics"))]
+use std::collections::HashSet;
+
+use crate::{
+    cell_layout::{Fixture, Shape},
+    collision::Solid,
+};
+#[cfg(not(feature = "physics"))]
+use crate::{
+    end_effector::{EndEffectorPlugin, Finger, GripperFingertip, Tool, Vacuum},
+    robot_cell::{FingerPos, RobotCellPlugin},
+};
+
+#[cfg(not(feature = "physics"))]
+const GRASP_TOLERANCE: f32 = 0.01; // m, between a fingertip and the surface
+#[cfg(not(feature = "physics"))]
+const RELEASE_MARGIN: f32 = 0.02; // opening past the grasp that releases, range [0.0, 1.0]
+const SPAWN_POS: Vec3 = Vec3::new(0.0, 1.0, 0.25); // m, dropped from there
+const WORKPIECE_COLOR: [f32; 3] = [0.9, 0.55, 0.1];
+
+#[derive(Component)]
+pub struct Workpiece {
+    // ( solid, its center in the workpiece frame ), None until the scene of a mesh is loaded
+    pub shape: Option<(Solid, Vec3)>,
+    #[cfg(not(feature = "physics"))]
+    pub grasp: Option<Grasp>,
+}
+
+#[cfg(not(feature = "physics"))]
+#[derive(Clone, Copy, Debug)]
+pub struct Grasp {
+    pub tool: Entity,
+    pub jaws: [f32; 2], // range [0.0, 1.0], where the jaws stopped on the workpiece
+}
+
+// released or spawned, comes to rest on what is below in the next frame
+#[cfg(not(feature = "physics"))]
+#[derive(Component)]
+struct Settling;
+
+pub struct WorkpiecePlugin;
+
+impl Plugin for WorkpiecePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, WorkpiecePlugin::fit_scenes);
+        #[cfg(not(feature = "physics"))]
+        app.add_systems(
+            Update,
+            (
+                WorkpiecePlugin::grasp
+                    .after(RobotCellPlugin::update_finger_pos)
+                    .before(EndEffectorPlugin::update_part_pos),
+                WorkpiecePlugin::settle,
+            ),
+        );
+    }
+}
+
+// Out: half size of the box around the solid, m
+#[cfg(not(feature = "physics"))]
+fn half_extents(solid: &Solid) -> Vec3 {
+    match *solid {
+        Solid::Box { half_size } => half_size,
+        Solid::Cylinder {
+            radius,
+            half_height,
+        } => Vec3::new(radius, half_height, radius),
+    }
+}
+
+// Out: corners of the box around center
+fn corners(center: Vec3, half: Vec3) -> [Vec3; 8] {
+    std::array::from_fn(|i| {
+        let sign = |bit: usize| if i & bit == 0 { -1.0 } else { 1.0 };
+        center + Vec3::new(sign(1), sign(2), sign(4)) * half
+    })
+}
+
+// Out: ( min, max ) of the points
+fn bounds(points: impl Iterator<Item = Vec3>) -> (Vec3, Vec3) {
+    points.fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), p| (min.min(p), max.max(p)),
+    )
+}
+
+// tf: solid frame in the world
+// Out: ( min, max ) of the box around the solid in the world, m
+#[cfg(not(feature = "physics"))]
+fn world_bounds(solid: &Solid, center: Vec3, tf: &Transform) -> (Vec3, Vec3) {
+    let corners = corners(center, half_extents(solid));
+    bounds(corners.into_iter().map(|p| tf.transform_point(p)))
+}
+
+// bounds: ( min, max ) of the falling workpiece
+// supports: ( min, max ) of what it can land on
+// Out: height its bottom comes to rest at, m, 0 on the floor
+#[cfg(not(feature = "physics"))]
+fn rest_height(bounds: (Vec3, Vec3), supports: &[(Vec3, Vec3)]) -> f32 {
+    let (min, max) = bounds;
+    supports
+        .iter()
+        .filter(|(smin, smax)| smin.x < max.x && smax.x > min.x && smin.z < max.z && smax.z > min.z)
+        // below the workpiece, or slightly into it
+        .map(|(_, smax)| smax.y)
+        .filter(|top| *top <= min.y + GRASP_TOLERANCE)
+        .fold(0.0, f32::max)
+}
+
+// tf: workpiece frame in the world
+// p: in the world
+// Out: m, 0 inside
+#[cfg(not(feature = "physics"))]
+fn surface_distance(solid: &Solid, center: Vec3, tf: &Transform, p: Vec3) -> f32 {
+    let local = tf.compute_affine().inverse().transform_point3(p) - center;
+    solid.distance(local)
+}
+
+// tips: fingertips in the world, on both sides of the workpiece and at its surface
+#[cfg(not(feature = "physics"))]
+fn jaws_close_on(solid: &Solid, center: Vec3, tf: &Transform, tips: [Vec3; 2]) -> bool {
+    let between = (tips[0] + tips[1]) / 2.0;
+    surface_distance(solid, center, tf, between) == 0.0
+        && tips
+            .iter()
+            .all(|&tip| surface_distance(solid, center, tf, tip) <= GRASP_TOLERANCE)
+}
+
+impl WorkpiecePlugin {
+    // placed as in the layout, at rest or not
+    pub fn spawn_workpiece(world: &mut World, layout: &Fixture) -> Entity {
+        let transform = layout.pose.transform();
+        let name = Name::new(layout.name.clone());
+        let Some((mesh, solid)) = layout.shape.mesh() else {
+            let scene = match &layout.shape {
+                Shape::Scene(path) => world.resource::<AssetServer>().load(path.as_str()),
+                _ => Handle::default(),
+            };
+            return world
+                .spawn((
+                    SceneBundle {
+                        scene,
+                        transform,
+                        ..default()
+                    },
+                    name,
+                    Workpiece {
+                        shape: None,
+                        #[cfg(not(feature = "physics"))]
+                        grasp: None,
+                    },
+                ))
+                .id();
+        };
+        let [r, g, b] = layout.color;
+        let mesh = world.resource_mut::<Assets<Mesh>>().add(mesh);
+        let material = world
+            .resource_mut::<Assets<StandardMaterial>>()
+            .add(Color::rgb(r, g, b).into());
+        world
+            .spawn((
+                PbrBundle {
+                    mesh,
+                    material,
+                    transform,
+                    ..default()
+                },
+                name,
+                Workpiece {
+                    shape: Some((solid, Vec3::ZERO)),
+                    #[cfg(not(feature = "physics"))]
+                    grasp: None,
+                },
+            ))
+            .id()
+    }
+
+    // a new workpiece dropped in front of the robots
+    pub fn add(world: &mut World, shape: Shape) {
+        let count = world.query::<&Workpiece>().iter(world).count();
+        let layout = Fixture {
+            name: format!("workpiece{}", count + 1),
+            shape,
+            pose: default(),
+            color: WORKPIECE_COLOR,
+        };
+        let entity = WorkpiecePlugin::spawn_workpiece(world, &layout);
+        world
+            .entity_mut(entity)
+            .insert(Transform::from_translation(SPAWN_POS));
+        #[cfg(not(feature = "physics"))]
+        world.entity_mut(entity).insert(Settling);
+    }
+
+    // held ones too
+    pub fn despawn_workpieces(world: &mut World) {
+        let workpieces: Vec<Entity> = world
+            .query_filtered::<Entity, With<Workpiece>>()
+            .iter(world)
+            .collect();
+        for entity in workpieces {
+            world.entity_mut(entity).despawn_recursive();
+        }
+    }
+
+    // drops what the tool holds, before the tool is despawned with its children
+    #[cfg(not(feature = "physics"))]
+    pub fn release_grasps(world: &mut World, tool: Entity) {
+        let held: Vec<(Entity, GlobalTransform)> = world
+            .query::<(Entity, &Workpiece, &GlobalTransform)>()
+            .iter(world)
+            .filter(|(_, workpiece, _)| workpiece.grasp.is_some_and(|g| g.tool == tool))
+            .map(|(entity, _, gt)| (entity, *gt))
+            .collect();
+        for (entity, gt) in held {
+            let mut entity = world.entity_mut(entity);
+            entity.remove_parent();
+            entity.insert((gt.compute_transform(), Settling));
+            if let Some(mut workpiece) = entity.get_mut::<Workpiece>() {
+                workpiece.grasp = None;
+            }
+        }
+    }
+
+    // the shape of an imported mesh is the box around its meshes
+    fn fit_scenes(
+        mut q_workpiece: Query<(Entity, &mut Workpiece, &GlobalTransform)>,
+        q_children: Query<&Children>,
+        q_mesh: Query<(&Aabb, &GlobalTransform)>,
+    ) {
+        for (entity, mut workpiece, gt) in q_workpiece.iter_mut() {
+            if workpiece.shape.is_some() {
+                continue;
+            }
+            let to_local = gt.affine().inverse();
+            let mut points = Vec::new();
+            let mut open = vec![entity];
+            while let Some(e) = open.pop() {
+                if let Ok((aabb, mesh_gt)) = q_mesh.get(e) {
+                    let to_workpiece = to_local * mesh_gt.affine();
+                    let corners = corners(aabb.center.into(), aabb.half_extents.into());
+                    points.extend(corners.map(|p| to_workpiece.transform_point3(p)));
+                }
+                if let Ok(children) = q_children.get(e) {
+                    open.extend(children.iter());
+                }
+            }
+            if !points.is_empty() {
+                let (min, max) = bounds(points.into_iter());
+                let half_size = (max - min) / 2.0;
+                workpiece.shape = Some((Solid::Box { half_size }, (min + max) / 2.0));
+            }
+        }
+    }
+
+    #[cfg(not(feature = "physics"))]
+    #[allow(clippy::type_complexity)]
+    fn grasp(
+        mut commands: Commands,
+        mut q_tool: Query<(
+            Entity,
+            &mut Tool,
+            &GlobalTransform,
+            &Children,
+            Option<&FingerPos>,
+            Option<&Vacuum>,
+        )>,
+        q_tip: Query<(&GripperFingertip, &GlobalTransform)>,
+        mut q_workpiece: Query<(Entity, &mut Workpiece, &GlobalTransform, &mut Transform)>,
+    ) {
+        // held workpieces stop the jaws, opening or releasing the vacuum drops them
+        let mut holding = HashSet::new();
+        for (entity, mut workpiece, gt, mut tf) in q_workpiece.iter_mut() {
+            let Some(grasp) = workpiece.grasp else {
+                continue;
+            };
+            let release = match q_tool.get_mut(grasp.tool) {
+                Ok((_, mut tool, _, _, fingers, vacuum)) if tool.robot.is_some() => {
+                    match (fingers, vacuum) {
+                        (Some(fingers), _) => {
+                            let target = fingers.0.map(|f| f / 100.0);
+                            let open = (0..2).any(|i| target[i] > grasp.jaws[i] + RELEASE_MARGIN);
+                            if !open {
+                                let jaws = [0, 1].map(|i| tool.jaws[i].max(grasp.jaws[i]));
+                                if tool.jaws != jaws {
+                                    tool.jaws = jaws;
+                                }
+                            }
+                            open
+                        }
+                        (None, Some(vacuum)) => !vacuum.0,
+                        (None, None) => true,
+                    }
+                }
+                _ => true,
+            };
+            if release {
+                workpiece.grasp = None;
+                *tf = gt.compute_transform();
+                commands.entity(entity).remove_parent().insert(Settling);
+            } else {
+                holding.insert(grasp.tool);
+            }
+        }
+
+        // a free tool closing on a workpiece picks it up
+        for (tool_entity, tool, tool_gt, children, fingers, vacuum) in q_tool.iter() {
+            if tool.robot.is_none() || holding.contains(&tool_entity) {
+                continue;
+            }
+            let mut tips = [None, None];
+            for &child in children.iter() {
+                if let Ok((tip, gt)) = q_tip.get(child) {
+                    let i = match tip.finger {
+                        Finger::One => 0,
+                        Finger::Two => 1,
+                    };
+                    tips[i] = Some(gt.translation());
+                }
+            }
+            let picks = |solid: &Solid, center: Vec3, tf: &Transform| match (fingers, vacuum, tips)
+            {
+                (Some(fingers), _, [Some(a), Some(b)]) => {
+                    let closing = (0..2).any(|i| fingers.0[i] / 100.0 < tool.jaws[i] - 1e-3);
+                    closing && jaws_close_on(solid, center, tf, [a, b])
+                }
+                (None, Some(vacuum), [Some(cup), _]) => {
+                    vacuum.0 && surface_distance(solid, center, tf, cup) <= GRASP_TOLERANCE
+                }
+                _ => false,
+            };
+            let picked = q_workpiece.iter_mut().find(|(_, workpiece, gt, _)| {
+                workpiece.grasp.is_none()
+                    && workpiece.shape.is_some_and(|(solid, center)| {
+                        picks(&solid, center, &gt.compute_transform())
+                    })
+            });
+            if let Some((entity, mut workpiece, gt, mut tf)) = picked {
+                workpiece.grasp = Some(Grasp {
+                    tool: tool_entity,
+                    jaws: tool.jaws,
+                });
+                *tf = gt.reparented_to(tool_gt);
+                commands.entity(tool_entity).add_child(entity);
+            }
+        }
+    }
+
+    // a workpiece let go of lands on the floor, a fixture or another workpiece
+    #[cfg(not(feature = "physics"))]
+    #[allow(clippy::type_complexity)]
+    fn settle(
+        mut commands: Commands,
+        mut q_settling: Query<(Entity, &Workpiece, &mut Transform), With<Settling>>,
+        q_workpiece: Query<(&Workpiece, &GlobalTransform), Without<Settling>>,
+        q_solid: Query<(&Solid, &GlobalTransform)>,
+    ) {
+        let supports: Vec<(Vec3, Vec3)> = q_workpiece
+            .iter()
+            .filter(|(w, _)| w.grasp.is_none())
+            .filter_map(|(w, gt)| w.shape.map(|s| (s, gt)))
+            .map(|((solid, center), gt)| world_bounds(&solid, center, &gt.compute_transform()))
+            .chain(
+                q_solid
+                    .iter()
+                    .map(|(solid, gt)| world_bounds(solid, Vec3::ZERO, &gt.compute_transform())),
+            )
+            .collect();
+        for (entity, workpiece, mut tf) in q_settling.iter_mut() {
+            // an imported mesh waits for its scene
+            let Some((solid, center)) = workpiece.shape else {
+                continue;
+            };
+            let bounds = world_bounds(&solid, center, &tf);
+            tf.translation.y += rest_height(bounds, &supports) - bounds.0.y;
+            commands.entity(entity).remove::<Settling>();
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "physics")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jaws_close_on_a_box() {
+        let solid = Solid::Box {
+            half_size: Vec3::new(0.02, 0.03, 0.02),
+        };
+        let tf = Transform::from_xyz(0.0, 0.03, 0.25);
+        let at = |x: f32| [Vec3::new(-x, 0.05, 0.25), Vec3::new(x, 0.05, 0.25)];
+        assert!(jaws_close_on(&solid, Vec3::ZERO, &tf, at(0.025)));
+        assert!(jaws_close_on(&solid, Vec3::ZERO, &tf, at(0.015)));
+        // still open, or closing beside the box
+        assert!(!jaws_close_on(&solid, Vec3::ZERO, &tf, at(0.04)));
+        let beside = at(0.02).map(|p| p + Vec3::Z * 0.05);
+        assert!(!jaws_close_on(&solid, Vec3::ZERO, &tf, beside));
+    }
+
+    #[test]
+    fn released_workpieces_stack() {
+        let cube = Solid::Box {
+            half_size: Vec3::splat(0.025),
+        };
+        let table = world_bounds(
+            &Solid::Box {
+                half_size: Vec3::new(0.3, 0.01, 0.2),
+            },
+            Vec3::ZERO,
+            &Transform::from_xyz(0.0, 0.3, 0.5),
+        );
+        let resting = world_bounds(&cube, Vec3::ZERO, &Transform::from_xyz(0.5, 0.025, 0.0));
+        let supports = [table, resting];
+
+        let above =
+            |x: f32, z: f32| world_bounds(&cube, Vec3::ZERO, &Transform::from_xyz(x, 0.8, z));
+        assert!((rest_height(above(0.1, 0.5), &supports) - 0.31).abs() < 1e-6);
+        assert!((rest_height(above(0.51, 0.01), &supports) - 0.05).abs() < 1e-6);
+        assert_eq!(rest_height(above(-0.5, 0.0), &supports), 0.0);
+        // below the table top
+        let under = world_bounds(&cube, Vec3::ZERO, &Transform::from_xyz(0.0, 0.1, 0.5));
+        assert_eq!(rest_height(under, &supports), 0.0);
+
+        // a rotated cylinder lies on its side
+        let cylinder = Solid::Cylinder {
+            radius: 0.02,
+            half_height: 0.05,
+        };
+        let lying = Transform::from_xyz(0.0, 0.5, 0.0)
+            .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2));
+        let (min, max) = world_bounds(&cylinder, Vec3::ZERO, &lying);
+        assert!((max.y - min.y - 0.04).abs() < 1e-5);
+        assert!((max.x - min.x - 0.1).abs() < 1e-5);
+    }
+
+    #[test]
+    fn swapped_tool_drops_what_it_holds() {
+        let mut world = World::new();
+        let tool = world
+            .spawn(TransformBundle::from_transform(Transform::from_xyz(
+                0.0, 0.5, 0.0,
+            )))
+            .id();
+        let held = world
+            .spawn((
+                TransformBundle {
+                    local: Transform::from_xyz(0.0, -0.1, 0.0),
+                    global: GlobalTransform::from_xyz(0.0, 0.4, 0.0),
+                },
+                Workpiece {
+                    shape: None,
+                    grasp: Some(Grasp {
+                        tool,
+                        jaws: [0.3, 0.3],
+                    }),
+                },
+            ))
+            .id();
+        world.entity_mut(tool).add_child(held);
+
+        WorkpiecePlugin::release_grasps(&mut world, tool);
+        world.entity_mut(tool).despawn_recursive();
+        let held = world.entity(held);
+        assert!(held.get::<Parent>().is_none());
+        assert!(held.contains::<Settling>());
+        assert!(held.get::<Workpiece>().unwrap().grasp.is_none());
+        assert_eq!(held.get::<Transform>().unwrap().translation, Vec3::Y * 0.4);
+    }
+}
@@ -0,0 +1,352 @@
+// Reachable workspace of the robots: the joint space is sampled, the tcp positions are binned in
+// voxels of the world holding the best manipulability reached there, and the shell of the voxels
+// is drawn colored from red ( near a singularity ) to green. The shared mode shows the voxels
+// reached by two robots or more. Sampling runs a slice per frame and restarts when a robot base moves.
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+};
+use bevy_egui::egui;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::robot_ur5::RobotUr5;
+
+const VOXEL_SIZE: f32 = 0.05; // m
+const SAMPLES: usize = 120_000;
+const FRAME_SAMPLES: usize = 4000;
+const JOINT_RANGE: f64 = 180.0; // deg, the joints repeat beyond
+const ALPHA: f32 = 0.6;
+
+// yoshikawa manipulability at the tcp, sqrt( det( J * J^T ) ) = | det( J ) |, 0 at a singularity
+// joints: rad
+pub fn manipulability(robot: &RobotUr5, joints: [f64; 6]) -> f64 {
+    robot.jacobian(joints).determinant().abs()
+}
+
+// Out: range [0.0, 1.0), the radical inverse of i
+fn halton(mut i: usize, base: usize) -> f64 {
+    let mut f = 1.0;
+    let mut out = 0.0;
+    while i > 0 {
+        f /= base as f64;
+        out += f * (i % base) as f64;
+        i /= base;
+    }
+    out
+}
+
+// i: sample index
+// Out: joints, rad, spread evenly, the last joint does not move the tcp
+fn sample(i: usize) -> [f64; 6] {
+    let mut joints = [0.0; 6];
+    for (j, base) in [2, 3, 5, 7, 11].into_iter().enumerate() {
+        joints[j] = ((halton(i + 1, base) * 2.0 - 1.0) * JOINT_RANGE).to_radians();
+    }
+    joints
+}
+
+// best manipulability per voxel of the world
+#[derive(Default, Clone, Debug)]
+pub struct VoxelGrid(pub HashMap<IVec3, f32>);
+
+impl VoxelGrid {
+    fn key(p: Vec3) -> IVec3 {
+        (p / VOXEL_SIZE).floor().as_ivec3()
+    }
+
+    pub fn insert(&mut self, p: Vec3, value: f32) {
+        let v = self.0.entry(VoxelGrid::key(p)).or_insert(value);
+        *v = v.max(value);
+    }
+
+    // voxels with a free face
+    pub fn shell(&self) -> impl Iterator<Item = (&IVec3, &f32)> {
+        self.0
+            .iter()
+            .filter(|(k, _)| FACES.iter().any(|(n, _)| !self.0.contains_key(&(**k + *n))))
+    }
+
+    // voxels in two grids or more, with the second best value
+    pub fn shared(grids: &[&VoxelGrid]) -> VoxelGrid {
+        let mut best: HashMap<IVec3, (f32, f32, usize)> = HashMap::new();
+        for grid in grids {
+            for (k, &v) in grid.0.iter() {
+                let (first, second, n) = best.entry(*k).or_insert((0.0, 0.0, 0));
+                if v > *first {
+                    *second = *first;
+                    *first = v;
+                } else {
+                    *second = second.max(v);
+                }
+                *n += 1;
+            }
+        }
+        VoxelGrid(
+            best.into_iter()
+                .filter(|(_, (_, _, n))| *n > 1)
+                .map(|(k, (_, second, _))| (k, second))
+                .collect(),
+        )
+    }
+}
+
+// ( neighbour, corners of the face seen from outside, counterclockwise ) in voxels
+const FACES: [(IVec3, [[f32; 3]; 4]); 6] = [
+    (
+        IVec3::X,
+        [[1., 0., 0.], [1., 1., 0.], [1., 1., 1.], [1., 0., 1.]],
+    ),
+    (
+        IVec3::NEG_X,
+        [[0., 0., 0.], [0., 0., 1.], [0., 1., 1.], [0., 1., 0.]],
+    ),
+    (
+        IVec3::Y,
+        [[0., 1., 0.], [0., 1., 1.], [1., 1., 1.], [1., 1., 0.]],
+    ),
+    (
+        IVec3::NEG_Y,
+        [[0., 0., 0.], [1., 0., 0.], [1., 0., 1.], [0., 0., 1.]],
+    ),
+    (
+        IVec3::Z,
+        [[0., 0., 1.], [1., 0., 1.], [1., 1., 1.], [0., 1., 1.]],
+    ),
+    (
+        IVec3::NEG_Z,
+        [[0., 0., 0.], [0., 1., 0.], [1., 1., 0.], [1., 0., 0.]],
+    ),
+];
+
+// t: range [0.0, 1.0], red to yellow to green
+fn heat(t: f32) -> [f32; 4] {
+    let t = t.clamp(0.0, 1.0);
+    [(2.0 - 2.0 * t).min(1.0), (2.0 * t).min(1.0), 0.1, ALPHA]
+}
+
+// the free faces of the voxels, colored by value / max
+fn shell_mesh(grids: &[&VoxelGrid], max: f32) -> Mesh {
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+    let mut indices = Vec::new();
+    for grid in grids {
+        for (k, &v) in grid.shell() {
+            let color = heat(v / max.max(f32::EPSILON));
+            for (n, corners) in FACES.iter() {
+                if grid.0.contains_key(&(*k + *n)) {
+                    continue;
+                }
+                let first = positions.len() as u32;
+                for c in corners {
+                    positions.push(((k.as_vec3() + Vec3::from(*c)) * VOXEL_SIZE).to_array());
+                    normals.push(n.as_vec3().to_array());
+                    colors.push(color);
+                }
+                indices.extend([0, 1, 2, 0, 2, 3].map(|i| first + i));
+            }
+        }
+    }
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum WorkspaceMode {
+    #[default]
+    Off,
+    Robots,
+    Shared,
+}
+
+impl WorkspaceMode {
+    const ALL: [WorkspaceMode; 3] = [
+        WorkspaceMode::Off,
+        WorkspaceMode::Robots,
+        WorkspaceMode::Shared,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            WorkspaceMode::Off => "Off",
+            WorkspaceMode::Robots => "Robots",
+            WorkspaceMode::Shared => "Shared",
+        }
+    }
+}
+
+// ( base in world, robot with its tool and model ), by id
+type Placement = Vec<(Transform, RobotUr5)>;
+
+fn same_placement(a: &Placement, b: &Placement) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b.iter()).all(|((base_a, a), (base_b, b))| {
+            base_a == base_b && a.id == b.id && a.tool == b.tool && a.same_model(b)
+        })
+}
+
+#[derive(Resource, Default)]
+pub struct Workspace {
+    pub mode: WorkspaceMode,
+    placement: Placement, // of the sampled grids
+    sampled: usize,
+    grids: BTreeMap<u64, VoxelGrid>,
+    max: f32,
+    shown: Option<(WorkspaceMode, Entity)>,
+}
+
+impl Workspace {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        egui::ComboBox::from_label("Workspace")
+            .selected_text(self.mode.name())
+            .show_ui(ui, |ui| {
+                for mode in WorkspaceMode::ALL {
+                    ui.selectable_value(&mut self.mode, mode, mode.name());
+                }
+            });
+        if self.mode != WorkspaceMode::Off && self.sampled < SAMPLES {
+            ui.label(format!("sampling {}%", self.sampled * 100 / SAMPLES));
+        }
+    }
+
+    // samples the next slice for every robot
+    fn sample(&mut self) {
+        let end = (self.sampled + FRAME_SAMPLES).min(SAMPLES);
+        for i in self.sampled..end {
+            let joints = sample(i);
+            for (base, robot) in self.placement.iter() {
+                let t = (robot.flange_at(joints) * robot.tool).translation.vector;
+                let p = base.transform_point(Vec3::new(t.x as f32, t.y as f32, t.z as f32));
+                // under the floor
+                if p.y < 0.0 {
+                    continue;
+                }
+                let w = manipulability(robot, joints) as f32;
+                self.max = self.max.max(w);
+                self.grids.entry(robot.id).or_default().insert(p, w);
+            }
+        }
+        self.sampled = end;
+    }
+}
+
+pub struct WorkspacePlugin;
+
+impl Plugin for WorkspacePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Workspace>()
+            .add_systems(Update, WorkspacePlugin::update);
+    }
+}
+
+impl WorkspacePlugin {
+    fn update(
+        mut commands: Commands,
+        mut workspace: ResMut<Workspace>,
+        mut meshes: ResMut<Assets<Mesh>>,
+        mut materials: ResMut<Assets<StandardMaterial>>,
+        q_robot: Query<(&RobotUr5, &GlobalTransform)>,
+    ) {
+        if workspace.mode == WorkspaceMode::Off {
+            if let Some((_, entity)) = workspace.shown.take() {
+                commands.entity(entity).despawn_recursive();
+            }
+            return;
+        }
+
+        let mut placement: Placement = q_robot
+            .iter()
+            .map(|(robot, gt)| (gt.compute_transform(), robot.clone()))
+            .collect();
+        placement.sort_by_key(|(_, robot)| robot.id);
+        if !same_placement(&placement, &workspace.placement) {
+            workspace.placement = placement;
+            workspace.sampled = 0;
+            workspace.grids.clear();
+            workspace.max = 0.0;
+        }
+        if workspace.sampled < SAMPLES {
+            workspace.sample();
+            if workspace.sampled < SAMPLES {
+                return;
+            }
+        } else if workspace.shown.map(|(mode, _)| mode) == Some(workspace.mode) {
+            return;
+        }
+
+        if let Some((_, entity)) = workspace.shown.take() {
+            commands.entity(entity).despawn_recursive();
+        }
+        let grids: Vec<&VoxelGrid> = workspace.grids.values().collect();
+        let mesh = match workspace.mode {
+            WorkspaceMode::Shared => shell_mesh(&[&VoxelGrid::shared(&grids)], workspace.max),
+            _ => shell_mesh(&grids, workspace.max),
+        };
+        let entity = commands
+            .spawn((
+                PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: materials.add(StandardMaterial {
+                        base_color: Color::WHITE,
+                        alpha_mode: AlphaMode::Blend,
+                        unlit: true,
+                        ..default()
+                    }),
+                    ..default()
+                },
+                Name::new("workspace"),
+            ))
+            .id();
+        workspace.shown = Some((workspace.mode, entity));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot_ur5::JOINTS_POS;
+
+    #[test]
+    fn manipulability_vanishes_at_singularities() {
+        let home = JOINTS_POS.map(f64::to_radians);
+        let robot = RobotUr5::new(0, home);
+        assert!(manipulability(&robot, home) > 1e-3);
+        // elbow stretched
+        let mut stretched = home;
+        stretched[2] = 0.0;
+        assert!(manipulability(&robot, stretched) < 1e-9);
+        // wrist 1 and 3 aligned
+        let mut wrist = home;
+        wrist[4] = 0.0;
+        assert!(manipulability(&robot, wrist) < 1e-9);
+    }
+
+    #[test]
+    fn shell_and_shared_voxels() {
+        let mut cube = VoxelGrid::default();
+        for x in 0..3 {
+            for y in 0..3 {
+                for z in 0..3 {
+                    let p = (Vec3::new(x as f32, y as f32, z as f32) + 0.5) * VOXEL_SIZE;
+                    cube.insert(p, x as f32);
+                }
+            }
+        }
+        assert_eq!(cube.0.len(), 27);
+        assert_eq!(cube.shell().count(), 26);
+
+        let mut other = VoxelGrid::default();
+        other.insert(Vec3::splat(0.5 * VOXEL_SIZE), 5.0);
+        other.insert(Vec3::splat(-0.5 * VOXEL_SIZE), 5.0);
+        let shared = VoxelGrid::shared(&[&cube, &other]);
+        assert_eq!(shared.0, HashMap::from([(IVec3::ZERO, 0.0)]));
+
+        // 26 voxels, 54 free faces of 4 vertices
+        let mesh = shell_mesh(&[&cube], 2.0);
+        assert_eq!(mesh.count_vertices(), 54 * 4);
+    }
+}